@@ -0,0 +1,176 @@
+//! Register map and compensation of the BMP280
+
+pub(crate) const CALIB00: u8 = 0x88;
+pub(crate) const ID: u8 = 0xd0;
+pub(crate) const RESET: u8 = 0xe0;
+pub(crate) const CTRL_MEAS: u8 = 0xf4;
+pub(crate) const CONFIG: u8 = 0xf5;
+pub(crate) const PRESS_MSB: u8 = 0xf7;
+
+pub(crate) const ID_VALUE: u8 = 0x58;
+pub(crate) const RESET_VALUE: u8 = 0xb6;
+
+/// Temperature oversampling x1, pressure oversampling x8 and normal mode,
+/// which measures continuously at around 40Hz.
+pub(crate) const CTRL_MEAS_CONTINUOUS: u8 = 0b001_100_11;
+/// Standby of 0.5ms between measurements and an IIR filter coefficient of 4.
+pub(crate) const CONFIG_FILTER_4: u8 = 0b000_010_00;
+
+pub(crate) const SAMPLE_HZ: f32 = 40.0;
+
+pub(crate) const CALIBRATION_LEN: usize = 24;
+/// Pressure and temperature, each 20 bits in three bytes.
+pub(crate) const DATA_LEN: usize = 6;
+
+/// Trimming parameters, stored in the chip at manufacture.
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+}
+
+impl Calibration {
+    pub(crate) fn parse(buf: &[u8; CALIBRATION_LEN]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let s = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]);
+
+        Self {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p2: s(8),
+            p3: s(10),
+            p4: s(12),
+            p5: s(14),
+            p6: s(16),
+            p7: s(18),
+            p8: s(20),
+            p9: s(22),
+        }
+    }
+
+    /// Returns the pressure in pascals and the temperature in degrees
+    /// Celsius, using the fixed point compensation from the data sheet.
+    pub(crate) fn compensate(&self, buf: &[u8; DATA_LEN]) -> (f32, f32) {
+        let raw = |i: usize| {
+            ((buf[i] as i32) << 12) | ((buf[i + 1] as i32) << 4) | ((buf[i + 2] as i32) >> 4)
+        };
+        let (adc_p, adc_t) = (raw(0), raw(3));
+
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+        let t_fine = var1 + var2;
+        let temperature = ((t_fine * 5 + 128) >> 8) as f32 / 100.0;
+
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * self.p6 as i64;
+        var2 += (var1 * self.p5 as i64) << 17;
+        var2 += (self.p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.p3 as i64) >> 8) + ((var1 * self.p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            // Avoids dividing by zero with an uncalibrated chip.
+            return (0.0, temperature);
+        }
+        let mut p = 1048576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (self.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (self.p8 as i64 * p) >> 19;
+        p = ((p + var1 + var2) >> 8) + ((self.p7 as i64) << 4);
+
+        // p is in pascals with 8 fractional bits.
+        (p as f32 / 256.0, temperature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(a - b < tolerance && b - a < tolerance, "{} != {}", a, b);
+    }
+
+    /// The trimming parameters of the data sheet's worked example, in
+    /// register order.
+    fn datasheet_calibration() -> [u8; CALIBRATION_LEN] {
+        let words = [
+            27504u16,
+            26435,
+            -1000i16 as u16,
+            36477,
+            -10685i16 as u16,
+            3024,
+            2855,
+            140,
+            -7i16 as u16,
+            15500,
+            -14600i16 as u16,
+            6000,
+        ];
+        let mut buf = [0; CALIBRATION_LEN];
+        for (bytes, word) in buf.chunks_exact_mut(2).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+
+        buf
+    }
+
+    #[test]
+    fn parses_calibration() {
+        let calibration = Calibration::parse(&datasheet_calibration());
+        assert_eq!(
+            (calibration.t1, calibration.t2, calibration.t3),
+            (27504, 26435, -1000)
+        );
+        assert_eq!(
+            [
+                calibration.p1 as i32,
+                calibration.p2 as i32,
+                calibration.p3 as i32,
+                calibration.p4 as i32,
+                calibration.p5 as i32,
+                calibration.p6 as i32,
+                calibration.p7 as i32,
+                calibration.p8 as i32,
+                calibration.p9 as i32,
+            ],
+            [36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000]
+        );
+    }
+
+    #[test]
+    fn compensates_the_datasheet_example() {
+        let calibration = Calibration::parse(&datasheet_calibration());
+        // adc_P = 415148 and adc_T = 519888, each in the top 20 bits of
+        // three bytes. The low four bits of the last byte aren't part of
+        // the reading.
+        let data = [0x65, 0x5a, 0xc0, 0x7e, 0xed, 0x0f];
+
+        let (pressure, temperature) = calibration.compensate(&data);
+        // t_fine is 128422.
+        assert_close(temperature, 25.08, 1e-3);
+        // The data sheet gives 100653.27 Pa. The 64 bit fixed point result is
+        // 25767233 / 256, within a few 256ths of a pascal of it.
+        assert_close(pressure, 100653.27, 0.05);
+    }
+
+    #[test]
+    fn uncalibrated_chip_reads_zero_pressure() {
+        let calibration = Calibration::parse(&[0; CALIBRATION_LEN]);
+        let (pressure, _) = calibration.compensate(&[0x65, 0x5a, 0xc0, 0x7e, 0xed, 0x00]);
+        assert_eq!(pressure, 0.0);
+    }
+}
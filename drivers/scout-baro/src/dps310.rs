@@ -0,0 +1,161 @@
+//! Register map and compensation of the DPS310
+
+pub(crate) const PSR_B2: u8 = 0x00;
+pub(crate) const PRS_CFG: u8 = 0x06;
+pub(crate) const TMP_CFG: u8 = 0x07;
+pub(crate) const MEAS_CFG: u8 = 0x08;
+pub(crate) const CFG_REG: u8 = 0x09;
+pub(crate) const RESET: u8 = 0x0c;
+pub(crate) const PRODUCT_ID: u8 = 0x0d;
+pub(crate) const COEF: u8 = 0x10;
+pub(crate) const COEF_SRCE: u8 = 0x28;
+
+pub(crate) const PRODUCT_ID_VALUE: u8 = 0x10;
+pub(crate) const RESET_SOFT_RST: u8 = 0b1001;
+
+pub(crate) const MEAS_CFG_COEF_RDY: u8 = 0b1000_0000;
+pub(crate) const MEAS_CFG_SENSOR_RDY: u8 = 0b0100_0000;
+/// Continuous pressure and temperature measurement.
+pub(crate) const MEAS_CFG_CONTINUOUS: u8 = 0b111;
+/// 32 measurements per second with 8 times oversampling.
+pub(crate) const PRS_CFG_32HZ_8X: u8 = 0b101_0011;
+/// 32 measurements per second without oversampling.
+pub(crate) const TMP_CFG_32HZ_1X: u8 = 0b101_0000;
+/// Set in TMP_CFG and COEF_SRCE when the calibration coefficients are for
+/// the external (MEMS) temperature sensor.
+pub(crate) const TMP_EXT: u8 = 0b1000_0000;
+
+pub(crate) const SAMPLE_HZ: f32 = 32.0;
+
+/// Compensation scale factors for the oversampling rates used.
+const PRESSURE_SCALE: f32 = 7864320.0;
+const TEMPERATURE_SCALE: f32 = 524288.0;
+
+pub(crate) const COEF_LEN: usize = 18;
+/// Pressure and temperature, each 24 bits.
+pub(crate) const DATA_LEN: usize = 6;
+
+/// Some chips report wildly wrong temperatures after power up, until this
+/// sequence is written. See Infineon's DPS310 errata.
+pub(crate) const TEMPERATURE_FIX: [(u8, u8); 5] = [
+    (0x0e, 0xa5),
+    (0x0f, 0x96),
+    (0x62, 0x02),
+    (0x0e, 0x00),
+    (0x0f, 0x00),
+];
+
+/// Calibration coefficients, stored in the chip at manufacture.
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct Coefficients {
+    c0: f32,
+    c1: f32,
+    c00: f32,
+    c10: f32,
+    c01: f32,
+    c11: f32,
+    c20: f32,
+    c21: f32,
+    c30: f32,
+}
+
+/// Sign extends the low `bits` bits of `value`.
+fn signed(value: u32, bits: u32) -> f32 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as f32
+}
+
+impl Coefficients {
+    pub(crate) fn parse(buf: &[u8; COEF_LEN]) -> Self {
+        let b = |i: usize| buf[i] as u32;
+        let word = |i: usize| signed((b(i) << 8) | b(i + 1), 16);
+
+        Self {
+            c0: signed((b(0) << 4) | (b(1) >> 4), 12),
+            c1: signed(((b(1) & 0x0f) << 8) | b(2), 12),
+            c00: signed((b(3) << 12) | (b(4) << 4) | (b(5) >> 4), 20),
+            c10: signed(((b(5) & 0x0f) << 16) | (b(6) << 8) | b(7), 20),
+            c01: word(8),
+            c11: word(10),
+            c20: word(12),
+            c21: word(14),
+            c30: word(16),
+        }
+    }
+
+    /// Returns the pressure in pascals and the temperature in degrees
+    /// Celsius.
+    pub(crate) fn compensate(&self, buf: &[u8; DATA_LEN]) -> (f32, f32) {
+        let raw = |i: usize| {
+            signed(
+                ((buf[i] as u32) << 16) | ((buf[i + 1] as u32) << 8) | buf[i + 2] as u32,
+                24,
+            )
+        };
+        let p = raw(0) / PRESSURE_SCALE;
+        let t = raw(3) / TEMPERATURE_SCALE;
+
+        let pressure = self.c00
+            + p * (self.c10 + p * (self.c20 + p * self.c30))
+            + t * self.c01
+            + t * p * (self.c11 + p * self.c21);
+        let temperature = self.c0 * 0.5 + self.c1 * t;
+
+        (pressure, temperature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(a - b < tolerance && b - a < tolerance, "{} != {}", a, b);
+    }
+
+    /// c0 = 204, c1 = -261, c00 = 80469, c10 = -54769, c01 = -2143,
+    /// c11 = 1380, c20 = -10011, c21 = 137 and c30 = -1263, packed as in the
+    /// COEF registers.
+    const COEF_BYTES: [u8; COEF_LEN] = [
+        0x0c, 0xce, 0xfb, 0x13, 0xa5, 0x5f, 0x2a, 0x0f, 0xf7, 0xa1, 0x05, 0x64, 0xd8, 0xe5, 0x00,
+        0x89, 0xfb, 0x11,
+    ];
+
+    #[test]
+    fn sign_extends() {
+        assert_eq!(signed(0x7ff, 12), 2047.0);
+        assert_eq!(signed(0x800, 12), -2048.0);
+        assert_eq!(signed(0xfff, 12), -1.0);
+        assert_eq!(signed(0x80000, 20), -524288.0);
+        assert_eq!(signed(0x7f_ffff, 24), 8388607.0);
+        // Bits above the field are ignored.
+        assert_eq!(signed(0xf_0001, 16), 1.0);
+    }
+
+    #[test]
+    fn parses_coefficients() {
+        let c = Coefficients::parse(&COEF_BYTES);
+        assert_eq!([c.c0, c.c1], [204.0, -261.0]);
+        assert_eq!([c.c00, c.c10], [80469.0, -54769.0]);
+        assert_eq!(
+            [c.c01, c.c11, c.c20, c.c21, c.c30],
+            [-2143.0, 1380.0, -10011.0, 137.0, -1263.0]
+        );
+    }
+
+    #[test]
+    fn compensates_pressure_and_temperature() {
+        let coefficients = Coefficients::parse(&COEF_BYTES);
+
+        // Without a reading, only the offsets are left.
+        let (pressure, temperature) = coefficients.compensate(&[0; DATA_LEN]);
+        assert_eq!(pressure, 80469.0);
+        assert_eq!(temperature, 102.0);
+
+        // Scaled readings of -0.3 and 0.25, worked through the data sheet's
+        // formulas by hand.
+        let data = [0xdc, 0x00, 0x00, 0x02, 0x00, 0x00];
+        let (pressure, temperature) = coefficients.compensate(&data);
+        assert_close(pressure, 95396.64, 0.02);
+        assert_close(temperature, 36.75, 1e-4);
+    }
+}
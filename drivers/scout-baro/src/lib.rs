@@ -0,0 +1,203 @@
+#![no_std]
+
+use embedded_hal::spi;
+use embedded_hal_async::{
+    delay::DelayUs,
+    spi::{transaction, SpiBus, SpiBusRead, SpiBusWrite, SpiDevice},
+};
+
+mod bmp280;
+mod dps310;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Chip {
+    Bmp280,
+    Dps310,
+}
+
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Sample {
+    /// Pressure in pascals.
+    pub pressure: f32,
+    /// Temperature in degrees Celsius.
+    pub temperature: f32,
+}
+
+#[derive(defmt::Format)]
+pub enum Error<SPIError> {
+    Spi(SPIError),
+    Delay,
+    /// Neither chip's ID register matched. Holds the value of the BMP280's.
+    UnknownChip(u8),
+    /// The chip did not finish starting up.
+    NotReady,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+enum Calibration {
+    Bmp280(bmp280::Calibration),
+    Dps310(dps310::Coefficients),
+}
+
+pub struct Baro<SPI> {
+    spi: SPI,
+    calibration: Calibration,
+}
+
+impl<SPI> Baro<SPI>
+where
+    SPI: SpiDevice,
+    SPI::Bus: SpiBus<u8>,
+{
+    /// Resets and identifies the chip, reads its calibration, and starts
+    /// continuous measurement.
+    pub async fn new<DELAY: DelayUs>(
+        spi: SPI,
+        mut delay: DELAY,
+    ) -> Result<Self, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut baro = Self {
+            spi,
+            // Overwritten once the chip is identified.
+            calibration: Calibration::Bmp280(bmp280::Calibration::parse(
+                &[0; bmp280::CALIBRATION_LEN],
+            )),
+        };
+
+        let id = baro.read_register(bmp280::ID).await?;
+        if id == bmp280::ID_VALUE {
+            baro.write_register(bmp280::RESET, bmp280::RESET_VALUE)
+                .await?;
+            delay.delay_ms(5).await.map_err(|_| Error::Delay)?;
+
+            let mut buf = [0; bmp280::CALIBRATION_LEN];
+            baro.read_registers(bmp280::CALIB00, &mut buf).await?;
+            baro.calibration = Calibration::Bmp280(bmp280::Calibration::parse(&buf));
+
+            baro.write_register(bmp280::CONFIG, bmp280::CONFIG_FILTER_4)
+                .await?;
+            baro.write_register(bmp280::CTRL_MEAS, bmp280::CTRL_MEAS_CONTINUOUS)
+                .await?;
+        } else if baro.read_register(dps310::PRODUCT_ID).await? == dps310::PRODUCT_ID_VALUE {
+            baro.write_register(dps310::RESET, dps310::RESET_SOFT_RST)
+                .await?;
+            // The data sheet gives 40ms for the coefficients to become
+            // available after a reset.
+            let ready = dps310::MEAS_CFG_COEF_RDY | dps310::MEAS_CFG_SENSOR_RDY;
+            let mut attempts = 0;
+            while baro.read_register(dps310::MEAS_CFG).await? & ready != ready {
+                attempts += 1;
+                if attempts > 10 {
+                    return Err(Error::NotReady);
+                }
+                delay.delay_ms(10).await.map_err(|_| Error::Delay)?;
+            }
+
+            let mut buf = [0; dps310::COEF_LEN];
+            baro.read_registers(dps310::COEF, &mut buf).await?;
+            baro.calibration = Calibration::Dps310(dps310::Coefficients::parse(&buf));
+
+            for (register, value) in dps310::TEMPERATURE_FIX {
+                baro.write_register(register, value).await?;
+            }
+
+            // The temperature sensor must be the one the coefficients were
+            // calibrated against.
+            let tmp_ext = baro.read_register(dps310::COEF_SRCE).await? & dps310::TMP_EXT;
+            baro.write_register(dps310::PRS_CFG, dps310::PRS_CFG_32HZ_8X)
+                .await?;
+            baro.write_register(dps310::TMP_CFG, dps310::TMP_CFG_32HZ_1X | tmp_ext)
+                .await?;
+            // Result shifts are only needed above 8 times oversampling.
+            baro.write_register(dps310::CFG_REG, 0).await?;
+            baro.write_register(dps310::MEAS_CFG, dps310::MEAS_CFG_CONTINUOUS)
+                .await?;
+        } else {
+            return Err(Error::UnknownChip(id));
+        }
+
+        Ok(baro)
+    }
+
+    pub fn chip(&self) -> Chip {
+        match self.calibration {
+            Calibration::Bmp280(_) => Chip::Bmp280,
+            Calibration::Dps310(_) => Chip::Dps310,
+        }
+    }
+
+    /// Rate at which the chip produces new measurements. Reading faster
+    /// than this returns repeated samples.
+    pub fn sample_hz(&self) -> f32 {
+        match self.calibration {
+            Calibration::Bmp280(_) => bmp280::SAMPLE_HZ,
+            Calibration::Dps310(_) => dps310::SAMPLE_HZ,
+        }
+    }
+
+    /// Reads the latest measurement.
+    pub async fn read(&mut self) -> Result<Sample, Error<<SPI as spi::ErrorType>::Error>> {
+        let (pressure, temperature) = match self.calibration {
+            Calibration::Bmp280(calibration) => {
+                let mut buf = [0; bmp280::DATA_LEN];
+                self.read_registers(bmp280::PRESS_MSB, &mut buf).await?;
+                calibration.compensate(&buf)
+            }
+            Calibration::Dps310(coefficients) => {
+                let mut buf = [0; dps310::DATA_LEN];
+                self.read_registers(dps310::PSR_B2, &mut buf).await?;
+                coefficients.compensate(&buf)
+            }
+        };
+
+        Ok(Sample {
+            pressure,
+            temperature,
+        })
+    }
+
+    async fn read_register(
+        &mut self,
+        register: u8,
+    ) -> Result<u8, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut buf = [register | READ, 0];
+
+        self.spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(Error::Spi)?;
+
+        Ok(buf[1])
+    }
+
+    async fn read_registers(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        transaction!(&mut self.spi, move |bus| async move {
+            bus.write(&[register | READ]).await?;
+
+            bus.read(buf).await?;
+
+            Ok(())
+        })
+        .await
+        .map_err(Error::Spi)
+    }
+
+    async fn write_register(
+        &mut self,
+        register: u8,
+        value: u8,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        // The BMP280's registers are all above 0x80, and it expects the
+        // high bit of the address to be cleared for writes.
+        self.spi
+            .write(&[register & !READ, value])
+            .await
+            .map_err(Error::Spi)
+    }
+}
+
+/// Set in the register address byte to read rather than write.
+const READ: u8 = 0b1000_0000;
@@ -0,0 +1,215 @@
+#![no_std]
+
+//! Driver for SPI NOR flash chips with the common JEDEC command set, such as
+//! the Winbond W25Q and Macronix MX25L series.
+//!
+//! Erased bytes read as 0xFF, and programming can only clear bits, so
+//! memory must be erased before it is written again. Only the first 16 MiB
+//! are addressed, since larger chips need four byte addresses.
+
+use embedded_hal::spi;
+use embedded_hal_async::{
+    delay::DelayUs,
+    spi::{transaction, SpiBus, SpiBusRead, SpiBusWrite, SpiDevice},
+};
+
+/// Programming must not cross a page boundary.
+pub const PAGE_SIZE: u32 = 256;
+/// The smallest unit which can be erased.
+pub const SECTOR_SIZE: u32 = 4096;
+
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS_1: u8 = 0x05;
+const STATUS_1_BUSY: u8 = 1 << 0;
+const READ_DATA: u8 = 0x03;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE: u8 = 0xC7;
+const RELEASE_POWER_DOWN: u8 = 0xAB;
+const JEDEC_ID: u8 = 0x9F;
+
+const MAX_CAPACITY: u32 = 1 << 24;
+
+#[derive(defmt::Format)]
+pub enum Error<SPIError> {
+    Spi(SPIError),
+    Delay,
+    /// No chip answered, or it didn't give its capacity.
+    UnknownChip([u8; 3]),
+    /// The address or length is outside the chip, or a write crosses a page
+    /// boundary.
+    OutOfRange,
+}
+
+pub struct SpiFlash<SPI, DELAY> {
+    spi: SPI,
+    delay: DELAY,
+    jedec_id: [u8; 3],
+    capacity: u32,
+}
+
+impl<SPI, DELAY> SpiFlash<SPI, DELAY>
+where
+    SPI: SpiDevice,
+    SPI::Bus: SpiBus<u8>,
+    DELAY: DelayUs,
+{
+    /// Wakes the chip in case it was powered down, and identifies it.
+    pub async fn new(
+        spi: SPI,
+        delay: DELAY,
+    ) -> Result<Self, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut flash = Self {
+            spi,
+            delay,
+            jedec_id: [0; 3],
+            capacity: 0,
+        };
+
+        flash.command(RELEASE_POWER_DOWN).await?;
+        // tRES1 is at most 3us on every supported chip.
+        flash.delay.delay_us(10).await.map_err(|_| Error::Delay)?;
+
+        let mut buf = [JEDEC_ID, 0, 0, 0];
+        flash
+            .spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(Error::Spi)?;
+        let jedec_id = [buf[1], buf[2], buf[3]];
+        // The last byte is the base two logarithm of the capacity in bytes.
+        if jedec_id[0] == 0x00 || jedec_id[0] == 0xFF || !(16..32).contains(&jedec_id[2]) {
+            return Err(Error::UnknownChip(jedec_id));
+        }
+        flash.jedec_id = jedec_id;
+        flash.capacity = (1 << jedec_id[2]).min(MAX_CAPACITY);
+
+        Ok(flash)
+    }
+
+    /// Manufacturer, memory type and capacity codes.
+    pub fn jedec_id(&self) -> [u8; 3] {
+        self.jedec_id
+    }
+
+    /// Capacity in bytes.
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub async fn read(
+        &mut self,
+        address: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.check_range(address, buf.len())?;
+
+        let command = address_command(READ_DATA, address);
+        transaction!(&mut self.spi, move |bus| async move {
+            bus.write(&command).await?;
+
+            bus.read(buf).await?;
+
+            Ok(())
+        })
+        .await
+        .map_err(Error::Spi)
+    }
+
+    /// Programs `data` at `address`, which must have been erased. The data
+    /// must not cross a page boundary. Returns once programming is finished.
+    pub async fn program(
+        &mut self,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.check_range(address, data.len())?;
+        if address % PAGE_SIZE + data.len() as u32 > PAGE_SIZE {
+            return Err(Error::OutOfRange);
+        }
+
+        self.command(WRITE_ENABLE).await?;
+        let command = address_command(PAGE_PROGRAM, address);
+        transaction!(&mut self.spi, move |bus| async move {
+            bus.write(&command).await?;
+
+            bus.write(data).await?;
+
+            Ok(())
+        })
+        .await
+        .map_err(Error::Spi)?;
+
+        // Page programming takes under a millisecond.
+        self.wait(100).await
+    }
+
+    /// Erases the sector containing `address`.
+    pub async fn erase_sector(
+        &mut self,
+        address: u32,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.check_range(address, 0)?;
+
+        self.command(WRITE_ENABLE).await?;
+        self.spi
+            .write(&address_command(SECTOR_ERASE, address))
+            .await
+            .map_err(Error::Spi)?;
+
+        self.wait(1000).await
+    }
+
+    /// Erases the whole chip, which takes from seconds to minutes depending
+    /// on its size.
+    pub async fn erase_chip(&mut self) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.command(WRITE_ENABLE).await?;
+        self.command(CHIP_ERASE).await?;
+
+        self.wait(100_000).await
+    }
+
+    async fn command(&mut self, command: u8) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.spi.write(&[command]).await.map_err(Error::Spi)
+    }
+
+    /// Polls the busy flag every `interval_us` until the chip finishes
+    /// programming or erasing, leaving the bus free in between.
+    async fn wait(
+        &mut self,
+        interval_us: u32,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        loop {
+            let mut buf = [READ_STATUS_1, 0];
+            self.spi
+                .transfer_in_place(&mut buf)
+                .await
+                .map_err(Error::Spi)?;
+            if buf[1] & STATUS_1_BUSY == 0 {
+                return Ok(());
+            }
+
+            self.delay
+                .delay_us(interval_us)
+                .await
+                .map_err(|_| Error::Delay)?;
+        }
+    }
+
+    fn check_range(
+        &self,
+        address: u32,
+        len: usize,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        match address.checked_add(len as u32) {
+            Some(end) if address < self.capacity && end <= self.capacity => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+}
+
+fn address_command(command: u8, address: u32) -> [u8; 4] {
+    let [_, high, middle, low] = address.to_be_bytes();
+
+    [command, high, middle, low]
+}
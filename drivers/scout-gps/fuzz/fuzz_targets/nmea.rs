@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use scout_gps::nmea::Parser;
+use scout_gps::{FixType, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::new();
+    for byte in data {
+        if let Some(Packet::Fix(fix)) = parser.push(*byte) {
+            if fix.fix_type == FixType::NoFix {
+                assert_eq!((fix.latitude, fix.longitude), (0, 0));
+            }
+            assert!(fix.altitude.is_finite());
+            assert!(fix.velocity.iter().all(|v| v.is_finite()));
+        }
+    }
+});
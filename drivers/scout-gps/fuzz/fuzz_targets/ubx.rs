@@ -0,0 +1,36 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use scout_gps::ubx::{Command, DynamicModel, MessageId, Parser, MAX_FRAME_LEN};
+use scout_gps::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::new();
+    for byte in data {
+        if let Some(Packet::Fix(fix)) = parser.push(*byte) {
+            assert!(fix.horizontal_accuracy.unwrap() >= 0.0);
+            assert!(fix.climb_rate.unwrap().is_finite());
+        }
+    }
+
+    // Commands with any parameters fit a frame.
+    if let [a, b, c, d, ..] = *data {
+        for command in [
+            Command::ConfigureUart {
+                baud_rate: u32::from_le_bytes([a, b, c, d]),
+            },
+            Command::SetRate {
+                period_ms: u16::from_le_bytes([a, b]),
+            },
+            Command::SetMessageRate {
+                message: MessageId { class: a, id: b },
+                rate: c,
+            },
+            Command::SetDynamicModel(DynamicModel::Airborne4g),
+        ] {
+            let mut buf = [0; MAX_FRAME_LEN];
+            let len = command.encode(&mut buf);
+            assert_eq!(u16::from_le_bytes([buf[4], buf[5]]) as usize, len - 8);
+        }
+    }
+});
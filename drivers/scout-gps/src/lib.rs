@@ -0,0 +1,96 @@
+#![no_std]
+
+pub mod nmea;
+pub mod ubx;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FixType {
+    NoFix,
+    Fix2d,
+    Fix3d,
+}
+
+/// A position and velocity solution.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Fix {
+    pub fix_type: FixType,
+    /// Satellites used in the solution.
+    pub satellites: u8,
+    /// In units of 1e-7 degrees, positive north.
+    pub latitude: i32,
+    /// In units of 1e-7 degrees, positive east.
+    pub longitude: i32,
+    /// Height above mean sea level, in meters.
+    pub altitude: f32,
+    /// North and east velocity, in meters per second.
+    pub velocity: [f32; 2],
+    /// In meters per second, positive upwards. Not available from NMEA.
+    pub climb_rate: Option<f32>,
+    /// Estimated horizontal position accuracy, in meters. Not available from
+    /// NMEA.
+    pub horizontal_accuracy: Option<f32>,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Packet {
+    Fix(Fix),
+    /// A UBX command was accepted.
+    Ack(ubx::MessageId),
+    /// A UBX command was rejected.
+    Nak(ubx::MessageId),
+}
+
+/// Parses both UBX and NMEA, so the protocol doesn't need to be known in
+/// advance.
+pub struct Parser {
+    ubx: ubx::Parser,
+    nmea: nmea::Parser,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            ubx: ubx::Parser::new(),
+            nmea: nmea::Parser::new(),
+        }
+    }
+
+    /// Returns a packet when `byte` completes a message of a supported type.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        let ubx = self.ubx.push(byte);
+        let nmea = self.nmea.push(byte);
+
+        ubx.or(nmea)
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_both_protocols() {
+        // An acknowledgement, which a receiver still sending NMEA after a
+        // restart gives to the first configuration command.
+        const ACK: [u8; 10] = [0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x08, 0x16, 0x3f];
+        const GGA: &[u8] = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+
+        let mut parser = Parser::new();
+        let mut packets = GGA
+            .iter()
+            .chain(&ACK)
+            .chain(GGA)
+            .filter_map(|byte| parser.push(*byte));
+
+        assert!(matches!(packets.next(), Some(Packet::Fix(fix)) if fix.latitude == 481_173_000));
+        assert!(matches!(packets.next(), Some(Packet::Ack(ubx::CFG_RATE))));
+        assert!(matches!(packets.next(), Some(Packet::Fix(_))));
+        assert!(packets.next().is_none());
+    }
+}
@@ -0,0 +1,362 @@
+//! NMEA 0183
+//!
+//! Sentences are ASCII lines laid out as `$TTSSS,field,..*CC`, where `TT`
+//! identifies the satellite system, `SSS` the sentence type and `CC` is the
+//! XOR of the characters between `$` and `*` in hexadecimal.
+//!
+//! The position comes from GGA sentences and the velocity from RMC
+//! sentences. Receivers send RMC before GGA, so each GGA sentence completes a
+//! fix with the velocity from the latest RMC sentence. GGA doesn't
+//! distinguish 2D and 3D fixes, so every fix is reported as 3D.
+
+use crate::{Fix, FixType, Packet};
+
+/// NMEA 0183 limits sentences to 82 characters, including the line ending.
+pub const MAX_SENTENCE_LEN: usize = 82;
+
+const KNOTS_TO_METERS_PER_SECOND: f32 = 1852.0 / 3600.0;
+
+pub struct Parser {
+    buf: [u8; MAX_SENTENCE_LEN],
+    len: usize,
+    /// North and east velocity from the latest valid RMC sentence.
+    velocity: [f32; 2],
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_SENTENCE_LEN],
+            len: 0,
+            velocity: [0.0; 2],
+        }
+    }
+
+    /// Returns a packet when `byte` completes a GGA sentence.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        match byte {
+            b'$' => {
+                self.len = 0;
+                None
+            }
+            b'\r' | b'\n' if self.len > 0 => {
+                let len = self.len;
+                self.len = 0;
+                let sentence = core::str::from_utf8(&self.buf[..len]).ok()?;
+                parse(sentence, &mut self.velocity)
+            }
+            _ if self.len < MAX_SENTENCE_LEN => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+            // Overlong sentences are truncated, and then won't parse.
+            _ => None,
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `sentence` excludes the leading `$` and the line ending. `velocity` is
+/// updated by RMC sentences, and used by GGA sentences.
+fn parse(sentence: &str, velocity: &mut [f32; 2]) -> Option<Packet> {
+    let (body, checksum) = sentence.split_once('*')?;
+    let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+    if body.bytes().fold(0, |checksum, byte| checksum ^ byte) != expected {
+        return None;
+    }
+
+    let mut fields = body.split(',');
+    let sentence_type = fields.next()?.get(2..)?;
+    let mut field = || fields.next().unwrap_or("");
+    match sentence_type {
+        "GGA" => {
+            let _time = field();
+            let latitude = coordinate(field(), field(), "S");
+            let longitude = coordinate(field(), field(), "W");
+            let quality: u8 = field().parse().ok()?;
+            let satellites = field().parse().unwrap_or(0);
+            let _hdop = field();
+            let altitude = number(field()).unwrap_or(0.0);
+
+            let (fix_type, latitude, longitude) = match (quality, latitude, longitude) {
+                (1.., Some(latitude), Some(longitude)) => (FixType::Fix3d, latitude, longitude),
+                _ => (FixType::NoFix, 0, 0),
+            };
+
+            Some(Packet::Fix(Fix {
+                fix_type,
+                satellites,
+                latitude,
+                longitude,
+                altitude,
+                velocity: *velocity,
+                climb_rate: None,
+                horizontal_accuracy: None,
+            }))
+        }
+        "RMC" => {
+            let _time = field();
+            let status = field();
+            for _ in 0..4 {
+                field();
+            }
+            let speed = number(field());
+            let course = number(field());
+
+            *velocity = match (status, speed) {
+                ("A", Some(speed)) => {
+                    // The course is left empty when stationary.
+                    let course = course.unwrap_or(0.0).to_radians();
+                    let speed = speed * KNOTS_TO_METERS_PER_SECOND;
+                    [speed * libm::cosf(course), speed * libm::sinf(course)]
+                }
+                _ => [0.0; 2],
+            };
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Parses a decimal field, which Rust would otherwise accept as `inf` or
+/// `NaN`.
+fn number(field: &str) -> Option<f32> {
+    field
+        .parse::<f32>()
+        .ok()
+        .filter(|number| number.is_finite())
+}
+
+/// Parses a latitude (`ddmm.mmmm`) or longitude (`dddmm.mmmm`) into units of
+/// 1e-7 degrees, negated when `hemisphere` is `negative`.
+fn coordinate(value: &str, hemisphere: &str, negative: &str) -> Option<i32> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if whole.is_empty() || whole.len() > 5 || !whole.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let whole: i64 = whole.parse().ok()?;
+    let degrees = whole / 100;
+    if degrees > 180 || whole % 100 >= 60 {
+        return None;
+    }
+
+    // Minutes in units of 1e-7.
+    let mut fraction_minutes: i64 = 0;
+    let mut scale = 1_000_000;
+    for digit in fraction.bytes().take(7) {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        fraction_minutes += (digit - b'0') as i64 * scale;
+        scale /= 10;
+    }
+
+    let minutes = (whole % 100) * 10_000_000 + fraction_minutes;
+    let coordinate = degrees * 10_000_000 + minutes / 60;
+
+    Some(if hemisphere == negative {
+        -coordinate
+    } else {
+        coordinate
+    } as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GGA: &[u8] = b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+    const RMC: &[u8] = b"$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n";
+    /// Sent by a u-blox receiver before its first fix.
+    const GGA_NO_FIX: &[u8] = b"$GPGGA,,,,,,0,00,99.99,,,,,,*48\r\n";
+    const RMC_NO_FIX: &[u8] = b"$GPRMC,,V,,,,,,,,,,N*53\r\n";
+    /// Multi-GNSS sentences in the southern and western hemispheres, with
+    /// the course left empty while stationary.
+    const GN_RMC: &[u8] = b"$GNRMC,083559.00,A,3352.12345,S,15112.54321,W,0.004,,091202,,,A*6E\r\n";
+    const GN_GGA: &[u8] =
+        b"$GNGGA,083559.00,3352.12345,S,15112.54321,W,1,12,0.72,32.4,M,21.0,M,,*7E\r\n";
+    const GN_GSA: &[u8] = b"$GNGSA,A,3,10,16,21,26,,,,,,,,,1.31,0.72,1.09*13\r\n";
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(libm::fabsf(a - b) < 1e-3, "{} != {}", a, b);
+    }
+
+    /// Pushes every byte, returning the number of packets and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Packet>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), packet| (count + 1, Some(packet)))
+    }
+
+    fn fix(packet: Option<Packet>) -> Fix {
+        match packet {
+            Some(Packet::Fix(fix)) => fix,
+            _ => panic!("not a fix"),
+        }
+    }
+
+    #[test]
+    fn decodes_gga() {
+        let (count, packet) = push_all(&mut Parser::new(), GGA);
+        let fix = fix(packet);
+
+        assert_eq!(count, 1);
+        assert!(fix.fix_type == FixType::Fix3d);
+        assert_eq!(fix.satellites, 8);
+        assert_eq!(fix.latitude, 481_173_000);
+        assert_eq!(fix.longitude, 115_166_666);
+        assert_close(fix.altitude, 545.4);
+        assert_eq!(fix.velocity, [0.0; 2]);
+        assert!(fix.climb_rate.is_none());
+        assert!(fix.horizontal_accuracy.is_none());
+    }
+
+    #[test]
+    fn velocity_comes_from_rmc() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, RMC).0, 0);
+        let velocity = fix(push_all(&mut parser, GGA).1).velocity;
+
+        // 22.4 knots towards 84.4 degrees.
+        let speed = 22.4 * KNOTS_TO_METERS_PER_SECOND;
+        assert_close(velocity[0], speed * libm::cosf(84.4f32.to_radians()));
+        assert_close(velocity[1], speed * libm::sinf(84.4f32.to_radians()));
+
+        // Until an RMC sentence without a fix.
+        push_all(&mut parser, RMC_NO_FIX);
+        assert_eq!(fix(push_all(&mut parser, GGA).1).velocity, [0.0; 2]);
+    }
+
+    #[test]
+    fn decodes_southern_and_western_hemispheres() {
+        let mut parser = Parser::new();
+        push_all(&mut parser, GN_RMC);
+        let fix = fix(push_all(&mut parser, GN_GGA).1);
+
+        assert!(fix.fix_type == FixType::Fix3d);
+        assert_eq!(fix.satellites, 12);
+        assert_eq!(fix.latitude, -338_687_241);
+        assert_eq!(fix.longitude, -1_512_090_535);
+        assert_close(fix.altitude, 32.4);
+        // No course, so all of the speed is north.
+        assert_close(fix.velocity[0], 0.004 * KNOTS_TO_METERS_PER_SECOND);
+        assert_close(fix.velocity[1], 0.0);
+    }
+
+    #[test]
+    fn decodes_no_fix() {
+        let fix = fix(push_all(&mut Parser::new(), GGA_NO_FIX).1);
+
+        assert!(fix.fix_type == FixType::NoFix);
+        assert_eq!(fix.satellites, 0);
+        assert_eq!((fix.latitude, fix.longitude), (0, 0));
+    }
+
+    #[test]
+    fn ignores_other_sentences() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, GN_GSA).0, 0);
+        assert_eq!(push_all(&mut parser, RMC).0, 0);
+        assert_eq!(push_all(&mut parser, GGA).0, 1);
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            push_all(
+                &mut parser,
+                b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48\r\n"
+            )
+            .0,
+            0
+        );
+        // A changed digit.
+        assert_eq!(
+            push_all(
+                &mut parser,
+                b"$GPGGA,123519,4807.039,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n"
+            )
+            .0,
+            0
+        );
+        // No checksum.
+        assert_eq!(
+            push_all(
+                &mut parser,
+                b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,\r\n"
+            )
+            .0,
+            0
+        );
+        assert_eq!(push_all(&mut parser, GGA).0, 1);
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() {
+        let mut parser = Parser::new();
+        let altitude = fix(push_all(
+            &mut parser,
+            b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,inf,M,46.9,M,,*08\r\n",
+        )
+        .1)
+        .altitude;
+        assert_eq!(altitude, 0.0);
+
+        push_all(
+            &mut parser,
+            b"$GPRMC,123519,A,4807.038,N,01131.000,E,NaN,084.4,230394,003.1,W*21\r\n",
+        );
+        assert_eq!(fix(push_all(&mut parser, GGA).1).velocity, [0.0; 2]);
+
+        // Too large for an f32.
+        push_all(
+            &mut parser,
+            b"$GPRMC,123519,A,4807.038,N,01131.000,E,1e39,084.4,230394,003.1,W*1E\r\n",
+        );
+        assert_eq!(fix(push_all(&mut parser, GGA).1).velocity, [0.0; 2]);
+    }
+
+    #[test]
+    fn resyncs_on_dollar() {
+        // A sentence cut short by the receiver restarting.
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &RMC[..30]).0, 0);
+        assert_eq!(push_all(&mut parser, GGA).0, 1);
+    }
+
+    #[test]
+    fn ignores_overlong_sentences() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, b"$GPTXT").0, 0);
+        assert_eq!(push_all(&mut parser, &[b','; 100]).0, 0);
+        assert_eq!(push_all(&mut parser, b"\r\n").0, 0);
+        assert_eq!(push_all(&mut parser, GGA).0, 1);
+    }
+
+    #[test]
+    fn parses_coordinates() {
+        assert_eq!(coordinate("4807.038", "N", "S"), Some(481_173_000));
+        assert_eq!(coordinate("4807.038", "S", "S"), Some(-481_173_000));
+        assert_eq!(coordinate("00000.0000", "E", "W"), Some(0));
+        assert_eq!(coordinate("18000.0000", "W", "W"), Some(-1_800_000_000));
+        // Without a fraction, and with more digits than are kept.
+        assert_eq!(coordinate("4807", "N", "S"), Some(481_166_666));
+        assert_eq!(coordinate("4807.123456789", "N", "S"), Some(481_187_242));
+
+        assert_eq!(coordinate("", "N", "S"), None);
+        assert_eq!(coordinate("4860.000", "N", "S"), None);
+        assert_eq!(coordinate("18100.000", "E", "W"), None);
+        assert_eq!(coordinate("123456.0", "N", "S"), None);
+        assert_eq!(coordinate("48a7.038", "N", "S"), None);
+        assert_eq!(coordinate("4807.0x8", "N", "S"), None);
+        assert_eq!(coordinate("-4807.038", "N", "S"), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! u-blox UBX protocol
+//!
+//! Frames are laid out as `[0xb5, 0x62, class, id, length (2 bytes), payload..,
+//! checksum (2 bytes)]`, with multi-byte fields little-endian. The checksum
+//! is an 8-bit Fletcher checksum over the class, id, length and payload.
+
+use crate::{Fix, FixType, Packet};
+
+/// The largest payload which is parsed. Longer messages are ignored.
+pub const MAX_PAYLOAD_LEN: usize = 100;
+/// The largest frame, including the header and checksum.
+pub const MAX_FRAME_LEN: usize = MAX_PAYLOAD_LEN + 8;
+
+const SYNC: [u8; 2] = [0xb5, 0x62];
+const HEADER_LEN: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MessageId {
+    pub class: u8,
+    pub id: u8,
+}
+
+pub const NAV_PVT: MessageId = MessageId {
+    class: 0x01,
+    id: 0x07,
+};
+pub const ACK_NAK: MessageId = MessageId {
+    class: 0x05,
+    id: 0x00,
+};
+pub const ACK_ACK: MessageId = MessageId {
+    class: 0x05,
+    id: 0x01,
+};
+pub const CFG_PRT: MessageId = MessageId {
+    class: 0x06,
+    id: 0x00,
+};
+pub const CFG_MSG: MessageId = MessageId {
+    class: 0x06,
+    id: 0x01,
+};
+pub const CFG_RATE: MessageId = MessageId {
+    class: 0x06,
+    id: 0x08,
+};
+pub const CFG_NAV5: MessageId = MessageId {
+    class: 0x06,
+    id: 0x24,
+};
+
+const NAV_PVT_LEN: usize = 92;
+const ACK_LEN: usize = 2;
+
+/// `fixType` values in NAV-PVT.
+const FIX_TYPE_2D: u8 = 2;
+const FIX_TYPE_3D: u8 = 3;
+const FIX_TYPE_GNSS_DEAD_RECKONING: u8 = 4;
+/// `flags` bit set when the fix is within the configured accuracy limits.
+const FLAGS_GNSS_FIX_OK: u8 = 0x01;
+
+pub struct Parser {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns a packet when `byte` completes a frame of a supported type.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        if self.len < SYNC.len() && byte != SYNC[self.len] {
+            // A repeated first sync byte may still start a frame.
+            self.len = (byte == SYNC[0]) as usize;
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < HEADER_LEN {
+            return None;
+        }
+        let payload_len = u16::from_le_bytes([self.buf[4], self.buf[5]]) as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            self.len = 0;
+            return None;
+        }
+        if self.len < HEADER_LEN + payload_len + 2 {
+            return None;
+        }
+        self.len = 0;
+
+        let checksum_idx = HEADER_LEN + payload_len;
+        if checksum(&self.buf[2..checksum_idx])
+            != [self.buf[checksum_idx], self.buf[checksum_idx + 1]]
+        {
+            return None;
+        }
+
+        let id = MessageId {
+            class: self.buf[2],
+            id: self.buf[3],
+        };
+        let payload = &self.buf[HEADER_LEN..checksum_idx];
+        match id {
+            NAV_PVT if payload.len() == NAV_PVT_LEN => Some(Packet::Fix(parse_nav_pvt(payload))),
+            ACK_ACK | ACK_NAK if payload.len() == ACK_LEN => {
+                let acked = MessageId {
+                    class: payload[0],
+                    id: payload[1],
+                };
+                Some(if id == ACK_ACK {
+                    Packet::Ack(acked)
+                } else {
+                    Packet::Nak(acked)
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_nav_pvt(payload: &[u8]) -> Fix {
+    let u32_at =
+        |i: usize| u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]]);
+    let i32_at = |i: usize| u32_at(i) as i32;
+
+    let fix_type = match payload[20] {
+        _ if payload[21] & FLAGS_GNSS_FIX_OK == 0 => FixType::NoFix,
+        FIX_TYPE_2D => FixType::Fix2d,
+        FIX_TYPE_3D | FIX_TYPE_GNSS_DEAD_RECKONING => FixType::Fix3d,
+        _ => FixType::NoFix,
+    };
+
+    Fix {
+        fix_type,
+        satellites: payload[23],
+        longitude: i32_at(24),
+        latitude: i32_at(28),
+        altitude: i32_at(36) as f32 * 1e-3,
+        velocity: [i32_at(48) as f32 * 1e-3, i32_at(52) as f32 * 1e-3],
+        climb_rate: Some(-i32_at(56) as f32 * 1e-3),
+        horizontal_accuracy: Some(u32_at(40) as f32 * 1e-3),
+    }
+}
+
+/// The platform model used by the receiver's navigation filter.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum DynamicModel {
+    Portable = 0,
+    Stationary = 2,
+    Pedestrian = 3,
+    Automotive = 4,
+    Sea = 5,
+    /// Airborne with less than 1g of acceleration.
+    Airborne1g = 6,
+    Airborne2g = 7,
+    Airborne4g = 8,
+}
+
+/// Configuration commands. The receiver answers each with an `Ack` or `Nak`
+/// packet.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Command {
+    /// Sets the baud rate of the receiver's UART1, which accepts UBX and
+    /// NMEA and only sends UBX afterwards. The receiver switches baud rate
+    /// immediately, so the acknowledgement may be lost.
+    ConfigureUart {
+        baud_rate: u32,
+    },
+    /// Sets the time between navigation solutions.
+    SetRate {
+        period_ms: u16,
+    },
+    /// Sets how often a message is sent on the current port, in navigation
+    /// solutions. Zero disables it.
+    SetMessageRate {
+        message: MessageId,
+        rate: u8,
+    },
+    SetDynamicModel(DynamicModel),
+}
+
+impl Command {
+    pub fn id(&self) -> MessageId {
+        match self {
+            Command::ConfigureUart { .. } => CFG_PRT,
+            Command::SetRate { .. } => CFG_RATE,
+            Command::SetMessageRate { .. } => CFG_MSG,
+            Command::SetDynamicModel(_) => CFG_NAV5,
+        }
+    }
+
+    /// Encodes a complete frame into `buf`, returning its length.
+    pub fn encode(&self, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let payload_len = match *self {
+            Command::ConfigureUart { baud_rate } => {
+                // Port 1 is UART1.
+                payload[0] = 1;
+                // 8 data bits, no parity and 1 stop bit.
+                payload[4..8].copy_from_slice(&0x08d0u32.to_le_bytes());
+                payload[8..12].copy_from_slice(&baud_rate.to_le_bytes());
+                // Protocol masks, where bit 0 is UBX and bit 1 is NMEA.
+                payload[12..14].copy_from_slice(&0x0003u16.to_le_bytes());
+                payload[14..16].copy_from_slice(&0x0001u16.to_le_bytes());
+                20
+            }
+            Command::SetRate { period_ms } => {
+                payload[0..2].copy_from_slice(&period_ms.to_le_bytes());
+                // A solution for every measurement, aligned to GPS time.
+                payload[2..4].copy_from_slice(&1u16.to_le_bytes());
+                payload[4..6].copy_from_slice(&1u16.to_le_bytes());
+                6
+            }
+            Command::SetMessageRate { message, rate } => {
+                payload[0] = message.class;
+                payload[1] = message.id;
+                payload[2] = rate;
+                3
+            }
+            Command::SetDynamicModel(model) => {
+                // Only the dynamic model is applied.
+                payload[0..2].copy_from_slice(&0x0001u16.to_le_bytes());
+                payload[2] = model as u8;
+                36
+            }
+        };
+
+        let id = self.id();
+        let checksum_idx = HEADER_LEN + payload_len;
+        buf[0..2].copy_from_slice(&SYNC);
+        buf[2] = id.class;
+        buf[3] = id.id;
+        buf[4..6].copy_from_slice(&(payload_len as u16).to_le_bytes());
+        buf[HEADER_LEN..checksum_idx].copy_from_slice(&payload[..payload_len]);
+        let [a, b] = checksum(&buf[2..checksum_idx]);
+        buf[checksum_idx] = a;
+        buf[checksum_idx + 1] = b;
+
+        checksum_idx + 2
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; 2] {
+    bytes.iter().fold([0u8, 0u8], |[a, b], byte| {
+        let a = a.wrapping_add(*byte);
+        [a, b.wrapping_add(a)]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A NAV-PVT frame with a 3D fix from 14 satellites at 48.1173 N,
+    /// 11.5166667 E and 545.4 m, moving at 1.2 m/s north, 0.8 m/s west and
+    /// 0.3 m/s up, with a horizontal accuracy of 1.5 m.
+    const NAV_PVT_FRAME: [u8; 100] = [
+        0xb5, 0x62, 0x01, 0x07, 0x5c, 0x00, 0x80, 0x39, 0x7a, 0x12, 0xe8, 0x07, 0x05, 0x0e, 0x0e,
+        0x06, 0x28, 0x37, 0xe8, 0x03, 0x00, 0x00, 0xc7, 0xcf, 0xff, 0xff, 0x03, 0x03, 0xea, 0x0e,
+        0xcb, 0x4d, 0xdd, 0x06, 0x08, 0x1e, 0xae, 0x1c, 0xac, 0x09, 0x09, 0x00, 0x78, 0x52, 0x08,
+        0x00, 0xdc, 0x05, 0x00, 0x00, 0xfc, 0x08, 0x00, 0x00, 0xb0, 0x04, 0x00, 0x00, 0xe0, 0xfc,
+        0xff, 0xff, 0xd4, 0xfe, 0xff, 0xff, 0xa2, 0x05, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00, 0x90,
+        0x01, 0x00, 0x00, 0x98, 0x3a, 0x00, 0x00, 0x78, 0x00, 0x00, 0x00, 0x30, 0x75, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6b, 0xed,
+    ];
+    /// Offsets of the `fixType` and `flags` fields in the frame.
+    const FIX_TYPE_IDX: usize = HEADER_LEN + 20;
+    const FLAGS_IDX: usize = HEADER_LEN + 21;
+
+    /// The acknowledgement of a CFG-RATE command.
+    const ACK_FRAME: [u8; 10] = [0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x08, 0x16, 0x3f];
+    /// The rejection of a CFG-NAV5 command.
+    const NAK_FRAME: [u8; 10] = [0xb5, 0x62, 0x05, 0x00, 0x02, 0x00, 0x06, 0x24, 0x31, 0x56];
+    /// A NAV-STATUS frame, which isn't parsed.
+    const NAV_STATUS_FRAME: [u8; 24] = [
+        0xb5, 0x62, 0x01, 0x03, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x6d,
+    ];
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(libm::fabsf(a - b) < 1e-4, "{} != {}", a, b);
+    }
+
+    /// Pushes every byte, returning the number of packets and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Packet>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), packet| (count + 1, Some(packet)))
+    }
+
+    fn fix(packet: Option<Packet>) -> Fix {
+        match packet {
+            Some(Packet::Fix(fix)) => fix,
+            _ => panic!("not a fix"),
+        }
+    }
+
+    /// `NAV_PVT_FRAME` with the payload byte at `idx` replaced.
+    fn with_payload_byte(idx: usize, value: u8) -> [u8; 100] {
+        let mut frame = NAV_PVT_FRAME;
+        frame[idx] = value;
+        let [a, b] = checksum(&frame[2..98]);
+        frame[98] = a;
+        frame[99] = b;
+        frame
+    }
+
+    #[test]
+    fn decodes_nav_pvt() {
+        let (count, packet) = push_all(&mut Parser::new(), &NAV_PVT_FRAME);
+        let fix = fix(packet);
+
+        assert_eq!(count, 1);
+        assert!(fix.fix_type == FixType::Fix3d);
+        assert_eq!(fix.satellites, 14);
+        assert_eq!(fix.latitude, 481_173_000);
+        assert_eq!(fix.longitude, 115_166_667);
+        assert_close(fix.altitude, 545.4);
+        assert_close(fix.velocity[0], 1.2);
+        assert_close(fix.velocity[1], -0.8);
+        assert_close(fix.climb_rate.unwrap(), 0.3);
+        assert_close(fix.horizontal_accuracy.unwrap(), 1.5);
+    }
+
+    #[test]
+    fn decodes_fix_types() {
+        for (fix_type, expected) in [
+            (0, FixType::NoFix),
+            (1, FixType::NoFix),
+            (2, FixType::Fix2d),
+            (3, FixType::Fix3d),
+            (4, FixType::Fix3d),
+            (5, FixType::NoFix),
+        ] {
+            let frame = with_payload_byte(FIX_TYPE_IDX, fix_type);
+            let fix = fix(push_all(&mut Parser::new(), &frame).1);
+            assert!(fix.fix_type == expected, "{}", fix_type);
+        }
+    }
+
+    #[test]
+    fn no_fix_outside_accuracy_limits() {
+        let frame = with_payload_byte(FLAGS_IDX, 0x02);
+        let fix = fix(push_all(&mut Parser::new(), &frame).1);
+        assert!(fix.fix_type == FixType::NoFix);
+    }
+
+    #[test]
+    fn decodes_acks() {
+        let mut parser = Parser::new();
+        assert!(matches!(
+            push_all(&mut parser, &ACK_FRAME).1,
+            Some(Packet::Ack(CFG_RATE))
+        ));
+        assert!(matches!(
+            push_all(&mut parser, &NAK_FRAME).1,
+            Some(Packet::Nak(CFG_NAV5))
+        ));
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        for idx in 2..NAV_PVT_FRAME.len() {
+            let mut frame = NAV_PVT_FRAME;
+            frame[idx] ^= 0x10;
+            let mut parser = Parser::new();
+            assert!(
+                !matches!(push_all(&mut parser, &frame).1, Some(Packet::Fix(_))),
+                "{}",
+                idx
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &NAV_STATUS_FRAME).0, 0);
+
+        // A NAV-PVT frame of the wrong length.
+        let mut frame = [0; 98];
+        frame[..6].copy_from_slice(&NAV_PVT_FRAME[..6]);
+        frame[4] = 90;
+        let [a, b] = checksum(&frame[2..96]);
+        frame[96] = a;
+        frame[97] = b;
+        assert_eq!(push_all(&mut parser, &frame).0, 0);
+
+        assert_eq!(push_all(&mut parser, &NAV_PVT_FRAME).0, 1);
+    }
+
+    #[test]
+    fn skips_overlong_messages() {
+        // MON-VER frames are longer than any which is parsed. Their header is
+        // dropped, so the parser looks for the next frame straight away.
+        let mut parser = Parser::new();
+        assert_eq!(
+            push_all(&mut parser, &[0xb5, 0x62, 0x0a, 0x04, 0xa0, 0x00]).0,
+            0
+        );
+        assert_eq!(push_all(&mut parser, &NAV_PVT_FRAME).0, 1);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            push_all(&mut parser, &[0x00, 0x62, 0xb5, 0xb5, 0x12, 0xb5]).0,
+            0
+        );
+        assert_eq!(push_all(&mut parser, &NAV_PVT_FRAME).0, 1);
+
+        // A repeated first sync byte.
+        assert_eq!(push_all(&mut parser, &[0xb5]).0, 0);
+        assert_eq!(push_all(&mut parser, &NAV_PVT_FRAME).0, 1);
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut stream = [0; 120];
+        stream[..100].copy_from_slice(&NAV_PVT_FRAME);
+        stream[100..110].copy_from_slice(&ACK_FRAME);
+        stream[110..].copy_from_slice(&NAK_FRAME);
+
+        let mut parser = Parser::new();
+        let packets = stream.iter().filter_map(|byte| parser.push(*byte));
+        let mut kinds = packets.map(|packet| match packet {
+            Packet::Fix(_) => 0,
+            Packet::Ack(_) => 1,
+            Packet::Nak(_) => 2,
+        });
+        assert_eq!(kinds.next(), Some(0));
+        assert_eq!(kinds.next(), Some(1));
+        assert_eq!(kinds.next(), Some(2));
+        assert_eq!(kinds.next(), None);
+    }
+
+    fn encode(command: Command) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = command.encode(&mut buf);
+
+        (buf, len)
+    }
+
+    #[test]
+    fn encodes_commands() {
+        // 5 Hz navigation solutions.
+        let (buf, len) = encode(Command::SetRate { period_ms: 200 });
+        assert_eq!(
+            buf[..len],
+            [0xb5, 0x62, 0x06, 0x08, 0x06, 0x00, 0xc8, 0x00, 0x01, 0x00, 0x01, 0x00, 0xde, 0x6a]
+        );
+
+        // NAV-PVT with every solution.
+        let (buf, len) = encode(Command::SetMessageRate {
+            message: NAV_PVT,
+            rate: 1,
+        });
+        assert_eq!(
+            buf[..len],
+            [0xb5, 0x62, 0x06, 0x01, 0x03, 0x00, 0x01, 0x07, 0x01, 0x13, 0x51]
+        );
+
+        let (buf, len) = encode(Command::ConfigureUart { baud_rate: 115_200 });
+        assert_eq!(len, 28);
+        assert_eq!(buf[6], 1);
+        assert_eq!(buf[14..18], 115_200u32.to_le_bytes());
+
+        let (buf, len) = encode(Command::SetDynamicModel(DynamicModel::Airborne4g));
+        assert_eq!(len, 44);
+        assert_eq!(buf[8], 8);
+    }
+
+    #[test]
+    fn commands_have_valid_headers_and_checksums() {
+        for command in [
+            Command::ConfigureUart { baud_rate: 9600 },
+            Command::SetRate { period_ms: 100 },
+            Command::SetMessageRate {
+                message: NAV_PVT,
+                rate: 0,
+            },
+            Command::SetDynamicModel(DynamicModel::Portable),
+        ] {
+            let (buf, len) = encode(command);
+            let id = command.id();
+            assert_eq!(buf[..4], [0xb5, 0x62, id.class, id.id]);
+            assert_eq!(
+                u16::from_le_bytes([buf[4], buf[5]]) as usize,
+                len - HEADER_LEN - 2
+            );
+            assert_eq!(checksum(&buf[2..len - 2]), [buf[len - 2], buf[len - 1]]);
+        }
+    }
+}
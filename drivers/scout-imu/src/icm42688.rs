@@ -0,0 +1,188 @@
+//! Register map and data layout of the ICM-42688-P
+//!
+//! All registers used here are in bank 0, which is selected after reset.
+
+use crate::{axes, AccelRange, GyroRange, OutputDataRate, Sample};
+
+pub(crate) const DEVICE_CONFIG: u8 = 0x11;
+pub(crate) const INT_CONFIG: u8 = 0x14;
+pub(crate) const FIFO_CONFIG: u8 = 0x16;
+pub(crate) const TEMP_DATA1: u8 = 0x1d;
+pub(crate) const FIFO_COUNTH: u8 = 0x2e;
+pub(crate) const FIFO_DATA: u8 = 0x30;
+pub(crate) const SIGNAL_PATH_RESET: u8 = 0x4b;
+pub(crate) const PWR_MGMT0: u8 = 0x4e;
+pub(crate) const GYRO_CONFIG0: u8 = 0x4f;
+pub(crate) const ACCEL_CONFIG0: u8 = 0x50;
+pub(crate) const FIFO_CONFIG1: u8 = 0x5f;
+pub(crate) const INT_CONFIG0: u8 = 0x63;
+pub(crate) const INT_CONFIG1: u8 = 0x64;
+pub(crate) const INT_SOURCE0: u8 = 0x65;
+
+pub(crate) const WHO_AM_I_VALUE: u8 = 0x47;
+
+pub(crate) const DEVICE_CONFIG_SOFT_RESET: u8 = 0b0000_0001;
+/// INT1 active high, push-pull, and held until the data is read.
+pub(crate) const INT_CONFIG_INT1_LATCHED: u8 = 0b0000_0111;
+/// Clear the data ready interrupt when the sensor data is read, rather than
+/// only when INT_STATUS is read.
+pub(crate) const INT_CONFIG0_DRDY_CLEAR_ON_DATA_READ: u8 = 0b0010_0000;
+pub(crate) const FIFO_CONFIG_STREAM: u8 = 0b0100_0000;
+pub(crate) const SIGNAL_PATH_RESET_FIFO_FLUSH: u8 = 0b0000_0010;
+/// Gyro and accel both in low noise mode.
+pub(crate) const PWR_MGMT0_LOW_NOISE: u8 = 0b0000_1111;
+/// Temperature, gyro and accel all enabled, which selects 16 byte records.
+pub(crate) const FIFO_CONFIG1_ALL: u8 = 0b0000_0111;
+pub(crate) const INT_SOURCE0_UI_DRDY: u8 = 0b0000_1000;
+
+/// Header, accel, gyro, temperature and timestamp.
+pub(crate) const FIFO_RECORD_LEN: usize = 16;
+/// Set in a record header when the FIFO had no data to return.
+const FIFO_HEADER_EMPTY: u8 = 0b1000_0000;
+
+/// Returns the ODR field shared by GYRO_CONFIG0 and ACCEL_CONFIG0.
+pub(crate) fn output_data_rate(output_data_rate: OutputDataRate) -> u8 {
+    match output_data_rate {
+        OutputDataRate::Hz8000 => 0b0011,
+        OutputDataRate::Hz4000 => 0b0100,
+        OutputDataRate::Hz2000 => 0b0101,
+        OutputDataRate::Hz1000 => 0b0110,
+    }
+}
+
+pub(crate) fn gyro_fs_sel(range: GyroRange) -> u8 {
+    let fs_sel = match range {
+        GyroRange::Dps2000 => 0,
+        GyroRange::Dps1000 => 1,
+        GyroRange::Dps500 => 2,
+        GyroRange::Dps250 => 3,
+    };
+
+    fs_sel << 5
+}
+
+pub(crate) fn accel_fs_sel(range: AccelRange) -> u8 {
+    let fs_sel = match range {
+        AccelRange::G16 => 0,
+        AccelRange::G8 => 1,
+        AccelRange::G4 => 2,
+        AccelRange::G2 => 3,
+    };
+
+    fs_sel << 5
+}
+
+pub(crate) fn temperature(raw: i16) -> f32 {
+    raw as f32 / 132.48 + 25.0
+}
+
+/// Parses temperature, accel and gyro, in that order.
+pub(crate) fn parse_sample(bytes: &[u8], gyro_scale: f32, accel_scale: f32) -> Sample {
+    Sample {
+        temperature: temperature(i16::from_be_bytes([bytes[0], bytes[1]])),
+        accel: axes(&bytes[2..8], accel_scale),
+        gyro: axes(&bytes[8..14], gyro_scale),
+    }
+}
+
+/// Returns `None` if the record is marked empty.
+pub(crate) fn parse_fifo_record(bytes: &[u8], gyro_scale: f32, accel_scale: f32) -> Option<Sample> {
+    if bytes[0] & FIFO_HEADER_EMPTY != 0 {
+        return None;
+    }
+
+    Some(Sample {
+        accel: axes(&bytes[1..7], accel_scale),
+        gyro: axes(&bytes[7..13], gyro_scale),
+        // The FIFO holds a lower resolution copy of the temperature.
+        temperature: bytes[13] as i8 as f32 / 2.07 + 25.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(a - b < 1e-3 && b - a < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn config0_fields() {
+        assert_eq!(output_data_rate(OutputDataRate::Hz1000), 0x06);
+        assert_eq!(output_data_rate(OutputDataRate::Hz8000), 0x03);
+        assert_eq!(gyro_fs_sel(GyroRange::Dps2000), 0x00);
+        assert_eq!(gyro_fs_sel(GyroRange::Dps250), 0x60);
+        assert_eq!(accel_fs_sel(AccelRange::G16), 0x00);
+        assert_eq!(accel_fs_sel(AccelRange::G2), 0x60);
+        // The full scale and ODR fields don't overlap.
+        for rate in [
+            OutputDataRate::Hz1000,
+            OutputDataRate::Hz2000,
+            OutputDataRate::Hz4000,
+            OutputDataRate::Hz8000,
+        ] {
+            assert_eq!(output_data_rate(rate) & 0xf0, 0);
+        }
+    }
+
+    #[test]
+    fn temperature_follows_datasheet_formula() {
+        assert_close(temperature(0), 25.0);
+        // 132.48 LSB per degree.
+        assert_close(temperature(1325), 35.002);
+        assert_close(temperature(-1325), 14.998);
+    }
+
+    #[test]
+    fn parses_registers_from_temp_data1() {
+        let bytes = [
+            // Temperature.
+            0x05, 0x2d, //
+            // Accel x, y and z.
+            0x08, 0x00, 0x00, 0x00, 0xf8, 0x00, //
+            // Gyro x, y and z.
+            0x80, 0x00, 0xff, 0x5c, 0x00, 0x00,
+        ];
+        let sample = parse_sample(&bytes, GyroRange::Dps2000.scale(), AccelRange::G16.scale());
+
+        assert_close(sample.temperature, 35.002);
+        assert_close(sample.accel[0], 1.0);
+        assert_close(sample.accel[1], 0.0);
+        assert_close(sample.accel[2], -1.0);
+        assert_close(sample.gyro[0], -2000.0);
+        assert_close(sample.gyro[1], -10.009);
+        assert_close(sample.gyro[2], 0.0);
+    }
+
+    #[test]
+    fn parses_fifo_records() {
+        let record = [
+            // Header: accel and gyro present, 16 byte record.
+            0x68, //
+            // Accel x, y and z.
+            0x00, 0x00, 0x08, 0x00, 0x00, 0x00, //
+            // Gyro x, y and z.
+            0x00, 0x00, 0x00, 0x00, 0x80, 0x00, //
+            // Temperature, then the timestamp.
+            0x15, 0x12, 0x34,
+        ];
+        let Some(sample) =
+            parse_fifo_record(&record, GyroRange::Dps2000.scale(), AccelRange::G16.scale())
+        else {
+            panic!("record not parsed");
+        };
+
+        assert_close(sample.accel[1], 1.0);
+        assert_close(sample.gyro[2], -2000.0);
+        assert_close(sample.temperature, 21.0 / 2.07 + 25.0);
+    }
+
+    #[test]
+    fn empty_fifo_records_are_skipped() {
+        let mut record = [0; FIFO_RECORD_LEN];
+        record[0] = FIFO_HEADER_EMPTY;
+
+        assert!(parse_fifo_record(&record, 1.0, 1.0).is_none());
+    }
+}
@@ -0,0 +1,431 @@
+#![no_std]
+
+use embedded_hal::spi;
+use embedded_hal_async::{
+    delay::DelayUs,
+    spi::{transaction, SpiBus, SpiBusRead, SpiBusWrite, SpiDevice},
+};
+
+mod icm42688;
+mod mpu6000;
+
+/// Largest number of bytes a single sample occupies in either chip's FIFO.
+const MAX_FIFO_RECORD_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Chip {
+    Mpu6000,
+    Icm42688,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum GyroRange {
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+/// Rate at which new gyro samples become available. The MPU-6000
+/// accelerometer is limited to 1kHz, and repeats samples at higher rates.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum OutputDataRate {
+    Hz1000,
+    Hz2000,
+    Hz4000,
+    Hz8000,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    pub gyro_range: GyroRange,
+    pub accel_range: AccelRange,
+    pub output_data_rate: OutputDataRate,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gyro_range: GyroRange::Dps2000,
+            accel_range: AccelRange::G16,
+            output_data_rate: OutputDataRate::Hz1000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Sample {
+    /// Rotation rate about the x, y and z axes, in degrees per second.
+    pub gyro: [f32; 3],
+    /// Acceleration along the x, y and z axes, in units of standard gravity.
+    pub accel: [f32; 3],
+    /// Die temperature in degrees Celsius.
+    pub temperature: f32,
+}
+
+#[derive(defmt::Format)]
+pub enum Error<SPIError> {
+    Spi(SPIError),
+    Delay,
+    /// The WHO_AM_I register did not match a supported chip.
+    UnknownChip(u8),
+}
+
+pub struct Imu<SPI> {
+    spi: SPI,
+    chip: Chip,
+    /// Degrees per second per LSB.
+    gyro_scale: f32,
+    /// Standard gravity per LSB.
+    accel_scale: f32,
+}
+
+impl GyroRange {
+    fn scale(&self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 250.0 / 32768.0,
+            GyroRange::Dps500 => 500.0 / 32768.0,
+            GyroRange::Dps1000 => 1000.0 / 32768.0,
+            GyroRange::Dps2000 => 2000.0 / 32768.0,
+        }
+    }
+}
+
+impl AccelRange {
+    fn scale(&self) -> f32 {
+        match self {
+            AccelRange::G2 => 2.0 / 32768.0,
+            AccelRange::G4 => 4.0 / 32768.0,
+            AccelRange::G8 => 8.0 / 32768.0,
+            AccelRange::G16 => 16.0 / 32768.0,
+        }
+    }
+}
+
+impl OutputDataRate {
+    pub const fn hz(&self) -> u32 {
+        match self {
+            OutputDataRate::Hz1000 => 1000,
+            OutputDataRate::Hz2000 => 2000,
+            OutputDataRate::Hz4000 => 4000,
+            OutputDataRate::Hz8000 => 8000,
+        }
+    }
+}
+
+impl<SPI> Imu<SPI>
+where
+    SPI: SpiDevice,
+    SPI::Bus: SpiBus<u8>,
+{
+    /// Resets and identifies the chip, then applies `config`.
+    ///
+    /// The MPU-6000 only allows register writes with the SPI clock at or
+    /// below 1MHz.
+    pub async fn new<DELAY: DelayUs>(
+        spi: SPI,
+        mut delay: DELAY,
+        config: Config,
+    ) -> Result<Self, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut imu = Self {
+            spi,
+            // Both chips keep WHO_AM_I at the same address, so this is
+            // overwritten once we've read it.
+            chip: Chip::Mpu6000,
+            gyro_scale: config.gyro_range.scale(),
+            accel_scale: config.accel_range.scale(),
+        };
+
+        imu.chip = match imu.read_register(mpu6000::WHO_AM_I).await? {
+            mpu6000::WHO_AM_I_VALUE => Chip::Mpu6000,
+            icm42688::WHO_AM_I_VALUE => Chip::Icm42688,
+            other => return Err(Error::UnknownChip(other)),
+        };
+
+        match imu.chip {
+            Chip::Mpu6000 => {
+                imu.write_register(mpu6000::PWR_MGMT_1, mpu6000::PWR_MGMT_1_DEVICE_RESET)
+                    .await?;
+                delay.delay_ms(100).await.map_err(|_| Error::Delay)?;
+                imu.write_register(mpu6000::SIGNAL_PATH_RESET, mpu6000::SIGNAL_PATH_RESET_ALL)
+                    .await?;
+                delay.delay_ms(100).await.map_err(|_| Error::Delay)?;
+                // The reset re-enables the I2C interface, which must be off
+                // for reliable SPI operation.
+                imu.write_register(mpu6000::USER_CTRL, mpu6000::USER_CTRL_I2C_IF_DIS)
+                    .await?;
+                imu.write_register(mpu6000::PWR_MGMT_1, mpu6000::PWR_MGMT_1_CLKSEL_PLL_X)
+                    .await?;
+            }
+            Chip::Icm42688 => {
+                imu.write_register(icm42688::DEVICE_CONFIG, icm42688::DEVICE_CONFIG_SOFT_RESET)
+                    .await?;
+                delay.delay_ms(1).await.map_err(|_| Error::Delay)?;
+                imu.write_register(icm42688::PWR_MGMT0, icm42688::PWR_MGMT0_LOW_NOISE)
+                    .await?;
+                // Data sheet specifies no register writes for 200us after
+                // enabling the sensors.
+                delay.delay_us(200).await.map_err(|_| Error::Delay)?;
+            }
+        }
+
+        imu.configure(config).await?;
+
+        Ok(imu)
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    pub async fn configure(
+        &mut self,
+        config: Config,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        match self.chip {
+            Chip::Mpu6000 => {
+                let (dlpf_cfg, sample_rate_div) =
+                    mpu6000::output_data_rate(config.output_data_rate);
+                self.write_register(mpu6000::CONFIG, dlpf_cfg).await?;
+                self.write_register(mpu6000::SMPLRT_DIV, sample_rate_div)
+                    .await?;
+                self.write_register(
+                    mpu6000::GYRO_CONFIG,
+                    mpu6000::gyro_config(config.gyro_range),
+                )
+                .await?;
+                self.write_register(
+                    mpu6000::ACCEL_CONFIG,
+                    mpu6000::accel_config(config.accel_range),
+                )
+                .await?;
+            }
+            Chip::Icm42688 => {
+                let odr = icm42688::output_data_rate(config.output_data_rate);
+                self.write_register(
+                    icm42688::GYRO_CONFIG0,
+                    icm42688::gyro_fs_sel(config.gyro_range) | odr,
+                )
+                .await?;
+                self.write_register(
+                    icm42688::ACCEL_CONFIG0,
+                    icm42688::accel_fs_sel(config.accel_range) | odr,
+                )
+                .await?;
+            }
+        }
+
+        self.gyro_scale = config.gyro_range.scale();
+        self.accel_scale = config.accel_range.scale();
+
+        Ok(())
+    }
+
+    /// Configures the interrupt pin to go high when a new sample is
+    /// available, until that sample is read.
+    pub async fn enable_data_ready_interrupt(
+        &mut self,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        match self.chip {
+            Chip::Mpu6000 => {
+                self.write_register(mpu6000::INT_PIN_CFG, mpu6000::INT_PIN_CFG_LATCH_ANY_READ)
+                    .await?;
+                self.write_register(mpu6000::INT_ENABLE, mpu6000::INT_ENABLE_DATA_RDY)
+                    .await?;
+            }
+            Chip::Icm42688 => {
+                self.write_register(icm42688::INT_CONFIG, icm42688::INT_CONFIG_INT1_LATCHED)
+                    .await?;
+                self.write_register(
+                    icm42688::INT_CONFIG0,
+                    icm42688::INT_CONFIG0_DRDY_CLEAR_ON_DATA_READ,
+                )
+                .await?;
+                // Data sheet requires INT_ASYNC_RESET to be cleared for the
+                // interrupt pins to operate correctly.
+                self.write_register(icm42688::INT_CONFIG1, 0).await?;
+                self.write_register(icm42688::INT_SOURCE0, icm42688::INT_SOURCE0_UI_DRDY)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the latest sample from the data registers.
+    pub async fn read(&mut self) -> Result<Sample, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut buf = [0; 14];
+        match self.chip {
+            Chip::Mpu6000 => {
+                self.read_registers(mpu6000::ACCEL_XOUT_H, &mut buf).await?;
+                Ok(mpu6000::parse_sample(
+                    &buf,
+                    self.gyro_scale,
+                    self.accel_scale,
+                ))
+            }
+            Chip::Icm42688 => {
+                self.read_registers(icm42688::TEMP_DATA1, &mut buf).await?;
+                Ok(icm42688::parse_sample(
+                    &buf,
+                    self.gyro_scale,
+                    self.accel_scale,
+                ))
+            }
+        }
+    }
+
+    pub async fn read_temperature(&mut self) -> Result<f32, Error<<SPI as spi::ErrorType>::Error>> {
+        let mut buf = [0; 2];
+        match self.chip {
+            Chip::Mpu6000 => {
+                self.read_registers(mpu6000::TEMP_OUT_H, &mut buf).await?;
+                Ok(mpu6000::temperature(i16::from_be_bytes(buf)))
+            }
+            Chip::Icm42688 => {
+                self.read_registers(icm42688::TEMP_DATA1, &mut buf).await?;
+                Ok(icm42688::temperature(i16::from_be_bytes(buf)))
+            }
+        }
+    }
+
+    /// Starts buffering every sample in the FIFO, discarding anything
+    /// already buffered. Once the FIFO is full, the MPU-6000 overwrites the
+    /// oldest samples and the ICM-42688 stops buffering, so it must be read
+    /// at least once per 1024 bytes of samples.
+    pub async fn enable_fifo(&mut self) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        match self.chip {
+            Chip::Mpu6000 => {
+                self.write_register(mpu6000::FIFO_EN, mpu6000::FIFO_EN_ALL)
+                    .await?;
+                self.write_register(
+                    mpu6000::USER_CTRL,
+                    mpu6000::USER_CTRL_I2C_IF_DIS | mpu6000::USER_CTRL_FIFO_RESET,
+                )
+                .await?;
+                self.write_register(
+                    mpu6000::USER_CTRL,
+                    mpu6000::USER_CTRL_I2C_IF_DIS | mpu6000::USER_CTRL_FIFO_EN,
+                )
+                .await?;
+            }
+            Chip::Icm42688 => {
+                self.write_register(icm42688::FIFO_CONFIG1, icm42688::FIFO_CONFIG1_ALL)
+                    .await?;
+                self.write_register(icm42688::FIFO_CONFIG, icm42688::FIFO_CONFIG_STREAM)
+                    .await?;
+                self.write_register(
+                    icm42688::SIGNAL_PATH_RESET,
+                    icm42688::SIGNAL_PATH_RESET_FIFO_FLUSH,
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads buffered samples, oldest first, until either the FIFO or
+    /// `samples` is exhausted. Returns the number of samples read.
+    pub async fn read_fifo(
+        &mut self,
+        samples: &mut [Sample],
+    ) -> Result<usize, Error<<SPI as spi::ErrorType>::Error>> {
+        let (count_register, data_register, record_len) = match self.chip {
+            Chip::Mpu6000 => (
+                mpu6000::FIFO_COUNTH,
+                mpu6000::FIFO_R_W,
+                mpu6000::FIFO_RECORD_LEN,
+            ),
+            Chip::Icm42688 => (
+                icm42688::FIFO_COUNTH,
+                icm42688::FIFO_DATA,
+                icm42688::FIFO_RECORD_LEN,
+            ),
+        };
+
+        let mut count = [0; 2];
+        self.read_registers(count_register, &mut count).await?;
+        let available = u16::from_be_bytes(count) as usize / record_len;
+
+        let mut read = 0;
+        for sample in samples.iter_mut().take(available) {
+            let mut buf = [0; MAX_FIFO_RECORD_LEN];
+            let record = &mut buf[..record_len];
+            self.read_registers(data_register, record).await?;
+
+            *sample = match self.chip {
+                Chip::Mpu6000 => mpu6000::parse_sample(record, self.gyro_scale, self.accel_scale),
+                Chip::Icm42688 => {
+                    match icm42688::parse_fifo_record(record, self.gyro_scale, self.accel_scale) {
+                        Some(sample) => sample,
+                        // The FIFO ran dry earlier than its count suggested.
+                        None => break,
+                    }
+                }
+            };
+            read += 1;
+        }
+
+        Ok(read)
+    }
+
+    async fn read_register(
+        &mut self,
+        register: u8,
+    ) -> Result<u8, Error<<SPI as spi::ErrorType>::Error>> {
+        // Both bytes are written on the SPI bus, but the chip ignores the
+        // second byte and uses those clock pulses to send a response.
+        let mut buf = [register | READ, 0];
+
+        self.spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(Error::Spi)?;
+
+        Ok(buf[1])
+    }
+
+    async fn read_registers(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        transaction!(&mut self.spi, move |bus| async move {
+            bus.write(&[register | READ]).await?;
+
+            bus.read(buf).await?;
+
+            Ok(())
+        })
+        .await
+        .map_err(Error::Spi)
+    }
+
+    async fn write_register(
+        &mut self,
+        register: u8,
+        value: u8,
+    ) -> Result<(), Error<<SPI as spi::ErrorType>::Error>> {
+        self.spi.write(&[register, value]).await.map_err(Error::Spi)
+    }
+}
+
+/// Set in the register address byte to read rather than write.
+const READ: u8 = 0b1000_0000;
+
+fn axes(bytes: &[u8], scale: f32) -> [f32; 3] {
+    [
+        i16::from_be_bytes([bytes[0], bytes[1]]) as f32 * scale,
+        i16::from_be_bytes([bytes[2], bytes[3]]) as f32 * scale,
+        i16::from_be_bytes([bytes[4], bytes[5]]) as f32 * scale,
+    ]
+}
@@ -0,0 +1,158 @@
+//! Register map and data layout of the MPU-6000
+
+use crate::{axes, AccelRange, GyroRange, OutputDataRate, Sample};
+
+pub(crate) const SMPLRT_DIV: u8 = 0x19;
+pub(crate) const CONFIG: u8 = 0x1a;
+pub(crate) const GYRO_CONFIG: u8 = 0x1b;
+pub(crate) const ACCEL_CONFIG: u8 = 0x1c;
+pub(crate) const FIFO_EN: u8 = 0x23;
+pub(crate) const INT_PIN_CFG: u8 = 0x37;
+pub(crate) const INT_ENABLE: u8 = 0x38;
+pub(crate) const ACCEL_XOUT_H: u8 = 0x3b;
+pub(crate) const TEMP_OUT_H: u8 = 0x41;
+pub(crate) const SIGNAL_PATH_RESET: u8 = 0x68;
+pub(crate) const USER_CTRL: u8 = 0x6a;
+pub(crate) const PWR_MGMT_1: u8 = 0x6b;
+pub(crate) const FIFO_COUNTH: u8 = 0x72;
+pub(crate) const FIFO_R_W: u8 = 0x74;
+pub(crate) const WHO_AM_I: u8 = 0x75;
+
+pub(crate) const WHO_AM_I_VALUE: u8 = 0x68;
+
+/// Temperature, gyro and accel all enabled.
+pub(crate) const FIFO_EN_ALL: u8 = 0b1111_1000;
+/// Clear the interrupt status on any read, rather than only on reads of
+/// INT_STATUS, and hold the pin high until then.
+pub(crate) const INT_PIN_CFG_LATCH_ANY_READ: u8 = 0b0011_0000;
+pub(crate) const INT_ENABLE_DATA_RDY: u8 = 0b0000_0001;
+/// Gyro, accel and temperature signal paths.
+pub(crate) const SIGNAL_PATH_RESET_ALL: u8 = 0b0000_0111;
+pub(crate) const USER_CTRL_FIFO_EN: u8 = 0b0100_0000;
+pub(crate) const USER_CTRL_I2C_IF_DIS: u8 = 0b0001_0000;
+pub(crate) const USER_CTRL_FIFO_RESET: u8 = 0b0000_0100;
+pub(crate) const PWR_MGMT_1_DEVICE_RESET: u8 = 0b1000_0000;
+/// Clock from the PLL referenced to the X axis gyro, which is more stable
+/// than the internal oscillator.
+pub(crate) const PWR_MGMT_1_CLKSEL_PLL_X: u8 = 0b0000_0001;
+
+/// Samples are stored in register order, so a record matches a burst read
+/// from ACCEL_XOUT_H.
+pub(crate) const FIFO_RECORD_LEN: usize = 14;
+
+/// Returns the values for CONFIG and SMPLRT_DIV. The digital low pass filter
+/// is left at its widest setting, which samples the gyro at 8kHz, and the
+/// sample rate divider brings that down to the requested rate.
+pub(crate) fn output_data_rate(output_data_rate: OutputDataRate) -> (u8, u8) {
+    let sample_rate_div = match output_data_rate {
+        OutputDataRate::Hz8000 => 0,
+        OutputDataRate::Hz4000 => 1,
+        OutputDataRate::Hz2000 => 3,
+        OutputDataRate::Hz1000 => 7,
+    };
+
+    (0, sample_rate_div)
+}
+
+pub(crate) fn gyro_config(range: GyroRange) -> u8 {
+    let fs_sel = match range {
+        GyroRange::Dps250 => 0,
+        GyroRange::Dps500 => 1,
+        GyroRange::Dps1000 => 2,
+        GyroRange::Dps2000 => 3,
+    };
+
+    fs_sel << 3
+}
+
+pub(crate) fn accel_config(range: AccelRange) -> u8 {
+    let afs_sel = match range {
+        AccelRange::G2 => 0,
+        AccelRange::G4 => 1,
+        AccelRange::G8 => 2,
+        AccelRange::G16 => 3,
+    };
+
+    afs_sel << 3
+}
+
+pub(crate) fn temperature(raw: i16) -> f32 {
+    raw as f32 / 340.0 + 36.53
+}
+
+/// Parses accel, temperature and gyro, in that order.
+pub(crate) fn parse_sample(bytes: &[u8], gyro_scale: f32, accel_scale: f32) -> Sample {
+    Sample {
+        accel: axes(&bytes[0..6], accel_scale),
+        temperature: temperature(i16::from_be_bytes([bytes[6], bytes[7]])),
+        gyro: axes(&bytes[8..14], gyro_scale),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(a - b < 1e-3 && b - a < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn sample_rate_divider_gives_output_data_rate() {
+        for rate in [
+            OutputDataRate::Hz1000,
+            OutputDataRate::Hz2000,
+            OutputDataRate::Hz4000,
+            OutputDataRate::Hz8000,
+        ] {
+            let (dlpf_cfg, sample_rate_div) = output_data_rate(rate);
+            // The gyro is sampled at 8kHz with the filter disabled.
+            assert_eq!(dlpf_cfg, 0);
+            assert_eq!(8000 / (1 + sample_rate_div as u32), rate.hz());
+        }
+    }
+
+    #[test]
+    fn full_scale_fields() {
+        assert_eq!(gyro_config(GyroRange::Dps250), 0x00);
+        assert_eq!(gyro_config(GyroRange::Dps2000), 0x18);
+        assert_eq!(accel_config(AccelRange::G2), 0x00);
+        assert_eq!(accel_config(AccelRange::G8), 0x10);
+        assert_eq!(accel_config(AccelRange::G16), 0x18);
+    }
+
+    #[test]
+    fn temperature_follows_datasheet_formula() {
+        assert_close(temperature(0), 36.53);
+        assert_close(temperature(-3920), 25.0);
+        assert_close(temperature(340), 37.53);
+    }
+
+    #[test]
+    fn parses_registers_from_accel_xout_h() {
+        let bytes = [
+            // Accel x, y and z.
+            0x08, 0x00, 0xf8, 0x00, 0x7f, 0xff, //
+            // Temperature.
+            0xf0, 0xb0, //
+            // Gyro x, y and z.
+            0x00, 0xa4, 0x80, 0x00, 0x00, 0x00,
+        ];
+        let sample = parse_sample(&bytes, GyroRange::Dps2000.scale(), AccelRange::G16.scale());
+
+        assert_close(sample.accel[0], 1.0);
+        assert_close(sample.accel[1], -1.0);
+        assert_close(sample.accel[2], 16.0);
+        assert_close(sample.temperature, 25.0);
+        // 16.4 LSB per degree per second.
+        assert_close(sample.gyro[0], 10.009);
+        assert_close(sample.gyro[1], -2000.0);
+        assert_close(sample.gyro[2], 0.0);
+    }
+
+    #[test]
+    fn fifo_records_are_burst_reads() {
+        assert_eq!(FIFO_RECORD_LEN, 14);
+        assert_eq!(TEMP_OUT_H - ACCEL_XOUT_H, 6);
+    }
+}
@@ -0,0 +1,38 @@
+//! Register map of the HMC5883L
+
+pub(crate) const ADDRESS: u8 = 0x1e;
+
+pub(crate) const CONFIG_A: u8 = 0x00;
+pub(crate) const CONFIG_B: u8 = 0x01;
+pub(crate) const MODE: u8 = 0x02;
+pub(crate) const DATA_X_MSB: u8 = 0x03;
+pub(crate) const ID_A: u8 = 0x0a;
+
+/// "H43" in the three identification registers.
+pub(crate) const ID_VALUE: [u8; 3] = *b"H43";
+
+/// 8 samples averaged per measurement, at 75Hz.
+pub(crate) const CONFIG_A_75HZ_8X: u8 = 0x78;
+/// +/- 1.3 gauss range.
+pub(crate) const CONFIG_B_GAIN_1_3: u8 = 0x20;
+pub(crate) const MODE_CONTINUOUS: u8 = 0x00;
+
+pub(crate) const SAMPLE_HZ: f32 = 75.0;
+const LSB_PER_GAUSS: f32 = 1090.0;
+
+/// X, Z and Y, each 16 bits big-endian.
+pub(crate) const DATA_LEN: usize = 6;
+
+/// The value a channel reads when the field is out of range.
+const OVERFLOW: i16 = -4096;
+
+/// Returns the field in gauss, or `None` if any axis overflowed.
+pub(crate) fn parse(buf: &[u8; DATA_LEN]) -> Option<[f32; 3]> {
+    let value = |i: usize| i16::from_be_bytes([buf[i], buf[i + 1]]);
+    let (x, z, y) = (value(0), value(2), value(4));
+    if [x, y, z].contains(&OVERFLOW) {
+        return None;
+    }
+
+    Some([x, y, z].map(|value| value as f32 / LSB_PER_GAUSS))
+}
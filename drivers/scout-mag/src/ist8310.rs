@@ -0,0 +1,49 @@
+//! Register map of the IST8310
+
+/// The address depends on how the chip's address pins are strapped, and
+/// these are the two found on GPS modules.
+pub(crate) const ADDRESSES: [u8; 2] = [0x0e, 0x0c];
+
+pub(crate) const WAI: u8 = 0x00;
+pub(crate) const DATA_X_LSB: u8 = 0x03;
+pub(crate) const CNTL1: u8 = 0x0a;
+pub(crate) const CNTL2: u8 = 0x0b;
+pub(crate) const AVGCNTL: u8 = 0x41;
+pub(crate) const PDCNTL: u8 = 0x42;
+
+pub(crate) const WAI_VALUE: u8 = 0x10;
+pub(crate) const CNTL2_SRST: u8 = 0x01;
+/// The chip has no continuous mode, so each measurement is started after
+/// the previous one is read.
+pub(crate) const CNTL1_SINGLE: u8 = 0x01;
+/// 16 samples averaged on every axis.
+pub(crate) const AVGCNTL_16X: u8 = 0x24;
+/// The pulse duration the data sheet recommends.
+pub(crate) const PDCNTL_NORMAL: u8 = 0xc0;
+
+/// Highest rate at which single measurements can be made with 16 times
+/// averaging.
+pub(crate) const SAMPLE_HZ: f32 = 100.0;
+/// 0.3 microtesla per LSB.
+const LSB_PER_GAUSS: f32 = 1.0 / 0.003;
+/// Each axis measures +/- 16 gauss.
+const MAX_VALUE: i16 = 16 * LSB_PER_GAUSS as i16;
+
+/// X, Y and Z, each 16 bits little-endian.
+pub(crate) const DATA_LEN: usize = 6;
+
+/// Returns the field in gauss, or `None` if any axis overflowed.
+pub(crate) fn parse(buf: &[u8; DATA_LEN]) -> Option<[f32; 3]> {
+    let value = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]);
+    let (x, y, z) = (value(0), value(2), value(4));
+    if [x, y, z]
+        .iter()
+        .any(|value| value.unsigned_abs() > MAX_VALUE as u16)
+    {
+        return None;
+    }
+
+    // The chip's axes are left-handed, with y to the right of x when z is
+    // up, so y is negated to match the other chips.
+    Some([x, -y, z].map(|value| value as f32 / LSB_PER_GAUSS))
+}
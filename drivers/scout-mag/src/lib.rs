@@ -0,0 +1,173 @@
+#![no_std]
+
+use embedded_hal::i2c::{self, I2c};
+use embedded_hal_async::delay::DelayUs;
+
+mod hmc5883;
+mod ist8310;
+mod qmc5883;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Chip {
+    Qmc5883,
+    Hmc5883,
+    Ist8310,
+}
+
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Sample {
+    /// Magnetic field along the x, y and z axes, in gauss. The axes are
+    /// right-handed, with z up when the chip is flat, for every chip.
+    pub field: [f32; 3],
+}
+
+#[derive(defmt::Format)]
+pub enum Error<I2CError> {
+    I2c(I2CError),
+    Delay,
+    /// No supported chip answered.
+    NotFound,
+    /// The field was outside the measurement range on some axis, which
+    /// happens near magnets and motors.
+    Overflow,
+}
+
+pub struct Mag<I2C> {
+    i2c: I2C,
+    chip: Chip,
+    address: u8,
+}
+
+impl<I2C> Mag<I2C>
+where
+    I2C: I2c,
+{
+    /// Looks for each chip at its address, resets the one found, and starts
+    /// measuring.
+    pub async fn new<DELAY: DelayUs>(
+        i2c: I2C,
+        mut delay: DELAY,
+    ) -> Result<Self, Error<<I2C as i2c::ErrorType>::Error>> {
+        let mut mag = Self {
+            i2c,
+            // Overwritten once the chip is identified.
+            chip: Chip::Qmc5883,
+            address: qmc5883::ADDRESS,
+        };
+
+        // Addresses without a chip don't acknowledge, so read errors while
+        // probing just mean the chip isn't there.
+        if mag.read_register(qmc5883::CHIP_ID).ok() == Some(qmc5883::CHIP_ID_VALUE) {
+            mag.write_register(qmc5883::CONTROL_2, qmc5883::CONTROL_2_SOFT_RST)?;
+            delay.delay_ms(10).await.map_err(|_| Error::Delay)?;
+
+            mag.write_register(qmc5883::SET_RESET_PERIOD, qmc5883::SET_RESET_PERIOD_VALUE)?;
+            mag.write_register(qmc5883::CONTROL_1, qmc5883::CONTROL_1_CONTINUOUS)?;
+
+            return Ok(mag);
+        }
+
+        mag.chip = Chip::Hmc5883;
+        mag.address = hmc5883::ADDRESS;
+        let mut id = [0; 3];
+        if mag.read_registers(hmc5883::ID_A, &mut id).is_ok() && id == hmc5883::ID_VALUE {
+            mag.write_register(hmc5883::CONFIG_A, hmc5883::CONFIG_A_75HZ_8X)?;
+            mag.write_register(hmc5883::CONFIG_B, hmc5883::CONFIG_B_GAIN_1_3)?;
+            mag.write_register(hmc5883::MODE, hmc5883::MODE_CONTINUOUS)?;
+            // The first measurement uses the previous gain.
+            delay.delay_ms(15).await.map_err(|_| Error::Delay)?;
+
+            return Ok(mag);
+        }
+
+        mag.chip = Chip::Ist8310;
+        for address in ist8310::ADDRESSES {
+            mag.address = address;
+            if mag.read_register(ist8310::WAI).ok() != Some(ist8310::WAI_VALUE) {
+                continue;
+            }
+
+            mag.write_register(ist8310::CNTL2, ist8310::CNTL2_SRST)?;
+            delay.delay_ms(10).await.map_err(|_| Error::Delay)?;
+
+            mag.write_register(ist8310::AVGCNTL, ist8310::AVGCNTL_16X)?;
+            mag.write_register(ist8310::PDCNTL, ist8310::PDCNTL_NORMAL)?;
+            mag.write_register(ist8310::CNTL1, ist8310::CNTL1_SINGLE)?;
+
+            return Ok(mag);
+        }
+
+        Err(Error::NotFound)
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Rate at which the chip produces new measurements. Reading faster
+    /// than this returns repeated samples, except from the IST8310, which
+    /// must not be read faster than this.
+    pub fn sample_hz(&self) -> f32 {
+        match self.chip {
+            Chip::Qmc5883 => qmc5883::SAMPLE_HZ,
+            Chip::Hmc5883 => hmc5883::SAMPLE_HZ,
+            Chip::Ist8310 => ist8310::SAMPLE_HZ,
+        }
+    }
+
+    /// Reads the latest measurement. This blocks while the bus transfers
+    /// the data.
+    pub fn read(&mut self) -> Result<Sample, Error<<I2C as i2c::ErrorType>::Error>> {
+        let field = match self.chip {
+            Chip::Qmc5883 => {
+                let mut buf = [0; qmc5883::DATA_LEN];
+                self.read_registers(qmc5883::DATA_X_LSB, &mut buf)?;
+                qmc5883::parse(&buf)
+            }
+            Chip::Hmc5883 => {
+                let mut buf = [0; hmc5883::DATA_LEN];
+                self.read_registers(hmc5883::DATA_X_MSB, &mut buf)?;
+                hmc5883::parse(&buf)
+            }
+            Chip::Ist8310 => {
+                let mut buf = [0; ist8310::DATA_LEN];
+                self.read_registers(ist8310::DATA_X_LSB, &mut buf)?;
+                self.write_register(ist8310::CNTL1, ist8310::CNTL1_SINGLE)?;
+                ist8310::parse(&buf)
+            }
+        };
+
+        Ok(Sample {
+            field: field.ok_or(Error::Overflow)?,
+        })
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<<I2C as i2c::ErrorType>::Error>> {
+        let mut buf = [0];
+        self.read_registers(register, &mut buf)?;
+
+        Ok(buf[0])
+    }
+
+    /// The register address auto-increments on every chip, so consecutive
+    /// registers are read in one transfer.
+    fn read_registers(
+        &mut self,
+        register: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<<I2C as i2c::ErrorType>::Error>> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .map_err(Error::I2c)
+    }
+
+    fn write_register(
+        &mut self,
+        register: u8,
+        value: u8,
+    ) -> Result<(), Error<<I2C as i2c::ErrorType>::Error>> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .map_err(Error::I2c)
+    }
+}
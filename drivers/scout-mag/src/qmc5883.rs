@@ -0,0 +1,36 @@
+//! Register map of the QMC5883L
+
+pub(crate) const ADDRESS: u8 = 0x0d;
+
+pub(crate) const DATA_X_LSB: u8 = 0x00;
+pub(crate) const CONTROL_1: u8 = 0x09;
+pub(crate) const CONTROL_2: u8 = 0x0a;
+pub(crate) const SET_RESET_PERIOD: u8 = 0x0b;
+pub(crate) const CHIP_ID: u8 = 0x0d;
+
+pub(crate) const CHIP_ID_VALUE: u8 = 0xff;
+pub(crate) const CONTROL_2_SOFT_RST: u8 = 0x80;
+/// The value the data sheet recommends.
+pub(crate) const SET_RESET_PERIOD_VALUE: u8 = 0x01;
+
+/// 512 times oversampling, +/- 8 gauss range, 200Hz and continuous mode.
+pub(crate) const CONTROL_1_CONTINUOUS: u8 = 0b00_01_11_01;
+
+pub(crate) const SAMPLE_HZ: f32 = 200.0;
+const LSB_PER_GAUSS: f32 = 3000.0;
+
+/// X, Y and Z, each 16 bits little-endian, followed by the status register.
+pub(crate) const DATA_LEN: usize = 7;
+
+/// Set in the status register when any axis is out of range.
+const STATUS_OVL: u8 = 0x02;
+
+/// Returns the field in gauss, or `None` if any axis overflowed.
+pub(crate) fn parse(buf: &[u8; DATA_LEN]) -> Option<[f32; 3]> {
+    if buf[6] & STATUS_OVL != 0 {
+        return None;
+    }
+    let value = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]);
+
+    Some([value(0), value(2), value(4)].map(|value| value as f32 / LSB_PER_GAUSS))
+}
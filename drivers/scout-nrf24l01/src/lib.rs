@@ -1,5 +1,7 @@
 #![no_std]
 
+use scout_rc::{AuxChannels, AxisCalibration, RcFrame, ThrottleCalibration};
+
 mod nrf24_syma;
 pub use nrf24_syma::SymaX5C;
 
@@ -11,4 +13,39 @@ pub struct FourChannelRadioData {
     pub yaw: i8,
     pub pitch: i8,
     pub roll: i8,
+    /// Momentary flip button.
+    pub flip: bool,
+    pub picture: bool,
+    pub video: bool,
+    pub high_rates: bool,
+}
+
+impl FourChannelRadioData {
+    /// Calibration covering the full range of the throttle field.
+    pub const THROTTLE_CALIBRATION: ThrottleCalibration = ThrottleCalibration::new(0, 255);
+    /// Calibration covering the full range of the yaw, pitch and roll fields.
+    pub const STICK_CALIBRATION: AxisCalibration = AxisCalibration::new(-127, 0, 127);
+}
+
+/// The flags are carried as auxiliary channels 0 to 3, in the order flip,
+/// picture, video and high rates. They are at full deflection in the
+/// positive direction when set, and the negative direction when not, so
+/// `STICK_CALIBRATION` applies to them as well.
+impl From<&FourChannelRadioData> for RcFrame {
+    fn from(radio_data: &FourChannelRadioData) -> Self {
+        let flag = |set: bool| if set { 127 } else { -127 };
+
+        Self {
+            throttle: radio_data.throttle.into(),
+            yaw: radio_data.yaw.into(),
+            pitch: radio_data.pitch.into(),
+            roll: radio_data.roll.into(),
+            aux: AuxChannels::new(&[
+                flag(radio_data.flip),
+                flag(radio_data.picture),
+                flag(radio_data.video),
+                flag(radio_data.high_rates),
+            ]),
+        }
+    }
 }
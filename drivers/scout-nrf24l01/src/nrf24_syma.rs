@@ -26,6 +26,12 @@ const DATA_CHANNELS: [u8; NUM_DATA_CHANNELS] = [
 const ADDR_LEN: usize = 5;
 const ADDR: [u8; ADDR_LEN] = [0x6d, 0x6a, 0x73, 0x73, 0x73];
 
+const FLAGS_IDX: usize = 14;
+const FLAG_FLIP: u8 = 0b0000_0001;
+const FLAG_HIGH_RATES: u8 = 0b0000_0100;
+const FLAG_PICTURE: u8 = 0b0000_1000;
+const FLAG_VIDEO: u8 = 0b0001_0000;
+
 pub struct SymaX5C<SPI, CE> {
     radio: Nrf23L01Plus<SPI, CE>,
     latest_packet: [u8; PAYLOAD_SIZE],
@@ -102,6 +108,10 @@ where
                 yaw: syma_convert_to_signed(self.latest_packet[1]),
                 pitch: -syma_convert_to_signed(self.latest_packet[2]),
                 roll: syma_convert_to_signed(self.latest_packet[3]),
+                flip: self.latest_packet[FLAGS_IDX] & FLAG_FLIP != 0,
+                picture: self.latest_packet[FLAGS_IDX] & FLAG_PICTURE != 0,
+                video: self.latest_packet[FLAGS_IDX] & FLAG_VIDEO != 0,
+                high_rates: self.latest_packet[FLAGS_IDX] & FLAG_HIGH_RATES != 0,
             };
 
             // Hop to the next channel after seeing two packets. This does
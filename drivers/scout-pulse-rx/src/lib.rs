@@ -0,0 +1,89 @@
+#![no_std]
+
+use scout_rc::{AxisCalibration, ThrottleCalibration};
+
+pub mod ppm;
+pub mod pwm;
+
+pub const STICK_CALIBRATION: AxisCalibration = AxisCalibration::new(1000, 1500, 2000);
+pub const THROTTLE_CALIBRATION: ThrottleCalibration = ThrottleCalibration::new(1000, 2000);
+
+/// Shortest channel pulse accepted, in microseconds.
+pub const MIN_PULSE_US: u32 = 750;
+/// Longest channel pulse accepted, in microseconds.
+pub const MAX_PULSE_US: u32 = 2250;
+
+/// Turns free running timer captures into the time between them.
+pub struct EdgeTimer {
+    last_capture: Option<u16>,
+}
+
+impl EdgeTimer {
+    pub const fn new() -> Self {
+        Self { last_capture: None }
+    }
+
+    /// Returns the time since the previous capture, in timer ticks. The
+    /// timer is expected to count through the full 16 bit range, and may
+    /// wrap at most once between captures.
+    pub fn capture(&mut self, capture: u16) -> Option<u32> {
+        let elapsed = self
+            .last_capture
+            .map(|last_capture| capture.wrapping_sub(last_capture) as u32);
+        self.last_capture = Some(capture);
+
+        elapsed
+    }
+
+    /// Forgets the previous capture, for example after switching the
+    /// captured edge.
+    pub fn reset(&mut self) {
+        self.last_capture = None;
+    }
+}
+
+impl Default for EdgeTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_valid_pulse(width_us: u32) -> bool {
+    (MIN_PULSE_US..=MAX_PULSE_US).contains(&width_us)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_capture_has_no_period() {
+        let mut timer = EdgeTimer::new();
+        assert_eq!(timer.capture(1000), None);
+        assert_eq!(timer.capture(2500), Some(1500));
+    }
+
+    #[test]
+    fn period_across_timer_wrap() {
+        let mut timer = EdgeTimer::new();
+        timer.capture(65000);
+        assert_eq!(timer.capture(964), Some(1500));
+    }
+
+    #[test]
+    fn reset_forgets_capture() {
+        let mut timer = EdgeTimer::new();
+        timer.capture(1000);
+        timer.reset();
+        assert_eq!(timer.capture(2000), None);
+        assert_eq!(timer.capture(3000), Some(1000));
+    }
+
+    #[test]
+    fn pulse_limits_are_inclusive() {
+        assert!(is_valid_pulse(MIN_PULSE_US));
+        assert!(is_valid_pulse(MAX_PULSE_US));
+        assert!(!is_valid_pulse(MIN_PULSE_US - 1));
+        assert!(!is_valid_pulse(MAX_PULSE_US + 1));
+    }
+}
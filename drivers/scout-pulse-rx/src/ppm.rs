@@ -0,0 +1,245 @@
+//! PPM sum signal decoding
+//!
+//! A PPM frame is a series of pulses, where the time between the start of
+//! consecutive pulses is a channel value. Frames are separated by a gap
+//! longer than any channel value.
+
+pub const MIN_CHANNELS: usize = 4;
+pub const MAX_CHANNELS: usize = 16;
+
+/// Gaps at least this long, in microseconds, mark the start of a frame.
+pub const SYNC_GAP_US: u32 = 2700;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Frame {
+    channels: [u16; MAX_CHANNELS],
+    len: usize,
+}
+
+impl Frame {
+    /// Channel values in microseconds.
+    pub fn channels(&self) -> &[u16] {
+        &self.channels[..self.len]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum State {
+    /// Waiting for a sync gap, either at startup or after an invalid pulse.
+    Unsynced,
+    Receiving,
+}
+
+pub struct Decoder {
+    state: State,
+    frame: Frame,
+    /// Channel count of the previous complete frame. A frame is only
+    /// published once two consecutive frames agree on this, which rejects
+    /// frames truncated by a missed edge.
+    previous_len: usize,
+    invalid_pulses: u32,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Unsynced,
+            frame: Frame {
+                channels: [0; MAX_CHANNELS],
+                len: 0,
+            },
+            previous_len: 0,
+            invalid_pulses: 0,
+        }
+    }
+
+    /// Takes the time since the previous rising edge in microseconds, and
+    /// returns a frame when that edge completed one.
+    pub fn push(&mut self, period_us: u32) -> Option<Frame> {
+        if period_us >= SYNC_GAP_US {
+            let frame = self.frame;
+            let complete = self.state == State::Receiving && frame.len >= MIN_CHANNELS;
+            let consistent = frame.len == self.previous_len;
+
+            self.previous_len = if complete { frame.len } else { 0 };
+            self.frame.len = 0;
+            self.state = State::Receiving;
+
+            return (complete && consistent).then_some(frame);
+        }
+
+        if self.state == State::Unsynced {
+            return None;
+        }
+
+        if !super::is_valid_pulse(period_us) || self.frame.len == MAX_CHANNELS {
+            self.invalid_pulses = self.invalid_pulses.wrapping_add(1);
+            self.state = State::Unsynced;
+            self.previous_len = 0;
+            return None;
+        }
+
+        self.frame.channels[self.frame.len] = period_us as u16;
+        self.frame.len += 1;
+
+        None
+    }
+
+    /// Number of pulses which were out of range or overflowed a frame,
+    /// each of which causes the decoder to wait for the next sync gap.
+    pub fn invalid_pulses(&self) -> u32 {
+        self.invalid_pulses
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MAX_PULSE_US, MIN_PULSE_US};
+
+    const CHANNELS: [u32; 8] = [1500, 1000, 2000, 1500, 1100, 1900, 1500, 1500];
+    /// What's left of a 22.5 ms frame after the channels.
+    const SYNC_US: u32 = 22_500 - 12_000;
+
+    /// Pushes the periods of one frame, which ends at the next sync gap,
+    /// returning the frame it completes.
+    fn push_frame(decoder: &mut Decoder, channels: &[u32]) -> Option<Frame> {
+        for channel in channels {
+            assert!(decoder.push(*channel).is_none());
+        }
+        decoder.push(SYNC_US)
+    }
+
+    fn as_u32(frame: &Frame) -> impl Iterator<Item = u32> + '_ {
+        frame.channels().iter().map(|channel| *channel as u32)
+    }
+
+    #[test]
+    fn decodes_after_two_consistent_frames() {
+        let mut decoder = Decoder::new();
+        assert!(decoder.push(SYNC_US).is_none());
+        assert!(push_frame(&mut decoder, &CHANNELS).is_none());
+
+        for _ in 0..3 {
+            let frame = push_frame(&mut decoder, &CHANNELS).unwrap();
+            assert!(as_u32(&frame).eq(CHANNELS));
+        }
+        assert_eq!(decoder.invalid_pulses(), 0);
+    }
+
+    #[test]
+    fn ignores_pulses_before_first_sync() {
+        let mut decoder = Decoder::new();
+        // Starting part way through a frame.
+        for channel in &CHANNELS[5..] {
+            assert!(decoder.push(*channel).is_none());
+        }
+        assert!(decoder.push(SYNC_US).is_none());
+        assert!(push_frame(&mut decoder, &CHANNELS).is_none());
+        assert!(push_frame(&mut decoder, &CHANNELS).is_some());
+        assert_eq!(decoder.invalid_pulses(), 0);
+    }
+
+    #[test]
+    fn sync_gap_threshold() {
+        let mut decoder = Decoder::new();
+        decoder.push(SYNC_GAP_US);
+        push_frame(&mut decoder, &CHANNELS);
+        for channel in CHANNELS {
+            decoder.push(channel);
+        }
+        // Just short of a sync gap is an invalid channel.
+        assert!(decoder.push(SYNC_GAP_US - 1).is_none());
+        assert_eq!(decoder.invalid_pulses(), 1);
+    }
+
+    #[test]
+    fn rejects_frame_truncated_by_missed_edge() {
+        let mut decoder = Decoder::new();
+        decoder.push(SYNC_US);
+        push_frame(&mut decoder, &CHANNELS);
+        assert!(push_frame(&mut decoder, &CHANNELS).is_some());
+
+        // A missed edge merges two channels into one period, which is out
+        // of range here and loses sync.
+        let mut merged = [0; 7];
+        merged[0] = CHANNELS[0] + CHANNELS[1];
+        merged[1..].copy_from_slice(&CHANNELS[2..]);
+        assert!(push_frame(&mut decoder, &merged).is_none());
+        assert_eq!(decoder.invalid_pulses(), 1);
+
+        // Both the next frame and the one after must agree again.
+        assert!(push_frame(&mut decoder, &CHANNELS).is_none());
+        assert!(push_frame(&mut decoder, &CHANNELS).is_some());
+    }
+
+    #[test]
+    fn rejects_frame_whose_length_changed() {
+        let mut decoder = Decoder::new();
+        decoder.push(SYNC_US);
+        push_frame(&mut decoder, &CHANNELS);
+        assert!(push_frame(&mut decoder, &CHANNELS).is_some());
+
+        assert!(push_frame(&mut decoder, &CHANNELS[..6]).is_none());
+        assert!(push_frame(&mut decoder, &CHANNELS[..6]).is_some());
+        assert_eq!(decoder.invalid_pulses(), 0);
+    }
+
+    #[test]
+    fn rejects_too_few_channels() {
+        let mut decoder = Decoder::new();
+        decoder.push(SYNC_US);
+        for _ in 0..3 {
+            assert!(push_frame(&mut decoder, &CHANNELS[..MIN_CHANNELS - 1]).is_none());
+        }
+    }
+
+    #[test]
+    fn accepts_channel_count_limits() {
+        for len in [MIN_CHANNELS, MAX_CHANNELS] {
+            let channels = [1500; MAX_CHANNELS];
+            let mut decoder = Decoder::new();
+            decoder.push(SYNC_US);
+            push_frame(&mut decoder, &channels[..len]);
+            let frame = push_frame(&mut decoder, &channels[..len]).unwrap();
+            assert_eq!(frame.channels().len(), len);
+        }
+    }
+
+    #[test]
+    fn too_many_channels_loses_sync() {
+        let channels = [1500; MAX_CHANNELS + 1];
+        let mut decoder = Decoder::new();
+        decoder.push(SYNC_US);
+        for _ in 0..3 {
+            for channel in channels {
+                decoder.push(channel);
+            }
+            assert!(decoder.push(SYNC_US).is_none());
+        }
+        assert_eq!(decoder.invalid_pulses(), 3);
+    }
+
+    #[test]
+    fn out_of_range_pulse_drops_frame() {
+        for invalid in [MIN_PULSE_US - 1, MAX_PULSE_US + 1] {
+            let mut decoder = Decoder::new();
+            decoder.push(SYNC_US);
+            push_frame(&mut decoder, &CHANNELS);
+
+            let mut channels = CHANNELS;
+            channels[3] = invalid;
+            for channel in channels {
+                assert!(decoder.push(channel).is_none());
+            }
+            assert!(decoder.push(SYNC_US).is_none());
+            assert_eq!(decoder.invalid_pulses(), 1);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Multi-channel PWM decoding
+//!
+//! Each channel is a separate signal, whose high time is the channel value.
+//! Receivers usually output the channels one after the other, but this
+//! does not rely on any particular order.
+
+pub struct Decoder<const N: usize> {
+    channels: [u16; N],
+    /// Bit n is set once channel n has received a valid pulse since the
+    /// last frame was published.
+    updated: u32,
+    invalid_pulses: u32,
+}
+
+impl<const N: usize> Decoder<N> {
+    pub const fn new() -> Self {
+        assert!(N <= 32);
+
+        Self {
+            channels: [0; N],
+            updated: 0,
+            invalid_pulses: 0,
+        }
+    }
+
+    /// Takes the high time of `channel` in microseconds, and returns channel
+    /// values in microseconds once every channel has been updated.
+    pub fn push(&mut self, channel: usize, width_us: u32) -> Option<[u16; N]> {
+        if !super::is_valid_pulse(width_us) {
+            self.invalid_pulses = self.invalid_pulses.wrapping_add(1);
+            return None;
+        }
+
+        self.channels[channel] = width_us as u16;
+        self.updated |= 1 << channel;
+
+        let all_updated = ((1u64 << N) - 1) as u32;
+        if self.updated == all_updated {
+            self.updated = 0;
+            Some(self.channels)
+        } else {
+            None
+        }
+    }
+
+    /// Number of pulses which were out of range, and so ignored.
+    pub fn invalid_pulses(&self) -> u32 {
+        self.invalid_pulses
+    }
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MAX_PULSE_US, MIN_PULSE_US};
+
+    #[test]
+    fn publishes_once_every_channel_updated() {
+        let mut decoder = Decoder::<4>::new();
+        assert!(decoder.push(0, 1500).is_none());
+        assert!(decoder.push(1, 1000).is_none());
+        assert!(decoder.push(2, 2000).is_none());
+        assert_eq!(decoder.push(3, 1200), Some([1500, 1000, 2000, 1200]));
+
+        // The next frame needs every channel again.
+        assert!(decoder.push(3, 1300).is_none());
+        assert!(decoder.push(0, 1400).is_none());
+        assert!(decoder.push(2, 1900).is_none());
+        assert_eq!(decoder.push(1, 1100), Some([1400, 1100, 1900, 1300]));
+    }
+
+    #[test]
+    fn repeated_channel_keeps_latest_value() {
+        let mut decoder = Decoder::<2>::new();
+        assert!(decoder.push(0, 1000).is_none());
+        assert!(decoder.push(0, 1600).is_none());
+        assert_eq!(decoder.push(1, 1500), Some([1600, 1500]));
+    }
+
+    #[test]
+    fn ignores_out_of_range_pulses() {
+        let mut decoder = Decoder::<2>::new();
+        assert!(decoder.push(0, 1500).is_none());
+        assert!(decoder.push(1, MIN_PULSE_US - 1).is_none());
+        assert!(decoder.push(1, MAX_PULSE_US + 1).is_none());
+        assert_eq!(decoder.invalid_pulses(), 2);
+        assert_eq!(
+            decoder.push(1, MAX_PULSE_US),
+            Some([1500, MAX_PULSE_US as u16])
+        );
+    }
+
+    #[test]
+    fn supports_32_channels() {
+        let mut decoder = Decoder::<32>::new();
+        for channel in 0..31 {
+            assert!(decoder.push(channel, 1500).is_none());
+        }
+        assert_eq!(decoder.push(31, 1500), Some([1500; 32]));
+    }
+}
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use scout_serial_rx::crsf::{Packet, Parser, Telemetry, MAX_FRAME_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::new();
+    for byte in data {
+        if let Some(Packet::RcChannels(channels)) = parser.push(*byte) {
+            assert!(channels.iter().all(|channel| *channel <= 0x07ff));
+        }
+    }
+
+    // Flight mode names of any length are truncated to fit a frame.
+    if let Ok(mode) = core::str::from_utf8(data) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = Telemetry::FlightMode(mode).encode(&mut buf);
+        assert!(len <= MAX_FRAME_LEN);
+        assert_eq!(buf[1] as usize, len - 2);
+    }
+});
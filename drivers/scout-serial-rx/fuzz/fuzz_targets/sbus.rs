@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use scout_serial_rx::sbus::Parser;
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::new();
+    for byte in data {
+        if let Some(frame) = parser.push(*byte) {
+            assert!(frame.channels.iter().all(|channel| *channel <= 0x07ff));
+        }
+    }
+});
@@ -0,0 +1,403 @@
+//! Crossfire / ExpressLRS CRSF
+//!
+//! CRSF runs at 420000 baud, 8 data bits, no parity and 1 stop bit. Frames
+//! are laid out as `[address, length, type, payload.., crc]`, where length
+//! counts the type, payload and CRC bytes.
+
+use scout_rc::{AxisCalibration, ThrottleCalibration};
+
+pub const BAUD_RATE: u32 = 420_000;
+
+pub const STICK_CALIBRATION: AxisCalibration = AxisCalibration::new(172, 992, 1811);
+pub const THROTTLE_CALIBRATION: ThrottleCalibration = ThrottleCalibration::new(172, 1811);
+
+/// The largest frame, including the address and length bytes.
+pub const MAX_FRAME_LEN: usize = 64;
+
+const ADDRESS_FLIGHT_CONTROLLER: u8 = 0xc8;
+
+const FRAME_TYPE_BATTERY_SENSOR: u8 = 0x08;
+const FRAME_TYPE_LINK_STATISTICS: u8 = 0x14;
+const FRAME_TYPE_RC_CHANNELS_PACKED: u8 = 0x16;
+const FRAME_TYPE_ATTITUDE: u8 = 0x1e;
+const FRAME_TYPE_FLIGHT_MODE: u8 = 0x21;
+
+const RC_CHANNELS_PACKED_LEN: usize = 22;
+const LINK_STATISTICS_LEN: usize = 10;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Packet {
+    /// Proportional channels, in the range 172..=1811 from most transmitters.
+    RcChannels([u16; 16]),
+    LinkStatistics(LinkStatistics),
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct LinkStatistics {
+    /// Received signal strength at each receiver antenna, in -dBm.
+    pub uplink_rssi: [u8; 2],
+    /// Percentage of packets received from the transmitter.
+    pub uplink_link_quality: u8,
+    /// Signal to noise ratio in dB.
+    pub uplink_snr: i8,
+    pub active_antenna: u8,
+    pub rf_mode: u8,
+    /// Index into the transmitter's table of power levels.
+    pub uplink_tx_power: u8,
+    /// Received signal strength at the transmitter, in -dBm.
+    pub downlink_rssi: u8,
+    /// Percentage of telemetry packets received by the transmitter.
+    pub downlink_link_quality: u8,
+    /// Signal to noise ratio in dB.
+    pub downlink_snr: i8,
+}
+
+pub struct Parser {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns a packet when `byte` completes a frame of a supported type.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        match self.len {
+            0 if byte != ADDRESS_FLIGHT_CONTROLLER => return None,
+            // The length byte must at least cover the type and CRC.
+            1 if !(2..=MAX_FRAME_LEN as u8 - 2).contains(&byte) => {
+                self.len = 0;
+                // The byte which broke the frame may start the next one.
+                if byte != ADDRESS_FLIGHT_CONTROLLER {
+                    return None;
+                }
+            }
+            _ => {}
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < 2 || self.len < self.buf[1] as usize + 2 {
+            return None;
+        }
+        let frame_len = self.len;
+        self.len = 0;
+
+        let (crc, body) = self.buf[2..frame_len].split_last()?;
+        if crc8_dvb_s2(body) != *crc {
+            return None;
+        }
+
+        let (frame_type, payload) = body.split_first()?;
+        match *frame_type {
+            FRAME_TYPE_RC_CHANNELS_PACKED if payload.len() == RC_CHANNELS_PACKED_LEN => {
+                Some(Packet::RcChannels(super::unpack_11_bit_channels(payload)))
+            }
+            FRAME_TYPE_LINK_STATISTICS if payload.len() == LINK_STATISTICS_LEN => {
+                Some(Packet::LinkStatistics(LinkStatistics {
+                    uplink_rssi: [payload[0], payload[1]],
+                    uplink_link_quality: payload[2],
+                    uplink_snr: payload[3] as i8,
+                    active_antenna: payload[4],
+                    rf_mode: payload[5],
+                    uplink_tx_power: payload[6],
+                    downlink_rssi: payload[7],
+                    downlink_link_quality: payload[8],
+                    downlink_snr: payload[9] as i8,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Telemetry sent from the flight controller to the receiver, which relays
+/// it to the transmitter.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Telemetry<'a> {
+    Battery {
+        /// In units of 0.1V.
+        voltage: u16,
+        /// In units of 0.1A.
+        current: u16,
+        /// Consumed capacity in mAh. Only the lower 24 bits are sent.
+        capacity_used: u32,
+        /// Remaining capacity in percent.
+        remaining: u8,
+    },
+    Attitude {
+        /// In units of 0.0001 radians.
+        pitch: i16,
+        /// In units of 0.0001 radians.
+        roll: i16,
+        /// In units of 0.0001 radians.
+        yaw: i16,
+    },
+    /// Truncated to fit in a frame.
+    FlightMode(&'a str),
+}
+
+impl Telemetry<'_> {
+    /// Encodes a complete frame into `buf`, returning its length.
+    pub fn encode(&self, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut payload = [0; MAX_FRAME_LEN - 4];
+        let (frame_type, payload_len) = match *self {
+            Telemetry::Battery {
+                voltage,
+                current,
+                capacity_used,
+                remaining,
+            } => {
+                payload[0..2].copy_from_slice(&voltage.to_be_bytes());
+                payload[2..4].copy_from_slice(&current.to_be_bytes());
+                payload[4..7].copy_from_slice(&capacity_used.to_be_bytes()[1..]);
+                payload[7] = remaining;
+                (FRAME_TYPE_BATTERY_SENSOR, 8)
+            }
+            Telemetry::Attitude { pitch, roll, yaw } => {
+                payload[0..2].copy_from_slice(&pitch.to_be_bytes());
+                payload[2..4].copy_from_slice(&roll.to_be_bytes());
+                payload[4..6].copy_from_slice(&yaw.to_be_bytes());
+                (FRAME_TYPE_ATTITUDE, 6)
+            }
+            Telemetry::FlightMode(mode) => {
+                // Leave room for the null terminator.
+                let len = mode.len().min(payload.len() - 1);
+                payload[..len].copy_from_slice(&mode.as_bytes()[..len]);
+                payload[len] = 0;
+                (FRAME_TYPE_FLIGHT_MODE, len + 1)
+            }
+        };
+
+        let frame_len = payload_len + 4;
+        buf[0] = ADDRESS_FLIGHT_CONTROLLER;
+        buf[1] = (payload_len + 2) as u8;
+        buf[2] = frame_type;
+        buf[3..frame_len - 1].copy_from_slice(&payload[..payload_len]);
+        buf[frame_len - 1] = crc8_dvb_s2(&buf[2..frame_len - 1]);
+
+        frame_len
+    }
+}
+
+fn crc8_dvb_s2(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0xd5
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An RC channels frame with every channel centered, as sent by an
+    /// ExpressLRS receiver.
+    const CENTERED: [u8; 26] = [
+        0xc8, 0x18, 0x16, 0xe0, 0x03, 0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0xe0,
+        0x03, 0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0xad,
+    ];
+
+    /// An RC channels frame with the first channel at its minimum, the
+    /// third at its maximum, and the fifth and sixth near their ends.
+    const STICKS: [u8; 26] = [
+        0xc8, 0x18, 0x16, 0xac, 0x00, 0xdf, 0xc4, 0xc1, 0xf7, 0x0b, 0x80, 0x83, 0x0f, 0x7c, 0xe0,
+        0x03, 0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0x1f,
+    ];
+    const STICK_CHANNELS: [u16; 16] = [
+        172, 992, 1811, 992, 191, 1792, 992, 992, 992, 992, 992, 992, 992, 992, 992, 992,
+    ];
+
+    const LINK_STATISTICS: [u8; 14] = [
+        0xc8, 0x0c, 0x14, 0x2d, 0x32, 0x64, 0x09, 0x01, 0x04, 0x03, 0x3c, 0x62, 0x07, 0xa3,
+    ];
+
+    /// Pushes every byte, returning the number of packets and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Packet>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), packet| (count + 1, Some(packet)))
+    }
+
+    fn channels(packet: Option<Packet>) -> Option<[u16; 16]> {
+        match packet {
+            Some(Packet::RcChannels(channels)) => Some(channels),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn decodes_rc_channels() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            channels(push_all(&mut parser, &CENTERED).1),
+            Some([992; 16])
+        );
+        assert_eq!(
+            channels(push_all(&mut parser, &STICKS).1),
+            Some(STICK_CHANNELS)
+        );
+    }
+
+    #[test]
+    fn decodes_link_statistics() {
+        let Some(Packet::LinkStatistics(stats)) = push_all(&mut Parser::new(), &LINK_STATISTICS).1
+        else {
+            panic!("no link statistics");
+        };
+
+        assert_eq!(stats.uplink_rssi, [45, 50]);
+        assert_eq!(stats.uplink_link_quality, 100);
+        assert_eq!(stats.uplink_snr, 9);
+        assert_eq!(stats.active_antenna, 1);
+        assert_eq!(stats.rf_mode, 4);
+        assert_eq!(stats.uplink_tx_power, 3);
+        assert_eq!(stats.downlink_rssi, 60);
+        assert_eq!(stats.downlink_link_quality, 98);
+        assert_eq!(stats.downlink_snr, 7);
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut bytes = CENTERED;
+        bytes[10] ^= 0x01;
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &bytes).0, 0);
+
+        // The next frame is still found.
+        assert_eq!(push_all(&mut parser, &CENTERED).0, 1);
+    }
+
+    #[test]
+    fn ignores_unsupported_frames() {
+        let mut telemetry = [0; MAX_FRAME_LEN];
+        let len = Telemetry::FlightMode("ACRO").encode(&mut telemetry);
+
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &telemetry[..len]).0, 0);
+        assert_eq!(push_all(&mut parser, &CENTERED).0, 1);
+    }
+
+    #[test]
+    fn skips_bytes_before_address() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[0x00, 0xee, 0x18, 0x16]).0, 0);
+        assert_eq!(push_all(&mut parser, &CENTERED).0, 1);
+    }
+
+    #[test]
+    fn bad_length_byte_may_start_next_frame() {
+        // A repeated address byte is an invalid length, but is the start of
+        // the frame which follows.
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[ADDRESS_FLIGHT_CONTROLLER]).0, 0);
+        assert_eq!(push_all(&mut parser, &CENTERED).0, 1);
+
+        for length in [0, 1, MAX_FRAME_LEN as u8 - 1] {
+            let mut parser = Parser::new();
+            push_all(&mut parser, &[ADDRESS_FLIGHT_CONTROLLER, length]);
+            assert_eq!(push_all(&mut parser, &CENTERED).0, 1);
+        }
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut bytes = [0; 2 * 26 + 14];
+        bytes[..26].copy_from_slice(&STICKS);
+        bytes[26..40].copy_from_slice(&LINK_STATISTICS);
+        bytes[40..].copy_from_slice(&CENTERED);
+
+        let (count, packet) = push_all(&mut Parser::new(), &bytes);
+        assert_eq!(count, 3);
+        assert_eq!(channels(packet), Some([992; 16]));
+    }
+
+    #[test]
+    fn encodes_battery_telemetry() {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = Telemetry::Battery {
+            voltage: 168,
+            current: 123,
+            capacity_used: 0x0102_0304,
+            remaining: 80,
+        }
+        .encode(&mut buf);
+
+        assert_eq!(
+            buf[..len - 1],
+            [
+                ADDRESS_FLIGHT_CONTROLLER,
+                10,
+                FRAME_TYPE_BATTERY_SENSOR,
+                0,
+                168,
+                0,
+                123,
+                0x02,
+                0x03,
+                0x04,
+                80
+            ]
+        );
+        assert_eq!(buf[len - 1], crc8_dvb_s2(&buf[2..len - 1]));
+    }
+
+    #[test]
+    fn encodes_attitude_telemetry() {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = Telemetry::Attitude {
+            pitch: -1,
+            roll: 0x1234,
+            yaw: 2,
+        }
+        .encode(&mut buf);
+
+        assert_eq!(
+            buf[..len - 1],
+            [
+                ADDRESS_FLIGHT_CONTROLLER,
+                8,
+                FRAME_TYPE_ATTITUDE,
+                0xff,
+                0xff,
+                0x12,
+                0x34,
+                0x00,
+                0x02
+            ]
+        );
+        assert_eq!(buf[len - 1], crc8_dvb_s2(&buf[2..len - 1]));
+    }
+
+    #[test]
+    fn truncates_long_flight_mode() {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let mode = core::str::from_utf8(&[b'A'; MAX_FRAME_LEN]).unwrap();
+        let len = Telemetry::FlightMode(mode).encode(&mut buf);
+
+        assert_eq!(len, MAX_FRAME_LEN);
+        assert_eq!(buf[1] as usize, len - 2);
+        assert_eq!(buf[len - 2], 0);
+        assert_eq!(buf[len - 1], crc8_dvb_s2(&buf[2..len - 1]));
+    }
+}
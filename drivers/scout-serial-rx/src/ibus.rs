@@ -0,0 +1,157 @@
+//! FlySky IBUS
+//!
+//! IBUS runs at 115200 baud, 8 data bits, no parity and 1 stop bit.
+
+use scout_rc::{AxisCalibration, ThrottleCalibration};
+
+pub const BAUD_RATE: u32 = 115_200;
+
+pub const STICK_CALIBRATION: AxisCalibration = AxisCalibration::new(1000, 1500, 2000);
+pub const THROTTLE_CALIBRATION: ThrottleCalibration = ThrottleCalibration::new(1000, 2000);
+
+const FRAME_LEN: usize = 32;
+/// The first byte of a frame is its length.
+const HEADER: [u8; 2] = [FRAME_LEN as u8, 0x40];
+const NUM_CHANNELS: usize = 14;
+const CHECKSUM_IDX: usize = FRAME_LEN - 2;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Frame {
+    /// Channel pulse widths in microseconds.
+    pub channels: [u16; NUM_CHANNELS],
+}
+
+pub struct Parser {
+    buf: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Returns a frame when `byte` completes one.
+    pub fn push(&mut self, byte: u8) -> Option<Frame> {
+        if self.len < HEADER.len() && byte != HEADER[self.len] {
+            self.len = 0;
+            // The byte which broke the header may start the next one.
+            if byte != HEADER[0] {
+                return None;
+            }
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < FRAME_LEN {
+            return None;
+        }
+        self.len = 0;
+
+        let sum = self.buf[..CHECKSUM_IDX]
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        let checksum = u16::from_le_bytes([self.buf[CHECKSUM_IDX], self.buf[CHECKSUM_IDX + 1]]);
+        if checksum != 0xffff - sum {
+            return None;
+        }
+
+        let mut channels = [0; NUM_CHANNELS];
+        for (channel, bytes) in channels
+            .iter_mut()
+            .zip(self.buf[HEADER.len()..CHECKSUM_IDX].chunks_exact(2))
+        {
+            // Some receivers use the upper nibbles to carry extra channels,
+            // which are not supported here.
+            *channel = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x0fff;
+        }
+
+        Some(Frame { channels })
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame as a FlySky receiver sends it, with channels at 1500, 1000
+    /// and 2000 us.
+    const FRAME: [u8; FRAME_LEN] = [
+        0x20, 0x40, 0xdc, 0x05, 0xe8, 0x03, 0xd0, 0x07, 0xdc, 0x05, 0xe8, 0x03, 0xd0, 0x07, 0xdc,
+        0x05, 0xdc, 0x05, 0xdc, 0x05, 0xdc, 0x05, 0xdc, 0x05, 0xdc, 0x05, 0xdc, 0x05, 0xdc, 0x05,
+        0x51, 0xf3,
+    ];
+    const CHANNELS: [u16; NUM_CHANNELS] = [
+        1500, 1000, 2000, 1500, 1000, 2000, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500,
+    ];
+
+    /// Pushes every byte, returning the number of frames and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Frame>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), frame| (count + 1, Some(frame)))
+    }
+
+    #[test]
+    fn decodes_frame() {
+        let (count, frame) = push_all(&mut Parser::new(), &FRAME);
+
+        assert_eq!(count, 1);
+        assert_eq!(frame.unwrap().channels, CHANNELS);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut bytes = FRAME;
+        bytes[CHECKSUM_IDX] ^= 0x01;
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &bytes).0, 0);
+        assert_eq!(push_all(&mut parser, &FRAME).0, 1);
+    }
+
+    #[test]
+    fn masks_extra_channel_nibbles() {
+        let mut bytes = FRAME;
+        bytes[3] |= 0x30;
+        let sum = bytes[..CHECKSUM_IDX]
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        bytes[CHECKSUM_IDX..].copy_from_slice(&(0xffff - sum).to_le_bytes());
+
+        assert_eq!(
+            push_all(&mut Parser::new(), &bytes).1.unwrap().channels,
+            CHANNELS
+        );
+    }
+
+    #[test]
+    fn resyncs_on_broken_header() {
+        // The length byte repeated, as after a byte of a frame was lost.
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[0x20, 0x20]).0, 0);
+        assert_eq!(push_all(&mut parser, &FRAME[1..]).0, 1);
+
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[0x00, 0x40, 0x20, 0x41]).0, 0);
+        assert_eq!(push_all(&mut parser, &FRAME).0, 1);
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut parser = Parser::new();
+        for _ in 0..3 {
+            assert_eq!(push_all(&mut parser, &FRAME).1.unwrap().channels, CHANNELS);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+#![no_std]
+
+pub mod crsf;
+pub mod ibus;
+pub mod sbus;
+
+/// Unpacks 16 little-endian 11 bit channel values, as used by both SBUS
+/// and CRSF.
+fn unpack_11_bit_channels(packed: &[u8]) -> [u16; 16] {
+    let mut channels = [0; 16];
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    let mut bytes = packed.iter();
+
+    for channel in channels.iter_mut() {
+        while bit_count < 11 {
+            bits |= (*bytes.next().unwrap_or(&0) as u32) << bit_count;
+            bit_count += 8;
+        }
+        *channel = (bits & 0x07ff) as u16;
+        bits >>= 11;
+        bit_count -= 11;
+    }
+
+    channels
+}
@@ -0,0 +1,204 @@
+//! Futaba SBUS
+//!
+//! SBUS runs at 100000 baud, 8 data bits, even parity and 2 stop bits, with
+//! the signal inverted. The STM32F4 USARTs cannot invert their input, so an
+//! external inverter is required.
+//!
+//! The header byte can also appear within a frame, so the parser can only
+//! find the start of a frame reliably from the gap between frames. Call
+//! `Parser::reset` when the line has been silent for longer than the time
+//! between bytes of a frame.
+
+use scout_rc::{AxisCalibration, ThrottleCalibration};
+
+pub const BAUD_RATE: u32 = 100_000;
+
+pub const STICK_CALIBRATION: AxisCalibration = AxisCalibration::new(172, 992, 1811);
+pub const THROTTLE_CALIBRATION: ThrottleCalibration = ThrottleCalibration::new(172, 1811);
+
+const FRAME_LEN: usize = 25;
+const HEADER: u8 = 0x0f;
+const FLAGS_IDX: usize = 23;
+const FOOTER_IDX: usize = 24;
+
+const FLAG_CH17: u8 = 0b0000_0001;
+const FLAG_CH18: u8 = 0b0000_0010;
+const FLAG_FRAME_LOST: u8 = 0b0000_0100;
+const FLAG_FAILSAFE: u8 = 0b0000_1000;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Frame {
+    /// Proportional channels, in the range 172..=1811 from most transmitters.
+    pub channels: [u16; 16],
+    pub ch17: bool,
+    pub ch18: bool,
+    /// Set by the receiver when it missed the latest frame from the transmitter.
+    pub frame_lost: bool,
+    /// Set by the receiver when it has lost the transmitter, in which case
+    /// the channel values are its configured failsafe positions.
+    pub failsafe: bool,
+}
+
+pub struct Parser {
+    buf: [u8; FRAME_LEN],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Discards any partly received frame, so that the next byte is taken
+    /// as the start of a frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// Returns a frame when `byte` completes one.
+    pub fn push(&mut self, byte: u8) -> Option<Frame> {
+        if self.len == 0 && byte != HEADER {
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        if self.len < FRAME_LEN {
+            return None;
+        }
+        self.len = 0;
+
+        // SBUS2 receivers cycle the upper nibble of the footer through
+        // telemetry slots, with 0x04 in the lower nibble.
+        let footer = self.buf[FOOTER_IDX];
+        if footer != 0x00 && footer & 0x0f != 0x04 {
+            return None;
+        }
+
+        let flags = self.buf[FLAGS_IDX];
+        Some(Frame {
+            channels: super::unpack_11_bit_channels(&self.buf[1..FLAGS_IDX]),
+            ch17: flags & FLAG_CH17 != 0,
+            ch18: flags & FLAG_CH18 != 0,
+            frame_lost: flags & FLAG_FRAME_LOST != 0,
+            failsafe: flags & FLAG_FAILSAFE != 0,
+        })
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame as a receiver sends it, with the first channel at its
+    /// minimum, the third at its maximum, the fifth and sixth near their
+    /// ends, and everything else centered.
+    const FRAME: [u8; FRAME_LEN] = [
+        0x0f, 0xac, 0x00, 0xdf, 0xc4, 0xc1, 0xf7, 0x0b, 0x80, 0x83, 0x0f, 0x7c, 0xe0, 0x03, 0x1f,
+        0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0x00, 0x00,
+    ];
+    const CHANNELS: [u16; 16] = [
+        172, 992, 1811, 992, 191, 1792, 992, 992, 992, 992, 992, 992, 992, 992, 992, 992,
+    ];
+
+    /// A frame sent after the receiver has lost the transmitter, with its
+    /// failsafe positions and both the frame lost and failsafe flags set.
+    const FAILSAFE_FRAME: [u8; FRAME_LEN] = [
+        0x0f, 0xe0, 0x03, 0x1f, 0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0xe0, 0x03, 0x1f,
+        0xf8, 0xc0, 0x07, 0x3e, 0xf0, 0x81, 0x0f, 0x7c, 0x0c, 0x00,
+    ];
+
+    /// Pushes every byte, returning the number of frames and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Frame>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), frame| (count + 1, Some(frame)))
+    }
+
+    #[test]
+    fn decodes_frame() {
+        let (count, frame) = push_all(&mut Parser::new(), &FRAME);
+        let frame = frame.unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(frame.channels, CHANNELS);
+        assert!(!frame.ch17 && !frame.ch18);
+        assert!(!frame.frame_lost && !frame.failsafe);
+    }
+
+    #[test]
+    fn decodes_failsafe_flags() {
+        let frame = push_all(&mut Parser::new(), &FAILSAFE_FRAME).1.unwrap();
+
+        assert_eq!(frame.channels, [992; 16]);
+        assert!(frame.frame_lost);
+        assert!(frame.failsafe);
+    }
+
+    #[test]
+    fn decodes_digital_channels() {
+        let mut bytes = FRAME;
+        bytes[FLAGS_IDX] = FLAG_CH17 | FLAG_CH18;
+        let frame = push_all(&mut Parser::new(), &bytes).1.unwrap();
+
+        assert!(frame.ch17 && frame.ch18);
+        assert!(!frame.frame_lost && !frame.failsafe);
+    }
+
+    #[test]
+    fn accepts_sbus2_footers() {
+        for slot in 0..4 {
+            let mut bytes = FRAME;
+            bytes[FOOTER_IDX] = slot << 4 | 0x04;
+            assert_eq!(push_all(&mut Parser::new(), &bytes).0, 1);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_footer() {
+        let mut bytes = FRAME;
+        bytes[FOOTER_IDX] = 0x55;
+        assert_eq!(push_all(&mut Parser::new(), &bytes).0, 0);
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &FRAME).0, 1);
+        assert!(push_all(&mut parser, &FAILSAFE_FRAME).1.unwrap().failsafe);
+        assert_eq!(push_all(&mut parser, &FRAME).1.unwrap().channels, CHANNELS);
+    }
+
+    #[test]
+    fn skips_bytes_before_header() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, &[0x00, 0xff, 0x12]).0, 0);
+        assert_eq!(push_all(&mut parser, &FRAME).1.unwrap().channels, CHANNELS);
+    }
+
+    #[test]
+    fn reset_resyncs_after_partial_frame() {
+        // A frame cut short, for example by the receiver starting up,
+        // leaves the parser out of step with the next frame.
+        let mut parser = Parser::new();
+        push_all(&mut parser, &FRAME[..10]);
+        let (_, frame) = push_all(&mut parser, &FRAME);
+        assert!(!matches!(frame, Some(frame) if frame.channels == CHANNELS));
+
+        let mut parser = Parser::new();
+        push_all(&mut parser, &FRAME[..10]);
+        parser.reset();
+        assert_eq!(push_all(&mut parser, &FRAME).1.unwrap().channels, CHANNELS);
+    }
+}
@@ -22,6 +22,8 @@ pub struct Peripherals {
     pub EXTI0: embassy_stm32::peripherals::EXTI0,
     pub EXTI1: embassy_stm32::peripherals::EXTI1,
 
+    pub TIM2: embassy_stm32::peripherals::TIM2,
+
     pub over_current_protection: OverCurrentProtection,
     pub OC_COMP_INT: Input<'static, embassy_stm32::peripherals::PB12>,
     pub OC_COMP_INT2: Input<'static, embassy_stm32::peripherals::PA12>,
@@ -86,6 +88,8 @@ pub fn init() -> Peripherals {
         EXTI0: p.EXTI0,
         EXTI1: p.EXTI1,
 
+        TIM2: p.TIM2,
+
         over_current_protection: OverCurrentProtection::init(p.over_current_protection),
         OC_COMP_INT: p.OC_COMP_INT,
         OC_COMP_INT2: p.OC_COMP_INT2,
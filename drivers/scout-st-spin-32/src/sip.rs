@@ -27,6 +27,8 @@ pub struct Peripherals {
     pub EXTI0: embassy_stm32::peripherals::EXTI0,
     pub EXTI1: embassy_stm32::peripherals::EXTI1,
 
+    pub TIM2: embassy_stm32::peripherals::TIM2,
+
     pub over_current_protection: OverCurrentProtection,
     pub OC_COMP_INT: Input<'static, embassy_stm32::peripherals::PB12>,
     pub OC_COMP_INT2: Input<'static, embassy_stm32::peripherals::PA12>,
@@ -100,6 +102,8 @@ pub fn init() -> Peripherals {
         EXTI0: p.EXTI0,
         EXTI1: p.EXTI1,
 
+        TIM2: p.TIM2,
+
         over_current_protection: OverCurrentProtection::init(p.PA11, p.PF6, p.PF7),
         OC_COMP_INT: Input::new(p.PB12, Pull::None),
         OC_COMP_INT2: Input::new(p.PA12, Pull::None),
@@ -0,0 +1,298 @@
+//! Altitude estimation
+//!
+//! Fuses barometric altitude with the vertical acceleration measured by the
+//! accelerometer, with the complementary filter in `inertial`. The estimate
+//! follows the barometer over the long term, and the accelerometer over the
+//! short term.
+
+use crate::{inertial::InertialFilter, Quaternion, Vector3};
+
+pub const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Altitude in meters above the level where the pressure is
+/// `reference_pressure`, using the international standard atmosphere.
+pub fn pressure_altitude(pressure: f32, reference_pressure: f32) -> f32 {
+    44330.0 * (1.0 - libm::powf(pressure / reference_pressure, 1.0 / 5.255))
+}
+
+/// Returns the upward acceleration in meters per second squared, excluding
+/// gravity, from an accelerometer measurement in the body frame and the
+/// attitude.
+pub fn vertical_acceleration(attitude: Quaternion, accel: Vector3) -> f32 {
+    // At rest the accelerometer measures 1g upwards, which is -1g along the
+    // earth frame's down axis.
+    -(attitude.rotate(accel).z + 1.0) * STANDARD_GRAVITY
+}
+
+pub struct AltitudeEstimator {
+    /// Altitude in meters, with the same reference as the barometric
+    /// altitude, and climb rate in meters per second.
+    filter: InertialFilter,
+    baro_altitude: Option<f32>,
+}
+
+impl AltitudeEstimator {
+    /// `time_constant` is roughly how long, in seconds, errors from the
+    /// barometer take to be corrected. Longer time constants trust the
+    /// accelerometer more, which rejects more barometer noise but is slower
+    /// to correct accelerometer drift.
+    pub fn new(time_constant: f32) -> Self {
+        Self {
+            filter: InertialFilter::new(time_constant),
+            baro_altitude: None,
+        }
+    }
+
+    /// Sets the latest barometric altitude, in meters. The estimate starts
+    /// from the first one.
+    pub fn update_baro(&mut self, altitude: f32) {
+        if self.baro_altitude.is_none() {
+            self.filter.position = altitude;
+        }
+        self.baro_altitude = Some(altitude);
+    }
+
+    /// `vertical_acceleration` is in meters per second squared, as returned
+    /// by `vertical_acceleration`. Does nothing until a barometric altitude
+    /// has been given.
+    pub fn update(&mut self, vertical_acceleration: f32, dt: f32) {
+        if let Some(baro_altitude) = self.baro_altitude {
+            self.filter.update(baro_altitude, vertical_acceleration, dt);
+        }
+    }
+
+    /// Returns `None` until a barometric altitude has been given.
+    pub fn altitude(&self) -> Option<f32> {
+        self.baro_altitude.map(|_| self.filter.position)
+    }
+
+    /// Climb rate in meters per second, positive upwards.
+    pub fn climb_rate(&self) -> f32 {
+        self.filter.velocity
+    }
+
+    /// Estimated accelerometer bias along the vertical axis, in meters per
+    /// second squared.
+    pub fn accel_bias(&self) -> f32 {
+        -self.filter.accel_correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EulerAngles;
+
+    /// Accelerometer samples at 1 kHz, and a barometer sample every 20, at
+    /// 50 Hz.
+    const DT: f32 = 0.001;
+    const BARO_INTERVAL: usize = 20;
+    const TIME_CONSTANT: f32 = 2.0;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    /// Deterministic noise in -amplitude..amplitude.
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self, amplitude: f32) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            ((self.0 >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+        }
+    }
+
+    /// A synthetic flight, as (altitude, climb rate, acceleration) at time
+    /// `t`: a climb from 10 m at up to 4 m/s, which levels off at 34 m.
+    fn climb(t: f32) -> (f32, f32, f32) {
+        const ACCELERATION: f32 = 2.0;
+        if t < 2.0 {
+            let velocity = ACCELERATION * t;
+            (10.0 + 0.5 * velocity * t, velocity, ACCELERATION)
+        } else if t < 6.0 {
+            (14.0 + 4.0 * (t - 2.0), 4.0, 0.0)
+        } else if t < 8.0 {
+            let t = t - 6.0;
+            let velocity = 4.0 - ACCELERATION * t;
+            (
+                30.0 + 4.0 * t - 0.5 * ACCELERATION * t * t,
+                velocity,
+                -ACCELERATION,
+            )
+        } else {
+            (34.0, 0.0, 0.0)
+        }
+    }
+
+    /// Runs the estimator over `seconds` of the flight, with noise of up to
+    /// `baro_noise` meters on the barometer and `accel_noise` on the
+    /// accelerometer, which also has a bias of `accel_bias`. `check` is
+    /// called with the estimator and the true state after each sample.
+    fn fly(
+        estimator: &mut AltitudeEstimator,
+        flight: impl Fn(f32) -> (f32, f32, f32),
+        seconds: f32,
+        [baro_noise, accel_noise, accel_bias]: [f32; 3],
+        mut check: impl FnMut(f32, &AltitudeEstimator, (f32, f32, f32)),
+    ) {
+        let mut noise = Noise(1);
+        for i in 0..(seconds / DT) as usize {
+            let t = i as f32 * DT;
+            let state = flight(t);
+            if i % BARO_INTERVAL == 0 {
+                estimator.update_baro(state.0 + noise.next(baro_noise));
+            }
+            estimator.update(state.2 + accel_bias + noise.next(accel_noise), DT);
+            check(t, estimator, state);
+        }
+    }
+
+    #[test]
+    fn pressure_altitude_follows_the_standard_atmosphere() {
+        assert_close(pressure_altitude(101_325.0, 101_325.0), 0.0, 1e-3);
+        assert_close(pressure_altitude(89_874.6, 101_325.0), 1000.0, 1.0);
+        assert_close(pressure_altitude(79_495.2, 101_325.0), 2000.0, 2.0);
+        // Relative to a reference taken on the ground.
+        assert_close(pressure_altitude(89_874.6, 89_874.6), 0.0, 1e-3);
+        assert_close(pressure_altitude(101_205.0, 101_325.0), 10.0, 0.1);
+        assert!(pressure_altitude(101_400.0, 101_325.0) < 0.0);
+    }
+
+    #[test]
+    fn vertical_acceleration_excludes_gravity() {
+        let level = Quaternion::identity();
+        assert_close(
+            vertical_acceleration(level, Vector3::new(0.0, 0.0, -1.0)),
+            0.0,
+            1e-5,
+        );
+        assert_close(
+            vertical_acceleration(level, Vector3::new(0.0, 0.0, -1.5)),
+            0.5 * STANDARD_GRAVITY,
+            1e-4,
+        );
+        // Free fall.
+        assert_close(
+            vertical_acceleration(level, Vector3::zero()),
+            -STANDARD_GRAVITY,
+            1e-4,
+        );
+
+        // At rest while tilted, the measurement is rotated into the earth
+        // frame first.
+        let tilted = Quaternion::from_euler(EulerAngles {
+            roll: 30f32.to_radians(),
+            pitch: -20f32.to_radians(),
+            yaw: 70f32.to_radians(),
+        });
+        let at_rest = tilted.rotate_inverse(Vector3::new(0.0, 0.0, -1.0));
+        assert_close(vertical_acceleration(tilted, at_rest), 0.0, 1e-4);
+    }
+
+    #[test]
+    fn no_estimate_until_the_barometer_is_read() {
+        let mut estimator = AltitudeEstimator::new(TIME_CONSTANT);
+        estimator.update(5.0, DT);
+        assert!(estimator.altitude().is_none());
+        assert_eq!(estimator.climb_rate(), 0.0);
+
+        estimator.update_baro(120.0);
+        assert_eq!(estimator.altitude(), Some(120.0));
+        assert_eq!(estimator.climb_rate(), 0.0);
+    }
+
+    #[test]
+    fn follows_a_climb() {
+        let mut estimator = AltitudeEstimator::new(TIME_CONSTANT);
+        fly(
+            &mut estimator,
+            climb,
+            20.0,
+            [0.5, 0.2, 0.0],
+            |_, estimator, (altitude, climb_rate, _)| {
+                assert_close(estimator.altitude().unwrap(), altitude, 0.3);
+                assert_close(estimator.climb_rate(), climb_rate, 0.3);
+            },
+        );
+
+        assert_close(estimator.altitude().unwrap(), 34.0, 0.1);
+        assert_close(estimator.climb_rate(), 0.0, 0.05);
+    }
+
+    #[test]
+    fn rejects_barometer_noise() {
+        let mut estimator = AltitudeEstimator::new(TIME_CONSTANT);
+        // The first reading sets the estimate, and with it an error of up to
+        // the noise, which takes a few time constants to correct.
+        fly(
+            &mut estimator,
+            |_| (50.0, 0.0, 0.0),
+            30.0,
+            [2.0, 0.0, 0.0],
+            |t, estimator, _| {
+                // A fifth of the noise.
+                if t > 10.0 {
+                    assert_close(estimator.altitude().unwrap(), 50.0, 0.4);
+                    assert_close(estimator.climb_rate(), 0.0, 0.25);
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn corrects_accelerometer_bias() {
+        let mut estimator = AltitudeEstimator::new(TIME_CONSTANT);
+        fly(
+            &mut estimator,
+            |_| (0.0, 0.0, 0.0),
+            60.0,
+            [0.0, 0.0, 0.5],
+            |_, estimator, _| {
+                // Without the correction, the bias alone would have drifted
+                // the estimate by hundreds of meters.
+                assert_close(estimator.altitude().unwrap(), 0.0, 2.0);
+            },
+        );
+
+        assert_close(estimator.accel_bias(), 0.5, 0.01);
+        assert_close(estimator.altitude().unwrap(), 0.0, 0.01);
+        assert_close(estimator.climb_rate(), 0.0, 0.01);
+
+        // With the bias learned, a climb is followed as closely as without
+        // it.
+        fly(
+            &mut estimator,
+            |t| {
+                let (altitude, climb_rate, acceleration) = climb(t);
+                (altitude - 10.0, climb_rate, acceleration)
+            },
+            20.0,
+            [0.0, 0.0, 0.5],
+            |_, estimator, (altitude, climb_rate, _)| {
+                assert_close(estimator.altitude().unwrap(), altitude, 0.1);
+                assert_close(estimator.climb_rate(), climb_rate, 0.1);
+            },
+        );
+    }
+
+    #[test]
+    fn converges_on_a_barometer_step() {
+        let mut estimator = AltitudeEstimator::new(TIME_CONSTANT);
+        estimator.update_baro(0.0);
+
+        // Such as a change in the weather, which the accelerometer does not
+        // see.
+        let mut overshoot: f32 = 0.0;
+        fly(
+            &mut estimator,
+            |_| (5.0, 0.0, 0.0),
+            10.0 * TIME_CONSTANT,
+            [0.0; 3],
+            |_, estimator, _| overshoot = overshoot.max(estimator.altitude().unwrap() - 5.0),
+        );
+
+        assert_close(estimator.altitude().unwrap(), 5.0, 0.05);
+        assert!(overshoot < 2.0, "{}", overshoot);
+    }
+}
@@ -0,0 +1,778 @@
+//! Sensor calibration
+//!
+//! Gyro bias and accelerometer offset and scale are measured with the
+//! aircraft held still, and the magnetometer's hard and soft iron distortion
+//! while it is turned through every orientation. All are applied to samples
+//! in the sensor's own axes, before `BoardAlignment` rotates them into the
+//! body frame.
+
+use crate::{ellipsoid, EulerAngles, Quaternion, Vector3};
+
+/// Averages samples while they stay within a band, starting again whenever
+/// they move outside it.
+struct StillAverage {
+    samples_needed: u32,
+    /// Largest spread between samples on any axis.
+    threshold: f32,
+    sum: Vector3,
+    min: Vector3,
+    max: Vector3,
+    count: u32,
+}
+
+impl StillAverage {
+    fn new(samples_needed: u32, threshold: f32) -> Self {
+        Self {
+            samples_needed,
+            threshold,
+            sum: Vector3::zero(),
+            min: Vector3::zero(),
+            max: Vector3::zero(),
+            count: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.sum = Vector3::zero();
+    }
+
+    /// Returns `Ok(Some(average))` once enough still samples have been seen,
+    /// and `Err(())` if the samples moved, in which case averaging starts
+    /// again from the next sample.
+    fn push(&mut self, sample: Vector3) -> Result<Option<Vector3>, ()> {
+        if self.count == 0 {
+            self.min = sample;
+            self.max = sample;
+        } else {
+            self.min = Vector3::new(
+                self.min.x.min(sample.x),
+                self.min.y.min(sample.y),
+                self.min.z.min(sample.z),
+            );
+            self.max = Vector3::new(
+                self.max.x.max(sample.x),
+                self.max.y.max(sample.y),
+                self.max.z.max(sample.z),
+            );
+        }
+
+        let spread = self.max - self.min;
+        if spread.x.max(spread.y).max(spread.z) > self.threshold {
+            self.reset();
+            return Err(());
+        }
+
+        self.sum += sample;
+        self.count += 1;
+        if self.count < self.samples_needed {
+            return Ok(None);
+        }
+
+        let average = self.sum * (1.0 / self.count as f32);
+        self.reset();
+
+        Ok(Some(average))
+    }
+}
+
+/// Measures the gyro bias while the aircraft is still.
+pub struct GyroCalibrator {
+    average: StillAverage,
+    retries: u32,
+}
+
+impl GyroCalibrator {
+    /// `threshold` is the largest spread between samples on any axis, in the
+    /// units of the samples, before the aircraft is considered to be moving.
+    pub fn new(samples_needed: u32, threshold: f32) -> Self {
+        Self {
+            average: StillAverage::new(samples_needed, threshold),
+            retries: 0,
+        }
+    }
+
+    /// Returns the bias once `samples_needed` consecutive samples have been
+    /// still. Calibration restarts if the aircraft moves.
+    pub fn push(&mut self, gyro: Vector3) -> Option<Vector3> {
+        match self.average.push(gyro) {
+            Ok(bias) => bias,
+            Err(()) => {
+                self.retries += 1;
+                None
+            }
+        }
+    }
+
+    /// Number of times calibration has restarted because of motion.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+/// Accelerometer offset and scale on each axis, where a calibrated reading
+/// is `(raw - offset) / scale`.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct AccelCalibration {
+    pub offset: Vector3,
+    pub scale: Vector3,
+}
+
+impl AccelCalibration {
+    pub const fn identity() -> Self {
+        Self {
+            offset: Vector3::zero(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn apply(&self, accel: Vector3) -> Vector3 {
+        let v = accel - self.offset;
+
+        Vector3::new(v.x / self.scale.x, v.y / self.scale.y, v.z / self.scale.z)
+    }
+}
+
+impl Default for AccelCalibration {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+/// The orientations the aircraft is held in for accelerometer calibration,
+/// named by the axis pointing up.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Position {
+    /// Nose up.
+    XUp,
+    /// Nose down.
+    XDown,
+    /// Left side down.
+    YUp,
+    /// Right side down.
+    YDown,
+    /// Upside down.
+    ZUp,
+    /// Level.
+    ZDown,
+}
+
+impl Position {
+    pub const ALL: [Position; 6] = [
+        Position::XUp,
+        Position::XDown,
+        Position::YUp,
+        Position::YDown,
+        Position::ZUp,
+        Position::ZDown,
+    ];
+
+    /// An accelerometer reads the direction opposite to gravity, so the
+    /// position is found from the axis with the largest reading.
+    fn from_accel(accel: Vector3) -> Option<Self> {
+        let (x, y, z) = (
+            libm::fabsf(accel.x),
+            libm::fabsf(accel.y),
+            libm::fabsf(accel.z),
+        );
+        // Reject readings which are too far from an axis to be trusted.
+        const MIN_AXIS: f32 = 0.8;
+
+        if x >= y && x >= z && x >= MIN_AXIS * accel.norm() {
+            Some(if accel.x > 0.0 {
+                Position::XUp
+            } else {
+                Position::XDown
+            })
+        } else if y >= z && y >= MIN_AXIS * accel.norm() {
+            Some(if accel.y > 0.0 {
+                Position::YUp
+            } else {
+                Position::YDown
+            })
+        } else if z >= MIN_AXIS * accel.norm() {
+            Some(if accel.z > 0.0 {
+                Position::ZUp
+            } else {
+                Position::ZDown
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Six position accelerometer calibration.
+///
+/// The aircraft is held still with each axis pointing up and then down.
+/// Each position is recorded once it has been still for long enough, in any
+/// order, and positions already recorded are ignored.
+pub struct AccelCalibrator {
+    average: StillAverage,
+    readings: [Option<Vector3>; 6],
+}
+
+impl AccelCalibrator {
+    /// `threshold` is the largest spread between samples on any axis, in g,
+    /// before the aircraft is considered to be moving.
+    pub fn new(samples_needed: u32, threshold: f32) -> Self {
+        Self {
+            average: StillAverage::new(samples_needed, threshold),
+            readings: [None; 6],
+        }
+    }
+
+    /// Returns the position recorded by this sample, if any.
+    pub fn push(&mut self, accel: Vector3) -> Option<Position> {
+        let position = Position::from_accel(accel)?;
+        let index = position as usize;
+        if self.readings[index].is_some() {
+            self.average.reset();
+            return None;
+        }
+
+        let reading = self.average.push(accel).ok().flatten()?;
+        // The aircraft may have been turned to another position while the
+        // samples were averaged.
+        if Position::from_accel(reading) != Some(position) {
+            return None;
+        }
+        self.readings[index] = Some(reading);
+
+        Some(position)
+    }
+
+    pub fn recorded(&self, position: Position) -> bool {
+        self.readings[position as usize].is_some()
+    }
+
+    /// Returns the calibration once all six positions have been recorded.
+    pub fn result(&self) -> Option<AccelCalibration> {
+        let reading = |position: Position| self.readings[position as usize];
+        let (x_up, x_down) = (reading(Position::XUp)?.x, reading(Position::XDown)?.x);
+        let (y_up, y_down) = (reading(Position::YUp)?.y, reading(Position::YDown)?.y);
+        let (z_up, z_down) = (reading(Position::ZUp)?.z, reading(Position::ZDown)?.z);
+
+        Some(AccelCalibration {
+            offset: Vector3::new(
+                (x_up + x_down) / 2.0,
+                (y_up + y_down) / 2.0,
+                (z_up + z_down) / 2.0,
+            ),
+            scale: Vector3::new(
+                (x_up - x_down) / 2.0,
+                (y_up - y_down) / 2.0,
+                (z_up - z_down) / 2.0,
+            ),
+        })
+    }
+}
+
+/// Magnetometer hard and soft iron correction, where a calibrated reading
+/// is `soft_iron * (raw - offset)`.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct MagCalibration {
+    /// Hard iron offset, in the units of the readings.
+    pub offset: Vector3,
+    /// Rows of the soft iron matrix.
+    pub soft_iron: [Vector3; 3],
+}
+
+impl MagCalibration {
+    pub const fn identity() -> Self {
+        Self {
+            offset: Vector3::zero(),
+            soft_iron: [
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(0.0, 1.0, 0.0),
+                Vector3::new(0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    pub fn apply(&self, mag: Vector3) -> Vector3 {
+        let v = mag - self.offset;
+        let [x, y, z] = self.soft_iron;
+
+        Vector3::new(x.dot(v), y.dot(v), z.dot(v))
+    }
+}
+
+impl Default for MagCalibration {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+/// Number of readings the magnetometer calibration is fitted to.
+pub const MAG_SAMPLES: usize = 200;
+
+/// Each octant around the fitted center must hold at least this many of the
+/// readings, so that the fit isn't extrapolated from one side.
+const MIN_OCTANT_SAMPLES: usize = MAG_SAMPLES / 40;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum MagFitError {
+    /// The readings don't lie on an ellipsoid, typically because the
+    /// aircraft was only turned about one axis.
+    NotEllipsoid,
+    /// The readings don't cover every direction.
+    PoorCoverage,
+    /// The RMS distance of the corrected readings from the sphere, relative
+    /// to its radius, was more than allowed. Holds the distance.
+    Residual(f32),
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct MagFit {
+    pub calibration: MagCalibration,
+    /// Strength of the corrected field, in the units of the readings.
+    pub field_strength: f32,
+    /// RMS distance of the corrected readings from the sphere, relative to
+    /// its radius.
+    pub residual: f32,
+}
+
+/// Magnetometer calibration by ellipsoid fitting.
+///
+/// The aircraft is turned slowly through every orientation, away from metal
+/// and with the motors stopped. Readings are recorded when they are at least
+/// `spacing` from every reading already recorded, and the fit is made once
+/// `MAG_SAMPLES` have been recorded.
+pub struct MagCalibrator {
+    spacing: f32,
+    max_residual: f32,
+    samples: [Vector3; MAG_SAMPLES],
+    count: usize,
+}
+
+impl MagCalibrator {
+    /// `spacing` is in the units of the readings, and should be around a
+    /// tenth of the field strength. `max_residual` is the largest RMS
+    /// distance of the corrected readings from a sphere, relative to its
+    /// radius, for the fit to be accepted.
+    pub fn new(spacing: f32, max_residual: f32) -> Self {
+        Self {
+            spacing,
+            max_residual,
+            samples: [Vector3::zero(); MAG_SAMPLES],
+            count: 0,
+        }
+    }
+
+    /// Returns true if the reading was recorded.
+    pub fn push(&mut self, mag: Vector3) -> bool {
+        if self.is_full()
+            || self.samples[..self.count]
+                .iter()
+                .any(|sample| (*sample - mag).norm() < self.spacing)
+        {
+            return false;
+        }
+
+        self.samples[self.count] = mag;
+        self.count += 1;
+
+        true
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count == MAG_SAMPLES
+    }
+
+    /// Fits the calibration to the readings recorded so far.
+    pub fn fit(&self) -> Result<MagFit, MagFitError> {
+        let samples = &self.samples[..self.count];
+        let ellipsoid = ellipsoid::fit(samples).ok_or(MagFitError::NotEllipsoid)?;
+        let calibration = MagCalibration {
+            offset: ellipsoid.center,
+            soft_iron: ellipsoid.transform,
+        };
+
+        let mut octants = [0; 8];
+        let mut squared_error = 0.0;
+        for sample in samples {
+            let v = calibration.apply(*sample);
+            let octant =
+                (v.x > 0.0) as usize | ((v.y > 0.0) as usize) << 1 | ((v.z > 0.0) as usize) << 2;
+            octants[octant] += 1;
+            let error = v.norm() / ellipsoid.radius - 1.0;
+            squared_error += error * error;
+        }
+        if octants.iter().any(|count| *count < MIN_OCTANT_SAMPLES) {
+            return Err(MagFitError::PoorCoverage);
+        }
+        let residual = libm::sqrtf(squared_error / samples.len() as f32);
+        if residual > self.max_residual || residual.is_nan() {
+            return Err(MagFitError::Residual(residual));
+        }
+
+        Ok(MagFit {
+            calibration,
+            field_strength: ellipsoid.radius,
+            residual,
+        })
+    }
+}
+
+/// The rotation of the flight controller board relative to the aircraft.
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct BoardAlignment {
+    rotation: Quaternion,
+}
+
+impl BoardAlignment {
+    /// Angles in degrees, by which the board is rolled, pitched and yawed
+    /// relative to the aircraft, using the same conventions as the attitude.
+    pub fn new(roll: f32, pitch: f32, yaw: f32) -> Self {
+        Self {
+            rotation: Quaternion::from_euler(EulerAngles {
+                roll: roll.to_radians(),
+                pitch: pitch.to_radians(),
+                yaw: yaw.to_radians(),
+            }),
+        }
+    }
+
+    /// Rotates a vector measured in the board's axes into the body frame.
+    pub fn apply(&self, v: Vector3) -> Vector3 {
+        self.rotation.rotate(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3, b: Vector3, tolerance: f32) {
+        assert!(
+            (a - b).norm() <= tolerance,
+            "({}, {}, {}) != ({}, {}, {})",
+            a.x,
+            a.y,
+            a.z,
+            b.x,
+            b.y,
+            b.z
+        );
+    }
+
+    /// Repeatable noise of up to `amplitude` on each axis.
+    fn noise(i: u32, amplitude: f32) -> Vector3 {
+        let value = |seed: u32| ((i * seed) % 101) as f32 / 50.0 - 1.0;
+
+        Vector3::new(value(7919), value(104_729), value(1_299_709)) * amplitude
+    }
+
+    #[test]
+    fn gyro_bias_is_the_average_of_still_samples() {
+        let bias = Vector3::new(0.02, -0.01, 0.005);
+        let mut calibrator = GyroCalibrator::new(1000, 0.035);
+
+        for i in 0..999 {
+            assert!(calibrator.push(bias + noise(i, 0.01)).is_none());
+        }
+        let measured = calibrator.push(bias).unwrap();
+
+        assert_close(measured, bias, 0.001);
+        assert_eq!(calibrator.retries(), 0);
+    }
+
+    #[test]
+    fn gyro_calibration_restarts_when_moved() {
+        let bias = Vector3::new(0.02, -0.01, 0.005);
+        let mut calibrator = GyroCalibrator::new(100, 0.035);
+        for _ in 0..50 {
+            assert!(calibrator.push(bias).is_none());
+        }
+
+        // A bump, and then the count starts again from the next sample.
+        assert!(calibrator
+            .push(bias + Vector3::new(0.0, 0.5, 0.0))
+            .is_none());
+        assert_eq!(calibrator.retries(), 1);
+        for _ in 0..99 {
+            assert!(calibrator.push(bias).is_none());
+        }
+        assert_close(calibrator.push(bias).unwrap(), bias, 1e-6);
+    }
+
+    #[test]
+    fn gyro_calibration_restarts_on_slow_drift() {
+        // Each sample is close to the last, but together they spread further
+        // than the threshold.
+        let mut calibrator = GyroCalibrator::new(100, 0.035);
+        for i in 0..100 {
+            let drift = Vector3::new(0.0, 0.0, i as f32 * 0.001);
+            assert!(calibrator.push(drift).is_none());
+        }
+        assert!(calibrator.retries() > 0);
+    }
+
+    /// A raw accelerometer with the given offset and scale.
+    fn raw(calibration: &AccelCalibration, accel: Vector3) -> Vector3 {
+        let (o, s) = (calibration.offset, calibration.scale);
+
+        Vector3::new(
+            accel.x * s.x + o.x,
+            accel.y * s.y + o.y,
+            accel.z * s.z + o.z,
+        )
+    }
+
+    const ACCEL: AccelCalibration = AccelCalibration {
+        offset: Vector3::new(0.02, -0.03, 0.05),
+        scale: Vector3::new(1.01, 0.98, 1.02),
+    };
+
+    fn up(position: Position) -> Vector3 {
+        match position {
+            Position::XUp => Vector3::new(1.0, 0.0, 0.0),
+            Position::XDown => Vector3::new(-1.0, 0.0, 0.0),
+            Position::YUp => Vector3::new(0.0, 1.0, 0.0),
+            Position::YDown => Vector3::new(0.0, -1.0, 0.0),
+            Position::ZUp => Vector3::new(0.0, 0.0, 1.0),
+            Position::ZDown => Vector3::new(0.0, 0.0, -1.0),
+        }
+    }
+
+    /// Holds the aircraft still in `position` until it is recorded.
+    fn hold(calibrator: &mut AccelCalibrator, position: Position) -> Option<Position> {
+        (0..100).find_map(|i| calibrator.push(raw(&ACCEL, up(position)) + noise(i, 0.005)))
+    }
+
+    #[test]
+    fn six_position_calibration() {
+        let mut calibrator = AccelCalibrator::new(100, 0.05);
+        // In any order.
+        for position in [
+            Position::ZDown,
+            Position::ZUp,
+            Position::XUp,
+            Position::YDown,
+            Position::XDown,
+            Position::YUp,
+        ] {
+            assert!(calibrator.result().is_none());
+            assert!(hold(&mut calibrator, position) == Some(position));
+            assert!(calibrator.recorded(position));
+        }
+
+        let calibration = calibrator.result().unwrap();
+        assert_close(calibration.offset, ACCEL.offset, 0.002);
+        assert_close(calibration.scale, ACCEL.scale, 0.002);
+        for position in Position::ALL {
+            assert_close(
+                calibration.apply(raw(&ACCEL, up(position))),
+                up(position),
+                0.003,
+            );
+        }
+    }
+
+    #[test]
+    fn recorded_positions_are_ignored() {
+        let mut calibrator = AccelCalibrator::new(100, 0.05);
+        assert!(hold(&mut calibrator, Position::ZDown) == Some(Position::ZDown));
+        assert!(hold(&mut calibrator, Position::ZDown).is_none());
+    }
+
+    #[test]
+    fn positions_between_axes_are_not_recorded() {
+        let mut calibrator = AccelCalibrator::new(100, 0.05);
+        let tilted = Vector3::new(0.7, 0.0, -0.7);
+        for _ in 0..500 {
+            assert!(calibrator.push(tilted).is_none());
+        }
+        assert!(!Position::ALL
+            .into_iter()
+            .any(|position| calibrator.recorded(position)));
+    }
+
+    #[test]
+    fn turning_restarts_the_position() {
+        let mut calibrator = AccelCalibrator::new(100, 0.05);
+        for _ in 0..60 {
+            assert!(calibrator.push(up(Position::ZDown)).is_none());
+        }
+        // Turned partway, still closest to level.
+        assert!(calibrator.push(Vector3::new(0.3, 0.0, -0.95)).is_none());
+        for _ in 0..99 {
+            assert!(calibrator.push(up(Position::ZDown)).is_none());
+        }
+        assert!(calibrator.push(up(Position::ZDown)) == Some(Position::ZDown));
+    }
+
+    #[test]
+    fn identity_calibrations_change_nothing() {
+        let v = Vector3::new(0.1, -0.5, 0.9);
+        assert_close(AccelCalibration::identity().apply(v), v, 0.0);
+        assert_close(MagCalibration::identity().apply(v), v, 0.0);
+        assert_close(BoardAlignment::default().apply(v), v, 1e-6);
+    }
+
+    #[test]
+    fn board_alignment() {
+        // A board yawed right: its nose points to the aircraft's right.
+        let yawed = BoardAlignment::new(0.0, 0.0, 90.0);
+        assert_close(
+            yawed.apply(Vector3::new(1.0, 0.0, 0.0)),
+            Vector3::new(0.0, 1.0, 0.0),
+            1e-6,
+        );
+
+        // A board mounted upside down, rolled half a turn.
+        let inverted = BoardAlignment::new(180.0, 0.0, 0.0);
+        assert_close(
+            inverted.apply(Vector3::new(0.0, 0.0, 1.0)),
+            Vector3::new(0.0, 0.0, -1.0),
+            1e-6,
+        );
+        assert_close(
+            inverted.apply(Vector3::new(1.0, 0.0, 0.0)),
+            Vector3::new(1.0, 0.0, 0.0),
+            1e-6,
+        );
+
+        // With the aircraft level, an accelerometer on a board pitched up
+        // reads part of the upward 1g along the board's nose.
+        let pitched = BoardAlignment::new(0.0, 10.0, 0.0);
+        let level = pitched.apply(Vector3::new(
+            libm::sinf(10f32.to_radians()),
+            0.0,
+            -libm::cosf(10f32.to_radians()),
+        ));
+        assert_close(level, Vector3::new(0.0, 0.0, -1.0), 1e-6);
+    }
+
+    /// Strength of the earth's field in gauss, and the spacing and residual
+    /// used by the flight controller.
+    const FIELD: f32 = 0.5;
+    const MAG_SPACING: f32 = 0.05;
+    const MAG_MAX_RESIDUAL: f32 = 0.05;
+
+    const HARD_IRON: Vector3 = Vector3::new(0.12, -0.08, 0.05);
+    /// Soft iron stretching along axes rotated from the sensor's.
+    const SOFT_IRON_SCALE: Vector3 = Vector3::new(1.2, 0.8, 1.0);
+
+    fn soft_iron_axes() -> Quaternion {
+        Quaternion::from_euler(EulerAngles {
+            roll: 30f32.to_radians(),
+            pitch: -20f32.to_radians(),
+            yaw: 45f32.to_radians(),
+        })
+    }
+
+    /// What the magnetometer reads for a field of `field`, with hard and
+    /// soft iron distortion.
+    fn distorted(field: Vector3) -> Vector3 {
+        let axes = soft_iron_axes();
+        let v = axes.rotate_inverse(field);
+        let scale = SOFT_IRON_SCALE;
+
+        axes.rotate(Vector3::new(v.x * scale.x, v.y * scale.y, v.z * scale.z)) + HARD_IRON
+    }
+
+    /// `count` directions spread evenly over the unit sphere, along a
+    /// Fibonacci spiral.
+    fn directions(count: usize) -> impl Iterator<Item = Vector3> {
+        let golden_angle = core::f32::consts::PI * (3.0 - libm::sqrtf(5.0));
+        (0..count).map(move |i| {
+            let z = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let r = libm::sqrtf(1.0 - z * z);
+            let (sin, cos) = libm::sincosf(golden_angle * i as f32);
+            Vector3::new(r * cos, r * sin, z)
+        })
+    }
+
+    /// Pushes distorted readings of the field in each of `directions`, with
+    /// noise of up to `noise_amplitude`.
+    fn record(
+        calibrator: &mut MagCalibrator,
+        directions: impl Iterator<Item = Vector3>,
+        noise_amplitude: f32,
+    ) {
+        for (i, direction) in directions.enumerate() {
+            calibrator.push(distorted(direction * FIELD) + noise(i as u32, noise_amplitude));
+        }
+    }
+
+    #[test]
+    fn mag_readings_are_spaced() {
+        let mut calibrator = MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL);
+        let reading = Vector3::new(0.2, 0.1, -0.4);
+        assert!(calibrator.push(reading));
+        assert!(!calibrator.push(reading));
+        assert!(!calibrator.push(reading + Vector3::new(0.04, 0.0, 0.0)));
+        assert!(calibrator.push(reading + Vector3::new(0.06, 0.0, 0.0)));
+        assert_eq!(calibrator.count(), 2);
+
+        for direction in directions(1000) {
+            calibrator.push(direction * 2.0);
+        }
+        assert!(calibrator.is_full());
+        assert!(!calibrator.push(Vector3::new(5.0, 5.0, 5.0)));
+    }
+
+    #[test]
+    fn mag_calibration_removes_hard_and_soft_iron() {
+        let mut calibrator = MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL);
+        record(&mut calibrator, directions(MAG_SAMPLES), 0.005);
+        assert!(calibrator.is_full());
+
+        let Ok(fit) = calibrator.fit() else { panic!("fit failed") };
+        assert_close(fit.calibration.offset, HARD_IRON, 0.005);
+        // The soft iron correction keeps the ellipsoid's volume.
+        let scale = SOFT_IRON_SCALE;
+        let volume_scale = libm::cbrtf(scale.x * scale.y * scale.z);
+        assert!(libm::fabsf(fit.field_strength - FIELD * volume_scale) < 0.005);
+        assert!(fit.residual < 0.01, "{}", fit.residual);
+
+        // Corrected readings point the same way as the field, which is what
+        // the heading is calculated from.
+        for direction in directions(50) {
+            let corrected = fit.calibration.apply(distorted(direction * FIELD));
+            let angle = libm::acosf(corrected.normalized().unwrap().dot(direction).min(1.0));
+            assert!(angle.to_degrees() < 1.0, "{}", angle.to_degrees());
+        }
+    }
+
+    #[test]
+    fn mag_calibration_needs_every_orientation() {
+        // Only turned about the yaw axis, so the readings lie on a circle.
+        let mut calibrator = MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL);
+        let inclination = 60f32.to_radians();
+        let yawed = (0..360).map(|degrees| {
+            let (sin, cos) = libm::sincosf((degrees as f32).to_radians());
+            let horizontal = libm::cosf(inclination);
+            Vector3::new(horizontal * cos, horizontal * sin, libm::sinf(inclination))
+        });
+        record(&mut calibrator, yawed, 0.0);
+        assert!(calibrator.fit() == Err(MagFitError::NotEllipsoid));
+
+        // Never turned upside down, so the fit is extrapolated to the
+        // readings which are missing.
+        let mut calibrator = MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL);
+        record(
+            &mut calibrator,
+            directions(MAG_SAMPLES).filter(|direction| direction.z > -0.2),
+            0.0,
+        );
+        assert!(calibrator.fit() == Err(MagFitError::PoorCoverage));
+    }
+
+    #[test]
+    fn mag_calibration_rejects_noisy_readings() {
+        // Such as with the motors running.
+        let mut calibrator = MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL);
+        record(&mut calibrator, directions(MAG_SAMPLES), 0.1);
+        assert!(matches!(
+            calibrator.fit(),
+            Err(MagFitError::Residual(residual)) if residual > MAG_MAX_RESIDUAL
+        ));
+    }
+}
@@ -0,0 +1,410 @@
+//! Ellipsoid fitting
+//!
+//! Readings from a magnetometer turned through every orientation lie on a
+//! sphere, which hard iron offsets move and soft iron distortion stretches
+//! into an ellipsoid. The general quadric
+//!
+//! `Ax² + By² + Cz² + 2Dxy + 2Exz + 2Fyz + 2Gx + 2Hy + 2Iz = 1`
+//!
+//! is fitted to the points by least squares, and its center and shape give
+//! the transform which maps the ellipsoid back onto a sphere.
+//!
+//! The fit is done in `f64`, since the normal equations square the fourth
+//! powers of the readings, and only runs once per calibration.
+
+use crate::Vector3;
+
+type Matrix3 = [[f64; 3]; 3];
+
+const PARAMETERS: usize = 9;
+
+/// An ellipsoid, as the points `p` where `|transform * (p - center)|` is
+/// `radius`.
+pub(crate) struct Ellipsoid {
+    pub center: Vector3,
+    /// Symmetric, with a determinant of one, so it changes the shape but not
+    /// the volume of the ellipsoid.
+    pub transform: [Vector3; 3],
+    /// Radius of the sphere with the same volume.
+    pub radius: f32,
+}
+
+/// Returns `None` if the points don't describe an ellipsoid, for example
+/// because they all lie in a plane.
+pub(crate) fn fit(points: &[Vector3]) -> Option<Ellipsoid> {
+    if points.len() < PARAMETERS {
+        return None;
+    }
+
+    // Fitting around the mean, scaled to unit size, keeps the normal
+    // equations well conditioned.
+    let n = points.len() as f64;
+    let mean = points.iter().fold([0.0; 3], |sum, p| {
+        let p = to_f64(*p);
+        [sum[0] + p[0] / n, sum[1] + p[1] / n, sum[2] + p[2] / n]
+    });
+    let scale = libm::sqrt(
+        points
+            .iter()
+            .map(|p| norm_squared(sub(to_f64(*p), mean)))
+            .sum::<f64>()
+            / n,
+    );
+    if scale == 0.0 {
+        return None;
+    }
+
+    let mut normal = [[0.0; PARAMETERS]; PARAMETERS];
+    let mut rhs = [0.0; PARAMETERS];
+    for p in points {
+        let [x, y, z] = sub(to_f64(*p), mean).map(|v| v / scale);
+        let row = [
+            x * x,
+            y * y,
+            z * z,
+            2.0 * x * y,
+            2.0 * x * z,
+            2.0 * y * z,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ];
+        for i in 0..PARAMETERS {
+            for j in 0..PARAMETERS {
+                normal[i][j] += row[i] * row[j];
+            }
+            rhs[i] += row[i];
+        }
+    }
+    let [a, b, c, d, e, f, g, h, i] = solve(normal, rhs)?;
+
+    let quadratic = [[a, d, e], [d, b, f], [e, f, c]];
+    let linear = [g, h, i];
+    // Completing the square gives (p - center)ᵀ Q (p - center) = k.
+    let center = mul_vector(&invert(&quadratic)?, linear).map(|v| -v);
+    let k = 1.0 + dot(center, mul_vector(&quadratic, center));
+    if k <= 0.0 {
+        return None;
+    }
+    let shape = quadratic.map(|row| row.map(|v| v / k));
+
+    // The shape's eigenvalues are the inverse squares of the radii, and all
+    // must be positive for the quadric to be an ellipsoid.
+    let (eigenvalues, eigenvectors) = eigen(shape);
+    if eigenvalues.iter().any(|eigenvalue| *eigenvalue <= 0.0) {
+        return None;
+    }
+    // The geometric mean of the radii.
+    let radius = libm::pow(eigenvalues.iter().product::<f64>(), -1.0 / 6.0);
+
+    // The square root of the shape maps the ellipsoid onto the unit sphere,
+    // and scaling it by the radius keeps the volume.
+    let mut transform = [[0.0; 3]; 3];
+    for (eigenvalue, vector) in eigenvalues.iter().zip(eigenvectors) {
+        let scale = libm::sqrt(*eigenvalue) * radius;
+        for row in 0..3 {
+            for column in 0..3 {
+                transform[row][column] += scale * vector[row] * vector[column];
+            }
+        }
+    }
+
+    Some(Ellipsoid {
+        center: to_f32(add(mean, center.map(|v| v * scale))),
+        transform: transform.map(to_f32),
+        radius: (radius * scale) as f32,
+    })
+}
+
+/// Solves `matrix * x = rhs` by Gaussian elimination with partial pivoting,
+/// returning `None` if the matrix is singular.
+fn solve(
+    mut matrix: [[f64; PARAMETERS]; PARAMETERS],
+    mut rhs: [f64; PARAMETERS],
+) -> Option<[f64; PARAMETERS]> {
+    let largest = matrix
+        .iter()
+        .flatten()
+        .fold(0.0, |largest: f64, v| largest.max(libm::fabs(*v)));
+    let min_pivot = largest * 1e-12;
+
+    for column in 0..PARAMETERS {
+        let pivot = (column..PARAMETERS).max_by(|a, b| {
+            libm::fabs(matrix[*a][column]).total_cmp(&libm::fabs(matrix[*b][column]))
+        })?;
+        if libm::fabs(matrix[pivot][column]) <= min_pivot {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+
+        let (pivot_rows, rows) = matrix.split_at_mut(column + 1);
+        let pivot_row = &pivot_rows[column];
+        for (i, row) in rows.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            for (v, pivot) in row.iter_mut().zip(pivot_row).skip(column) {
+                *v -= factor * pivot;
+            }
+            rhs[column + 1 + i] -= factor * rhs[column];
+        }
+    }
+
+    let mut x = [0.0; PARAMETERS];
+    for row in (0..PARAMETERS).rev() {
+        let sum: f64 = (row + 1..PARAMETERS).map(|i| matrix[row][i] * x[i]).sum();
+        x[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+
+    Some(x)
+}
+
+/// Returns the eigenvalues of a symmetric matrix, and the unit eigenvectors
+/// in the same order, by Jacobi rotations.
+fn eigen(mut matrix: Matrix3) -> ([f64; 3], [[f64; 3]; 3]) {
+    // Columns are the eigenvectors.
+    let mut vectors = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0..50 {
+        let off_diagonal =
+            matrix[0][1] * matrix[0][1] + matrix[0][2] * matrix[0][2] + matrix[1][2] * matrix[1][2];
+        if off_diagonal < 1e-30 {
+            break;
+        }
+
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if matrix[p][q] == 0.0 {
+                continue;
+            }
+
+            // The rotation in the p-q plane which zeroes matrix[p][q].
+            let theta = (matrix[q][q] - matrix[p][p]) / (2.0 * matrix[p][q]);
+            let t =
+                libm::copysign(1.0, theta) / (libm::fabs(theta) + libm::sqrt(theta * theta + 1.0));
+            let c = 1.0 / libm::sqrt(t * t + 1.0);
+            let s = t * c;
+
+            for row in matrix.iter_mut() {
+                let (kp, kq) = (row[p], row[q]);
+                row[p] = c * kp - s * kq;
+                row[q] = s * kp + c * kq;
+            }
+            let (row_p, row_q) = (matrix[p], matrix[q]);
+            matrix[p] = [0, 1, 2].map(|k| c * row_p[k] - s * row_q[k]);
+            matrix[q] = [0, 1, 2].map(|k| s * row_p[k] + c * row_q[k]);
+            for row in vectors.iter_mut() {
+                let (vp, vq) = (row[p], row[q]);
+                row[p] = c * vp - s * vq;
+                row[q] = s * vp + c * vq;
+            }
+        }
+    }
+
+    let eigenvalues = [matrix[0][0], matrix[1][1], matrix[2][2]];
+    let eigenvectors = [0, 1, 2].map(|i| [vectors[0][i], vectors[1][i], vectors[2][i]]);
+
+    (eigenvalues, eigenvectors)
+}
+
+fn invert(m: &Matrix3) -> Option<Matrix3> {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let adjugate = [
+        [
+            cofactor(1, 2, 1, 2),
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 1, 1, 2),
+        ],
+        [
+            -cofactor(1, 2, 0, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 1, 0, 2),
+        ],
+        [
+            cofactor(1, 2, 0, 1),
+            -cofactor(0, 2, 0, 1),
+            cofactor(0, 1, 0, 1),
+        ],
+    ];
+    let determinant =
+        m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+    if determinant == 0.0 {
+        return None;
+    }
+
+    Some(adjugate.map(|row| row.map(|v| v / determinant)))
+}
+
+fn mul_vector(m: &Matrix3, v: [f64; 3]) -> [f64; 3] {
+    m.map(|row| dot(row, v))
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn norm_squared(v: [f64; 3]) -> f64 {
+    dot(v, v)
+}
+
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn to_f64(v: Vector3) -> [f64; 3] {
+    [v.x as f64, v.y as f64, v.z as f64]
+}
+
+fn to_f32(v: [f64; 3]) -> Vector3 {
+    Vector3::new(v[0] as f32, v[1] as f32, v[2] as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EulerAngles, Quaternion};
+
+    fn assert_close(a: Vector3, b: Vector3, tolerance: f32) {
+        assert!(
+            (a - b).norm() <= tolerance,
+            "({}, {}, {}) != ({}, {}, {})",
+            a.x,
+            a.y,
+            a.z,
+            b.x,
+            b.y,
+            b.z
+        );
+    }
+
+    /// `count` directions spread evenly over the unit sphere, along a
+    /// Fibonacci spiral.
+    fn directions(count: usize) -> impl Iterator<Item = Vector3> {
+        let golden_angle = core::f32::consts::PI * (3.0 - libm::sqrtf(5.0));
+        (0..count).map(move |i| {
+            let z = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let r = libm::sqrtf(1.0 - z * z);
+            let (sin, cos) = libm::sincosf(golden_angle * i as f32);
+            Vector3::new(r * cos, r * sin, z)
+        })
+    }
+
+    fn mul(m: &[Vector3; 3], v: Vector3) -> Vector3 {
+        Vector3::new(m[0].dot(v), m[1].dot(v), m[2].dot(v))
+    }
+
+    /// Stretches `v` by `scale` along axes rotated by `rotation`, which is a
+    /// symmetric distortion like that of soft iron.
+    fn distort(v: Vector3, rotation: Quaternion, scale: Vector3) -> Vector3 {
+        let v = rotation.rotate_inverse(v);
+
+        rotation.rotate(Vector3::new(v.x * scale.x, v.y * scale.y, v.z * scale.z))
+    }
+
+    fn fit_points(points: impl Iterator<Item = Vector3>) -> Option<Ellipsoid> {
+        let mut buf = [Vector3::zero(); 200];
+        let mut count = 0;
+        for (slot, point) in buf.iter_mut().zip(points) {
+            *slot = point;
+            count += 1;
+        }
+
+        fit(&buf[..count])
+    }
+
+    #[test]
+    fn fits_a_sphere() {
+        let center = Vector3::new(0.1, -0.2, 0.3);
+        let ellipsoid = fit_points(directions(100).map(|v| v * 0.5 + center)).unwrap();
+
+        assert_close(ellipsoid.center, center, 1e-5);
+        assert!(libm::fabsf(ellipsoid.radius - 0.5) < 1e-5);
+        let identity = [
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+        ];
+        for (row, expected) in ellipsoid.transform.iter().zip(identity) {
+            assert_close(*row, expected, 1e-4);
+        }
+    }
+
+    #[test]
+    fn fits_a_rotated_ellipsoid() {
+        let center = Vector3::new(0.12, -0.08, 0.05);
+        let rotation = Quaternion::from_euler(EulerAngles {
+            roll: 30f32.to_radians(),
+            pitch: -20f32.to_radians(),
+            yaw: 45f32.to_radians(),
+        });
+        let scale = Vector3::new(1.2, 0.8, 1.0);
+        let ellipsoid =
+            fit_points(directions(150).map(|v| distort(v * 0.5, rotation, scale) + center))
+                .unwrap();
+
+        // The sphere with the same volume.
+        let volume_scale = libm::cbrtf(scale.x * scale.y * scale.z);
+        assert_close(ellipsoid.center, center, 1e-4);
+        assert!(libm::fabsf(ellipsoid.radius - 0.5 * volume_scale) < 1e-4);
+
+        // The transform undoes the distortion, up to the change in volume,
+        // so directions are preserved.
+        for v in directions(20) {
+            let corrected = mul(&ellipsoid.transform, distort(v, rotation, scale));
+            assert_close(corrected, v * volume_scale, 1e-4);
+        }
+
+        // It is symmetric, with a determinant of one.
+        let [x, y, z] = ellipsoid.transform;
+        assert!(libm::fabsf(x.y - y.x) < 1e-5);
+        assert!(libm::fabsf(x.z - z.x) < 1e-5);
+        assert!(libm::fabsf(y.z - z.y) < 1e-5);
+        assert!(libm::fabsf(x.dot(y.cross(z)) - 1.0) < 1e-4);
+    }
+
+    #[test]
+    fn rejects_too_few_points() {
+        assert!(fit_points(directions(8)).is_none());
+        assert!(fit_points((0..50).map(|_| Vector3::new(0.1, 0.2, 0.3))).is_none());
+    }
+
+    #[test]
+    fn rejects_points_in_a_plane() {
+        // As when the aircraft is only turned about its yaw axis.
+        let circle = (0..50).map(|i| {
+            let (sin, cos) = libm::sincosf(i as f32 * 0.2);
+            Vector3::new(0.3 * cos, 0.3 * sin, 0.4)
+        });
+        assert!(fit_points(circle).is_none());
+    }
+
+    #[test]
+    fn rejects_other_quadrics() {
+        // A hyperboloid, x² + y² - z² = 1.
+        let hyperboloid = (0..100).map(|i| {
+            let t = (i % 10) as f32 / 5.0 - 1.0;
+            let (sin, cos) = libm::sincosf((i / 10) as f32 * 0.6);
+            Vector3::new(libm::coshf(t) * cos, libm::coshf(t) * sin, libm::sinhf(t))
+        });
+        assert!(fit_points(hyperboloid).is_none());
+    }
+
+    #[test]
+    fn eigen_decomposition() {
+        let matrix = [[2.0, 1.0, 0.0], [1.0, 2.0, 0.5], [0.0, 0.5, 3.0]];
+        let (eigenvalues, eigenvectors) = eigen(matrix);
+
+        for (eigenvalue, vector) in eigenvalues.iter().zip(eigenvectors) {
+            assert!(libm::fabs(norm_squared(vector) - 1.0) < 1e-12);
+            let product = mul_vector(&matrix, vector);
+            for (p, v) in product.iter().zip(vector) {
+                assert!(libm::fabs(p - eigenvalue * v) < 1e-12);
+            }
+        }
+        // The trace is the sum of the eigenvalues.
+        assert!(libm::fabs(eigenvalues.iter().sum::<f64>() - 7.0) < 1e-12);
+    }
+}
@@ -0,0 +1,45 @@
+//! The complementary filter shared by the altitude and position estimators
+//!
+//! A third order filter along one axis. Acceleration is integrated to follow
+//! fast changes, and the error from a position measurement corrects the
+//! position, the velocity and the accelerometer's bias, so the estimate
+//! follows the measurement over the long term.
+
+pub(crate) struct InertialFilter {
+    k1: f32,
+    k2: f32,
+    k3: f32,
+    pub(crate) position: f32,
+    pub(crate) velocity: f32,
+    /// Correction added to the measured acceleration, which converges on
+    /// the negated accelerometer bias.
+    pub(crate) accel_correction: f32,
+}
+
+impl InertialFilter {
+    /// `time_constant` is roughly how long, in seconds, errors from the
+    /// position measurement take to be corrected. Longer time constants
+    /// trust the accelerometer more, which rejects more measurement noise
+    /// but is slower to correct accelerometer drift.
+    pub(crate) fn new(time_constant: f32) -> Self {
+        Self {
+            k1: 3.0 / time_constant,
+            k2: 3.0 / (time_constant * time_constant),
+            k3: 1.0 / (time_constant * time_constant * time_constant),
+            position: 0.0,
+            velocity: 0.0,
+            accel_correction: 0.0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, measured_position: f32, acceleration: f32, dt: f32) {
+        let error = measured_position - self.position;
+        self.accel_correction += error * self.k3 * dt;
+        self.velocity += error * self.k2 * dt;
+        self.position += error * self.k1 * dt;
+
+        let acceleration = acceleration + self.accel_correction;
+        self.position += (self.velocity + acceleration * 0.5 * dt) * dt;
+        self.velocity += acceleration * dt;
+    }
+}
@@ -0,0 +1,18 @@
+#![no_std]
+
+mod math;
+pub use math::{EulerAngles, Quaternion, Vector3};
+
+mod mahony;
+pub use mahony::Mahony;
+
+pub mod altitude;
+pub use altitude::AltitudeEstimator;
+
+mod inertial;
+
+pub mod position;
+pub use position::{Location, PositionEstimator};
+
+pub mod calibration;
+mod ellipsoid;
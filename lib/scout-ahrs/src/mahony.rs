@@ -0,0 +1,341 @@
+//! Mahony complementary filter
+//!
+//! The gyro is integrated to track attitude, and the error between the
+//! measured and estimated directions of gravity (and optionally magnetic
+//! north) is fed back as a proportional and integral correction to the gyro.
+//! The integral term converges on the negated gyro bias.
+
+use crate::{EulerAngles, Quaternion, Vector3};
+
+pub struct Mahony {
+    attitude: Quaternion,
+    integral: Vector3,
+    /// Proportional gain. Higher values trust the accelerometer more, which
+    /// converges faster but lets more vibration into the estimate.
+    pub kp: f32,
+    /// Integral gain, which sets how quickly gyro bias is learnt. Zero
+    /// disables bias estimation.
+    pub ki: f32,
+    /// The accelerometer is ignored when the magnitude of its measurement
+    /// differs from 1g by more than this, in units of standard gravity, as
+    /// it is then dominated by manoeuvring rather than gravity.
+    pub accel_rejection: f32,
+    /// Angle from true north to magnetic north in radians, positive when
+    /// magnetic north is to the east, so that the heading is relative to
+    /// true north.
+    pub declination: f32,
+}
+
+impl Mahony {
+    pub const fn new(kp: f32, ki: f32) -> Self {
+        Self {
+            attitude: Quaternion::identity(),
+            integral: Vector3::zero(),
+            kp,
+            ki,
+            accel_rejection: 0.25,
+            declination: 0.0,
+        }
+    }
+
+    /// Sets the attitude without changing the learnt gyro bias, for example
+    /// to start from the attitude given by the accelerometer.
+    pub fn reset(&mut self, attitude: Quaternion) {
+        self.attitude = attitude;
+    }
+
+    /// `gyro` is in radians per second, and `accel` in units of standard
+    /// gravity, both in the body frame. Returns the gyro rate with the
+    /// estimated bias removed.
+    pub fn update(&mut self, gyro: Vector3, accel: Vector3, dt: f32) -> Vector3 {
+        let error = self.accel_error(accel);
+
+        self.correct(gyro, error, dt)
+    }
+
+    /// As `update`, with a magnetometer measurement in any units. Only its
+    /// direction is used, to correct heading.
+    pub fn update_with_mag(
+        &mut self,
+        gyro: Vector3,
+        accel: Vector3,
+        mag: Vector3,
+        dt: f32,
+    ) -> Vector3 {
+        let error = self.accel_error(accel) + self.mag_error(mag);
+
+        self.correct(gyro, error, dt)
+    }
+
+    pub fn attitude(&self) -> Quaternion {
+        self.attitude
+    }
+
+    pub fn euler(&self) -> EulerAngles {
+        self.attitude.to_euler()
+    }
+
+    /// Estimated gyro bias in radians per second.
+    pub fn gyro_bias(&self) -> Vector3 {
+        -self.integral
+    }
+
+    fn accel_error(&self, accel: Vector3) -> Vector3 {
+        let magnitude = accel.norm();
+        if magnitude == 0.0 || libm::fabsf(magnitude - 1.0) > self.accel_rejection {
+            return Vector3::zero();
+        }
+
+        // At rest the accelerometer measures the reaction to gravity, which
+        // points up, so along -z in the earth frame.
+        let measured = accel * (1.0 / magnitude);
+        let estimated = self.attitude.rotate_inverse(Vector3::new(0.0, 0.0, -1.0));
+
+        measured.cross(estimated)
+    }
+
+    fn mag_error(&self, mag: Vector3) -> Vector3 {
+        let Some(measured) = mag.normalized() else {
+            return Vector3::zero();
+        };
+
+        // Only the heading should be corrected, so the earth frame reference
+        // is the measured field rotated to point to magnetic north without
+        // changing its inclination.
+        let earth = self.attitude.rotate(measured);
+        let horizontal = libm::sqrtf(earth.x * earth.x + earth.y * earth.y);
+        let (sin, cos) = libm::sincosf(self.declination);
+        let estimated =
+            self.attitude
+                .rotate_inverse(Vector3::new(horizontal * cos, horizontal * sin, earth.z));
+
+        measured.cross(estimated)
+    }
+
+    fn correct(&mut self, gyro: Vector3, error: Vector3, dt: f32) -> Vector3 {
+        if self.ki > 0.0 {
+            self.integral += error * (self.ki * dt);
+        }
+
+        let corrected = gyro + self.integral;
+        self.attitude = self.attitude.integrate(corrected + error * self.kp, dt);
+
+        corrected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.001;
+    const KP: f32 = 0.5;
+    const KI: f32 = 0.05;
+
+    fn attitude(roll: f32, pitch: f32, yaw: f32) -> Quaternion {
+        Quaternion::from_euler(EulerAngles {
+            roll: roll.to_radians(),
+            pitch: pitch.to_radians(),
+            yaw: yaw.to_radians(),
+        })
+    }
+
+    /// What the accelerometer measures at rest in the given attitude.
+    fn gravity(attitude: Quaternion) -> Vector3 {
+        attitude.rotate_inverse(Vector3::new(0.0, 0.0, -1.0))
+    }
+
+    /// The angle of the rotation between two attitudes, in degrees.
+    fn error_degrees(a: Quaternion, b: Quaternion) -> f32 {
+        let dot = a.w * b.w + a.x * b.x + a.y * b.y + a.z * b.z;
+        (2.0 * libm::acosf(libm::fabsf(dot).min(1.0))).to_degrees()
+    }
+
+    /// Deterministic noise in -amplitude..amplitude.
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self, amplitude: f32) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            ((self.0 >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+        }
+
+        fn vector(&mut self, amplitude: f32) -> Vector3 {
+            Vector3::new(
+                self.next(amplitude),
+                self.next(amplitude),
+                self.next(amplitude),
+            )
+        }
+    }
+
+    #[test]
+    fn converges_to_level_from_tilt() {
+        let mut mahony = Mahony::new(KP, KI);
+        mahony.reset(attitude(30.0, -20.0, 0.0));
+
+        // The integral winds up while the error is large, and takes around
+        // kp / ki seconds to unwind again.
+        for _ in 0..60_000 {
+            mahony.update(Vector3::zero(), gravity(Quaternion::identity()), DT);
+        }
+
+        let euler = mahony.euler();
+        assert!(libm::fabsf(euler.roll.to_degrees()) < 0.5);
+        assert!(libm::fabsf(euler.pitch.to_degrees()) < 0.5);
+    }
+
+    #[test]
+    fn converges_to_static_tilt() {
+        let truth = attitude(20.0, -10.0, 0.0);
+        let mut mahony = Mahony::new(KP, KI);
+
+        for _ in 0..60_000 {
+            mahony.update(Vector3::zero(), gravity(truth), DT);
+        }
+
+        let euler = mahony.euler();
+        assert!(libm::fabsf(euler.roll.to_degrees() - 20.0) < 0.5);
+        assert!(libm::fabsf(euler.pitch.to_degrees() + 10.0) < 0.5);
+    }
+
+    #[test]
+    fn learns_gyro_bias() {
+        let bias = Vector3::new(0.02, -0.01, 0.0);
+        let mut mahony = Mahony::new(KP, KI);
+
+        for _ in 0..120_000 {
+            mahony.update(bias, gravity(Quaternion::identity()), DT);
+        }
+
+        let learnt = mahony.gyro_bias();
+        assert!(libm::fabsf(learnt.x - bias.x) < 0.001);
+        assert!(libm::fabsf(learnt.y - bias.y) < 0.001);
+        let corrected = mahony.update(bias, gravity(Quaternion::identity()), DT);
+        assert!(corrected.norm() < 0.001);
+        assert!(error_degrees(mahony.attitude(), Quaternion::identity()) < 0.5);
+    }
+
+    #[test]
+    fn without_integral_gain_bias_is_not_learnt() {
+        let mut mahony = Mahony::new(KP, 0.0);
+        for _ in 0..10_000 {
+            mahony.update(
+                Vector3::new(0.02, 0.0, 0.0),
+                gravity(Quaternion::identity()),
+                DT,
+            );
+        }
+
+        assert!(mahony.gyro_bias() == Vector3::zero());
+        // The proportional term alone leaves a standing tilt of bias / kp.
+        let roll = mahony.euler().roll;
+        assert!(libm::fabsf(roll - 0.02 / KP) < 0.005);
+    }
+
+    #[test]
+    fn tracks_rotation() {
+        // Roll right at 90 deg/s, pitch up in the rolled frame, then yaw,
+        // for half a second each, with the true attitude integrated more
+        // finely than the estimator's.
+        let rates = [
+            Vector3::new(90f32.to_radians(), 0.0, 0.0),
+            Vector3::new(0.0, 90f32.to_radians(), 0.0),
+            Vector3::new(0.0, 0.0, 90f32.to_radians()),
+        ];
+        let mut truth = Quaternion::identity();
+        let mut mahony = Mahony::new(KP, KI);
+
+        for rate in rates {
+            for _ in 0..500 {
+                for _ in 0..10 {
+                    truth = truth.integrate(rate, DT / 10.0);
+                }
+                mahony.update(rate, gravity(truth), DT);
+                assert!(error_degrees(mahony.attitude(), truth) < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_does_not_cause_drift() {
+        let mut noise = Noise(1);
+        let truth = attitude(10.0, 5.0, 0.0);
+        let mut mahony = Mahony::new(KP, KI);
+        mahony.reset(truth);
+
+        for _ in 0..60_000 {
+            let gyro = noise.vector(0.05);
+            let accel = gravity(truth) + noise.vector(0.05);
+            mahony.update(gyro, accel, DT);
+
+            let euler = mahony.euler();
+            assert!(libm::fabsf(euler.roll.to_degrees() - 10.0) < 1.0);
+            assert!(libm::fabsf(euler.pitch.to_degrees() - 5.0) < 1.0);
+        }
+        assert!(libm::fabsf(mahony.euler().yaw.to_degrees()) < 2.0);
+    }
+
+    #[test]
+    fn ignores_accelerometer_while_manoeuvring() {
+        let mut mahony = Mahony::new(KP, KI);
+        let tilted = attitude(30.0, 0.0, 0.0);
+        mahony.reset(tilted);
+
+        // A 2 g pull up is not gravity, so mustn't level the estimate.
+        for _ in 0..5_000 {
+            mahony.update(Vector3::zero(), Vector3::new(0.0, 0.0, -2.0), DT);
+        }
+        assert!(error_degrees(mahony.attitude(), tilted) < 0.01);
+
+        // Nor is free fall.
+        mahony.update(Vector3::zero(), Vector3::zero(), DT);
+        assert!(error_degrees(mahony.attitude(), tilted) < 0.01);
+    }
+
+    #[test]
+    fn magnetometer_corrects_heading() {
+        for declination in [0.0f32, 10.0, -25.0] {
+            let truth = attitude(15.0, -5.0, 60.0);
+            let (sin, cos) = libm::sincosf(declination.to_radians());
+            // A field of 0.2 gauss horizontally and 0.4 gauss down, with
+            // magnetic north east of true north by the declination.
+            let field = Vector3::new(0.2 * cos, 0.2 * sin, 0.4);
+
+            // Only a fraction of the field is horizontal, so heading
+            // settles around five times slower than tilt, and with an
+            // integral gain takes minutes to settle from a large error.
+            let mut mahony = Mahony::new(KP, 0.0);
+            mahony.declination = declination.to_radians();
+            mahony.reset(attitude(15.0, -5.0, 40.0));
+            for _ in 0..60_000 {
+                mahony.update_with_mag(
+                    Vector3::zero(),
+                    gravity(truth),
+                    truth.rotate_inverse(field),
+                    DT,
+                );
+            }
+
+            assert!(error_degrees(mahony.attitude(), truth) < 1.0);
+        }
+    }
+
+    #[test]
+    fn magnetometer_does_not_tilt_estimate() {
+        let truth = attitude(0.0, 0.0, 0.0);
+        // A steeply inclined field, which would pull the estimate over if
+        // it corrected more than heading.
+        let field = Vector3::new(0.1, 0.0, 0.6);
+        let mut mahony = Mahony::new(KP, KI);
+
+        for _ in 0..10_000 {
+            mahony.update_with_mag(Vector3::zero(), gravity(truth), field, DT);
+        }
+
+        let euler = mahony.euler();
+        assert!(libm::fabsf(euler.roll.to_degrees()) < 0.1);
+        assert!(libm::fabsf(euler.pitch.to_degrees()) < 0.1);
+        assert!(libm::fabsf(euler.yaw.to_degrees()) < 0.1);
+    }
+}
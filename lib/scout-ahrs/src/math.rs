@@ -0,0 +1,292 @@
+//! Vector and quaternion types used by the estimators
+
+use core::ops::{Add, AddAssign, Mul, Neg, Sub};
+
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct Vector3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// A rotation from the body frame to the earth frame.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Angles in radians, applied in yaw, pitch, roll order.
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct EulerAngles {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl Vector3 {
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    pub const fn zero() -> Self {
+        Self::new(0.0, 0.0, 0.0)
+    }
+
+    pub fn dot(&self, other: Vector3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Vector3) -> Vector3 {
+        Vector3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn norm(&self) -> f32 {
+        libm::sqrtf(self.dot(*self))
+    }
+
+    /// Returns `None` for the zero vector, which has no direction.
+    pub fn normalized(&self) -> Option<Vector3> {
+        let norm = self.norm();
+
+        (norm > 0.0).then(|| *self * (1.0 / norm))
+    }
+}
+
+impl Add for Vector3 {
+    type Output = Vector3;
+
+    fn add(self, other: Vector3) -> Vector3 {
+        Vector3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl AddAssign for Vector3 {
+    fn add_assign(&mut self, other: Vector3) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Vector3 {
+    type Output = Vector3;
+
+    fn sub(self, other: Vector3) -> Vector3 {
+        Vector3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f32> for Vector3 {
+    type Output = Vector3;
+
+    fn mul(self, scale: f32) -> Vector3 {
+        Vector3::new(self.x * scale, self.y * scale, self.z * scale)
+    }
+}
+
+impl Neg for Vector3 {
+    type Output = Vector3;
+
+    fn neg(self) -> Vector3 {
+        self * -1.0
+    }
+}
+
+impl Quaternion {
+    pub const fn identity() -> Self {
+        Self {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    pub fn from_euler(angles: EulerAngles) -> Self {
+        let (sr, cr) = libm::sincosf(angles.roll * 0.5);
+        let (sp, cp) = libm::sincosf(angles.pitch * 0.5);
+        let (sy, cy) = libm::sincosf(angles.yaw * 0.5);
+
+        Self {
+            w: cr * cp * cy + sr * sp * sy,
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+        }
+    }
+
+    /// Pitch is limited to +/- 90 degrees, so the other angles jump by 180
+    /// degrees when the body passes through vertical.
+    pub fn to_euler(&self) -> EulerAngles {
+        let Quaternion { w, x, y, z } = *self;
+
+        EulerAngles {
+            roll: libm::atan2f(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y)),
+            pitch: libm::asinf((2.0 * (w * y - z * x)).clamp(-1.0, 1.0)),
+            yaw: libm::atan2f(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z)),
+        }
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Self {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    pub fn normalized(&self) -> Self {
+        let norm =
+            libm::sqrtf(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z);
+
+        Self {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+
+    /// Rotates a body frame vector into the earth frame.
+    pub fn rotate(&self, v: Vector3) -> Vector3 {
+        let v = Quaternion {
+            w: 0.0,
+            x: v.x,
+            y: v.y,
+            z: v.z,
+        };
+        let rotated = *self * v * self.conjugate();
+
+        Vector3::new(rotated.x, rotated.y, rotated.z)
+    }
+
+    /// Rotates an earth frame vector into the body frame.
+    pub fn rotate_inverse(&self, v: Vector3) -> Vector3 {
+        self.conjugate().rotate(v)
+    }
+
+    /// Integrates a body frame rotation rate in radians per second over `dt`
+    /// seconds.
+    pub fn integrate(&self, rate: Vector3, dt: f32) -> Self {
+        let rate = Quaternion {
+            w: 0.0,
+            x: rate.x,
+            y: rate.y,
+            z: rate.z,
+        };
+        let derivative = *self * rate;
+
+        Quaternion {
+            w: self.w + derivative.w * 0.5 * dt,
+            x: self.x + derivative.x * 0.5 * dt,
+            y: self.y + derivative.y * 0.5 * dt,
+            z: self.z + derivative.z * 0.5 * dt,
+        }
+        .normalized()
+    }
+}
+
+impl Default for Quaternion {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, o: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+            x: self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+            y: self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+            z: self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3, b: Vector3) {
+        assert!((a - b).norm() < 1e-5);
+    }
+
+    #[test]
+    fn euler_round_trip() {
+        for (roll, pitch, yaw) in [(0.3, -0.2, 1.0), (-2.5, 1.2, -3.0), (0.0, 0.0, 0.0)] {
+            let angles = EulerAngles { roll, pitch, yaw };
+            let back = Quaternion::from_euler(angles).to_euler();
+
+            assert!(libm::fabsf(back.roll - roll) < 1e-5);
+            assert!(libm::fabsf(back.pitch - pitch) < 1e-5);
+            assert!(libm::fabsf(back.yaw - yaw) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn rotation_directions() {
+        // Yawing right turns the nose from north to east.
+        let yaw = Quaternion::from_euler(EulerAngles {
+            yaw: core::f32::consts::FRAC_PI_2,
+            ..EulerAngles::default()
+        });
+        assert_close(
+            yaw.rotate(Vector3::new(1.0, 0.0, 0.0)),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+
+        // Rolling right points the right wing down.
+        let roll = Quaternion::from_euler(EulerAngles {
+            roll: core::f32::consts::FRAC_PI_2,
+            ..EulerAngles::default()
+        });
+        assert_close(
+            roll.rotate(Vector3::new(0.0, 1.0, 0.0)),
+            Vector3::new(0.0, 0.0, 1.0),
+        );
+    }
+
+    #[test]
+    fn rotate_inverse_undoes_rotate() {
+        let q = Quaternion::from_euler(EulerAngles {
+            roll: 0.4,
+            pitch: -0.7,
+            yaw: 2.0,
+        });
+        let v = Vector3::new(1.0, -2.0, 0.5);
+
+        assert_close(q.rotate_inverse(q.rotate(v)), v);
+        assert!(libm::fabsf(q.rotate(v).norm() - v.norm()) < 1e-5);
+    }
+
+    #[test]
+    fn integrate_body_rate() {
+        let mut q = Quaternion::identity();
+        for _ in 0..1000 {
+            q = q.integrate(Vector3::new(0.0, 0.0, 1.0), 0.001);
+        }
+
+        assert!(libm::fabsf(q.to_euler().yaw - 1.0) < 1e-3);
+    }
+
+    #[test]
+    fn cross_and_normalize() {
+        let x = Vector3::new(1.0, 0.0, 0.0);
+        let y = Vector3::new(0.0, 1.0, 0.0);
+
+        assert_close(x.cross(y), Vector3::new(0.0, 0.0, 1.0));
+        assert!(Vector3::zero().normalized().is_none());
+        assert_close(
+            Vector3::new(3.0, 0.0, 4.0).normalized().unwrap(),
+            Vector3::new(0.6, 0.0, 0.8),
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Horizontal position estimation
+//!
+//! GPS positions are converted to meters north and east of an origin, and
+//! fused with the horizontal acceleration measured by the accelerometer,
+//! with the complementary filter in `inertial` along each axis. The GPS
+//! velocity also corrects the estimated velocity directly.
+//!
+//! The acceleration is rotated into the earth frame with the estimated
+//! heading, which drifts without a magnetometer.
+
+use crate::{altitude::STANDARD_GRAVITY, inertial::InertialFilter, Quaternion, Vector3};
+
+/// Mean radius of the earth, in meters.
+pub const EARTH_RADIUS: f32 = 6_371_000.0;
+
+/// Units of 1e-7 degrees in a full turn of longitude.
+const FULL_TURN: i64 = 3_600_000_000;
+
+/// A location in units of 1e-7 degrees, as reported by GPS receivers.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Location {
+    /// Positive north.
+    pub latitude: i32,
+    /// Positive east.
+    pub longitude: i32,
+}
+
+impl Location {
+    /// Returns the offset of `self` north and east of `origin`, in meters.
+    ///
+    /// The earth is treated as flat around `origin`, which is accurate to
+    /// well under a meter over the few kilometers a multirotor flies.
+    pub fn offset_from(&self, origin: Location) -> [f32; 2] {
+        let to_meters = (1e-7f32).to_radians() * EARTH_RADIUS;
+        let north = (self.latitude as i64 - origin.latitude as i64) as f32 * to_meters;
+
+        // Take the short way around when crossing the antimeridian.
+        let mut east = self.longitude as i64 - origin.longitude as i64;
+        if east > FULL_TURN / 2 {
+            east -= FULL_TURN;
+        } else if east < -FULL_TURN / 2 {
+            east += FULL_TURN;
+        }
+        let latitude = (origin.latitude as f32 * 1e-7).to_radians();
+        let east = east as f32 * to_meters * libm::cosf(latitude);
+
+        [north, east]
+    }
+}
+
+/// Returns the north and east acceleration in meters per second squared,
+/// from an accelerometer measurement in the body frame and the attitude.
+pub fn horizontal_acceleration(attitude: Quaternion, accel: Vector3) -> [f32; 2] {
+    // Gravity is vertical, so the measurement needs no correction for it.
+    let accel = attitude.rotate(accel);
+
+    [accel.x * STANDARD_GRAVITY, accel.y * STANDARD_GRAVITY]
+}
+
+pub struct PositionEstimator {
+    /// North and east position in meters, and velocity in meters per second.
+    filters: [InertialFilter; 2],
+    velocity_gain: f32,
+    /// The latest GPS position and velocity.
+    gps: Option<([f32; 2], [f32; 2])>,
+}
+
+impl PositionEstimator {
+    /// `time_constant` is roughly how long, in seconds, errors from GPS take
+    /// to be corrected. Longer time constants trust the accelerometer more,
+    /// which rejects more GPS noise but is slower to correct drift.
+    pub fn new(time_constant: f32) -> Self {
+        Self {
+            filters: [
+                InertialFilter::new(time_constant),
+                InertialFilter::new(time_constant),
+            ],
+            velocity_gain: 1.0 / time_constant,
+            gps: None,
+        }
+    }
+
+    /// Sets the latest GPS position, in meters north and east of the
+    /// origin, and velocity, in meters per second. The estimate starts from
+    /// the first ones.
+    pub fn update_gps(&mut self, position: [f32; 2], velocity: [f32; 2]) {
+        if self.gps.is_none() {
+            for (filter, (position, velocity)) in
+                self.filters.iter_mut().zip(position.iter().zip(velocity))
+            {
+                filter.position = *position;
+                filter.velocity = velocity;
+            }
+        }
+        self.gps = Some((position, velocity));
+    }
+
+    /// Forgets the GPS measurements, such as when the origin changes or the
+    /// fix is lost.
+    pub fn reset(&mut self) {
+        self.gps = None;
+    }
+
+    /// `acceleration` is north and east in meters per second squared, as
+    /// returned by `horizontal_acceleration`. Does nothing until a GPS
+    /// position has been given.
+    pub fn update(&mut self, acceleration: [f32; 2], dt: f32) {
+        let Some((position, velocity)) = self.gps else { return };
+
+        for (i, filter) in self.filters.iter_mut().enumerate() {
+            filter.velocity += (velocity[i] - filter.velocity) * self.velocity_gain * dt;
+            filter.update(position[i], acceleration[i], dt);
+        }
+    }
+
+    /// Meters north and east of the origin, or `None` until a GPS position
+    /// has been given.
+    pub fn position(&self) -> Option<[f32; 2]> {
+        self.gps
+            .map(|_| [self.filters[0].position, self.filters[1].position])
+    }
+
+    /// North and east velocity, in meters per second.
+    pub fn velocity(&self) -> [f32; 2] {
+        [self.filters[0].velocity, self.filters[1].velocity]
+    }
+}
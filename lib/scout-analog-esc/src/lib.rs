@@ -0,0 +1,182 @@
+#![no_std]
+
+//! Analog ESC protocols
+//!
+//! Each protocol sends one pulse per period, and the pulse width, between a
+//! minimum for zero throttle and a maximum for full throttle, sets the
+//! throttle. Oneshot and Multishot are sent at a rate which leaves a short
+//! gap after the longest pulse.
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Protocol {
+    /// Standard servo style PWM, with 1000 to 2000us pulses. Most ESCs
+    /// accept rates from 50 to 490Hz.
+    Pwm { rate_hz: u32 },
+    /// 125 to 250us pulses.
+    Oneshot125,
+    /// 42 to 84us pulses.
+    Oneshot42,
+    /// 5 to 25us pulses.
+    Multishot,
+}
+
+impl Protocol {
+    /// PWM rates outside 50 to 490Hz are clamped to that range.
+    pub fn timing(&self) -> PulseTiming {
+        let (rate_hz, min_us, max_us) = match *self {
+            Protocol::Pwm { rate_hz } => (rate_hz.clamp(50, 490), 1000, 2000),
+            Protocol::Oneshot125 => (2_000, 125, 250),
+            Protocol::Oneshot42 => (6_000, 42, 84),
+            Protocol::Multishot => (32_000, 5, 25),
+        };
+
+        PulseTiming {
+            rate_hz,
+            min_us,
+            max_us,
+        }
+    }
+}
+
+/// Pulses for a protocol, with widths in microseconds.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PulseTiming {
+    pub rate_hz: u32,
+    pub min_us: u32,
+    pub max_us: u32,
+}
+
+impl PulseTiming {
+    /// Duty cycles for zero and full throttle, on a timer running at
+    /// `rate_hz` whose full duty cycle is `max_duty`.
+    pub fn duty_range(&self, max_duty: u16) -> DutyRange {
+        let duty = |pulse_us: u32| {
+            (pulse_us as u64 * self.rate_hz as u64 * max_duty as u64 / 1_000_000) as u16
+        };
+
+        DutyRange {
+            min: duty(self.min_us),
+            max: duty(self.max_us),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DutyRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl DutyRange {
+    /// `throttle` is in the range 0.0..=1.0. Values outside it are clamped,
+    /// and NaN gives zero throttle.
+    pub fn duty(&self, throttle: f32) -> u16 {
+        let range = (self.max - self.min) as f32;
+
+        self.min + (throttle.clamp(0.0, 1.0) * range) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROTOCOLS: [Protocol; 6] = [
+        Protocol::Pwm { rate_hz: 50 },
+        Protocol::Pwm { rate_hz: 400 },
+        Protocol::Pwm { rate_hz: 490 },
+        Protocol::Oneshot125,
+        Protocol::Oneshot42,
+        Protocol::Multishot,
+    ];
+
+    #[test]
+    fn pulse_widths() {
+        let widths = |protocol: Protocol| {
+            let timing = protocol.timing();
+            (timing.min_us, timing.max_us)
+        };
+        assert_eq!(widths(Protocol::Pwm { rate_hz: 400 }), (1000, 2000));
+        assert_eq!(widths(Protocol::Oneshot125), (125, 250));
+        assert_eq!(widths(Protocol::Oneshot42), (42, 84));
+        assert_eq!(widths(Protocol::Multishot), (5, 25));
+    }
+
+    #[test]
+    fn pwm_rate_is_clamped() {
+        assert_eq!(Protocol::Pwm { rate_hz: 10 }.timing().rate_hz, 50);
+        assert_eq!(Protocol::Pwm { rate_hz: 1000 }.timing().rate_hz, 490);
+        assert_eq!(Protocol::Pwm { rate_hz: 333 }.timing().rate_hz, 333);
+    }
+
+    #[test]
+    fn longest_pulse_fits_in_the_period() {
+        for protocol in PROTOCOLS {
+            let timing = protocol.timing();
+            assert!(timing.min_us < timing.max_us);
+            assert!(timing.max_us * timing.rate_hz < 1_000_000);
+        }
+    }
+
+    #[test]
+    fn duty_ranges() {
+        let range = |protocol: Protocol| protocol.timing().duty_range(10_000);
+
+        // 2500us periods.
+        assert!(
+            range(Protocol::Pwm { rate_hz: 400 })
+                == DutyRange {
+                    min: 4000,
+                    max: 8000
+                }
+        );
+        // 500us periods.
+        assert!(
+            range(Protocol::Oneshot125)
+                == DutyRange {
+                    min: 2500,
+                    max: 5000
+                }
+        );
+        // 166.7us periods.
+        assert!(
+            range(Protocol::Oneshot42)
+                == DutyRange {
+                    min: 2520,
+                    max: 5040
+                }
+        );
+        // 31.25us periods.
+        assert!(
+            range(Protocol::Multishot)
+                == DutyRange {
+                    min: 1600,
+                    max: 8000
+                }
+        );
+    }
+
+    #[test]
+    fn full_duty_does_not_overflow() {
+        for protocol in PROTOCOLS {
+            let range = protocol.timing().duty_range(u16::MAX);
+            assert!(range.min < range.max);
+            assert!(range.max < u16::MAX);
+        }
+    }
+
+    #[test]
+    fn throttle_to_duty() {
+        let range = DutyRange {
+            min: 4000,
+            max: 8000,
+        };
+        assert_eq!(range.duty(0.0), 4000);
+        assert_eq!(range.duty(0.5), 6000);
+        assert_eq!(range.duty(1.0), 8000);
+
+        assert_eq!(range.duty(-0.5), 4000);
+        assert_eq!(range.duty(1.5), 8000);
+        assert_eq!(range.duty(f32::NAN), 4000);
+    }
+}
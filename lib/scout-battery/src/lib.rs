@@ -0,0 +1,463 @@
+#![no_std]
+
+//! Battery monitoring
+//!
+//! The monitor is updated periodically with the voltages at the battery
+//! voltage divider and current sensor pins. Voltages are scaled and
+//! smoothed, and the cell count is detected once the battery has been
+//! connected for `SETTLE_TIME`. The voltage compared against the thresholds
+//! is raised by the current times the internal resistance, to estimate what
+//! the battery would read without load. Once a threshold has been crossed
+//! for `alert_delay`, the level stays raised until the battery is
+//! disconnected, since a battery doesn't recover charge in flight.
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Battery volts per volt at the voltage pin, set by the divider.
+    pub voltage_scale: f32,
+    /// Amps per volt at the current pin, or zero without a current sensor.
+    pub current_scale: f32,
+    /// Voltage at the current pin with no current flowing.
+    pub current_offset: f32,
+    /// Resistance of the battery and its wiring in ohms, for sag
+    /// compensation. Zero disables it.
+    pub internal_resistance: f32,
+    /// Number of cells in series, or zero to detect it from the voltage.
+    pub cells: u8,
+    /// Voltage of a fully charged cell. Packs are detected as the fewest
+    /// cells which could give the voltage measured at connection, which
+    /// needs a charged pack: an n cell pack below (n - 1) / n of this per
+    /// cell is detected as a cell short, and one above it, such as a high
+    /// voltage pack, as a cell over. With 4.3V, 4S is detected down to 3.23V
+    /// per cell, but 3S at more than 4.3V per cell is detected as 4S.
+    pub max_cell_voltage: f32,
+    /// Cell voltages, after sag compensation, below which the battery is
+    /// low.
+    pub warning_cell_voltage: f32,
+    pub critical_cell_voltage: f32,
+    /// How long the cell voltage must stay below a threshold to raise the
+    /// level, in seconds.
+    pub alert_delay: f32,
+    /// Capacity in mAh, or zero if unknown. With a current sensor, the
+    /// remaining charge is counted down from this rather than estimated
+    /// from the voltage.
+    pub capacity: f32,
+    /// Time constant of the voltage and current smoothing, in seconds.
+    pub time_constant: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Level {
+    /// No battery is connected, for example while powered over USB.
+    NotConnected,
+    Ok,
+    Warning,
+    Critical,
+}
+
+/// A snapshot of the battery's state.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Battery {
+    pub level: Level,
+    /// Smoothed pack voltage.
+    pub voltage: f32,
+    /// Sag compensated voltage of each cell, once the cell count is known.
+    pub cell_voltage: Option<f32>,
+    pub cells: Option<u8>,
+    /// Smoothed current in amps, without a current sensor `None`.
+    pub current: Option<f32>,
+    /// Charge drawn since the battery was connected, in mAh.
+    pub consumed: Option<f32>,
+    /// Remaining charge in percent.
+    pub remaining: Option<u8>,
+}
+
+/// Voltage below which no battery is connected.
+const CONNECTED_VOLTAGE: f32 = 2.5;
+/// How long the voltage must stay above or below `CONNECTED_VOLTAGE` for a
+/// battery to be connected or disconnected, in seconds.
+const SETTLE_TIME: f32 = 0.5;
+/// The most cells detected.
+const MAX_CELLS: u8 = 8;
+
+/// Typical resting voltage of a lithium polymer cell against its remaining
+/// charge in percent.
+const DISCHARGE_CURVE: [(f32, f32); 11] = [
+    (3.30, 0.0),
+    (3.68, 10.0),
+    (3.73, 20.0),
+    (3.77, 30.0),
+    (3.80, 40.0),
+    (3.84, 50.0),
+    (3.87, 60.0),
+    (3.95, 70.0),
+    (4.02, 80.0),
+    (4.11, 90.0),
+    (4.20, 100.0),
+];
+
+pub struct BatteryMonitor {
+    pub config: Config,
+    voltage: Option<f32>,
+    current: f32,
+    /// Time the raw voltage has been on the other side of
+    /// `CONNECTED_VOLTAGE` from `connected`.
+    settle_time: f32,
+    connected: bool,
+    cells: Option<u8>,
+    consumed: f32,
+    /// Charge estimated from the voltage at connection, in mAh.
+    initial_charge: f32,
+    level: Level,
+    /// Time the cell voltage has been below the warning and critical
+    /// thresholds.
+    low_time: [f32; 2],
+}
+
+impl BatteryMonitor {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            voltage: None,
+            current: 0.0,
+            settle_time: 0.0,
+            connected: false,
+            cells: None,
+            consumed: 0.0,
+            initial_charge: 0.0,
+            level: Level::NotConnected,
+            low_time: [0.0; 2],
+        }
+    }
+
+    fn has_current_sensor(&self) -> bool {
+        self.config.current_scale != 0.0
+    }
+
+    /// `voltage_pin` and `current_pin` are the voltages measured at the
+    /// pins, and `dt` is the time since the last update in seconds.
+    pub fn update(&mut self, voltage_pin: f32, current_pin: f32, dt: f32) {
+        let config = &self.config;
+        let raw_voltage = voltage_pin * config.voltage_scale;
+        let raw_current = (current_pin - config.current_offset) * config.current_scale;
+
+        // Smoothing starts from the first sample rather than from zero.
+        let k = dt / (config.time_constant + dt);
+        let voltage = match self.voltage {
+            Some(voltage) => voltage + (raw_voltage - voltage) * k,
+            None => raw_voltage,
+        };
+        self.voltage = Some(voltage);
+        self.current += (raw_current - self.current) * k;
+
+        if (raw_voltage >= CONNECTED_VOLTAGE) != self.connected {
+            self.settle_time += dt;
+            if self.settle_time >= SETTLE_TIME {
+                self.settle_time = 0.0;
+                self.connected = !self.connected;
+                if self.connected {
+                    // The smoothed voltage still lags the step from zero.
+                    self.voltage = Some(raw_voltage);
+                    self.connect(raw_voltage);
+                } else {
+                    self.cells = None;
+                    self.level = Level::NotConnected;
+                }
+            }
+        } else {
+            self.settle_time = 0.0;
+        }
+
+        if self.connected && self.has_current_sensor() {
+            self.consumed += self.current * dt * (1000.0 / 3600.0);
+        }
+
+        let Some(cell_voltage) = self.cell_voltage() else { return };
+        let thresholds = [
+            (self.config.warning_cell_voltage, Level::Warning),
+            (self.config.critical_cell_voltage, Level::Critical),
+        ];
+        for ((threshold, level), low_time) in thresholds.into_iter().zip(&mut self.low_time) {
+            if cell_voltage < threshold {
+                *low_time += dt;
+                if *low_time >= self.config.alert_delay {
+                    self.level = self.level.max(level);
+                }
+            } else {
+                *low_time = 0.0;
+            }
+        }
+    }
+
+    fn connect(&mut self, voltage: f32) {
+        let cells = match self.config.cells {
+            0 => libm::ceilf(voltage / self.config.max_cell_voltage).clamp(1.0, MAX_CELLS as f32)
+                as u8,
+            cells => cells,
+        };
+
+        self.cells = Some(cells);
+        self.consumed = 0.0;
+        self.initial_charge = charge(voltage / cells as f32) / 100.0 * self.config.capacity;
+        self.level = Level::Ok;
+        self.low_time = [0.0; 2];
+    }
+
+    /// Sag compensated voltage of each cell, once the cell count is known.
+    fn cell_voltage(&self) -> Option<f32> {
+        let cells = self.cells?;
+        let mut voltage = self.voltage?;
+        if self.has_current_sensor() {
+            voltage += self.current.max(0.0) * self.config.internal_resistance;
+        }
+
+        Some(voltage / cells as f32)
+    }
+
+    pub fn battery(&self) -> Battery {
+        let current_sensor = self.has_current_sensor();
+        let cell_voltage = self.cell_voltage();
+        let remaining = if current_sensor && self.config.capacity > 0.0 {
+            self.cells
+                .map(|_| (self.initial_charge - self.consumed) / self.config.capacity * 100.0)
+        } else {
+            cell_voltage.map(charge)
+        };
+
+        Battery {
+            level: self.level,
+            voltage: self.voltage.unwrap_or(0.0),
+            cell_voltage,
+            cells: self.cells,
+            current: current_sensor.then_some(self.current),
+            consumed: (current_sensor && self.connected).then_some(self.consumed),
+            remaining: remaining.map(|remaining| remaining.clamp(0.0, 100.0) as u8),
+        }
+    }
+}
+
+/// Estimates the remaining charge in percent from the resting voltage of a
+/// cell.
+fn charge(cell_voltage: f32) -> f32 {
+    let (first, last) = (
+        DISCHARGE_CURVE[0],
+        DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1],
+    );
+    if cell_voltage <= first.0 {
+        return first.1;
+    }
+
+    DISCHARGE_CURVE
+        .windows(2)
+        .find(|points| cell_voltage < points[1].0)
+        .map_or(last.1, |points| {
+            let [(v0, c0), (v1, c1)] = [points[0], points[1]];
+            c0 + (cell_voltage - v0) / (v1 - v0) * (c1 - c0)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        voltage_scale: 11.0,
+        current_scale: 0.0,
+        current_offset: 0.0,
+        internal_resistance: 0.0,
+        cells: 0,
+        max_cell_voltage: 4.3,
+        warning_cell_voltage: 3.5,
+        critical_cell_voltage: 3.3,
+        alert_delay: 2.0,
+        capacity: 0.0,
+        // Without smoothing, so that thresholds are crossed straight away.
+        time_constant: 0.0,
+    };
+    /// 10A per volt, reading zero at 0.5V.
+    const CURRENT_SENSOR: Config = Config {
+        current_scale: 10.0,
+        current_offset: 0.5,
+        internal_resistance: 0.02,
+        capacity: 1000.0,
+        ..CONFIG
+    };
+    const DT: f32 = 1.0 / 64.0;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    /// Runs `seconds` with the battery at `voltage` and drawing `current`.
+    fn run(monitor: &mut BatteryMonitor, voltage: f32, current: f32, seconds: f32) {
+        let config = monitor.config;
+        let current_pin = if config.current_scale != 0.0 {
+            current / config.current_scale + config.current_offset
+        } else {
+            0.0
+        };
+        for _ in 0..(seconds / DT) as usize {
+            monitor.update(voltage / config.voltage_scale, current_pin, DT);
+        }
+    }
+
+    fn connected(config: Config, voltage: f32) -> BatteryMonitor {
+        let mut monitor = BatteryMonitor::new(config);
+        run(&mut monitor, voltage, 0.0, SETTLE_TIME);
+        assert!(monitor.battery().level == Level::Ok);
+
+        monitor
+    }
+
+    #[test]
+    fn detects_cell_count() {
+        for (voltage, cells) in [
+            (4.2, 1),
+            (8.4, 2),
+            (12.6, 3),
+            (11.1, 3),
+            (16.8, 4),
+            (15.2, 4),
+            (25.2, 6),
+        ] {
+            let monitor = connected(CONFIG, voltage);
+            assert_eq!(monitor.battery().cells, Some(cells), "{}", voltage);
+        }
+    }
+
+    #[test]
+    fn cell_count_boundaries() {
+        // Above `max_cell_voltage` per cell, 3S reads as 4S.
+        let monitor = connected(CONFIG, 3.0 * 4.35);
+        assert_eq!(monitor.battery().cells, Some(4));
+        // Below 3/4 of it per cell, 4S reads as 3S.
+        let monitor = connected(CONFIG, 4.0 * 3.2);
+        assert_eq!(monitor.battery().cells, Some(3));
+
+        // A configured cell count is used as it is.
+        let config = Config { cells: 4, ..CONFIG };
+        let monitor = connected(config, 4.0 * 3.2);
+        assert_eq!(monitor.battery().cells, Some(4));
+    }
+
+    #[test]
+    fn connects_and_disconnects_after_settling() {
+        let mut monitor = BatteryMonitor::new(CONFIG);
+        run(&mut monitor, 16.8, 0.0, SETTLE_TIME - DT);
+        assert!(monitor.battery().level == Level::NotConnected);
+
+        // A dropout starts the wait again.
+        run(&mut monitor, 0.0, 0.0, DT);
+        run(&mut monitor, 16.8, 0.0, SETTLE_TIME - DT);
+        assert!(monitor.battery().level == Level::NotConnected);
+        run(&mut monitor, 16.8, 0.0, DT);
+        assert!(monitor.battery().level == Level::Ok);
+        assert_eq!(monitor.battery().cells, Some(4));
+
+        run(&mut monitor, 0.0, 0.0, SETTLE_TIME - DT);
+        assert!(monitor.battery().level == Level::Ok);
+        run(&mut monitor, 0.0, 0.0, DT);
+        assert!(monitor.battery().level == Level::NotConnected);
+        assert_eq!(monitor.battery().cells, None);
+
+        // A different pack is detected afresh.
+        run(&mut monitor, 12.6, 0.0, SETTLE_TIME);
+        assert_eq!(monitor.battery().cells, Some(3));
+    }
+
+    #[test]
+    fn compensates_sag_under_load() {
+        let mut monitor = connected(CURRENT_SENSOR, 16.0);
+        // 20A through 0.02 ohms sags the pack by 0.4V.
+        run(&mut monitor, 15.6, 20.0, 1.0);
+        let battery = monitor.battery();
+        assert_close(battery.voltage, 15.6, 1e-3);
+        assert_close(battery.current.unwrap(), 20.0, 1e-3);
+        assert_close(battery.cell_voltage.unwrap(), 4.0, 1e-3);
+
+        // Charging current doesn't lower the estimate.
+        run(&mut monitor, 16.0, -5.0, 1.0);
+        assert_close(monitor.battery().cell_voltage.unwrap(), 4.0, 1e-3);
+
+        // Without a current sensor there is no compensation.
+        let mut monitor = connected(CONFIG, 16.0);
+        run(&mut monitor, 15.6, 20.0, 1.0);
+        assert_close(monitor.battery().cell_voltage.unwrap(), 3.9, 1e-3);
+        assert!(monitor.battery().current.is_none());
+    }
+
+    #[test]
+    fn counts_consumed_charge() {
+        let mut monitor = connected(CURRENT_SENSOR, 16.8);
+        assert_close(monitor.battery().consumed.unwrap(), 0.0, 1e-6);
+        assert_eq!(monitor.battery().remaining, Some(100));
+
+        // 10A for 36 seconds is 100mAh.
+        run(&mut monitor, 16.8, 10.0, 36.0);
+        assert_close(monitor.battery().consumed.unwrap(), 100.0, 0.01);
+        // 102.8mAh is 89.7% remaining, which is rounded down.
+        run(&mut monitor, 16.8, 10.0, 1.0);
+        let battery = monitor.battery();
+        assert_close(battery.consumed.unwrap(), 102.78, 0.01);
+        assert_eq!(battery.remaining, Some(89));
+
+        // The count starts again with the next pack.
+        run(&mut monitor, 0.0, 0.0, SETTLE_TIME);
+        assert!(monitor.battery().consumed.is_none());
+        run(&mut monitor, 16.8, 0.0, SETTLE_TIME);
+        assert_close(monitor.battery().consumed.unwrap(), 0.0, 1e-6);
+    }
+
+    #[test]
+    fn estimates_charge_from_the_discharge_curve() {
+        assert_close(charge(4.25), 100.0, 1e-3);
+        assert_close(charge(4.2), 100.0, 1e-3);
+        assert_close(charge(3.95), 70.0, 1e-3);
+        // Halfway between points.
+        assert_close(charge(3.82), 45.0, 1e-3);
+        assert_close(charge(3.3), 0.0, 1e-3);
+        assert_close(charge(3.0), 0.0, 1e-3);
+
+        // Charge rises with voltage everywhere on the curve.
+        let mut previous = -1.0;
+        for millivolts in 3300..=4200 {
+            let charge = charge(millivolts as f32 / 1000.0);
+            assert!(charge >= previous, "{}", millivolts);
+            previous = charge;
+        }
+
+        // Without a current sensor, the remaining charge is estimated from
+        // the cell voltage.
+        let monitor = connected(CONFIG, 4.0 * 3.82);
+        assert_eq!(monitor.battery().remaining, Some(45));
+    }
+
+    #[test]
+    fn alerts_after_the_delay_and_stay_raised() {
+        let mut monitor = connected(CONFIG, 16.0);
+
+        // Dips shorter than the delay are ignored.
+        run(&mut monitor, 4.0 * 3.4, 0.0, CONFIG.alert_delay - DT);
+        assert!(monitor.battery().level == Level::Ok);
+        run(&mut monitor, 16.0, 0.0, DT);
+        run(&mut monitor, 4.0 * 3.4, 0.0, CONFIG.alert_delay - DT);
+        assert!(monitor.battery().level == Level::Ok);
+        run(&mut monitor, 4.0 * 3.4, 0.0, DT);
+        assert!(monitor.battery().level == Level::Warning);
+
+        // The voltage recovers as the load drops, but the level doesn't.
+        run(&mut monitor, 16.0, 0.0, 10.0);
+        assert!(monitor.battery().level == Level::Warning);
+
+        run(&mut monitor, 4.0 * 3.2, 0.0, CONFIG.alert_delay);
+        assert!(monitor.battery().level == Level::Critical);
+        run(&mut monitor, 4.0 * 3.4, 0.0, 10.0);
+        assert!(monitor.battery().level == Level::Critical);
+
+        // Only a new pack clears it.
+        run(&mut monitor, 0.0, 0.0, SETTLE_TIME);
+        assert!(monitor.battery().level == Level::NotConnected);
+        run(&mut monitor, 16.0, 0.0, SETTLE_TIME);
+        assert!(monitor.battery().level == Level::Ok);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    crc8, varint, End, FrameKind, Header, Record, FIELD_COUNT, FRAME_OVERHEAD, MAGIC, VERSION,
+};
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Frame {
+    Header(Header),
+    Record(Record),
+    End(End),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// `len` bytes from `position` were skipped, because they weren't valid
+    /// frames. Erased flash between logs is skipped without an error unless
+    /// it is next to damaged data.
+    Corrupt { position: usize, len: usize },
+    /// The delta frame at `position` couldn't be decoded, because a frame
+    /// before it was lost. Records decode again from the next keyframe.
+    MissingKeyframe { position: usize },
+    /// The log starting at `position` was recorded in a format this version
+    /// can't read, and its frames are skipped.
+    UnsupportedVersion { position: usize, version: u8 },
+}
+
+/// Reads frames from recorded data, which may hold any number of logs.
+/// Damaged data is reported and skipped, and reading continues at the next
+/// valid frame.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// The last decoded record, which delta frames are relative to.
+    previous: Option<[i32; FIELD_COUNT]>,
+    /// Whether the current log is in a format this version can read. Frames
+    /// before the first header are read as if it was.
+    supported: bool,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            previous: None,
+            supported: true,
+        }
+    }
+
+    /// Position of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Decodes a frame whose length and checksum are valid. Returns `None`
+    /// for frames which are skipped without an error.
+    fn decode(
+        &mut self,
+        kind: FrameKind,
+        payload: &[u8],
+        position: usize,
+    ) -> Option<Result<Frame, Error>> {
+        let corrupt = Error::Corrupt {
+            position,
+            len: payload.len() + FRAME_OVERHEAD,
+        };
+
+        match kind {
+            FrameKind::Header => {
+                self.previous = None;
+                let Some(rest) = payload.strip_prefix(&MAGIC) else {
+                    return Some(Err(corrupt));
+                };
+                let [version, field_count, ref interval @ ..] = *rest else {
+                    return Some(Err(corrupt));
+                };
+                self.supported = version == VERSION && field_count as usize == FIELD_COUNT;
+                if !self.supported {
+                    return Some(Err(Error::UnsupportedVersion { position, version }));
+                }
+
+                match varint::read(interval) {
+                    Some((interval_us, len)) if len == interval.len() => {
+                        Some(Ok(Frame::Header(Header { interval_us })))
+                    }
+                    _ => Some(Err(corrupt)),
+                }
+            }
+            FrameKind::Keyframe | FrameKind::Delta => {
+                if !self.supported {
+                    return None;
+                }
+                let previous = match (kind, self.previous) {
+                    (FrameKind::Keyframe, _) => [0; FIELD_COUNT],
+                    (_, Some(previous)) => previous,
+                    (_, None) => return Some(Err(Error::MissingKeyframe { position })),
+                };
+
+                let mut fields = [0; FIELD_COUNT];
+                let mut len = 0;
+                for (field, previous) in fields.iter_mut().zip(previous) {
+                    let Some((value, value_len)) = varint::read_signed(&payload[len..]) else {
+                        self.previous = None;
+                        return Some(Err(corrupt));
+                    };
+                    *field = previous.wrapping_add(value);
+                    len += value_len;
+                }
+                if len != payload.len() {
+                    self.previous = None;
+                    return Some(Err(corrupt));
+                }
+                self.previous = Some(fields);
+
+                Some(Ok(Frame::Record(Record::from_fields(&fields))))
+            }
+            FrameKind::End => {
+                self.previous = None;
+                if !self.supported {
+                    return None;
+                }
+
+                let end = varint::read(payload).and_then(|(records, len)| {
+                    let (dropped, dropped_len) = varint::read(&payload[len..])?;
+                    (len + dropped_len == payload.len()).then_some(End { records, dropped })
+                });
+                Some(end.map(Frame::End).ok_or(corrupt))
+            }
+        }
+    }
+}
+
+impl Iterator for Reader<'_> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Start of the bytes skipped looking for a valid frame, and whether
+        // any of them weren't erased flash.
+        let mut skipped_from = self.position;
+        let mut damaged = false;
+        loop {
+            let rest = &self.bytes[self.position..];
+            if rest.is_empty() {
+                return damaged.then_some(Err(Error::Corrupt {
+                    position: skipped_from,
+                    len: self.position - skipped_from,
+                }));
+            }
+
+            let Some((kind, payload)) = parse_frame(rest) else {
+                damaged |= rest[0] != 0xFF;
+                self.position += 1;
+                continue;
+            };
+            if damaged {
+                // The valid frame is read by the next call.
+                self.previous = None;
+                return Some(Err(Error::Corrupt {
+                    position: skipped_from,
+                    len: self.position - skipped_from,
+                }));
+            }
+
+            let position = self.position;
+            self.position += payload.len() + FRAME_OVERHEAD;
+            match self.decode(kind, payload, position) {
+                Some(item) => return Some(item),
+                None => skipped_from = self.position,
+            }
+        }
+    }
+}
+
+/// Returns the kind and payload of the frame at the start of `bytes`, if it
+/// has a known kind, fits and has a valid checksum.
+fn parse_frame(bytes: &[u8]) -> Option<(FrameKind, &[u8])> {
+    let kind = FrameKind::from_byte(*bytes.first()?)?;
+    let len = *bytes.get(1)? as usize;
+    let checksum = *bytes.get(2 + len)?;
+    if crc8(&bytes[..2 + len]) != checksum {
+        return None;
+    }
+
+    Some((kind, &bytes[2..2 + len]))
+}
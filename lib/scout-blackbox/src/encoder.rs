@@ -0,0 +1,98 @@
+use crate::{
+    crc8, varint, End, FrameKind, Header, Record, FIELD_COUNT, FRAME_OVERHEAD, MAGIC, VERSION,
+};
+
+/// Length of the longest frame, a keyframe with every field at its longest.
+pub const MAX_FRAME_LEN: usize = FRAME_OVERHEAD + FIELD_COUNT * varint::MAX_LEN;
+/// A keyframe is written every this many records, so a reader can recover
+/// from damaged frames.
+pub const KEYFRAME_INTERVAL: u32 = 32;
+
+pub struct Encoder {
+    previous: [i32; FIELD_COUNT],
+    records: u32,
+    /// Records until the next keyframe.
+    keyframe_countdown: u32,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            previous: [0; FIELD_COUNT],
+            records: 0,
+            keyframe_countdown: 0,
+        }
+    }
+
+    /// Starts a new log. Each method encodes a complete frame into `buf`,
+    /// returning its length.
+    pub fn header(&mut self, header: &Header, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        self.records = 0;
+        self.keyframe_countdown = 0;
+
+        let payload = &mut buf[2..];
+        payload[..MAGIC.len()].copy_from_slice(&MAGIC);
+        let mut len = MAGIC.len();
+        payload[len] = VERSION;
+        payload[len + 1] = FIELD_COUNT as u8;
+        len += 2;
+        len += varint::write(header.interval_us, &mut payload[len..]);
+
+        finish(buf, FrameKind::Header, len)
+    }
+
+    pub fn record(&mut self, record: &Record, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let fields = record.to_fields();
+        let kind = if self.keyframe_countdown == 0 {
+            self.keyframe_countdown = KEYFRAME_INTERVAL;
+            FrameKind::Keyframe
+        } else {
+            FrameKind::Delta
+        };
+        self.keyframe_countdown -= 1;
+
+        let payload = &mut buf[2..];
+        let mut len = 0;
+        for (field, previous) in fields.iter().zip(&self.previous) {
+            let value = match kind {
+                FrameKind::Keyframe => *field,
+                _ => field.wrapping_sub(*previous),
+            };
+            len += varint::write_signed(value, &mut payload[len..]);
+        }
+        self.previous = fields;
+        self.records = self.records.wrapping_add(1);
+
+        finish(buf, kind, len)
+    }
+
+    /// Ends the log. `dropped` is the number of records which were not
+    /// passed to the encoder.
+    pub fn end(&mut self, dropped: u32, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let end = End {
+            records: self.records,
+            dropped,
+        };
+
+        let payload = &mut buf[2..];
+        let mut len = varint::write(end.records, payload);
+        len += varint::write(end.dropped, &mut payload[len..]);
+
+        finish(buf, FrameKind::End, len)
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fills in the kind, length and checksum around a payload of `len` bytes.
+fn finish(buf: &mut [u8; MAX_FRAME_LEN], kind: FrameKind, len: usize) -> usize {
+    buf[0] = kind.byte();
+    buf[1] = len as u8;
+    buf[2 + len] = crc8(&buf[..2 + len]);
+
+    len + FRAME_OVERHEAD
+}
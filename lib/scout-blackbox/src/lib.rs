@@ -0,0 +1,404 @@
+#![no_std]
+
+//! Blackbox log format
+//!
+//! A log is a sequence of frames, each made up of a kind byte, the length of
+//! the payload, the payload, and a CRC-8 of everything before it. A log
+//! starts with a header frame, whose payload starts with `MAGIC`, and ends
+//! with an end frame, unless power was lost while recording.
+//!
+//! Each record is stored as the integer fields in `FIELDS`. Keyframes hold
+//! the fields themselves, and delta frames their differences from the
+//! previous record, both as zigzag encoded variable length integers.
+//!
+//! The encoder runs on the flight controller, and the reader on the tools
+//! which decode the logs, so both sides always agree on the format.
+
+use core::array;
+
+mod decoder;
+mod encoder;
+mod varint;
+
+pub use decoder::{Error, Frame, Reader};
+pub use encoder::{Encoder, KEYFRAME_INTERVAL, MAX_FRAME_LEN};
+
+/// Starts the payload of every header frame.
+pub const MAGIC: [u8; 4] = *b"SCBB";
+/// Changed whenever the format or the fields change.
+pub const VERSION: u8 = 1;
+
+pub const MOTOR_COUNT: usize = 4;
+
+/// Kind, length and checksum bytes.
+const FRAME_OVERHEAD: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameKind {
+    Header,
+    Keyframe,
+    Delta,
+    End,
+}
+
+impl FrameKind {
+    pub const fn byte(&self) -> u8 {
+        match self {
+            FrameKind::Header => b'H',
+            FrameKind::Keyframe => b'I',
+            FrameKind::Delta => b'P',
+            FrameKind::End => b'E',
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'H' => Some(FrameKind::Header),
+            b'I' => Some(FrameKind::Keyframe),
+            b'P' => Some(FrameKind::Delta),
+            b'E' => Some(FrameKind::End),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Header {
+    /// Time between records, in microseconds.
+    pub interval_us: u32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct End {
+    pub records: u32,
+    /// Records which were dropped because the storage couldn't keep up.
+    pub dropped: u32,
+}
+
+/// One control loop iteration.
+#[derive(Clone, Copy, Default, PartialEq, defmt::Format)]
+pub struct Record {
+    /// Microseconds since the log started.
+    pub time_us: u32,
+    /// Filtered roll, pitch and yaw rates, in degrees per second.
+    pub gyro: [f32; 3],
+    /// Roll, pitch and yaw rate setpoints, in degrees per second.
+    pub setpoint: [f32; 3],
+    /// P, I, D and feedforward terms of the roll, pitch and yaw rate
+    /// controllers, where 1.0 is full motor authority.
+    pub pid: [[f32; 4]; 3],
+    /// Motor outputs, from 0.0 to 1.0.
+    pub motors: [f32; MOTOR_COUNT],
+    /// Roll, pitch and yaw commands from -1.0 to 1.0, and throttle from 0.0
+    /// to 1.0.
+    pub rc: [f32; 4],
+}
+
+/// A field of a record, as stored in the log.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Field {
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Value of one step of the stored integer.
+    pub resolution: f32,
+}
+
+const fn field(name: &'static str, unit: &'static str, resolution: f32) -> Field {
+    Field {
+        name,
+        unit,
+        resolution,
+    }
+}
+
+const GYRO: usize = 1;
+const SETPOINT: usize = GYRO + 3;
+const PID: usize = SETPOINT + 3;
+const MOTORS: usize = PID + 12;
+const RC: usize = MOTORS + MOTOR_COUNT;
+pub const FIELD_COUNT: usize = RC + 4;
+
+const RATE: f32 = 0.1;
+const PID_TERM: f32 = 0.0001;
+const FRACTION: f32 = 0.001;
+
+/// The fields of a record, in the order they are stored.
+pub const FIELDS: [Field; FIELD_COUNT] = [
+    field("time", "us", 1.0),
+    field("gyro_roll", "deg/s", RATE),
+    field("gyro_pitch", "deg/s", RATE),
+    field("gyro_yaw", "deg/s", RATE),
+    field("setpoint_roll", "deg/s", RATE),
+    field("setpoint_pitch", "deg/s", RATE),
+    field("setpoint_yaw", "deg/s", RATE),
+    field("p_roll", "", PID_TERM),
+    field("i_roll", "", PID_TERM),
+    field("d_roll", "", PID_TERM),
+    field("f_roll", "", PID_TERM),
+    field("p_pitch", "", PID_TERM),
+    field("i_pitch", "", PID_TERM),
+    field("d_pitch", "", PID_TERM),
+    field("f_pitch", "", PID_TERM),
+    field("p_yaw", "", PID_TERM),
+    field("i_yaw", "", PID_TERM),
+    field("d_yaw", "", PID_TERM),
+    field("f_yaw", "", PID_TERM),
+    field("motor_0", "", FRACTION),
+    field("motor_1", "", FRACTION),
+    field("motor_2", "", FRACTION),
+    field("motor_3", "", FRACTION),
+    field("rc_roll", "", FRACTION),
+    field("rc_pitch", "", FRACTION),
+    field("rc_yaw", "", FRACTION),
+    field("rc_throttle", "", FRACTION),
+];
+
+impl Record {
+    /// Quantizes the record to the resolution of each field. Values outside
+    /// the range of an `i32` saturate.
+    pub fn to_fields(&self) -> [i32; FIELD_COUNT] {
+        let mut fields = [0; FIELD_COUNT];
+        fields[0] = self.time_us as i32;
+
+        let values = self
+            .gyro
+            .iter()
+            .chain(&self.setpoint)
+            .chain(self.pid.iter().flatten())
+            .chain(&self.motors)
+            .chain(&self.rc);
+        for ((stored, value), field) in fields[GYRO..].iter_mut().zip(values).zip(&FIELDS[GYRO..]) {
+            *stored = libm::roundf(value / field.resolution) as i32;
+        }
+
+        fields
+    }
+
+    pub fn from_fields(fields: &[i32; FIELD_COUNT]) -> Self {
+        let value = |i: usize| fields[i] as f32 * FIELDS[i].resolution;
+
+        Self {
+            time_us: fields[0] as u32,
+            gyro: array::from_fn(|i| value(GYRO + i)),
+            setpoint: array::from_fn(|i| value(SETPOINT + i)),
+            pid: array::from_fn(|axis| array::from_fn(|term| value(PID + axis * 4 + term))),
+            motors: array::from_fn(|i| value(MOTORS + i)),
+            rc: array::from_fn(|i| value(RC + i)),
+        }
+    }
+}
+
+/// CRC-8 with the polynomial 0x07.
+pub(crate) fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDS: usize = 2 * KEYFRAME_INTERVAL as usize + 6;
+    const LOG_LEN: usize = (RECORDS + 2) * MAX_FRAME_LEN;
+
+    /// A log of `RECORDS` varied records, with the start of each frame.
+    struct Log {
+        bytes: [u8; LOG_LEN],
+        len: usize,
+        frames: [usize; RECORDS + 2],
+        records: [Record; RECORDS],
+    }
+
+    impl Log {
+        fn new() -> Self {
+            let mut seed = 1u32;
+            let mut noise = || {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0
+            };
+            let mut records = [Record::default(); RECORDS];
+            for (i, record) in records.iter_mut().enumerate() {
+                *record = Record {
+                    time_us: i as u32 * 1000,
+                    gyro: [noise() * 2000.0, noise() * 20.0, noise() * 0.5],
+                    setpoint: [noise() * 670.0, 0.0, -noise() * 670.0],
+                    pid: [[noise() * 0.5; 4], [noise(); 4], [noise() * 0.01; 4]],
+                    motors: [noise() * 0.5 + 0.5; MOTOR_COUNT],
+                    rc: [noise(), noise(), noise(), noise() * 0.5 + 0.5],
+                };
+            }
+            // Saturated values either side of a delta frame, whose
+            // difference overflows.
+            records[40].gyro[0] = 1e12;
+            records[41].gyro[0] = -1e12;
+
+            let mut log = Self {
+                bytes: [0xff; LOG_LEN],
+                len: 0,
+                frames: [0; RECORDS + 2],
+                records,
+            };
+            let mut encoder = Encoder::new();
+            let mut buf = [0; MAX_FRAME_LEN];
+            log.push(
+                0,
+                encoder.header(&Header { interval_us: 1000 }, &mut buf),
+                &buf,
+            );
+            for (i, record) in records.iter().enumerate() {
+                let len = encoder.record(record, &mut buf);
+                log.push(i + 1, len, &buf);
+            }
+            log.push(RECORDS + 1, encoder.end(3, &mut buf), &buf);
+
+            log
+        }
+
+        fn push(&mut self, frame: usize, len: usize, buf: &[u8; MAX_FRAME_LEN]) {
+            self.frames[frame] = self.len;
+            self.bytes[self.len..self.len + len].copy_from_slice(&buf[..len]);
+            self.len += len;
+        }
+
+        /// The start of the frame holding record `i`.
+        fn record_frame(&self, i: usize) -> usize {
+            self.frames[i + 1]
+        }
+
+        /// Record `i` as the reader returns it, at the log's resolution.
+        fn expected(&self, i: usize) -> Frame {
+            Frame::Record(Record::from_fields(&self.records[i].to_fields()))
+        }
+    }
+
+    fn is_record(frame: Option<Result<Frame, Error>>, expected: Frame) -> bool {
+        match (frame, expected) {
+            (Some(Ok(Frame::Record(record))), Frame::Record(expected)) => record == expected,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn round_trips_across_keyframes() {
+        let log = Log::new();
+        for i in 0..RECORDS {
+            let kind = FrameKind::from_byte(log.bytes[log.record_frame(i)]);
+            let keyframe = i % KEYFRAME_INTERVAL as usize == 0;
+            assert!(
+                kind == Some(if keyframe {
+                    FrameKind::Keyframe
+                } else {
+                    FrameKind::Delta
+                })
+            );
+        }
+
+        // Erased flash after the log is skipped.
+        let mut reader = Reader::new(&log.bytes);
+        assert!(matches!(
+            reader.next(),
+            Some(Ok(Frame::Header(Header { interval_us: 1000 })))
+        ));
+        for i in 0..RECORDS {
+            assert!(is_record(reader.next(), log.expected(i)), "record {}", i);
+        }
+        assert!(matches!(
+            reader.next(),
+            Some(Ok(Frame::End(End {
+                records,
+                dropped: 3
+            }))) if records == RECORDS as u32
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn saturates_out_of_range_values() {
+        let log = Log::new();
+        let fields = |i: usize| log.records[i].to_fields()[GYRO];
+        assert_eq!((fields(40), fields(41)), (i32::MAX, i32::MIN));
+    }
+
+    #[test]
+    fn reports_a_cut_off_log() {
+        let mut log = Log::new();
+        // Power was lost partway through writing record 10.
+        let cut = log.record_frame(10) + 4;
+        log.bytes[cut..].fill(0xff);
+
+        let mut reader = Reader::new(&log.bytes);
+        assert!(matches!(reader.next(), Some(Ok(Frame::Header(_)))));
+        for i in 0..10 {
+            assert!(is_record(reader.next(), log.expected(i)), "record {}", i);
+        }
+        assert!(
+            reader.next().and_then(Result::err)
+                == Some(Error::Corrupt {
+                    position: log.record_frame(10),
+                    len: LOG_LEN - log.record_frame(10),
+                })
+        );
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn recovers_from_a_corrupted_frame_at_the_next_keyframe() {
+        let mut log = Log::new();
+        let damaged = log.record_frame(10);
+        log.bytes[damaged + 5] ^= 0x10;
+
+        let mut reader = Reader::new(&log.bytes[..log.len]);
+        assert!(matches!(reader.next(), Some(Ok(Frame::Header(_)))));
+        for i in 0..10 {
+            assert!(is_record(reader.next(), log.expected(i)), "record {}", i);
+        }
+        assert!(
+            reader.next().and_then(Result::err)
+                == Some(Error::Corrupt {
+                    position: damaged,
+                    len: log.record_frame(11) - damaged,
+                })
+        );
+        // The deltas after it have nothing to apply to.
+        for i in 11..KEYFRAME_INTERVAL as usize {
+            assert!(
+                reader.next().and_then(Result::err)
+                    == Some(Error::MissingKeyframe {
+                        position: log.record_frame(i)
+                    })
+            );
+        }
+        for i in KEYFRAME_INTERVAL as usize..RECORDS {
+            assert!(is_record(reader.next(), log.expected(i)), "record {}", i);
+        }
+        assert!(matches!(reader.next(), Some(Ok(Frame::End(_)))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn rejects_frames_which_overrun_the_data() {
+        let log = Log::new();
+        // A frame whose length runs past the end of the data.
+        let end = log.record_frame(3) + 10;
+        let mut reader = Reader::new(&log.bytes[..end]);
+        assert!(matches!(reader.next(), Some(Ok(Frame::Header(_)))));
+        for i in 0..3 {
+            assert!(is_record(reader.next(), log.expected(i)), "record {}", i);
+        }
+        assert!(
+            reader.next().and_then(Result::err)
+                == Some(Error::Corrupt {
+                    position: log.record_frame(3),
+                    len: 10,
+                })
+        );
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Variable length integers
+//!
+//! Unsigned values are stored seven bits per byte, least significant first,
+//! with the high bit set on every byte but the last. Signed values are first
+//! zigzag encoded, so that small negative values are also short.
+
+/// Length of the longest encoded `u32`.
+pub const MAX_LEN: usize = 5;
+
+/// Writes `value` to the start of `buf`, returning the number of bytes
+/// written. `buf` must have room for `MAX_LEN` bytes.
+pub fn write(mut value: u32, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while value >= 0x80 {
+        buf[len] = value as u8 | 0x80;
+        value >>= 7;
+        len += 1;
+    }
+    buf[len] = value as u8;
+
+    len + 1
+}
+
+pub fn write_signed(value: i32, buf: &mut [u8]) -> usize {
+    write(((value << 1) ^ (value >> 31)) as u32, buf)
+}
+
+/// Reads a value from the start of `bytes`, returning it and its length, or
+/// `None` if it is cut off or longer than any `u32`.
+pub fn read(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().take(MAX_LEN).enumerate() {
+        if i == MAX_LEN - 1 && *byte > 0x0F {
+            return None;
+        }
+        value |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+
+    None
+}
+
+pub fn read_signed(bytes: &[u8]) -> Option<(i32, usize)> {
+    read(bytes).map(|(value, len)| ((value >> 1) as i32 ^ -((value & 1) as i32), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `value`, checks its encoding and reads it back.
+    fn round_trip(value: u32, encoded: &[u8]) {
+        let mut buf = [0; MAX_LEN];
+        let len = write(value, &mut buf);
+        assert_eq!(&buf[..len], encoded, "{}", value);
+        assert_eq!(read(&buf[..len]), Some((value, len)));
+    }
+
+    #[test]
+    fn unsigned_byte_boundaries() {
+        round_trip(0, &[0x00]);
+        round_trip(1, &[0x01]);
+        round_trip(0x7f, &[0x7f]);
+        round_trip(0x80, &[0x80, 0x01]);
+        round_trip(0x3fff, &[0xff, 0x7f]);
+        round_trip(0x4000, &[0x80, 0x80, 0x01]);
+        round_trip(0x1f_ffff, &[0xff, 0xff, 0x7f]);
+        round_trip(0x20_0000, &[0x80, 0x80, 0x80, 0x01]);
+        round_trip(0xfff_ffff, &[0xff, 0xff, 0xff, 0x7f]);
+        round_trip(0x1000_0000, &[0x80, 0x80, 0x80, 0x80, 0x01]);
+        round_trip(u32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x0f]);
+    }
+
+    #[test]
+    fn zigzag_encodes_small_values_short() {
+        for (value, zigzag) in [
+            (0, 0),
+            (-1, 1),
+            (1, 2),
+            (-2, 3),
+            (63, 126),
+            (-64, 127),
+            (64, 128),
+            (i32::MAX, u32::MAX - 1),
+            (i32::MIN, u32::MAX),
+        ] {
+            let mut signed = [0; MAX_LEN];
+            let len = write_signed(value, &mut signed);
+            let mut unsigned = [0; MAX_LEN];
+            let unsigned_len = write(zigzag, &mut unsigned);
+            assert_eq!(&signed[..len], &unsigned[..unsigned_len]);
+            assert_eq!(read_signed(&signed[..len]), Some((value, len)), "{}", value);
+        }
+    }
+
+    #[test]
+    fn reads_only_the_first_value() {
+        assert_eq!(read(&[0x96, 0x01, 0x05]), Some((150, 2)));
+        assert_eq!(read_signed(&[0x03, 0x02]), Some((-2, 1)));
+    }
+
+    #[test]
+    fn rejects_cut_off_and_overlong_values() {
+        assert_eq!(read(&[]), None);
+        assert_eq!(read(&[0x80]), None);
+        assert_eq!(read(&[0xff, 0xff, 0xff, 0xff]), None);
+        // The fifth byte only has room for the top four bits of a u32.
+        assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0x10]), None);
+        assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]), None);
+        assert_eq!(read_signed(&[0x80]), None);
+    }
+}
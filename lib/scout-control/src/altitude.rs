@@ -0,0 +1,250 @@
+//! Altitude hold
+//!
+//! The throttle stick sets the climb rate instead of the throttle. Around
+//! center stick the climb rate is zero, and the altitude is held. A target
+//! altitude moves with the requested climb rate, and the error from the
+//! estimated altitude adjusts the climb rate setpoint. A PI controller then
+//! finds the throttle which gives the climb rate, around the hover throttle.
+//!
+//! The controller starts from the altitude and throttle when the mode is
+//! entered, so the aircraft doesn't jump.
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Climb rate at full throttle stick, and descent rate at zero throttle,
+    /// in meters per second.
+    pub max_climb_rate: f32,
+    /// Throttle stick movement either side of center which holds altitude.
+    pub deadband: f32,
+    /// Climb rate requested per meter of altitude error, in meters per
+    /// second.
+    pub altitude_gain: f32,
+    /// Throttle per meter per second of climb rate error.
+    pub climb_rate_p: f32,
+    /// Throttle per meter of accumulated climb rate error.
+    pub climb_rate_i: f32,
+    /// Limit of the integral term's throttle.
+    pub i_limit: f32,
+    /// Throttle which holds the aircraft level in a hover. The integral term
+    /// corrects for errors in this.
+    pub hover_throttle: f32,
+    /// Throttle limits while holding altitude.
+    pub min_throttle: f32,
+    pub max_throttle: f32,
+}
+
+pub struct AltitudeHold {
+    pub config: Config,
+    /// `None` while the mode is inactive.
+    target_altitude: Option<f32>,
+    integral: f32,
+}
+
+/// Tilt compensation is limited to that needed at 60 degrees.
+const MIN_TILT_COS: f32 = 0.5;
+
+impl AltitudeHold {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            target_altitude: None,
+            integral: 0.0,
+        }
+    }
+
+    /// Forgets the target altitude. Called while the mode is inactive, so
+    /// the next `update` starts from the current altitude and throttle.
+    pub fn reset(&mut self) {
+        self.target_altitude = None;
+        self.integral = 0.0;
+    }
+
+    /// Returns the throttle which holds or changes altitude as the throttle
+    /// stick requests.
+    ///
+    /// `throttle` is the throttle stick, from 0.0 to 1.0. `altitude` in
+    /// meters and `climb_rate` in meters per second are the estimated
+    /// values, `tilt_cos` is the cosine of the aircraft's tilt from level,
+    /// and `dt` is the time since the last update in seconds.
+    pub fn update(
+        &mut self,
+        throttle: f32,
+        altitude: f32,
+        climb_rate: f32,
+        tilt_cos: f32,
+        dt: f32,
+    ) -> f32 {
+        let stick_climb_rate = self.stick_climb_rate(throttle);
+        self.update_climb_rate(
+            stick_climb_rate,
+            throttle,
+            altitude,
+            climb_rate,
+            tilt_cos,
+            dt,
+        )
+    }
+
+    /// Returns the throttle which climbs at `climb_rate_setpoint`, in meters
+    /// per second, for modes which control the altitude themselves. The
+    /// altitude reached is held while the setpoint is zero.
+    ///
+    /// `throttle` is the throttle in use before the mode was entered, which
+    /// the output starts from. The other arguments are as for `update`.
+    pub fn update_climb_rate(
+        &mut self,
+        climb_rate_setpoint: f32,
+        throttle: f32,
+        altitude: f32,
+        climb_rate: f32,
+        tilt_cos: f32,
+        dt: f32,
+    ) -> f32 {
+        let tilt_cos = tilt_cos.max(MIN_TILT_COS);
+        let config = &self.config;
+
+        let target_altitude = match self.target_altitude {
+            Some(target_altitude) => target_altitude,
+            None => {
+                // The integral term takes up the difference between the
+                // previous throttle and the hover throttle, so the output
+                // starts where the throttle was.
+                self.integral = (throttle * tilt_cos - config.hover_throttle)
+                    .clamp(-config.i_limit, config.i_limit);
+                altitude
+            }
+        };
+
+        // The target can't get further ahead than the climb rate can make
+        // up, which would otherwise wind up when the aircraft can't keep
+        // up with the setpoint.
+        let max_error = config.max_climb_rate / config.altitude_gain;
+        let target_altitude = (target_altitude + climb_rate_setpoint * dt)
+            .clamp(altitude - max_error, altitude + max_error);
+        self.target_altitude = Some(target_altitude);
+
+        let climb_rate_setpoint = (climb_rate_setpoint
+            + (target_altitude - altitude) * config.altitude_gain)
+            .clamp(-config.max_climb_rate, config.max_climb_rate);
+        let error = climb_rate_setpoint - climb_rate;
+        self.integral = (self.integral + error * config.climb_rate_i * dt)
+            .clamp(-config.i_limit, config.i_limit);
+
+        let output = config.hover_throttle + self.integral + error * config.climb_rate_p;
+        (output / tilt_cos).clamp(config.min_throttle, config.max_throttle)
+    }
+
+    /// Returns the climb rate requested by the throttle stick, in meters per
+    /// second.
+    fn stick_climb_rate(&self, throttle: f32) -> f32 {
+        let deflection = (throttle - 0.5) * 2.0;
+        let deadband = self.config.deadband * 2.0;
+        let magnitude = ((libm::fabsf(deflection) - deadband) / (1.0 - deadband)).clamp(0.0, 1.0);
+
+        libm::copysignf(magnitude, deflection) * self.config.max_climb_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        max_climb_rate: 2.0,
+        deadband: 0.1,
+        altitude_gain: 1.0,
+        climb_rate_p: 0.1,
+        climb_rate_i: 0.05,
+        i_limit: 0.2,
+        hover_throttle: 0.4,
+        min_throttle: 0.1,
+        max_throttle: 0.9,
+    };
+    const DT: f32 = 1.0 / 1024.0;
+    const GRAVITY: f32 = 9.80665;
+    /// The aircraft actually hovers at more throttle than configured.
+    const TRUE_HOVER_THROTTLE: f32 = 0.45;
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    /// A point mass whose thrust is proportional to the throttle, flying
+    /// level.
+    struct Aircraft {
+        altitude: f32,
+        climb_rate: f32,
+    }
+
+    impl Aircraft {
+        /// Runs `seconds` of altitude hold with the throttle stick held at
+        /// `stick`, returning the last throttle.
+        fn fly(&mut self, controller: &mut AltitudeHold, stick: f32, seconds: f32) -> f32 {
+            let mut throttle = 0.0;
+            for _ in 0..(seconds / DT) as usize {
+                throttle = controller.update(stick, self.altitude, self.climb_rate, 1.0, DT);
+                let acceleration = GRAVITY * (throttle / TRUE_HOVER_THROTTLE - 1.0);
+                self.climb_rate += acceleration * DT;
+                self.altitude += self.climb_rate * DT;
+            }
+
+            throttle
+        }
+    }
+
+    #[test]
+    fn returns_to_the_held_altitude() {
+        let mut controller = AltitudeHold::new(CONFIG);
+        // Entering the mode while still climbing.
+        let mut aircraft = Aircraft {
+            altitude: 10.0,
+            climb_rate: 1.0,
+        };
+
+        let throttle = aircraft.fly(&mut controller, 0.5, 30.0);
+        assert_close(aircraft.altitude, 10.0, 0.02);
+        assert_close(aircraft.climb_rate, 0.0, 0.01);
+        // The integral has taken up the error in the hover throttle.
+        assert_close(throttle, TRUE_HOVER_THROTTLE, 1e-3);
+        assert_close(
+            controller.integral,
+            TRUE_HOVER_THROTTLE - CONFIG.hover_throttle,
+            1e-3,
+        );
+    }
+
+    #[test]
+    fn climbs_at_the_stick_rate() {
+        let mut controller = AltitudeHold::new(CONFIG);
+        let mut aircraft = Aircraft {
+            altitude: 0.0,
+            climb_rate: 0.0,
+        };
+
+        aircraft.fly(&mut controller, 1.0, 20.0);
+        assert_close(aircraft.climb_rate, CONFIG.max_climb_rate, 0.01);
+        aircraft.fly(&mut controller, 0.0, 20.0);
+        assert_close(aircraft.climb_rate, -CONFIG.max_climb_rate, 0.01);
+
+        // Inside the deadband the altitude reached is held.
+        aircraft.fly(&mut controller, 0.5, 20.0);
+        let held = aircraft.altitude;
+        aircraft.fly(&mut controller, 0.5 + CONFIG.deadband * 0.9, 10.0);
+        assert_close(aircraft.altitude, held, 0.02);
+    }
+
+    #[test]
+    fn starts_from_the_current_throttle() {
+        let mut controller = AltitudeHold::new(CONFIG);
+        let throttle = controller.update(0.5, 10.0, 0.0, 1.0, DT);
+        assert_close(throttle, 0.5, 1e-3);
+
+        // Entered while tilted, the output also starts from the throttle,
+        // and levelling out removes the tilt compensation.
+        controller.reset();
+        let throttle = controller.update(0.5, 10.0, 0.0, 0.8, DT);
+        assert_close(throttle, 0.5, 1e-3);
+        let level = controller.update(0.5, 10.0, 0.0, 1.0, DT);
+        assert_close(throttle * 0.8, level, 1e-3);
+    }
+}
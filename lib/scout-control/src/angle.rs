@@ -0,0 +1,241 @@
+//! Self-levelling flight modes
+//!
+//! An outer loop around the rate controller. In angle mode the roll and pitch
+//! sticks set target angles, and the error from the attitude estimate becomes
+//! the rate setpoint. Horizon mode behaves like angle mode around center stick
+//! and fades to acro towards full deflection. Yaw is always rate controlled.
+//!
+//! Changing mode fades between the old and new behaviour over
+//! `Config::transition_time`, so the rate setpoint never steps.
+
+use scout_rc::{
+    modes::{Mode, ModeSet},
+    RateSetpoint, RcCommand,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlightMode {
+    Acro,
+    Angle,
+    Horizon,
+}
+
+impl FlightMode {
+    /// Angle mode takes priority over horizon mode, and acro is used when
+    /// neither is active.
+    pub fn from_modes(modes: &ModeSet) -> Self {
+        if modes.contains(Mode::Angle) {
+            FlightMode::Angle
+        } else if modes.contains(Mode::Horizon) {
+            FlightMode::Horizon
+        } else {
+            FlightMode::Acro
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Roll and pitch angle at full stick deflection, in degrees.
+    pub max_angle: f32,
+    /// Rate requested per degree of angle error, in degrees per second.
+    pub level_gain: f32,
+    /// Time taken to fade between modes, in seconds.
+    pub transition_time: f32,
+}
+
+pub struct AttitudeController {
+    pub config: Config,
+    /// How much self-levelling is applied, from 0.0 (acro) to 1.0.
+    level: f32,
+    /// How much the self-levelling fades out with stick deflection, from 0.0
+    /// (angle) to 1.0 (horizon).
+    horizon: f32,
+}
+
+impl AttitudeController {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            level: 0.0,
+            horizon: 0.0,
+        }
+    }
+
+    /// Returns the rate setpoint for `mode`.
+    ///
+    /// `acro` is the setpoint derived from the sticks as in acro mode, `roll`
+    /// and `pitch` are the estimated attitude in degrees, and `dt` is the time
+    /// since the last update in seconds.
+    pub fn update(
+        &mut self,
+        mode: FlightMode,
+        command: &RcCommand,
+        acro: &RateSetpoint,
+        roll: f32,
+        pitch: f32,
+        dt: f32,
+    ) -> RateSetpoint {
+        let (level_target, horizon_target) = match mode {
+            FlightMode::Acro => (0.0, 0.0),
+            FlightMode::Angle => (1.0, 0.0),
+            FlightMode::Horizon => (1.0, 1.0),
+        };
+        let max_step = if self.config.transition_time > 0.0 {
+            dt / self.config.transition_time
+        } else {
+            1.0
+        };
+        self.level = slew(self.level, level_target, max_step);
+        self.horizon = slew(self.horizon, horizon_target, max_step);
+
+        if self.level <= 0.0 {
+            return *acro;
+        }
+
+        let deflection = libm::fabsf(command.roll)
+            .max(libm::fabsf(command.pitch))
+            .clamp(0.0, 1.0);
+        let weight = self.level * (1.0 - self.horizon * deflection);
+
+        let level_rate = |stick: f32, angle: f32| {
+            (stick * self.config.max_angle - angle) * self.config.level_gain
+        };
+
+        RateSetpoint {
+            yaw: acro.yaw,
+            pitch: blend(acro.pitch, level_rate(command.pitch, pitch), weight),
+            roll: blend(acro.roll, level_rate(command.roll, roll), weight),
+        }
+    }
+}
+
+fn slew(current: f32, target: f32, max_step: f32) -> f32 {
+    current + (target - current).clamp(-max_step, max_step)
+}
+
+fn blend(acro: f32, level: f32, weight: f32) -> f32 {
+    acro + (level - acro) * weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        max_angle: 45.0,
+        level_gain: 5.0,
+        transition_time: 0.2,
+    };
+    const DT: f32 = 1.0 / 1024.0;
+
+    fn command(roll: f32, pitch: f32) -> RcCommand {
+        RcCommand {
+            roll,
+            pitch,
+            ..RcCommand::default()
+        }
+    }
+
+    fn setpoint(roll: f32, pitch: f32, yaw: f32) -> RateSetpoint {
+        RateSetpoint { yaw, pitch, roll }
+    }
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn switching_modes_never_steps_the_setpoint() {
+        let mut controller = AttitudeController::new(CONFIG);
+        let command = command(0.3, -0.2);
+        let acro = setpoint(100.0, -50.0, 30.0);
+        // Tilted away from where the sticks would level it.
+        let (roll, pitch) = (20.0, -10.0);
+        let mut previous = controller.update(FlightMode::Acro, &command, &acro, roll, pitch, DT);
+
+        for mode in [
+            FlightMode::Angle,
+            FlightMode::Horizon,
+            FlightMode::Acro,
+            FlightMode::Horizon,
+            FlightMode::Angle,
+            FlightMode::Acro,
+        ] {
+            // Long enough for each transition to finish.
+            for _ in 0..512 {
+                let setpoint = controller.update(mode, &command, &acro, roll, pitch, DT);
+                // Switching straight to angle mode would step the roll by
+                // over 100 degrees per second.
+                assert!(libm::fabsf(setpoint.roll - previous.roll) < 1.0);
+                assert!(libm::fabsf(setpoint.pitch - previous.pitch) < 1.0);
+                assert_eq!(setpoint.yaw, acro.yaw);
+                previous = setpoint;
+            }
+
+            match mode {
+                FlightMode::Acro => {
+                    assert_eq!(previous.roll, acro.roll);
+                    assert_eq!(previous.pitch, acro.pitch);
+                }
+                FlightMode::Angle => {
+                    assert_close(previous.roll, (0.3 * 45.0 - 20.0) * 5.0, 1e-3);
+                    assert_close(previous.pitch, (-0.2 * 45.0 + 10.0) * 5.0, 1e-3);
+                }
+                FlightMode::Horizon => {
+                    // Levelling is weighted by 1 - 0.3.
+                    assert_close(previous.roll, 100.0 + (-32.5 - 100.0) * 0.7, 1e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn angle_mode_settles_at_the_stick_angle() {
+        let mut controller = AttitudeController::new(CONFIG);
+        let acro = setpoint(0.0, 0.0, 0.0);
+        let (mut roll, mut pitch) = (-10.0, 5.0);
+
+        for (stick_roll, stick_pitch) in [(0.5, -1.0), (-0.2, 0.8), (0.0, 0.0)] {
+            let command = command(stick_roll, stick_pitch);
+            // The aircraft follows the rate setpoint perfectly.
+            for _ in 0..2048 {
+                let setpoint =
+                    controller.update(FlightMode::Angle, &command, &acro, roll, pitch, DT);
+                roll += setpoint.roll * DT;
+                pitch += setpoint.pitch * DT;
+            }
+
+            assert_close(roll, stick_roll * CONFIG.max_angle, 0.01);
+            assert_close(pitch, stick_pitch * CONFIG.max_angle, 0.01);
+        }
+    }
+
+    #[test]
+    fn horizon_mode_is_acro_at_full_stick() {
+        let mut controller = AttitudeController::new(CONFIG);
+        let acro = setpoint(600.0, -200.0, 0.0);
+        for _ in 0..1024 {
+            controller.update(FlightMode::Horizon, &command(0.0, 0.0), &acro, 0.0, 0.0, DT);
+        }
+
+        // Either stick at full deflection gives the acro rates on both axes.
+        for command in [command(1.0, 0.0), command(-1.0, 0.5), command(0.2, 1.0)] {
+            let setpoint = controller.update(FlightMode::Horizon, &command, &acro, 30.0, 15.0, DT);
+            assert_eq!(setpoint.roll, acro.roll);
+            assert_eq!(setpoint.pitch, acro.pitch);
+        }
+
+        // At center stick it levels like angle mode.
+        let setpoint = controller.update(
+            FlightMode::Horizon,
+            &command(0.0, 0.0),
+            &acro,
+            30.0,
+            15.0,
+            DT,
+        );
+        assert_close(setpoint.roll, -150.0, 1e-3);
+        assert_close(setpoint.pitch, -75.0, 1e-3);
+    }
+}
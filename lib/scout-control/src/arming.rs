@@ -0,0 +1,609 @@
+//! Arming and disarming
+//!
+//! The motors only spin while armed. Arming is refused while any of the
+//! pre-arm checks fail, and the failing checks are reported as
+//! `BlockedReasons`. The checks only apply when arming: once armed, only the
+//! pilot or the inactivity timeout disarms. The timeout only runs until the
+//! throttle is first raised, so that it can't stop the motors in flight.
+
+use scout_rc::RcCommand;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BlockReason {
+    ThrottleHigh,
+    RxLinkLost,
+    Failsafe,
+    ImuNotCalibrated,
+    BatteryLow,
+    /// The aircraft is tilted further than `Config::max_tilt`.
+    Tilted,
+    LoopTiming,
+    /// The arm switch was on when the other checks passed, and must be
+    /// turned off and on again. This prevents arming unexpectedly when a
+    /// check clears.
+    ArmSwitchOn,
+}
+
+impl BlockReason {
+    pub const ALL: [BlockReason; 8] = [
+        BlockReason::ThrottleHigh,
+        BlockReason::RxLinkLost,
+        BlockReason::Failsafe,
+        BlockReason::ImuNotCalibrated,
+        BlockReason::BatteryLow,
+        BlockReason::Tilted,
+        BlockReason::LoopTiming,
+        BlockReason::ArmSwitchOn,
+    ];
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A set of reasons arming is blocked, stored as a bitfield.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockedReasons(u16);
+
+impl BlockedReasons {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, reason: BlockReason) -> bool {
+        self.0 & reason.bit() != 0
+    }
+
+    pub fn insert(&mut self, reason: BlockReason) {
+        self.0 |= reason.bit();
+    }
+
+    pub fn remove(&mut self, reason: BlockReason) {
+        self.0 &= !reason.bit();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BlockReason> + '_ {
+        BlockReason::ALL
+            .into_iter()
+            .filter(|reason| self.contains(*reason))
+    }
+}
+
+impl defmt::Format for BlockedReasons {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "[");
+        for (i, reason) in self.iter().enumerate() {
+            if i > 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{}", reason);
+        }
+        defmt::write!(f, "]");
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ArmMethod {
+    /// Arm while the arm switch is on.
+    Switch,
+    /// With the throttle low, hold yaw fully right to arm, or fully left to
+    /// disarm.
+    StickGesture,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    pub method: ArmMethod,
+    /// Highest throttle, in the range 0.0..=1.0, which counts as low.
+    pub low_throttle: f32,
+    /// Largest roll or pitch angle at which arming is allowed, in degrees.
+    pub max_tilt: f32,
+    /// How long a stick gesture must be held, in seconds.
+    pub gesture_time: f32,
+    /// Disarm if the throttle is left low for this long after arming, in
+    /// seconds. Once the throttle has been raised, a descent or hover at low
+    /// throttle doesn't disarm. Zero disables auto-disarm.
+    pub auto_disarm_time: f32,
+}
+
+/// The state of the aircraft, as needed by the pre-arm checks.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Status {
+    pub rx_link_ok: bool,
+    pub failsafe: bool,
+    pub imu_calibrated: bool,
+    pub battery_ok: bool,
+    /// Largest of the roll and pitch angles, in degrees.
+    pub tilt: f32,
+    pub loop_timing_ok: bool,
+}
+
+/// How far yaw must be held for a stick gesture.
+const GESTURE_YAW: f32 = 0.9;
+
+pub struct Arming {
+    pub config: Config,
+    armed: bool,
+    blocked: BlockedReasons,
+    /// Whether the arm switch must be turned off before it can arm.
+    switch_must_toggle: bool,
+    gesture_time: f32,
+    low_throttle_time: f32,
+    /// Whether the throttle has been raised since arming.
+    launched: bool,
+}
+
+impl Arming {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            armed: false,
+            blocked: BlockedReasons::empty(),
+            // Don't arm at power on if the switch was left on.
+            switch_must_toggle: true,
+            gesture_time: 0.0,
+            low_throttle_time: 0.0,
+            launched: false,
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Reasons arming was blocked at the last update.
+    pub fn blocked_reasons(&self) -> BlockedReasons {
+        self.blocked
+    }
+
+    pub fn disarm(&mut self) {
+        self.armed = false;
+        self.gesture_time = 0.0;
+        self.switch_must_toggle = true;
+    }
+
+    /// Returns whether the aircraft is armed. `arm_switch` is the state of
+    /// the arm switch, and is ignored when arming with stick gestures. `dt`
+    /// is the time since the last update in seconds.
+    pub fn update(
+        &mut self,
+        command: &RcCommand,
+        arm_switch: bool,
+        status: &Status,
+        dt: f32,
+    ) -> bool {
+        let throttle_low = command.throttle <= self.config.low_throttle;
+        self.blocked = self.checks(throttle_low, status);
+
+        if self.armed {
+            self.update_armed(command, arm_switch, throttle_low, dt);
+        } else {
+            self.update_disarmed(command, arm_switch, dt);
+        }
+
+        self.armed
+    }
+
+    fn checks(&self, throttle_low: bool, status: &Status) -> BlockedReasons {
+        let mut blocked = BlockedReasons::empty();
+        for (failed, reason) in [
+            (!throttle_low, BlockReason::ThrottleHigh),
+            (!status.rx_link_ok, BlockReason::RxLinkLost),
+            (status.failsafe, BlockReason::Failsafe),
+            (!status.imu_calibrated, BlockReason::ImuNotCalibrated),
+            (!status.battery_ok, BlockReason::BatteryLow),
+            (
+                status.tilt > self.config.max_tilt || status.tilt.is_nan(),
+                BlockReason::Tilted,
+            ),
+            (!status.loop_timing_ok, BlockReason::LoopTiming),
+        ] {
+            if failed {
+                blocked.insert(reason);
+            }
+        }
+
+        if self.config.method == ArmMethod::Switch && self.switch_must_toggle {
+            blocked.insert(BlockReason::ArmSwitchOn);
+        }
+
+        blocked
+    }
+
+    fn update_disarmed(&mut self, command: &RcCommand, arm_switch: bool, dt: f32) {
+        match self.config.method {
+            ArmMethod::Switch => {
+                if !arm_switch {
+                    self.switch_must_toggle = false;
+                    self.blocked.remove(BlockReason::ArmSwitchOn);
+                } else if self.blocked.is_empty() {
+                    self.arm();
+                } else {
+                    // Arming was refused, so the switch must be toggled
+                    // before trying again.
+                    self.switch_must_toggle = true;
+                    self.blocked.insert(BlockReason::ArmSwitchOn);
+                }
+            }
+            ArmMethod::StickGesture => {
+                // The gesture only counts while nothing blocks arming, so
+                // that clearing a check doesn't arm straight away.
+                let held = self.blocked.is_empty() && command.yaw >= GESTURE_YAW;
+                if self.hold_gesture(held, dt) {
+                    self.arm();
+                }
+            }
+        }
+    }
+
+    fn update_armed(&mut self, command: &RcCommand, arm_switch: bool, throttle_low: bool, dt: f32) {
+        let disarm = match self.config.method {
+            ArmMethod::Switch => !arm_switch,
+            ArmMethod::StickGesture => {
+                self.hold_gesture(throttle_low && command.yaw <= -GESTURE_YAW, dt)
+            }
+        };
+
+        if !throttle_low {
+            self.launched = true;
+        }
+        if !self.launched {
+            self.low_throttle_time += dt;
+        }
+        let inactive = self.config.auto_disarm_time > 0.0
+            && !self.launched
+            && self.low_throttle_time >= self.config.auto_disarm_time;
+
+        if disarm || inactive {
+            self.disarm();
+            // Turning the switch off is the toggle needed to arm again.
+            if !arm_switch {
+                self.switch_must_toggle = false;
+            }
+        }
+    }
+
+    /// Returns whether a gesture has been held for long enough.
+    fn hold_gesture(&mut self, held: bool, dt: f32) -> bool {
+        if !held {
+            self.gesture_time = 0.0;
+            return false;
+        }
+        self.gesture_time += dt;
+
+        self.gesture_time >= self.config.gesture_time
+    }
+
+    fn arm(&mut self) {
+        self.armed = true;
+        self.gesture_time = 0.0;
+        self.low_throttle_time = 0.0;
+        self.launched = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A power of two, so that the times add up exactly.
+    const DT: f32 = 1.0 / 128.0;
+
+    const CONFIG: Config = Config {
+        method: ArmMethod::Switch,
+        low_throttle: 0.05,
+        max_tilt: 25.0,
+        gesture_time: 1.0,
+        auto_disarm_time: 5.0,
+    };
+
+    const GESTURE: Config = Config {
+        method: ArmMethod::StickGesture,
+        ..CONFIG
+    };
+
+    const OK: Status = Status {
+        rx_link_ok: true,
+        failsafe: false,
+        imu_calibrated: true,
+        battery_ok: true,
+        tilt: 2.0,
+        loop_timing_ok: true,
+    };
+
+    fn sticks(throttle: f32, yaw: f32) -> RcCommand {
+        RcCommand {
+            throttle,
+            yaw,
+            ..RcCommand::default()
+        }
+    }
+
+    /// Sticks centered and the throttle down.
+    fn low() -> RcCommand {
+        sticks(0.0, 0.0)
+    }
+
+    /// Runs updates for `seconds`, returning whether the aircraft is armed at
+    /// the end.
+    fn hold(
+        arming: &mut Arming,
+        command: &RcCommand,
+        arm_switch: bool,
+        status: &Status,
+        seconds: f32,
+    ) -> bool {
+        for _ in 0..(seconds / DT + 0.5) as usize {
+            arming.update(command, arm_switch, status, DT);
+        }
+
+        arming.is_armed()
+    }
+
+    /// Arms with the switch, turning it off first.
+    fn armed(config: Config) -> Arming {
+        let mut arming = Arming::new(config);
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.update(&low(), true, &OK, DT));
+
+        arming
+    }
+
+    /// A failing check for each reason, other than `ArmSwitchOn`.
+    fn failing(reason: BlockReason) -> (RcCommand, Status) {
+        let mut command = low();
+        let mut status = OK;
+        match reason {
+            BlockReason::ThrottleHigh => command.throttle = 0.3,
+            BlockReason::RxLinkLost => status.rx_link_ok = false,
+            BlockReason::Failsafe => status.failsafe = true,
+            BlockReason::ImuNotCalibrated => status.imu_calibrated = false,
+            BlockReason::BatteryLow => status.battery_ok = false,
+            BlockReason::Tilted => status.tilt = 30.0,
+            BlockReason::LoopTiming => status.loop_timing_ok = false,
+            BlockReason::ArmSwitchOn => unreachable!(),
+        }
+
+        (command, status)
+    }
+
+    const CHECKS: [BlockReason; 7] = [
+        BlockReason::ThrottleHigh,
+        BlockReason::RxLinkLost,
+        BlockReason::Failsafe,
+        BlockReason::ImuNotCalibrated,
+        BlockReason::BatteryLow,
+        BlockReason::Tilted,
+        BlockReason::LoopTiming,
+    ];
+
+    #[test]
+    fn blocked_reasons_set() {
+        let mut blocked = BlockedReasons::empty();
+        assert!(blocked.is_empty());
+        for reason in BlockReason::ALL {
+            blocked.insert(reason);
+        }
+        assert_eq!(blocked.bits(), 0xff);
+        assert!(blocked.iter().eq(BlockReason::ALL));
+
+        blocked.remove(BlockReason::Tilted);
+        assert!(!blocked.contains(BlockReason::Tilted));
+        assert!(blocked.contains(BlockReason::LoopTiming));
+    }
+
+    #[test]
+    fn each_check_blocks_switch_arming() {
+        for reason in CHECKS {
+            let (command, status) = failing(reason);
+            let mut arming = Arming::new(CONFIG);
+            arming.update(&command, false, &status, DT);
+            assert!(arming.blocked_reasons().iter().eq([reason]));
+
+            assert!(!arming.update(&command, true, &status, DT));
+            let blocked = arming.blocked_reasons();
+            assert!(blocked.contains(reason));
+            assert!(blocked.contains(BlockReason::ArmSwitchOn));
+            assert_eq!(blocked.iter().count(), 2);
+        }
+    }
+
+    #[test]
+    fn each_check_blocks_gesture_arming() {
+        for reason in CHECKS
+            .into_iter()
+            .filter(|r| *r != BlockReason::ThrottleHigh)
+        {
+            let (mut command, status) = failing(reason);
+            command.yaw = 1.0;
+            let mut arming = Arming::new(GESTURE);
+            assert!(!hold(&mut arming, &command, false, &status, 3.0));
+            assert!(arming.blocked_reasons().iter().eq([reason]));
+        }
+
+        // Yaw right with the throttle up isn't a gesture either.
+        let mut arming = Arming::new(GESTURE);
+        assert!(!hold(&mut arming, &sticks(0.3, 1.0), false, &OK, 3.0));
+        assert!(arming
+            .blocked_reasons()
+            .iter()
+            .eq([BlockReason::ThrottleHigh]));
+    }
+
+    #[test]
+    fn tilt_limit_and_nan() {
+        let mut arming = Arming::new(GESTURE);
+        arming.update(&low(), false, &Status { tilt: 25.0, ..OK }, DT);
+        assert!(arming.blocked_reasons().is_empty());
+
+        arming.update(
+            &low(),
+            false,
+            &Status {
+                tilt: f32::NAN,
+                ..OK
+            },
+            DT,
+        );
+        assert!(arming.blocked_reasons().iter().eq([BlockReason::Tilted]));
+    }
+
+    #[test]
+    fn switch_left_on_at_power_up_must_be_toggled() {
+        let mut arming = Arming::new(CONFIG);
+        assert!(!hold(&mut arming, &low(), true, &OK, 1.0));
+        assert!(arming
+            .blocked_reasons()
+            .iter()
+            .eq([BlockReason::ArmSwitchOn]));
+
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.blocked_reasons().is_empty());
+        assert!(arming.update(&low(), true, &OK, DT));
+    }
+
+    #[test]
+    fn refused_switch_must_be_toggled_after_the_check_clears() {
+        let mut arming = Arming::new(CONFIG);
+        arming.update(&low(), false, &OK, DT);
+
+        // Switched on with the throttle up, and then the throttle lowered.
+        assert!(!arming.update(&sticks(0.5, 0.0), true, &OK, DT));
+        assert!(!hold(&mut arming, &low(), true, &OK, 1.0));
+        assert!(arming
+            .blocked_reasons()
+            .iter()
+            .eq([BlockReason::ArmSwitchOn]));
+
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.update(&low(), true, &OK, DT));
+    }
+
+    #[test]
+    fn switch_off_disarms_and_can_arm_again() {
+        let mut arming = armed(CONFIG);
+        assert!(!arming.update(&sticks(0.5, 0.0), false, &OK, DT));
+
+        // Switching off was the toggle, so switching on arms again.
+        assert!(arming.update(&low(), true, &OK, DT));
+    }
+
+    #[test]
+    fn disarming_with_the_switch_on_needs_a_toggle() {
+        let mut arming = armed(CONFIG);
+        arming.disarm();
+        assert!(!arming.update(&low(), true, &OK, DT));
+        assert!(arming
+            .blocked_reasons()
+            .iter()
+            .eq([BlockReason::ArmSwitchOn]));
+
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.update(&low(), true, &OK, DT));
+    }
+
+    #[test]
+    fn checks_do_not_disarm() {
+        let mut arming = armed(CONFIG);
+        for reason in CHECKS {
+            let (command, status) = failing(reason);
+            assert!(arming.update(&command, true, &status, DT));
+            assert!(arming.blocked_reasons().contains(reason));
+        }
+    }
+
+    #[test]
+    fn gesture_arms_once_held() {
+        let mut arming = Arming::new(GESTURE);
+        let right = sticks(0.0, 1.0);
+        assert!(!hold(&mut arming, &right, false, &OK, 0.9));
+        assert!(hold(&mut arming, &right, false, &OK, 0.1));
+    }
+
+    #[test]
+    fn releasing_the_gesture_restarts_it() {
+        let mut arming = Arming::new(GESTURE);
+        let right = sticks(0.0, 1.0);
+        assert!(!hold(&mut arming, &right, false, &OK, 0.8));
+        assert!(!hold(&mut arming, &sticks(0.0, 0.5), false, &OK, 0.1));
+        assert!(!hold(&mut arming, &right, false, &OK, 0.8));
+        assert!(hold(&mut arming, &right, false, &OK, 0.2));
+    }
+
+    #[test]
+    fn gesture_only_counts_once_checks_pass() {
+        let mut arming = Arming::new(GESTURE);
+        let right = sticks(0.0, 1.0);
+        let (_, uncalibrated) = failing(BlockReason::ImuNotCalibrated);
+        assert!(!hold(&mut arming, &right, false, &uncalibrated, 2.0));
+
+        // Clearing the check doesn't arm straight away.
+        assert!(!hold(&mut arming, &right, false, &OK, 0.9));
+        assert!(hold(&mut arming, &right, false, &OK, 0.1));
+    }
+
+    #[test]
+    fn gesture_ignores_the_arm_switch() {
+        let mut arming = Arming::new(GESTURE);
+        assert!(!hold(&mut arming, &low(), true, &OK, 2.0));
+        assert!(arming.blocked_reasons().is_empty());
+        assert!(hold(&mut arming, &sticks(0.0, 1.0), true, &OK, 1.0));
+        assert!(hold(&mut arming, &low(), false, &OK, 1.0));
+    }
+
+    #[test]
+    fn gesture_disarms_with_low_throttle_and_yaw_left() {
+        let mut arming = Arming::new(GESTURE);
+        assert!(hold(&mut arming, &sticks(0.0, 1.0), false, &OK, 1.0));
+
+        // Yaw left in flight doesn't disarm.
+        assert!(hold(&mut arming, &sticks(0.5, -1.0), false, &OK, 2.0));
+
+        assert!(hold(&mut arming, &sticks(0.0, -1.0), false, &OK, 0.9));
+        assert!(!hold(&mut arming, &sticks(0.0, -1.0), false, &OK, 0.1));
+    }
+
+    #[test]
+    fn auto_disarm_before_launch() {
+        let mut arming = armed(CONFIG);
+        assert!(hold(&mut arming, &low(), true, &OK, 4.9));
+        assert!(!hold(&mut arming, &low(), true, &OK, 0.1));
+
+        // Turning the switch off and on again arms with a fresh timeout.
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.update(&low(), true, &OK, DT));
+        assert!(hold(&mut arming, &low(), true, &OK, 4.9));
+    }
+
+    #[test]
+    fn no_auto_disarm_once_launched() {
+        let mut arming = armed(CONFIG);
+        assert!(hold(&mut arming, &low(), true, &OK, 3.0));
+        assert!(hold(&mut arming, &sticks(0.4, 0.0), true, &OK, 0.1));
+
+        // A long descent at low throttle keeps the motors running.
+        assert!(hold(&mut arming, &low(), true, &OK, 30.0));
+
+        // After landing and disarming, the timeout applies again.
+        arming.update(&low(), false, &OK, DT);
+        assert!(arming.update(&low(), true, &OK, DT));
+        assert!(!hold(&mut arming, &low(), true, &OK, 5.0));
+    }
+
+    #[test]
+    fn auto_disarm_can_be_turned_off() {
+        let mut arming = armed(Config {
+            auto_disarm_time: 0.0,
+            ..CONFIG
+        });
+        assert!(hold(&mut arming, &low(), true, &OK, 30.0));
+    }
+}
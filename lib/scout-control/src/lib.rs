@@ -0,0 +1,17 @@
+#![no_std]
+
+pub mod altitude;
+pub mod angle;
+pub mod arming;
+pub mod mixer;
+pub mod navigation;
+pub mod pid;
+
+/// Torque demanded about each axis, where 1.0 is the full authority of the
+/// motors.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Torque {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
@@ -0,0 +1,481 @@
+//! Mapping of throttle and torque demands onto motors
+//!
+//! Each motor has a `MotorMix` giving how much it contributes to thrust and
+//! to torque about each axis. Motors are numbered as in Betaflight, so that
+//! existing wiring diagrams can be followed, and the default propeller
+//! direction is props-in: on a quad, the rear right and front left motors
+//! spin clockwise seen from above.
+
+use crate::Torque;
+
+pub const MAX_MOTORS: usize = 8;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct MotorMix {
+    pub throttle: f32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl MotorMix {
+    pub const fn new(throttle: f32, roll: f32, pitch: f32, yaw: f32) -> Self {
+        Self {
+            throttle,
+            roll,
+            pitch,
+            yaw,
+        }
+    }
+}
+
+/// An airframe layout. Custom layouts can be described in the same way as
+/// the built in ones.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Geometry<'a> {
+    /// At most `MAX_MOTORS` motors.
+    pub motors: &'a [MotorMix],
+    /// Whether yaw is controlled by tilting a motor with a servo, rather than
+    /// by differential motor torque.
+    pub yaw_servo: bool,
+}
+
+impl Geometry<'static> {
+    /// Motors: rear right, front right, rear left, front left.
+    pub const QUAD_X: Self = Self {
+        motors: &[
+            MotorMix::new(1.0, -1.0, -1.0, -1.0),
+            MotorMix::new(1.0, -1.0, 1.0, 1.0),
+            MotorMix::new(1.0, 1.0, -1.0, 1.0),
+            MotorMix::new(1.0, 1.0, 1.0, -1.0),
+        ],
+        yaw_servo: false,
+    };
+
+    /// Motors: rear, right, left, front.
+    pub const QUAD_PLUS: Self = Self {
+        motors: &[
+            MotorMix::new(1.0, 0.0, -1.0, -1.0),
+            MotorMix::new(1.0, -1.0, 0.0, 1.0),
+            MotorMix::new(1.0, 1.0, 0.0, 1.0),
+            MotorMix::new(1.0, 0.0, 1.0, -1.0),
+        ],
+        yaw_servo: false,
+    };
+
+    /// Motors: rear right, front right, rear left, front left, right, left.
+    pub const HEX_X: Self = Self {
+        motors: &[
+            MotorMix::new(1.0, -0.5, -0.866025, 1.0),
+            MotorMix::new(1.0, -0.5, 0.866025, 1.0),
+            MotorMix::new(1.0, 0.5, -0.866025, -1.0),
+            MotorMix::new(1.0, 0.5, 0.866025, -1.0),
+            MotorMix::new(1.0, -1.0, 0.0, -1.0),
+            MotorMix::new(1.0, 1.0, 0.0, 1.0),
+        ],
+        yaw_servo: false,
+    };
+
+    /// Motors: rear, front right, front left. The rear motor is tilted by
+    /// the yaw servo.
+    pub const TRI: Self = Self {
+        motors: &[
+            MotorMix::new(1.0, 0.0, -1.333333, 0.0),
+            MotorMix::new(1.0, -1.0, 0.666667, 0.0),
+            MotorMix::new(1.0, 1.0, 0.666667, 0.0),
+        ],
+        yaw_servo: true,
+    };
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Output of every motor at zero throttle, in the range 0.0..1.0. This
+    /// keeps the motors spinning while armed.
+    pub idle_throttle: f32,
+    /// Keep full torque authority at zero throttle by raising the throttle
+    /// as needed. Without airmode the motors are clipped at idle instead.
+    pub airmode: bool,
+    /// Output index of each motor in the geometry, for boards whose motor
+    /// outputs are not wired in the standard order.
+    pub motor_order: [usize; MAX_MOTORS],
+    /// Reverses yaw, for props-out builds where every motor spins the
+    /// opposite way to the standard layout. Also reverses the yaw servo.
+    pub yaw_reversed: bool,
+}
+
+impl Config {
+    pub const STANDARD_MOTOR_ORDER: [usize; MAX_MOTORS] = [0, 1, 2, 3, 4, 5, 6, 7];
+}
+
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Output {
+    /// Motor outputs in the range 0.0..=1.0, in output order. Outputs beyond
+    /// the number of motors in the geometry are zero.
+    pub motors: [f32; MAX_MOTORS],
+    /// Yaw servo position in the range -1.0..=1.0, if the geometry has one.
+    pub servo: Option<f32>,
+    /// Whether the torque demand could not be met in full. The rate
+    /// controller should stop winding up while this is set.
+    pub saturated: bool,
+}
+
+pub struct Mixer<'a> {
+    pub geometry: Geometry<'a>,
+    pub config: Config,
+}
+
+impl<'a> Mixer<'a> {
+    pub fn new(geometry: Geometry<'a>, config: Config) -> Self {
+        Self { geometry, config }
+    }
+
+    pub fn motor_count(&self) -> usize {
+        self.geometry.motors.len().min(MAX_MOTORS)
+    }
+
+    /// `throttle` is in the range 0.0..=1.0, and `torque` in the range
+    /// -1.0..=1.0 on each axis.
+    pub fn mix(&self, throttle: f32, torque: &Torque) -> Output {
+        let motors = &self.geometry.motors[..self.motor_count()];
+        let yaw = if self.config.yaw_reversed {
+            -torque.yaw
+        } else {
+            torque.yaw
+        };
+        let motor_yaw = if self.geometry.yaw_servo { 0.0 } else { yaw };
+
+        let mut torque_mix = [0.0; MAX_MOTORS];
+        for (mix, motor) in torque_mix.iter_mut().zip(motors) {
+            *mix = torque.roll * motor.roll + torque.pitch * motor.pitch + motor_yaw * motor.yaw;
+        }
+        let torque_mix = &mut torque_mix[..motors.len()];
+
+        let (min, max) = torque_mix.iter().fold((0.0f32, 0.0f32), |(min, max), mix| {
+            (min.min(*mix), max.max(*mix))
+        });
+
+        // When the spread between motors is larger than the motors can
+        // deliver, scale the torques down together so their balance, and
+        // so the direction of the correction, is preserved.
+        let range = max - min;
+        let mut saturated = false;
+        let (min, max) = if range > 1.0 {
+            saturated = true;
+            for mix in torque_mix.iter_mut() {
+                *mix /= range;
+            }
+            (min / range, max / range)
+        } else {
+            (min, max)
+        };
+
+        // Move the throttle so that every motor is within range, giving up
+        // thrust rather than torque. Without airmode, the throttle is only
+        // ever lowered, and motors which would go below idle are clipped.
+        let mut throttle = throttle.clamp(0.0, 1.0);
+        if throttle + max > 1.0 {
+            throttle = 1.0 - max;
+        }
+        if throttle + min < 0.0 {
+            if self.config.airmode {
+                throttle = -min;
+            } else {
+                saturated = true;
+            }
+        }
+
+        let mut output = Output {
+            servo: self.geometry.yaw_servo.then(|| yaw.clamp(-1.0, 1.0)),
+            saturated,
+            ..Output::default()
+        };
+        for (i, (motor, mix)) in motors.iter().zip(torque_mix.iter()).enumerate() {
+            let value = (throttle * motor.throttle + mix).clamp(0.0, 1.0);
+            let index = self.config.motor_order[i];
+            if let Some(out) = output.motors.get_mut(index) {
+                *out = self.config.idle_throttle + (1.0 - self.config.idle_throttle) * value;
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        idle_throttle: 0.0,
+        airmode: true,
+        motor_order: Config::STANDARD_MOTOR_ORDER,
+        yaw_reversed: false,
+    };
+
+    fn torque(roll: f32, pitch: f32, yaw: f32) -> Torque {
+        Torque { roll, pitch, yaw }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(libm::fabsf(a - b) < 1e-5, "{} != {}", a, b);
+    }
+
+    fn assert_motors(motors: &[f32], expected: &[f32]) {
+        assert_eq!(motors.len(), expected.len());
+        for (motor, expected) in motors.iter().zip(expected) {
+            assert_close(*motor, *expected);
+        }
+    }
+
+    /// The torque the motors actually produce about each axis, and their
+    /// mean output.
+    fn produced(geometry: &Geometry, output: &Output) -> (Torque, f32) {
+        let mut produced = Torque::default();
+        let mut thrust = 0.0;
+        for (motor, value) in geometry.motors.iter().zip(output.motors) {
+            produced.roll += motor.roll * value;
+            produced.pitch += motor.pitch * value;
+            produced.yaw += motor.yaw * value;
+            thrust += value;
+        }
+
+        (produced, thrust / geometry.motors.len() as f32)
+    }
+
+    #[test]
+    fn built_in_geometries_are_balanced() {
+        for geometry in [
+            Geometry::QUAD_X,
+            Geometry::QUAD_PLUS,
+            Geometry::HEX_X,
+            Geometry::TRI,
+        ] {
+            let sum = |axis: fn(&MotorMix) -> f32| geometry.motors.iter().map(axis).sum::<f32>();
+            assert_close(sum(|motor| motor.roll), 0.0);
+            assert_close(sum(|motor| motor.pitch), 0.0);
+            assert_close(sum(|motor| motor.yaw), 0.0);
+
+            // With no torque demand every motor runs at the throttle.
+            let output = Mixer::new(geometry, CONFIG).mix(0.4, &Torque::default());
+            assert!(!output.saturated);
+            for value in &output.motors[..geometry.motors.len()] {
+                assert_close(*value, 0.4);
+            }
+        }
+    }
+
+    #[test]
+    fn torque_is_produced_in_the_requested_direction() {
+        for geometry in [Geometry::QUAD_X, Geometry::QUAD_PLUS, Geometry::HEX_X] {
+            let mixer = Mixer::new(geometry, CONFIG);
+            for demand in [
+                torque(0.2, 0.0, 0.0),
+                torque(0.0, -0.2, 0.0),
+                torque(0.0, 0.0, 0.2),
+            ] {
+                let output = mixer.mix(0.5, &demand);
+                let (produced, thrust) = produced(&geometry, &output);
+                // Each demand is produced on its own axis only.
+                let axes = [
+                    (produced.roll, demand.roll),
+                    (produced.pitch, demand.pitch),
+                    (produced.yaw, demand.yaw),
+                ];
+                for (produced, demand) in axes {
+                    if demand == 0.0 {
+                        assert_close(produced, 0.0);
+                    } else {
+                        assert!(produced * demand > 0.0);
+                    }
+                }
+                assert_close(thrust, 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn full_throttle_gives_up_thrust_to_keep_torque() {
+        let mixer = Mixer::new(Geometry::QUAD_X, CONFIG);
+        let output = mixer.mix(1.0, &torque(0.3, 0.0, 0.0));
+
+        assert!(!output.saturated);
+        // The left motors are at full output and the right ones 0.6 below.
+        assert_motors(&output.motors[..4], &[0.4, 0.4, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn airmode_raises_zero_throttle_to_keep_torque() {
+        let mixer = Mixer::new(Geometry::QUAD_X, CONFIG);
+        let output = mixer.mix(0.0, &torque(0.0, 0.25, 0.0));
+
+        assert!(!output.saturated);
+        assert_motors(&output.motors[..4], &[0.0, 0.5, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn without_airmode_zero_throttle_is_clipped() {
+        let mixer = Mixer::new(
+            Geometry::QUAD_X,
+            Config {
+                airmode: false,
+                ..CONFIG
+            },
+        );
+        let output = mixer.mix(0.0, &torque(0.0, 0.25, 0.0));
+
+        assert!(output.saturated);
+        assert_motors(&output.motors[..4], &[0.0, 0.25, 0.0, 0.25]);
+
+        // Low throttle is lowered no further, and only the motors which
+        // would go below zero are clipped.
+        let output = mixer.mix(0.1, &torque(0.0, 0.25, 0.0));
+        assert!(output.saturated);
+        assert_close(output.motors[1], 0.35);
+        assert_close(output.motors[0], 0.0);
+    }
+
+    #[test]
+    fn demands_beyond_the_motor_range_are_scaled_down_together() {
+        let mixer = Mixer::new(Geometry::QUAD_X, CONFIG);
+        let output = mixer.mix(0.5, &torque(1.0, 0.5, 0.0));
+
+        assert!(output.saturated);
+        // The spread between motors is 3.0 and is scaled to 1.0, keeping
+        // the roll to pitch ratio.
+        let (produced, _) = produced(&Geometry::QUAD_X, &output);
+        assert_close(produced.roll, 2.0 * 1.0 / 1.5);
+        assert_close(produced.pitch, 2.0 * 0.5 / 1.5);
+        for value in &output.motors[..4] {
+            assert!((0.0..=1.0).contains(value));
+        }
+        assert_close(output.motors[0], 0.0);
+        assert_close(output.motors[3], 1.0);
+    }
+
+    #[test]
+    fn full_demand_on_every_axis_stays_within_range() {
+        for geometry in [Geometry::QUAD_X, Geometry::QUAD_PLUS, Geometry::HEX_X] {
+            let mixer = Mixer::new(geometry, CONFIG);
+            for throttle in [0.0, 0.5, 1.0] {
+                let output = mixer.mix(throttle, &torque(1.0, -1.0, 1.0));
+                assert!(output.saturated);
+                assert!(output
+                    .motors
+                    .iter()
+                    .all(|value| (0.0..=1.0).contains(value)));
+            }
+        }
+    }
+
+    #[test]
+    fn throttle_outside_its_range_is_clamped() {
+        let mixer = Mixer::new(Geometry::QUAD_X, CONFIG);
+        assert_motors(&mixer.mix(1.5, &Torque::default()).motors[..4], &[1.0; 4]);
+        assert_motors(&mixer.mix(-0.5, &Torque::default()).motors[..4], &[0.0; 4]);
+    }
+
+    #[test]
+    fn idle_throttle_is_the_bottom_of_the_output_range() {
+        let mixer = Mixer::new(
+            Geometry::QUAD_X,
+            Config {
+                idle_throttle: 0.05,
+                ..CONFIG
+            },
+        );
+        assert_motors(&mixer.mix(0.0, &Torque::default()).motors[..4], &[0.05; 4]);
+        assert_motors(&mixer.mix(1.0, &Torque::default()).motors[..4], &[1.0; 4]);
+        assert_close(mixer.mix(0.5, &Torque::default()).motors[0], 0.525);
+    }
+
+    #[test]
+    fn motor_order_remaps_outputs() {
+        let mixer = Mixer::new(
+            Geometry::QUAD_X,
+            Config {
+                motor_order: [3, 2, 1, 0, 4, 5, 6, 7],
+                ..CONFIG
+            },
+        );
+        let standard = Mixer::new(Geometry::QUAD_X, CONFIG).mix(0.5, &torque(0.1, 0.2, 0.0));
+        let remapped = mixer.mix(0.5, &torque(0.1, 0.2, 0.0));
+
+        for i in 0..4 {
+            assert_close(remapped.motors[3 - i], standard.motors[i]);
+        }
+    }
+
+    #[test]
+    fn unused_outputs_are_zero() {
+        let mixer = Mixer::new(
+            Geometry::QUAD_X,
+            Config {
+                idle_throttle: 0.05,
+                ..CONFIG
+            },
+        );
+        let output = mixer.mix(0.5, &torque(0.1, 0.0, 0.0));
+        assert_motors(&output.motors[4..], &[0.0; 4]);
+        assert!(output.servo.is_none());
+    }
+
+    #[test]
+    fn yaw_reversed_swaps_the_yaw_motors() {
+        let demand = torque(0.0, 0.0, 0.2);
+        let standard = Mixer::new(Geometry::QUAD_X, CONFIG).mix(0.5, &demand);
+        let reversed = Mixer::new(
+            Geometry::QUAD_X,
+            Config {
+                yaw_reversed: true,
+                ..CONFIG
+            },
+        )
+        .mix(0.5, &demand);
+
+        assert_motors(&standard.motors[..4], &[0.3, 0.7, 0.7, 0.3]);
+        assert_motors(&reversed.motors[..4], &[0.7, 0.3, 0.3, 0.7]);
+    }
+
+    #[test]
+    fn tri_yaws_with_the_servo() {
+        let mixer = Mixer::new(Geometry::TRI, CONFIG);
+        let output = mixer.mix(0.5, &torque(0.0, 0.0, 0.4));
+
+        assert!(output.servo == Some(0.4));
+        assert_motors(&output.motors[..3], &[0.5; 3]);
+        assert!(!output.saturated);
+
+        // The servo is clamped, and reversed along with the yaw.
+        assert!(mixer.mix(0.5, &torque(0.0, 0.0, 2.0)).servo == Some(1.0));
+        let reversed = Mixer::new(
+            Geometry::TRI,
+            Config {
+                yaw_reversed: true,
+                ..CONFIG
+            },
+        );
+        assert!(reversed.mix(0.5, &torque(0.0, 0.0, 0.4)).servo == Some(-0.4));
+    }
+
+    #[test]
+    fn custom_geometries_are_mixed_like_built_in_ones() {
+        // A bicopter-like pair with no yaw or pitch authority.
+        let motors = [
+            MotorMix::new(1.0, -1.0, 0.0, 0.0),
+            MotorMix::new(1.0, 1.0, 0.0, 0.0),
+        ];
+        let mixer = Mixer::new(
+            Geometry {
+                motors: &motors,
+                yaw_servo: false,
+            },
+            CONFIG,
+        );
+        assert_eq!(mixer.motor_count(), 2);
+
+        let output = mixer.mix(0.5, &torque(0.2, 1.0, 1.0));
+        assert_motors(&output.motors[..3], &[0.3, 0.7, 0.0]);
+        assert!(!output.saturated);
+    }
+}
@@ -0,0 +1,581 @@
+//! GPS navigation modes
+//!
+//! Position hold keeps the aircraft over the point where the roll and pitch
+//! sticks were centered. Return to home climbs to a safe altitude, flies back
+//! to the home position, descends over it and lands.
+//!
+//! Both steer with roll and pitch angles for the attitude controller in angle
+//! mode, and return to home also sets a climb rate for `AltitudeHold`. The
+//! horizontal position is controlled by a P controller on the position
+//! error, which sets a velocity, and a PI controller on the velocity error,
+//! which sets an acceleration. The integral term holds the tilt needed
+//! against wind.
+//!
+//! Positions are in meters north and east, and altitudes in meters up, with
+//! any origin. Velocities are in meters per second.
+
+use core::f32::consts::PI;
+
+const GRAVITY: f32 = 9.80665;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Speed towards the target per meter of position error, in meters per
+    /// second.
+    pub position_gain: f32,
+    /// Acceleration per meter per second of velocity error, in meters per
+    /// second squared.
+    pub velocity_p: f32,
+    /// Acceleration per meter of accumulated velocity error, in meters per
+    /// second squared.
+    pub velocity_i: f32,
+    /// Limit of the integral term's acceleration, in meters per second
+    /// squared.
+    pub i_limit: f32,
+    /// Largest roll or pitch angle requested, in degrees.
+    pub max_angle: f32,
+    /// Speed while returning home, and the limit while holding position.
+    pub max_speed: f32,
+    /// Return home at least this high above home, in meters.
+    pub return_altitude: f32,
+    /// Descent starts within this distance of home, in meters.
+    pub arrival_radius: f32,
+    /// Climb rate before returning, in meters per second.
+    pub climb_rate: f32,
+    /// Descent rate over home, in meters per second.
+    pub descent_rate: f32,
+    /// The descent slows to `landing_rate` below this height above home, in
+    /// meters.
+    pub landing_altitude: f32,
+    pub landing_rate: f32,
+    /// The aircraft has landed when it has stopped descending for this long
+    /// while landing, in seconds.
+    pub landed_time: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Request {
+    PositionHold,
+    ReturnToHome,
+}
+
+/// Phases of return to home, in order.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phase {
+    Climb,
+    Return,
+    Descend,
+    Land,
+    Landed,
+}
+
+/// The estimated state of the aircraft.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Estimate {
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    pub altitude: f32,
+    pub climb_rate: f32,
+    /// Heading in radians, clockwise from north.
+    pub yaw: f32,
+}
+
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Output {
+    /// Roll and pitch angles in degrees, or `None` while the pilot is
+    /// steering.
+    pub angles: Option<[f32; 2]>,
+    /// Climb rate setpoint, or `None` while the pilot controls the altitude.
+    pub climb_rate: Option<f32>,
+    /// Set once return to home has landed, after which the aircraft should
+    /// be disarmed.
+    pub landed: bool,
+}
+
+pub struct Navigator {
+    pub config: Config,
+    home: Option<([f32; 2], f32)>,
+    request: Option<Request>,
+    phase: Phase,
+    /// Position being held, or flown to.
+    target: Option<[f32; 2]>,
+    integral: [f32; 2],
+    stopped_time: f32,
+}
+
+/// How far the roll and pitch sticks must move for the pilot to steer in
+/// position hold.
+const STICK_DEADBAND: f32 = 0.05;
+/// The aircraft has stopped descending below this climb rate, in meters per
+/// second.
+const STOPPED_CLIMB_RATE: f32 = 0.2;
+
+impl Navigator {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            home: None,
+            request: None,
+            phase: Phase::Climb,
+            target: None,
+            integral: [0.0; 2],
+            stopped_time: 0.0,
+        }
+    }
+
+    /// Sets the home position and altitude, normally where the aircraft was
+    /// armed.
+    pub fn set_home(&mut self, position: [f32; 2], altitude: f32) {
+        self.home = Some((position, altitude));
+    }
+
+    pub fn home(&self) -> Option<([f32; 2], f32)> {
+        self.home
+    }
+
+    /// Returns the phase of return to home, or `None` when not returning.
+    pub fn phase(&self) -> Option<Phase> {
+        (self.request == Some(Request::ReturnToHome)).then_some(self.phase)
+    }
+
+    /// Stops navigating. Called while no navigation mode is active, so the
+    /// next `update` starts afresh.
+    pub fn reset(&mut self) {
+        self.request = None;
+    }
+
+    /// Returns what the attitude and altitude controllers should do for
+    /// `request`.
+    ///
+    /// `sticks` are the roll and pitch sticks, from -1.0 to 1.0, and `dt` is
+    /// the time since the last update in seconds. Return to home without a
+    /// home position holds position instead.
+    pub fn update(
+        &mut self,
+        request: Request,
+        estimate: &Estimate,
+        sticks: [f32; 2],
+        dt: f32,
+    ) -> Output {
+        let request = match (request, self.home) {
+            (Request::ReturnToHome, None) => Request::PositionHold,
+            _ => request,
+        };
+        if self.request != Some(request) {
+            self.request = Some(request);
+            self.phase = Phase::Climb;
+            self.target = None;
+            self.integral = [0.0; 2];
+            self.stopped_time = 0.0;
+        }
+
+        match request {
+            Request::PositionHold => self.position_hold(estimate, sticks, dt),
+            Request::ReturnToHome => self.return_to_home(estimate, dt),
+        }
+    }
+
+    fn position_hold(&mut self, estimate: &Estimate, sticks: [f32; 2], dt: f32) -> Output {
+        let steering = sticks
+            .iter()
+            .any(|stick| libm::fabsf(*stick) > STICK_DEADBAND);
+        if steering {
+            self.target = None;
+            self.integral = [0.0; 2];
+            return Output::default();
+        }
+
+        // Hold where the sticks were centered. The velocity controller
+        // brakes to a stop, overshooting a little.
+        let target = *self.target.get_or_insert(estimate.position);
+        Output {
+            angles: Some(self.fly_to(target, self.config.max_speed, estimate, dt)),
+            climb_rate: None,
+            landed: false,
+        }
+    }
+
+    fn return_to_home(&mut self, estimate: &Estimate, dt: f32) -> Output {
+        let Some((home, home_altitude)) = self.home else {
+            return Output::default();
+        };
+        let height = estimate.altitude - home_altitude;
+        let config = &self.config;
+
+        let distance = libm::hypotf(
+            estimate.position[0] - home[0],
+            estimate.position[1] - home[1],
+        );
+        self.phase = match self.phase {
+            Phase::Climb if height >= config.return_altitude => Phase::Return,
+            Phase::Return if distance <= config.arrival_radius => Phase::Descend,
+            Phase::Descend if height <= config.landing_altitude => Phase::Land,
+            phase => phase,
+        };
+        // Returning from nearby doesn't need to climb.
+        if self.phase == Phase::Climb && distance <= config.arrival_radius {
+            self.phase = Phase::Descend;
+        }
+
+        let climb_rate = match self.phase {
+            Phase::Climb => config.climb_rate,
+            Phase::Return | Phase::Landed => 0.0,
+            Phase::Descend => -config.descent_rate,
+            Phase::Land => -config.landing_rate,
+        };
+
+        if self.phase == Phase::Land {
+            if libm::fabsf(estimate.climb_rate) < STOPPED_CLIMB_RATE {
+                self.stopped_time += dt;
+            } else {
+                self.stopped_time = 0.0;
+            }
+            if self.stopped_time >= config.landed_time {
+                self.phase = Phase::Landed;
+            }
+        }
+
+        // Climb where return to home started, then head for home.
+        let target = match self.phase {
+            Phase::Climb => *self.target.get_or_insert(estimate.position),
+            _ => home,
+        };
+        let max_speed = config.max_speed;
+        Output {
+            angles: Some(self.fly_to(target, max_speed, estimate, dt)),
+            climb_rate: Some(climb_rate),
+            landed: self.phase == Phase::Landed,
+        }
+    }
+
+    /// Returns the roll and pitch angles, in degrees, which fly towards
+    /// `target` and stop over it.
+    fn fly_to(
+        &mut self,
+        target: [f32; 2],
+        max_speed: f32,
+        estimate: &Estimate,
+        dt: f32,
+    ) -> [f32; 2] {
+        let config = &self.config;
+
+        let velocity_setpoint = limit(
+            [
+                (target[0] - estimate.position[0]) * config.position_gain,
+                (target[1] - estimate.position[1]) * config.position_gain,
+            ],
+            max_speed,
+        );
+        let error = [
+            velocity_setpoint[0] - estimate.velocity[0],
+            velocity_setpoint[1] - estimate.velocity[1],
+        ];
+        self.integral = limit(
+            [
+                self.integral[0] + error[0] * config.velocity_i * dt,
+                self.integral[1] + error[1] * config.velocity_i * dt,
+            ],
+            config.i_limit,
+        );
+        let max_acceleration = libm::tanf(config.max_angle * PI / 180.0) * GRAVITY;
+        let [north, east] = limit(
+            [
+                error[0] * config.velocity_p + self.integral[0],
+                error[1] * config.velocity_p + self.integral[1],
+            ],
+            max_acceleration,
+        );
+
+        // Into the frame of the aircraft's heading.
+        let (sin_yaw, cos_yaw) = (libm::sinf(estimate.yaw), libm::cosf(estimate.yaw));
+        let forward = north * cos_yaw + east * sin_yaw;
+        let right = -north * sin_yaw + east * cos_yaw;
+
+        // Tilting forwards, which is negative pitch, accelerates forwards.
+        // Rolling right accelerates right.
+        [
+            libm::atanf(right / GRAVITY) * 180.0 / PI,
+            -libm::atanf(forward / GRAVITY) * 180.0 / PI,
+        ]
+    }
+}
+
+/// Scales `v` down to `max` long, if it is longer.
+fn limit(v: [f32; 2], max: f32) -> [f32; 2] {
+    let length = libm::hypotf(v[0], v[1]);
+    if length <= max {
+        return v;
+    }
+
+    let scale = max / length;
+    [v[0] * scale, v[1] * scale]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::angle::{self, AttitudeController, FlightMode};
+    use scout_rc::{RateSetpoint, RcCommand};
+
+    const CONFIG: Config = Config {
+        position_gain: 1.0,
+        velocity_p: 1.5,
+        velocity_i: 0.5,
+        i_limit: 4.0,
+        max_angle: 30.0,
+        max_speed: 8.0,
+        return_altitude: 30.0,
+        arrival_radius: 3.0,
+        climb_rate: 2.0,
+        descent_rate: 1.5,
+        landing_altitude: 5.0,
+        landing_rate: 0.5,
+        landed_time: 1.0,
+    };
+    const DT: f32 = 1.0 / 256.0;
+    /// Acceleration per meter per second of airspeed, in meters per second
+    /// squared.
+    const DRAG: f32 = 0.3;
+    /// How quickly the attitude follows the requested angles, in seconds.
+    const ATTITUDE_TIME_CONSTANT: f32 = 0.1;
+    const WIND: [f32; 2] = [3.0, -4.0];
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    /// A point mass in a steady wind, tilting towards the requested angles
+    /// and climbing at the requested rate, above flat ground at zero
+    /// altitude.
+    struct Aircraft {
+        position: [f32; 2],
+        velocity: [f32; 2],
+        altitude: f32,
+        climb_rate: f32,
+        yaw: f32,
+        /// Roll and pitch in degrees.
+        angles: [f32; 2],
+    }
+
+    impl Aircraft {
+        fn new(position: [f32; 2], altitude: f32, yaw: f32) -> Self {
+            Self {
+                position,
+                velocity: [0.0; 2],
+                altitude,
+                climb_rate: 0.0,
+                yaw,
+                angles: [0.0; 2],
+            }
+        }
+
+        fn estimate(&self) -> Estimate {
+            Estimate {
+                position: self.position,
+                velocity: self.velocity,
+                altitude: self.altitude,
+                climb_rate: self.climb_rate,
+                yaw: self.yaw,
+            }
+        }
+
+        fn step(&mut self, output: &Output) {
+            // The pilot holds the aircraft level while steering.
+            let target = output.angles.unwrap_or([0.0; 2]);
+            for (angle, target) in self.angles.iter_mut().zip(target) {
+                *angle += (target - *angle) * DT / ATTITUDE_TIME_CONSTANT;
+            }
+
+            // Nose down, which is negative pitch, accelerates forwards, and
+            // right wing down accelerates right.
+            let [roll, pitch] = self.angles;
+            let forward = GRAVITY * libm::tanf(-pitch * PI / 180.0);
+            let right = GRAVITY * libm::tanf(roll * PI / 180.0);
+            let (sin_yaw, cos_yaw) = (libm::sinf(self.yaw), libm::cosf(self.yaw));
+            let thrust = [
+                forward * cos_yaw - right * sin_yaw,
+                forward * sin_yaw + right * cos_yaw,
+            ];
+            let acceleration: [f32; 2] = core::array::from_fn(|axis| {
+                thrust[axis] + (WIND[axis] - self.velocity[axis]) * DRAG
+            });
+            for (axis, acceleration) in acceleration.into_iter().enumerate() {
+                self.velocity[axis] += acceleration * DT;
+                self.position[axis] += self.velocity[axis] * DT;
+            }
+
+            self.climb_rate = output.climb_rate.unwrap_or(0.0);
+            self.altitude += self.climb_rate * DT;
+            if self.altitude <= 0.0 {
+                self.altitude = 0.0;
+                self.climb_rate = 0.0;
+            }
+        }
+
+        /// Flies `seconds` of `request` with the sticks at `sticks`,
+        /// returning the last output.
+        fn fly(
+            &mut self,
+            navigator: &mut Navigator,
+            request: Request,
+            sticks: [f32; 2],
+            seconds: f32,
+        ) -> Output {
+            let mut output = Output::default();
+            for _ in 0..(seconds / DT) as usize {
+                output = navigator.update(request, &self.estimate(), sticks, DT);
+                self.step(&output);
+            }
+
+            output
+        }
+    }
+
+    #[test]
+    fn position_hold_settles_against_the_wind() {
+        let mut navigator = Navigator::new(CONFIG);
+        let mut aircraft = Aircraft::new([20.0, 10.0], 15.0, 1.0);
+
+        aircraft.fly(&mut navigator, Request::PositionHold, [0.0; 2], 60.0);
+        assert_close(aircraft.position[0], 20.0, 0.05);
+        assert_close(aircraft.position[1], 10.0, 0.05);
+        assert_close(
+            libm::hypotf(aircraft.velocity[0], aircraft.velocity[1]),
+            0.0,
+            0.01,
+        );
+
+        // The integral term alone holds the tilt against the wind.
+        for (integral, wind) in navigator.integral.into_iter().zip(WIND) {
+            assert_close(integral, -wind * DRAG, 0.01);
+        }
+    }
+
+    #[test]
+    fn steering_clears_the_target_and_integral() {
+        let mut navigator = Navigator::new(CONFIG);
+        let mut aircraft = Aircraft::new([0.0; 2], 15.0, 0.0);
+        aircraft.fly(&mut navigator, Request::PositionHold, [0.0; 2], 30.0);
+        assert!(navigator.target.is_some());
+
+        // Within the deadband the position is still held.
+        let sticks = [0.0, STICK_DEADBAND * 0.9];
+        let output = aircraft.fly(&mut navigator, Request::PositionHold, sticks, 1.0);
+        assert!(output.angles.is_some());
+
+        let sticks = [STICK_DEADBAND * 1.1, 0.0];
+        let output = aircraft.fly(&mut navigator, Request::PositionHold, sticks, DT);
+        assert!(output.angles.is_none());
+        assert!(navigator.target.is_none());
+        assert!(navigator.integral == [0.0; 2]);
+
+        // The wind carries the aircraft off, and centering the sticks holds
+        // where they were centered.
+        aircraft.fly(&mut navigator, Request::PositionHold, sticks, 2.0);
+        let centered = aircraft.position;
+        aircraft.fly(&mut navigator, Request::PositionHold, [0.0; 2], DT);
+        assert!(navigator.target == Some(centered));
+    }
+
+    #[test]
+    fn return_to_home_lands_at_home() {
+        let mut navigator = Navigator::new(CONFIG);
+        navigator.set_home([0.0; 2], 0.0);
+        let mut aircraft = Aircraft::new([60.0, -40.0], 10.0, 2.0);
+
+        let mut phases = [Phase::Climb; 5];
+        let mut phase_count = 0;
+        let mut output = Output::default();
+        for _ in 0..(120.0 / DT) as usize {
+            output = navigator.update(Request::ReturnToHome, &aircraft.estimate(), [0.0; 2], DT);
+            aircraft.step(&output);
+
+            let phase = navigator.phase().unwrap();
+            if phase_count == 0 || phases[phase_count - 1] != phase {
+                phases[phase_count] = phase;
+                phase_count += 1;
+            }
+            if output.landed {
+                break;
+            }
+        }
+
+        assert!(output.landed);
+        assert!(
+            phases[..phase_count]
+                == [
+                    Phase::Climb,
+                    Phase::Return,
+                    Phase::Descend,
+                    Phase::Land,
+                    Phase::Landed
+                ]
+        );
+        assert!(libm::hypotf(aircraft.position[0], aircraft.position[1]) <= CONFIG.arrival_radius);
+        assert_eq!(aircraft.altitude, 0.0);
+    }
+
+    #[test]
+    fn return_to_home_without_home_holds_position() {
+        let mut navigator = Navigator::new(CONFIG);
+        let mut aircraft = Aircraft::new([5.0, 5.0], 10.0, 0.0);
+
+        let output = aircraft.fly(&mut navigator, Request::ReturnToHome, [0.0; 2], 30.0);
+        assert!(navigator.phase().is_none());
+        assert!(output.climb_rate.is_none());
+        assert!(!output.landed);
+        assert_close(aircraft.position[0], 5.0, 0.1);
+        assert_close(aircraft.position[1], 5.0, 0.1);
+        assert_eq!(aircraft.altitude, 10.0);
+    }
+
+    #[test]
+    fn angles_match_angle_mode() {
+        let mut navigator = Navigator::new(CONFIG);
+        let mut controller = AttitudeController::new(angle::Config {
+            max_angle: 45.0,
+            level_gain: 5.0,
+            transition_time: 0.0,
+        });
+        navigator.set_home([10.0, 0.0], 0.0);
+
+        // Already at the return altitude, home is straight ahead when facing
+        // north, and to the left when facing east. The acceleration towards
+        // it is limited to that of the largest angle.
+        for (yaw, expected) in [(0.0, [0.0, -30.0]), (PI / 2.0, [-30.0, 0.0])] {
+            let estimate = Estimate {
+                position: [0.0; 2],
+                velocity: [0.0; 2],
+                altitude: CONFIG.return_altitude,
+                climb_rate: 0.0,
+                yaw,
+            };
+            navigator.reset();
+            let [roll, pitch] = navigator
+                .update(Request::ReturnToHome, &estimate, [0.0; 2], DT)
+                .angles
+                .unwrap();
+            assert_close(roll, expected[0], 1e-3);
+            assert_close(pitch, expected[1], 1e-3);
+
+            // Angle mode, given the angles as sticks, turns the aircraft the
+            // same way from level: negative pitch rate is nose down, and
+            // negative roll rate is left wing down.
+            let command = RcCommand {
+                roll: roll / 45.0,
+                pitch: pitch / 45.0,
+                ..RcCommand::default()
+            };
+            let rates = controller.update(
+                FlightMode::Angle,
+                &command,
+                &RateSetpoint::default(),
+                0.0,
+                0.0,
+                DT,
+            );
+            assert_close(rates.roll, roll * 5.0, 1e-3);
+            assert_close(rates.pitch, pitch * 5.0, 1e-3);
+        }
+    }
+}
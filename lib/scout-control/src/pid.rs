@@ -0,0 +1,659 @@
+//! Rate controller
+//!
+//! A PID loop per axis turns the error between the requested and measured
+//! body rates into a torque demand. All rates are in degrees per second.
+
+use scout_filter::{Filter, Pt1};
+use scout_rc::RateSetpoint;
+
+use crate::Torque;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct Gains {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    /// Feedforward, applied to the rate of change of the setpoint.
+    pub f: f32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    pub roll: Gains,
+    pub pitch: Gains,
+    pub yaw: Gains,
+    /// Largest magnitude of the I term on each axis.
+    pub i_limit: f32,
+    /// Largest magnitude of the torque demand on each axis.
+    pub output_limit: f32,
+    /// Cutoff of the low pass filter on the D term, in Hz. Zero disables
+    /// filtering.
+    pub d_lowpass_hz: f32,
+    /// Cutoff of the low pass filter on the feedforward term, in Hz. This
+    /// smooths the steps between radio frames. Zero disables filtering.
+    pub feedforward_lowpass_hz: f32,
+    /// I term relax cutoff in Hz. Setpoint changes faster than this reduce I
+    /// term accumulation, which prevents the I term from winding up while
+    /// the aircraft catches up with a quick stick movement. Zero disables
+    /// I term relax.
+    pub iterm_relax_hz: f32,
+    /// How far the setpoint may lead its low passed value, in degrees per
+    /// second, before I term accumulation stops entirely.
+    pub iterm_relax_threshold: f32,
+    /// Throttle above which P and D are attenuated, in the range 0.0..1.0.
+    pub tpa_breakpoint: f32,
+    /// Fraction by which P and D are attenuated at full throttle, falling
+    /// linearly to none at `tpa_breakpoint`.
+    pub tpa_rate: f32,
+}
+
+/// The individual terms of the most recent update on one axis.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Terms {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    pub f: f32,
+}
+
+impl Terms {
+    pub fn sum(&self) -> f32 {
+        self.p + self.i + self.d + self.f
+    }
+}
+
+struct AxisState {
+    integral: f32,
+    previous_measurement: Option<f32>,
+    previous_setpoint: Option<f32>,
+    d_filter: Pt1,
+    feedforward_filter: Pt1,
+    iterm_relax_filter: Pt1,
+    terms: Terms,
+}
+
+pub struct RateController {
+    pub config: Config,
+    mixer_saturated: bool,
+    roll: AxisState,
+    pitch: AxisState,
+    yaw: AxisState,
+}
+
+impl RateController {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            mixer_saturated: false,
+            roll: AxisState::default(),
+            pitch: AxisState::default(),
+            yaw: AxisState::default(),
+        }
+    }
+
+    /// Clears the I terms and filter state, for example while disarmed.
+    pub fn reset(&mut self) {
+        self.roll = AxisState::default();
+        self.pitch = AxisState::default();
+        self.yaw = AxisState::default();
+    }
+
+    /// Set from `mixer::Output::saturated`. While the mixer is saturated, the I
+    /// terms may shrink but not grow.
+    pub fn set_mixer_saturated(&mut self, saturated: bool) {
+        self.mixer_saturated = saturated;
+    }
+
+    /// `gyro` is the measured roll, pitch and yaw rate, `throttle` is in the
+    /// range 0.0..=1.0, and `dt` is the time since the last update in
+    /// seconds.
+    pub fn update(
+        &mut self,
+        setpoint: &RateSetpoint,
+        gyro: [f32; 3],
+        throttle: f32,
+        dt: f32,
+    ) -> Torque {
+        let config = &self.config;
+        let step = Step {
+            tpa: tpa_factor(config, throttle),
+            mixer_saturated: self.mixer_saturated,
+            dt,
+        };
+
+        Torque {
+            roll: self
+                .roll
+                .update(config, &config.roll, setpoint.roll, gyro[0], &step),
+            pitch: self
+                .pitch
+                .update(config, &config.pitch, setpoint.pitch, gyro[1], &step),
+            yaw: self
+                .yaw
+                .update(config, &config.yaw, setpoint.yaw, gyro[2], &step),
+        }
+    }
+
+    /// Roll, pitch and yaw terms from the most recent update.
+    pub fn terms(&self) -> [Terms; 3] {
+        [self.roll.terms, self.pitch.terms, self.yaw.terms]
+    }
+}
+
+impl Default for AxisState {
+    fn default() -> Self {
+        // The cutoffs are set on every update, since the loop time varies.
+        Self {
+            integral: 0.0,
+            previous_measurement: None,
+            previous_setpoint: None,
+            d_filter: Pt1::new(0.0, 1.0),
+            feedforward_filter: Pt1::new(0.0, 1.0),
+            iterm_relax_filter: Pt1::new(0.0, 1.0),
+            terms: Terms::default(),
+        }
+    }
+}
+
+/// Conditions shared by every axis in one update.
+struct Step {
+    tpa: f32,
+    mixer_saturated: bool,
+    dt: f32,
+}
+
+fn tpa_factor(config: &Config, throttle: f32) -> f32 {
+    if throttle <= config.tpa_breakpoint || config.tpa_breakpoint >= 1.0 {
+        return 1.0;
+    }
+
+    let above_breakpoint = (throttle - config.tpa_breakpoint) / (1.0 - config.tpa_breakpoint);
+
+    1.0 - config.tpa_rate * above_breakpoint.min(1.0)
+}
+
+/// Applies `filter` for an update `dt` seconds after the last.
+fn low_pass(filter: &mut Pt1, input: f32, cutoff_hz: f32, dt: f32) -> f32 {
+    filter.set_cutoff(cutoff_hz, 1.0 / dt);
+
+    filter.apply(input)
+}
+
+impl AxisState {
+    fn update(
+        &mut self,
+        config: &Config,
+        gains: &Gains,
+        setpoint: f32,
+        measurement: f32,
+        step: &Step,
+    ) -> f32 {
+        let Step {
+            tpa,
+            mixer_saturated,
+            dt,
+        } = *step;
+
+        if dt <= 0.0 {
+            return self.terms.sum();
+        }
+
+        let error = setpoint - measurement;
+
+        let p = gains.p * tpa * error;
+
+        // Taking D from the measurement rather than the error avoids a spike
+        // in the output when the setpoint steps.
+        let measurement_rate = match self.previous_measurement {
+            Some(previous) => (measurement - previous) / dt,
+            None => 0.0,
+        };
+        self.previous_measurement = Some(measurement);
+        let d = -gains.d
+            * tpa
+            * low_pass(
+                &mut self.d_filter,
+                measurement_rate,
+                config.d_lowpass_hz,
+                dt,
+            );
+
+        let setpoint_rate = match self.previous_setpoint {
+            Some(previous) => (setpoint - previous) / dt,
+            None => 0.0,
+        };
+        self.previous_setpoint = Some(setpoint);
+        let f = gains.f
+            * low_pass(
+                &mut self.feedforward_filter,
+                setpoint_rate,
+                config.feedforward_lowpass_hz,
+                dt,
+            );
+
+        let mut i_error = error;
+        if config.iterm_relax_hz > 0.0 && config.iterm_relax_threshold > 0.0 {
+            let setpoint_lead = setpoint
+                - low_pass(
+                    &mut self.iterm_relax_filter,
+                    setpoint,
+                    config.iterm_relax_hz,
+                    dt,
+                );
+            let relax = 1.0 - libm::fabsf(setpoint_lead) / config.iterm_relax_threshold;
+            i_error *= relax.max(0.0);
+        }
+
+        // Stop accumulating when the output is already saturated in the
+        // direction the error would push it, or when the mixer can't deliver
+        // any more torque.
+        let unclamped = p + self.integral + d + f;
+        let saturated = libm::fabsf(unclamped) >= config.output_limit;
+        let integral =
+            (self.integral + gains.i * i_error * dt).clamp(-config.i_limit, config.i_limit);
+        let winding_up = libm::fabsf(integral) > libm::fabsf(self.integral);
+        if !(saturated && unclamped * i_error > 0.0 || mixer_saturated && winding_up) {
+            self.integral = integral;
+        }
+
+        self.terms = Terms {
+            p,
+            i: self.integral,
+            d,
+            f,
+        };
+
+        self.terms
+            .sum()
+            .clamp(-config.output_limit, config.output_limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.001;
+
+    const GAINS: Gains = Gains {
+        p: 0.004,
+        i: 0.0,
+        d: 0.0,
+        f: 0.0,
+    };
+
+    /// Only P, with every filter, I term relax and TPA turned off, so that
+    /// each test turns on what it looks at.
+    const CONFIG: Config = Config {
+        roll: GAINS,
+        pitch: GAINS,
+        yaw: GAINS,
+        i_limit: 0.3,
+        output_limit: 1.0,
+        d_lowpass_hz: 0.0,
+        feedforward_lowpass_hz: 0.0,
+        iterm_relax_hz: 0.0,
+        iterm_relax_threshold: 0.0,
+        tpa_breakpoint: 1.0,
+        tpa_rate: 0.0,
+    };
+
+    /// Angular acceleration per unit of torque, in degrees per second
+    /// squared.
+    const AUTHORITY: f32 = 5000.0;
+    /// Drag, as the fraction of the rate lost per second.
+    const DRAG: f32 = 5.0;
+
+    fn config(roll: Gains) -> Config {
+        Config { roll, ..CONFIG }
+    }
+
+    fn roll(rate: f32) -> RateSetpoint {
+        RateSetpoint {
+            roll: rate,
+            ..RateSetpoint::default()
+        }
+    }
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!(libm::fabsf(a - b) <= tolerance, "{} != {}", a, b);
+    }
+
+    /// Steps the roll setpoint from rest, flying an aircraft with drag for
+    /// `seconds`, and returns the roll rate over time.
+    fn step_response(
+        controller: &mut RateController,
+        setpoint: f32,
+        seconds: f32,
+    ) -> impl Iterator<Item = f32> + '_ {
+        let mut rate = 0.0;
+        (0..(seconds / DT) as usize).map(move |_| {
+            let torque = controller.update(&roll(setpoint), [rate, 0.0, 0.0], 0.0, DT);
+            rate += (AUTHORITY * torque.roll - DRAG * rate) * DT;
+            rate
+        })
+    }
+
+    #[test]
+    fn p_alone_leaves_a_steady_state_error() {
+        let mut controller = RateController::new(CONFIG);
+        let rate = step_response(&mut controller, 200.0, 2.0).last().unwrap();
+
+        // The torque needed to hold the rate against drag only comes from
+        // the error.
+        let gain = AUTHORITY * GAINS.p;
+        assert_close(rate, 200.0 * gain / (gain + DRAG), 0.5);
+    }
+
+    #[test]
+    fn i_term_removes_the_steady_state_error() {
+        let mut controller = RateController::new(config(Gains { i: 0.03, ..GAINS }));
+        let mut peak: f32 = 0.0;
+        let mut rate = 0.0;
+        for r in step_response(&mut controller, 200.0, 3.0) {
+            peak = peak.max(r);
+            rate = r;
+        }
+
+        assert_close(rate, 200.0, 1.0);
+        assert!(peak < 210.0, "overshoot to {}", peak);
+        assert_close(controller.terms()[0].i, DRAG * 200.0 / AUTHORITY, 0.005);
+    }
+
+    #[test]
+    fn axes_are_independent() {
+        let mut controller = RateController::new(CONFIG);
+        let setpoint = RateSetpoint {
+            roll: 100.0,
+            pitch: -50.0,
+            yaw: 0.0,
+        };
+        let torque = controller.update(&setpoint, [0.0, 0.0, 20.0], 0.0, DT);
+
+        assert_close(torque.roll, 0.4, 1e-6);
+        assert_close(torque.pitch, -0.2, 1e-6);
+        assert_close(torque.yaw, -0.08, 1e-6);
+    }
+
+    #[test]
+    fn output_is_clamped() {
+        let mut controller = RateController::new(CONFIG);
+        let torque = controller.update(&roll(1000.0), [0.0; 3], 0.0, DT);
+        assert_close(torque.roll, 1.0, 0.0);
+        let torque = controller.update(&roll(-1000.0), [0.0; 3], 0.0, DT);
+        assert_close(torque.roll, -1.0, 0.0);
+
+        // The terms are reported before clamping.
+        assert_close(controller.terms()[0].p, -4.0, 1e-6);
+    }
+
+    #[test]
+    fn i_term_is_clamped() {
+        let mut controller = RateController::new(config(Gains {
+            p: 0.0,
+            i: 1.0,
+            ..GAINS
+        }));
+        for _ in 0..1000 {
+            controller.update(&roll(100.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, CONFIG.i_limit, 0.0);
+
+        for _ in 0..1000 {
+            controller.update(&roll(-100.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, -CONFIG.i_limit, 0.0);
+    }
+
+    #[test]
+    fn i_term_holds_while_the_output_is_saturated() {
+        let mut controller = RateController::new(config(Gains { i: 0.03, ..GAINS }));
+
+        // P alone asks for four times the output limit.
+        for _ in 0..1000 {
+            controller.update(&roll(1000.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, 0.0, 0.0);
+
+        // Once the output comes out of saturation the I term builds again.
+        controller.update(&roll(100.0), [0.0; 3], 0.0, DT);
+        assert!(controller.terms()[0].i > 0.0);
+    }
+
+    #[test]
+    fn i_term_unwinds_while_saturated_against_the_error() {
+        let mut controller = RateController::new(config(Gains {
+            i: 1.0,
+            d: 0.0001,
+            ..GAINS
+        }));
+        for _ in 0..1000 {
+            controller.update(&roll(10.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, CONFIG.i_limit, 0.0);
+
+        // Overshooting and then falling back quickly saturates the output
+        // through D, against the error, which the I term must still be
+        // allowed to reduce.
+        controller.update(&roll(0.0), [20.0, 0.0, 0.0], 0.0, DT);
+        let torque = controller.update(&roll(0.0), [10.0, 0.0, 0.0], 0.0, DT);
+        assert_close(torque.roll, CONFIG.output_limit, 0.0);
+        assert!(controller.terms()[0].i < CONFIG.i_limit);
+    }
+
+    #[test]
+    fn mixer_saturation_lets_the_i_term_shrink_but_not_grow() {
+        let mut controller = RateController::new(config(Gains {
+            p: 0.0,
+            i: 1.0,
+            ..GAINS
+        }));
+        for _ in 0..100 {
+            controller.update(&roll(1.0), [0.0; 3], 0.0, DT);
+        }
+        let before = controller.terms()[0].i;
+        assert_close(before, 0.1, 1e-4);
+
+        controller.set_mixer_saturated(true);
+        for _ in 0..100 {
+            controller.update(&roll(1.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, before, 0.0);
+
+        controller.update(&roll(-1.0), [0.0; 3], 0.0, DT);
+        assert!(controller.terms()[0].i < before);
+
+        controller.set_mixer_saturated(false);
+        let shrunk = controller.terms()[0].i;
+        controller.update(&roll(1.0), [0.0; 3], 0.0, DT);
+        assert!(controller.terms()[0].i > shrunk);
+    }
+
+    #[test]
+    fn d_term_ignores_setpoint_steps() {
+        let mut controller = RateController::new(config(Gains {
+            p: 0.0,
+            d: 0.00004,
+            ..GAINS
+        }));
+        controller.update(&roll(0.0), [0.0; 3], 0.0, DT);
+        controller.update(&roll(500.0), [0.0; 3], 0.0, DT);
+        assert_close(controller.terms()[0].d, 0.0, 0.0);
+
+        // It opposes changes in the measured rate instead, here an
+        // acceleration of 10000 degrees per second squared.
+        controller.update(&roll(500.0), [10.0, 0.0, 0.0], 0.0, DT);
+        assert_close(controller.terms()[0].d, -0.4, 1e-5);
+    }
+
+    #[test]
+    fn d_lowpass_attenuates_gyro_noise() {
+        let gains = Gains {
+            p: 0.0,
+            d: 0.00004,
+            ..GAINS
+        };
+        let largest_d = |config: Config| {
+            let mut controller = RateController::new(config);
+            let mut largest: f32 = 0.0;
+            // 250 Hz noise of one degree per second.
+            for n in 0..1000 {
+                let noise = [0.0, 1.0, 0.0, -1.0][n % 4];
+                controller.update(&roll(0.0), [noise, 0.0, 0.0], 0.0, DT);
+                if n > 100 {
+                    largest = largest.max(libm::fabsf(controller.terms()[0].d));
+                }
+            }
+            largest
+        };
+
+        let unfiltered = largest_d(config(gains));
+        let filtered = largest_d(Config {
+            d_lowpass_hz: 100.0,
+            ..config(gains)
+        });
+        assert_close(unfiltered, 0.04, 1e-5);
+        assert!(
+            filtered < unfiltered / 2.0,
+            "{} vs {}",
+            filtered,
+            unfiltered
+        );
+    }
+
+    #[test]
+    fn feedforward_follows_the_setpoint_rate() {
+        let gains = Gains {
+            p: 0.0,
+            f: 0.00002,
+            ..GAINS
+        };
+
+        // A ramp of 1000 degrees per second squared.
+        let mut controller = RateController::new(config(gains));
+        for n in 0..3 {
+            controller.update(&roll(n as f32), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].f, 0.02, 1e-5);
+
+        // Filtered, it reaches the same value once the ramp has gone on for
+        // a few time constants.
+        let mut controller = RateController::new(Config {
+            feedforward_lowpass_hz: 30.0,
+            ..config(gains)
+        });
+        controller.update(&roll(0.0), [0.0; 3], 0.0, DT);
+        controller.update(&roll(1.0), [0.0; 3], 0.0, DT);
+        assert!(controller.terms()[0].f < 0.01);
+        for n in 2..200 {
+            controller.update(&roll(n as f32), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].f, 0.02, 1e-4);
+
+        // A held setpoint has none.
+        for _ in 0..200 {
+            controller.update(&roll(200.0), [0.0; 3], 0.0, DT);
+        }
+        assert_close(controller.terms()[0].f, 0.0, 1e-4);
+    }
+
+    #[test]
+    fn tpa_attenuates_p_and_d_above_the_breakpoint() {
+        let tpa = Config {
+            tpa_breakpoint: 0.5,
+            tpa_rate: 0.3,
+            ..config(Gains {
+                p: 0.004,
+                i: 0.03,
+                d: 0.00004,
+                f: 0.00002,
+            })
+        };
+        let terms = |throttle: f32| {
+            let mut controller = RateController::new(tpa);
+            controller.update(&roll(0.0), [0.0; 3], throttle, DT);
+            controller.update(&roll(1.0), [-10.0, 0.0, 0.0], throttle, DT);
+            controller.terms()[0]
+        };
+
+        let low = terms(0.2);
+        assert_close(terms(0.5).p, low.p, 0.0);
+        let half = terms(0.75);
+        assert_close(half.p, low.p * 0.85, 1e-6);
+        assert_close(half.d, low.d * 0.85, 1e-6);
+        let full = terms(1.0);
+        assert_close(full.p, low.p * 0.7, 1e-6);
+        assert_close(full.d, low.d * 0.7, 1e-6);
+
+        // I and feedforward are left alone.
+        assert_close(full.i, low.i, 0.0);
+        assert_close(full.f, low.f, 0.0);
+    }
+
+    #[test]
+    fn iterm_relax_holds_the_i_term_during_quick_stick_movements() {
+        let mut controller = RateController::new(Config {
+            iterm_relax_hz: 15.0,
+            iterm_relax_threshold: 40.0,
+            ..config(Gains {
+                p: 0.0,
+                i: 0.03,
+                ..GAINS
+            })
+        });
+        controller.update(&roll(0.0), [0.0; 3], 0.0, DT);
+
+        // A sudden stick movement leads its filtered value by more than the
+        // threshold, so the I term doesn't grow while the aircraft catches
+        // up.
+        let gyro = [190.0, 0.0, 0.0];
+        for _ in 0..5 {
+            controller.update(&roll(200.0), gyro, 0.0, DT);
+        }
+        assert_close(controller.terms()[0].i, 0.0, 0.0);
+
+        // Once the stick has been held, the I term accumulates normally.
+        for _ in 0..200 {
+            controller.update(&roll(200.0), gyro, 0.0, DT);
+        }
+        let held = controller.terms()[0].i;
+        controller.update(&roll(200.0), gyro, 0.0, DT);
+        assert_close(controller.terms()[0].i - held, 0.03 * 10.0 * DT, 1e-6);
+    }
+
+    #[test]
+    fn reset_clears_the_i_term_and_filters() {
+        let mut controller = RateController::new(Config {
+            d_lowpass_hz: 100.0,
+            ..config(Gains {
+                i: 0.03,
+                d: 0.00004,
+                ..GAINS
+            })
+        });
+        for n in 0..100 {
+            controller.update(&roll(50.0), [n as f32, 0.0, 0.0], 0.0, DT);
+        }
+        assert!(controller.terms()[0].i > 0.0);
+
+        controller.reset();
+        assert_close(controller.terms()[0].sum(), 0.0, 0.0);
+
+        // The first update after a reset has no previous measurement to
+        // take D from.
+        controller.update(&roll(0.0), [100.0, 0.0, 0.0], 0.0, DT);
+        assert_close(controller.terms()[0].d, 0.0, 0.0);
+    }
+
+    #[test]
+    fn zero_dt_repeats_the_previous_output() {
+        let mut controller = RateController::new(config(Gains { i: 0.03, ..GAINS }));
+        let torque = controller.update(&roll(50.0), [0.0; 3], 0.0, DT);
+
+        let repeated = controller.update(&roll(-50.0), [100.0, 0.0, 0.0], 0.0, 0.0);
+        assert_close(repeated.roll, torque.roll, 0.0);
+        let repeated = controller.update(&roll(-50.0), [100.0, 0.0, 0.0], 0.0, -DT);
+        assert_close(repeated.roll, torque.roll, 0.0);
+        assert_close(controller.terms()[0].p, 0.2, 1e-6);
+    }
+}
@@ -0,0 +1,94 @@
+//! DShot special commands
+//!
+//! Commands are sent in place of a throttle value while the motor is
+//! stopped.
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Command {
+    MotorStop = 0,
+    Beep1 = 1,
+    Beep2 = 2,
+    Beep3 = 3,
+    Beep4 = 4,
+    Beep5 = 5,
+    EscInfo = 6,
+    SpinDirection1 = 7,
+    SpinDirection2 = 8,
+    ThreeDModeOff = 9,
+    ThreeDModeOn = 10,
+    SettingsRequest = 11,
+    SaveSettings = 12,
+    ExtendedTelemetryEnable = 13,
+    ExtendedTelemetryDisable = 14,
+    SpinDirectionNormal = 20,
+    SpinDirectionReversed = 21,
+}
+
+impl Command {
+    /// Number of consecutive frames the command must be sent in before the
+    /// ESC acts on it. Commands which change settings are repeated to guard
+    /// against corrupted frames.
+    pub const fn repeat_count(&self) -> usize {
+        match self {
+            Command::SpinDirection1
+            | Command::SpinDirection2
+            | Command::ThreeDModeOff
+            | Command::ThreeDModeOn
+            | Command::SaveSettings
+            | Command::ExtendedTelemetryEnable
+            | Command::ExtendedTelemetryDisable
+            | Command::SpinDirectionNormal
+            | Command::SpinDirectionReversed => 6,
+            _ => 1,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Option<Self> {
+        Some(match value {
+            0 => Command::MotorStop,
+            1 => Command::Beep1,
+            2 => Command::Beep2,
+            3 => Command::Beep3,
+            4 => Command::Beep4,
+            5 => Command::Beep5,
+            6 => Command::EscInfo,
+            7 => Command::SpinDirection1,
+            8 => Command::SpinDirection2,
+            9 => Command::ThreeDModeOff,
+            10 => Command::ThreeDModeOn,
+            11 => Command::SettingsRequest,
+            12 => Command::SaveSettings,
+            13 => Command::ExtendedTelemetryEnable,
+            14 => Command::ExtendedTelemetryDisable,
+            20 => Command::SpinDirectionNormal,
+            21 => Command::SpinDirectionReversed,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        for value in 0..48 {
+            if let Some(command) = Command::from_value(value) {
+                assert_eq!(command as u16, value);
+            }
+        }
+        assert!(Command::from_value(15).is_none());
+        assert!(Command::from_value(47).is_none());
+    }
+
+    #[test]
+    fn setting_changes_are_repeated() {
+        assert_eq!(Command::Beep1.repeat_count(), 1);
+        assert_eq!(Command::EscInfo.repeat_count(), 1);
+        assert_eq!(Command::SpinDirectionReversed.repeat_count(), 6);
+        assert_eq!(Command::ThreeDModeOn.repeat_count(), 6);
+        assert_eq!(Command::SaveSettings.repeat_count(), 6);
+    }
+}
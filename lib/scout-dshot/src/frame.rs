@@ -0,0 +1,190 @@
+use crate::Command;
+
+/// Lowest value which is a throttle rather than a command.
+pub const MIN_THROTTLE: u16 = 48;
+pub const MAX_THROTTLE: u16 = 2047;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    Crc,
+    /// A bidirectional DShot reply contained an invalid GCR symbol.
+    Gcr,
+}
+
+/// The meaning of a frame's value.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Packet {
+    Command(Command),
+    /// A command value which isn't a known command.
+    UnknownCommand(u16),
+    /// Throttle in the range 0..=1999.
+    Throttle(u16),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Frame {
+    value: u16,
+    telemetry: bool,
+}
+
+impl Frame {
+    pub const MOTOR_STOP: Self = Self {
+        value: 0,
+        telemetry: false,
+    };
+
+    /// `value` is truncated to 11 bits.
+    pub const fn new(value: u16, telemetry: bool) -> Self {
+        Self {
+            value: value & 0x07ff,
+            telemetry,
+        }
+    }
+
+    /// `throttle` is in the range 0.0..=1.0, where 0.0 is the lowest throttle
+    /// which still spins the motor. Use `MOTOR_STOP` to stop it.
+    pub fn throttle(throttle: f32, telemetry: bool) -> Self {
+        let range = (MAX_THROTTLE - MIN_THROTTLE) as f32;
+        let value = MIN_THROTTLE + (throttle.clamp(0.0, 1.0) * range + 0.5) as u16;
+
+        Self::new(value, telemetry)
+    }
+
+    /// Commands are always sent with the telemetry bit set, which is how ESCs
+    /// tell them apart from a stray command value.
+    pub const fn command(command: Command) -> Self {
+        Self::new(command as u16, true)
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn telemetry(&self) -> bool {
+        self.telemetry
+    }
+
+    pub fn packet(&self) -> Packet {
+        if self.value >= MIN_THROTTLE {
+            Packet::Throttle(self.value - MIN_THROTTLE)
+        } else {
+            match Command::from_value(self.value) {
+                Some(command) => Packet::Command(command),
+                None => Packet::UnknownCommand(self.value),
+            }
+        }
+    }
+
+    /// Returns the 16 bit word to send.
+    pub fn encode(&self) -> u16 {
+        let data = self.data();
+
+        (data << 4) | crc(data)
+    }
+
+    /// Returns the 16 bit word to send with bidirectional DShot, which uses
+    /// an inverted CRC so that ESCs know to reply. The signal itself is also
+    /// inverted, which is left to the hardware.
+    pub fn encode_bidirectional(&self) -> u16 {
+        self.encode() ^ 0x000f
+    }
+
+    pub fn decode(word: u16) -> Result<Self, Error> {
+        let data = word >> 4;
+        if crc(data) != word & 0x000f {
+            return Err(Error::Crc);
+        }
+
+        Ok(Self::new(data >> 1, data & 1 != 0))
+    }
+
+    pub fn decode_bidirectional(word: u16) -> Result<Self, Error> {
+        Self::decode(word ^ 0x000f)
+    }
+
+    /// The value and telemetry bit, without the CRC.
+    fn data(&self) -> u16 {
+        (self.value << 1) | self.telemetry as u16
+    }
+}
+
+fn crc(data: u16) -> u16 {
+    (data ^ (data >> 4) ^ (data >> 8)) & 0x000f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_the_reference_frame() {
+        // Throttle value 1046 without telemetry, the example frame in the
+        // DShot documentation.
+        assert_eq!(Frame::new(1046, false).encode(), 0b1000_0010_1100_0110);
+        assert_eq!(Frame::new(1046, true).encode(), 0b1000_0010_1101_0111);
+        assert_eq!(Frame::MOTOR_STOP.encode(), 0);
+    }
+
+    #[test]
+    fn every_frame_round_trips() {
+        for value in 0..=MAX_THROTTLE {
+            for telemetry in [false, true] {
+                let frame = Frame::new(value, telemetry);
+                assert!(Frame::decode(frame.encode()) == Ok(frame));
+                assert!(Frame::decode_bidirectional(frame.encode_bidirectional()) == Ok(frame));
+            }
+        }
+    }
+
+    #[test]
+    fn single_bit_errors_fail_the_crc() {
+        let word = Frame::new(1046, false).encode();
+        for bit in 0..16 {
+            assert!(Frame::decode(word ^ (1 << bit)) == Err(Error::Crc));
+        }
+    }
+
+    #[test]
+    fn normal_and_bidirectional_frames_are_told_apart() {
+        for value in [0, 1, 47, 48, 1046, MAX_THROTTLE] {
+            let frame = Frame::new(value, true);
+            assert!(Frame::decode(frame.encode_bidirectional()) == Err(Error::Crc));
+            assert!(Frame::decode_bidirectional(frame.encode()) == Err(Error::Crc));
+        }
+    }
+
+    #[test]
+    fn value_is_truncated_to_11_bits() {
+        assert_eq!(Frame::new(0xffff, false).value(), MAX_THROTTLE);
+        assert_eq!(Frame::new(MAX_THROTTLE + 1, false).value(), 0);
+    }
+
+    #[test]
+    fn throttle_spans_the_throttle_values() {
+        assert_eq!(Frame::throttle(0.0, false).value(), MIN_THROTTLE);
+        assert_eq!(Frame::throttle(1.0, false).value(), MAX_THROTTLE);
+        assert_eq!(Frame::throttle(0.5, false).value(), 1048);
+        assert_eq!(Frame::throttle(-1.0, false).value(), MIN_THROTTLE);
+        assert_eq!(Frame::throttle(2.0, false).value(), MAX_THROTTLE);
+        assert!(Frame::throttle(0.5, true).telemetry());
+
+        assert!(Frame::throttle(0.0, false).packet() == Packet::Throttle(0));
+        assert!(Frame::throttle(1.0, false).packet() == Packet::Throttle(1999));
+    }
+
+    #[test]
+    fn commands_set_the_telemetry_bit() {
+        let frame = Frame::command(Command::SaveSettings);
+        assert_eq!(frame.value(), 12);
+        assert!(frame.telemetry());
+        assert!(frame.packet() == Packet::Command(Command::SaveSettings));
+    }
+
+    #[test]
+    fn packets() {
+        assert!(Frame::MOTOR_STOP.packet() == Packet::Command(Command::MotorStop));
+        assert!(Frame::new(15, true).packet() == Packet::UnknownCommand(15));
+        assert!(Frame::new(47, true).packet() == Packet::UnknownCommand(47));
+        assert!(Frame::new(48, false).packet() == Packet::Throttle(0));
+    }
+}
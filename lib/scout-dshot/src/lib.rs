@@ -0,0 +1,133 @@
+#![no_std]
+
+mod command;
+pub use command::Command;
+
+mod frame;
+pub use frame::{Error, Frame, Packet, MAX_THROTTLE, MIN_THROTTLE};
+
+pub mod telemetry;
+pub use telemetry::ErpmTelemetry;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Speed {
+    Dshot150,
+    Dshot300,
+    Dshot600,
+}
+
+impl Speed {
+    pub const fn bit_rate(&self) -> u32 {
+        match self {
+            Speed::Dshot150 => 150_000,
+            Speed::Dshot300 => 300_000,
+            Speed::Dshot600 => 600_000,
+        }
+    }
+
+    /// Bit rate of bidirectional DShot replies.
+    pub const fn telemetry_bit_rate(&self) -> u32 {
+        self.bit_rate() * 5 / 4
+    }
+}
+
+/// Bit timing in ticks of the timer generating or measuring the signal.
+///
+/// Every bit starts high. A zero stays high for 3/8 of the bit period and a
+/// one for 3/4 of it.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct BitTiming {
+    pub period: u32,
+    pub zero_high: u32,
+    pub one_high: u32,
+}
+
+impl BitTiming {
+    pub const fn new(timer_hz: u32, speed: Speed) -> Self {
+        let period = timer_hz / speed.bit_rate();
+
+        Self {
+            period,
+            zero_high: period * 3 / 8,
+            one_high: period * 3 / 4,
+        }
+    }
+
+    /// Writes the high time of each bit of `word`, most significant bit
+    /// first.
+    pub fn high_times(&self, word: u16, high_times: &mut [u32; 16]) {
+        for (i, high_time) in high_times.iter_mut().enumerate() {
+            *high_time = if word & (0x8000 >> i) != 0 {
+                self.one_high
+            } else {
+                self.zero_high
+            };
+        }
+    }
+
+    /// Time from the start of a bit, halfway between the high times of a
+    /// zero and a one. A receiver which samples the line at this point sees
+    /// a one as still high and a zero as already low.
+    pub const fn sample_time(&self) -> u32 {
+        (self.zero_high + self.one_high) / 2
+    }
+
+    /// Classifies a measured high time as a zero or a one. Returns `None` if
+    /// it is too short or too long to be either.
+    pub fn bit(&self, high_time: u32) -> Option<bool> {
+        if high_time < self.zero_high / 2 || high_time >= self.period {
+            None
+        } else {
+            Some(high_time >= self.sample_time())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_timing() {
+        // TIM1 at 180 MHz.
+        let timing = BitTiming::new(180_000_000, Speed::Dshot600);
+        assert_eq!(timing.period, 300);
+        assert_eq!(timing.zero_high, 112);
+        assert_eq!(timing.one_high, 225);
+
+        assert_eq!(Speed::Dshot300.telemetry_bit_rate(), 375_000);
+    }
+
+    #[test]
+    fn high_times_round_trip() {
+        let timing = BitTiming::new(180_000_000, Speed::Dshot300);
+        let word = Frame::new(1046, true).encode();
+        let mut high_times = [0; 16];
+        timing.high_times(word, &mut high_times);
+
+        assert_eq!(high_times[0], timing.one_high);
+        assert_eq!(high_times[1], timing.zero_high);
+        let decoded = high_times.iter().fold(0u16, |word, high_time| {
+            (word << 1) | timing.bit(*high_time).unwrap() as u16
+        });
+        assert_eq!(decoded, word);
+    }
+
+    #[test]
+    fn sampling_at_the_sample_time_tells_the_bits_apart() {
+        let timing = BitTiming::new(48_000_000, Speed::Dshot600);
+        assert!(timing.zero_high < timing.sample_time());
+        assert!(timing.sample_time() < timing.one_high);
+        assert_eq!(timing.bit(timing.sample_time()), Some(true));
+        assert_eq!(timing.bit(timing.sample_time() - 1), Some(false));
+    }
+
+    #[test]
+    fn bit_tolerates_jitter_but_not_glitches() {
+        let timing = BitTiming::new(180_000_000, Speed::Dshot600);
+        assert_eq!(timing.bit(timing.zero_high + 20), Some(false));
+        assert_eq!(timing.bit(timing.one_high - 20), Some(true));
+        assert_eq!(timing.bit(10), None);
+        assert_eq!(timing.bit(timing.period), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! Bidirectional DShot eRPM telemetry
+//!
+//! With bidirectional DShot the signal idles high, and after each frame the
+//! ESC replies on the same wire with its electrical period. The reply is 21
+//! bits at 5/4 of the DShot bit rate. A 12 bit period (3 bit exponent, 9 bit
+//! mantissa) and 4 bit CRC are split into nibbles, each nibble is mapped to a
+//! 5 bit GCR symbol, and the 20 GCR bits are sent after a low start bit with a
+//! change of level for every one bit.
+
+use crate::Error;
+
+/// Length of a reply, including the start bit.
+pub const TELEMETRY_BITS: u32 = 21;
+
+const GCR_ENCODE: [u8; 16] = [
+    0x19, 0x1b, 0x12, 0x13, 0x1d, 0x15, 0x16, 0x17, 0x1a, 0x09, 0x0a, 0x0b, 0x1e, 0x0d, 0x0e, 0x0f,
+];
+
+/// Electrical period reported by the ESC.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ErpmTelemetry {
+    /// Period in microseconds as `mantissa << exponent`, in the form
+    /// `eeem_mmmm_mmmm`.
+    value: u16,
+}
+
+impl ErpmTelemetry {
+    /// Sent when the motor is stopped, as it is the longest period that can
+    /// be represented.
+    pub const STOPPED: Self = Self { value: 0x0fff };
+
+    /// Periods too long to represent are reported as `STOPPED`.
+    pub fn from_period_us(period_us: u32) -> Self {
+        let mut mantissa = period_us;
+        let mut exponent = 0;
+        while mantissa > 0x1ff {
+            if exponent == 7 {
+                return Self::STOPPED;
+            }
+            mantissa >>= 1;
+            exponent += 1;
+        }
+
+        Self {
+            value: (exponent << 9) | mantissa as u16,
+        }
+    }
+
+    /// Returns `None` when the motor is stopped.
+    pub fn period_us(&self) -> Option<u32> {
+        if *self == Self::STOPPED {
+            return None;
+        }
+        let mantissa = (self.value & 0x01ff) as u32;
+        let exponent = self.value >> 9;
+
+        Some(mantissa << exponent).filter(|period| *period > 0)
+    }
+
+    /// Electrical revolutions per minute.
+    pub fn erpm(&self) -> u32 {
+        self.period_us().map_or(0, |period| 60_000_000 / period)
+    }
+
+    /// Mechanical revolutions per minute, for a motor with `motor_poles`
+    /// magnet poles.
+    pub fn rpm(&self, motor_poles: u8) -> u32 {
+        self.erpm() * 2 / (motor_poles.max(2) as u32)
+    }
+
+    /// Returns the line levels of the reply, most significant bit first, in
+    /// the low `TELEMETRY_BITS` bits.
+    pub fn encode(&self) -> u32 {
+        let word = (self.value << 4) | inverted_crc(self.value);
+
+        let mut gcr = 0u32;
+        for nibble in (0..4).rev() {
+            gcr = (gcr << 5) | GCR_ENCODE[((word >> (nibble * 4)) & 0xf) as usize] as u32;
+        }
+
+        // Each one bit changes the level, starting from the low start bit.
+        let mut line = 0u32;
+        let mut level = 0u32;
+        for bit in (0..TELEMETRY_BITS - 1).rev() {
+            level ^= (gcr >> bit) & 1;
+            line = (line << 1) | level;
+        }
+
+        line
+    }
+
+    /// Decodes the line levels of a reply, as returned by `encode` or
+    /// `line_from_edges`.
+    pub fn decode(line: u32) -> Result<Self, Error> {
+        let gcr = (line ^ (line >> 1)) & 0x000f_ffff;
+
+        let mut word = 0u16;
+        for symbol in (0..4).rev() {
+            let symbol = ((gcr >> (symbol * 5)) & 0x1f) as u8;
+            let nibble = GCR_ENCODE
+                .iter()
+                .position(|gcr| *gcr == symbol)
+                .ok_or(Error::Gcr)?;
+            word = (word << 4) | nibble as u16;
+        }
+
+        let value = word >> 4;
+        if inverted_crc(value) != word & 0x000f {
+            return Err(Error::Crc);
+        }
+
+        Ok(Self { value })
+    }
+}
+
+/// Rebuilds the line levels of a reply from the capture times of its edges,
+/// in timer ticks, where `bit_ticks` is the length of one reply bit. The
+/// first edge must be the falling edge of the start bit. Captures may wrap.
+///
+/// Returns `None` if there are too few edges, or the edges are not a whole
+/// number of bits apart.
+pub fn line_from_edges(edges: &[u32], bit_ticks: u32) -> Option<u32> {
+    if edges.len() < 2 || bit_ticks == 0 {
+        return None;
+    }
+
+    let mut line = 0u32;
+    let mut bits = 0;
+    let mut level = 0;
+    for pair in edges.windows(2) {
+        let run = (pair[1].wrapping_sub(pair[0]) + bit_ticks / 2) / bit_ticks;
+        if run == 0 {
+            return None;
+        }
+        for _ in 0..run.min(TELEMETRY_BITS - bits) {
+            line = (line << 1) | level;
+        }
+        bits += run.min(TELEMETRY_BITS - bits);
+        level ^= 1;
+    }
+
+    // The level after the last edge lasts until the end of the reply.
+    for _ in bits..TELEMETRY_BITS {
+        line = (line << 1) | level;
+    }
+
+    Some(line)
+}
+
+fn inverted_crc(value: u16) -> u16 {
+    !(value ^ (value >> 4) ^ (value >> 8)) & 0x000f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The capture times of the edges of `line`, sent from `start` with
+    /// `bit_ticks` per bit, and each edge moved by up to `jitter` ticks.
+    fn edges(line: u32, start: u32, bit_ticks: u32, jitter: i32) -> ([u32; 22], usize) {
+        let mut edges = [0; 22];
+        let mut count = 0;
+        let mut level = 1;
+        for bit in 0..TELEMETRY_BITS {
+            let bit_level = (line >> (TELEMETRY_BITS - 1 - bit)) & 1;
+            if bit_level != level {
+                let offset = if count % 2 == 0 { jitter } else { -jitter };
+                edges[count] = start
+                    .wrapping_add(bit * bit_ticks)
+                    .wrapping_add(offset as u32);
+                count += 1;
+                level = bit_level;
+            }
+        }
+
+        (edges, count)
+    }
+
+    #[test]
+    fn every_value_round_trips() {
+        for value in 0..=0x0fff {
+            let telemetry = ErpmTelemetry { value };
+            let line = telemetry.encode();
+            assert!(line < 1 << TELEMETRY_BITS);
+            assert!(ErpmTelemetry::decode(line) == Ok(telemetry));
+        }
+    }
+
+    #[test]
+    fn replies_start_low_and_change_level_often() {
+        for value in [0, 0x0123, 0x0fff] {
+            let line = ErpmTelemetry { value }.encode();
+            assert_eq!(line >> (TELEMETRY_BITS - 1), 0);
+
+            // GCR never has more than two zeros in a row, so the level never
+            // holds for more than three bits.
+            let mut run = 0;
+            let mut previous = 0;
+            for bit in (0..TELEMETRY_BITS).rev() {
+                let level = (line >> bit) & 1;
+                run = if level == previous { run + 1 } else { 1 };
+                previous = level;
+                assert!(run <= 3);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_replies_are_rejected() {
+        let line = ErpmTelemetry::from_period_us(1000).encode();
+        for bit in 0..TELEMETRY_BITS - 1 {
+            assert!(ErpmTelemetry::decode(line ^ (1 << bit)).is_err());
+        }
+
+        // A symbol which isn't in the GCR table.
+        assert!(ErpmTelemetry::decode(0) == Err(Error::Gcr));
+    }
+
+    #[test]
+    fn period_conversion() {
+        let telemetry = ErpmTelemetry::from_period_us(100);
+        assert_eq!(telemetry.period_us(), Some(100));
+
+        // Longer periods lose their low bits.
+        assert_eq!(ErpmTelemetry::from_period_us(1000).period_us(), Some(1000));
+        assert_eq!(ErpmTelemetry::from_period_us(1001).period_us(), Some(1000));
+        assert_eq!(
+            ErpmTelemetry::from_period_us(0x1fe << 7).period_us(),
+            Some(0x1fe << 7)
+        );
+
+        // The longest period has the same code as a stopped motor.
+        assert!(ErpmTelemetry::from_period_us(0x1ff << 7) == ErpmTelemetry::STOPPED);
+        assert!(ErpmTelemetry::from_period_us(0x200 << 7) == ErpmTelemetry::STOPPED);
+        assert_eq!(ErpmTelemetry::STOPPED.period_us(), None);
+        assert_eq!(ErpmTelemetry::from_period_us(0).period_us(), None);
+    }
+
+    #[test]
+    fn erpm_to_rpm() {
+        let telemetry = ErpmTelemetry::from_period_us(1000);
+        assert_eq!(telemetry.erpm(), 60_000);
+        assert_eq!(telemetry.rpm(14), 8571);
+        assert_eq!(telemetry.rpm(2), 60_000);
+
+        assert_eq!(ErpmTelemetry::STOPPED.erpm(), 0);
+        assert_eq!(ErpmTelemetry::STOPPED.rpm(14), 0);
+        // Fewer than two poles would divide by zero or make no sense.
+        assert_eq!(telemetry.rpm(0), 60_000);
+    }
+
+    #[test]
+    fn edges_round_trip() {
+        // Edges may each be out by up to a quarter of a bit.
+        let bit_ticks = 72;
+        for period in [50, 1000, 20_000] {
+            let telemetry = ErpmTelemetry::from_period_us(period);
+            let line = telemetry.encode();
+            for (start, jitter) in [(0, 0), (1_000, 15), (1_000, -15), (u32::MAX - 500, 10)] {
+                let (edges, count) = edges(line, start, bit_ticks, jitter);
+                let captured = line_from_edges(&edges[..count], bit_ticks);
+                assert_eq!(captured, Some(line));
+                assert!(ErpmTelemetry::decode(captured.unwrap()) == Ok(telemetry));
+            }
+        }
+    }
+
+    #[test]
+    fn bad_edges_are_rejected() {
+        assert_eq!(line_from_edges(&[], 72), None);
+        assert_eq!(line_from_edges(&[100], 72), None);
+        assert_eq!(line_from_edges(&[100, 200], 0), None);
+        // Edges closer together than half a bit.
+        assert_eq!(line_from_edges(&[100, 120], 72), None);
+    }
+}
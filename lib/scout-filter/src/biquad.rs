@@ -0,0 +1,215 @@
+//! Second order IIR filters
+//!
+//! Coefficients follow the Audio EQ Cookbook by Robert Bristow-Johnson, and
+//! the filter runs in transposed direct form II.
+
+use core::f32::consts::PI;
+
+use crate::Filter;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    state: [f32; 2],
+}
+
+impl Biquad {
+    /// Q of a low pass filter with a maximally flat passband.
+    pub const BUTTERWORTH_Q: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+    /// A filter which passes its input through unchanged.
+    pub const fn passthrough() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            state: [0.0; 2],
+        }
+    }
+
+    pub fn low_pass(cutoff_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let mut filter = Self::passthrough();
+        filter.set_low_pass(cutoff_hz, sample_hz, q);
+
+        filter
+    }
+
+    pub fn notch(center_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let mut filter = Self::passthrough();
+        filter.set_notch(center_hz, sample_hz, q);
+
+        filter
+    }
+
+    /// Q of a notch centered on `center_hz` whose lower -3dB point is
+    /// `lower_cutoff_hz`.
+    pub fn notch_q(center_hz: f32, lower_cutoff_hz: f32) -> f32 {
+        center_hz * lower_cutoff_hz / (center_hz * center_hz - lower_cutoff_hz * lower_cutoff_hz)
+    }
+
+    /// Changes the cutoff without resetting the filter. The filter passes
+    /// its input through if the cutoff is not between zero and the Nyquist
+    /// frequency.
+    pub fn set_low_pass(&mut self, cutoff_hz: f32, sample_hz: f32, q: f32) {
+        let Some((cos, alpha)) = Self::omega(cutoff_hz, sample_hz, q) else {
+            self.set_passthrough();
+            return;
+        };
+
+        let b = (1.0 - cos) / 2.0;
+        self.set_coefficients(b, 1.0 - cos, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha);
+    }
+
+    /// Changes the center frequency without resetting the filter. The filter
+    /// passes its input through if the center is not between zero and the
+    /// Nyquist frequency.
+    pub fn set_notch(&mut self, center_hz: f32, sample_hz: f32, q: f32) {
+        let Some((cos, alpha)) = Self::omega(center_hz, sample_hz, q) else {
+            self.set_passthrough();
+            return;
+        };
+
+        self.set_coefficients(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha);
+    }
+
+    fn set_passthrough(&mut self) {
+        self.set_coefficients(1.0, 0.0, 0.0, 1.0, 0.0, 0.0);
+    }
+
+    fn omega(frequency_hz: f32, sample_hz: f32, q: f32) -> Option<(f32, f32)> {
+        if frequency_hz <= 0.0 || frequency_hz >= sample_hz / 2.0 || q <= 0.0 {
+            return None;
+        }
+
+        let (sin, cos) = libm::sincosf(2.0 * PI * frequency_hz / sample_hz);
+
+        Some((cos, sin / (2.0 * q)))
+    }
+
+    fn set_coefficients(&mut self, b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) {
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+}
+
+impl Default for Biquad {
+    fn default() -> Self {
+        Self::passthrough()
+    }
+}
+
+impl Filter for Biquad {
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.state[0];
+        self.state[0] = self.b1 * input - self.a1 * output + self.state[1];
+        self.state[1] = self.b2 * input - self.a2 * output;
+
+        output
+    }
+
+    fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sine_gain;
+
+    /// High enough that the responses are close to those of the analog
+    /// filters the coefficients are designed from.
+    const SAMPLE_HZ: f32 = 8000.0;
+
+    fn gain(filter: Biquad, frequency_hz: f32) -> f32 {
+        sine_gain(&mut { filter }, frequency_hz, SAMPLE_HZ)
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut filter = Biquad::passthrough();
+        for input in [1.0, -2.0, 0.5] {
+            assert_eq!(filter.apply(input), input);
+        }
+    }
+
+    #[test]
+    fn low_pass_response() {
+        let filter = Biquad::low_pass(100.0, SAMPLE_HZ, Biquad::BUTTERWORTH_Q);
+
+        assert!(gain(filter, 5.0) > 0.99);
+        let cutoff = gain(filter, 100.0);
+        assert!((0.69..0.72).contains(&cutoff), "{}", cutoff);
+        // Second order, so 40dB per decade.
+        assert!(gain(filter, 1000.0) < 0.011);
+    }
+
+    #[test]
+    fn low_pass_settles_to_its_input() {
+        let mut filter = Biquad::low_pass(50.0, SAMPLE_HZ, Biquad::BUTTERWORTH_Q);
+        let mut output = 0.0;
+        for _ in 0..2000 {
+            output = filter.apply(1.0);
+        }
+        assert!(libm::fabsf(output - 1.0) < 1e-4);
+    }
+
+    #[test]
+    fn notch_response() {
+        let q = Biquad::notch_q(200.0, 160.0);
+        let filter = Biquad::notch(200.0, SAMPLE_HZ, q);
+
+        assert!(gain(filter, 200.0) < 0.01);
+        let edge = gain(filter, 160.0);
+        assert!((0.68..0.73).contains(&edge), "{}", edge);
+        assert!(gain(filter, 20.0) > 0.98);
+        assert!(gain(filter, 450.0) > 0.95);
+    }
+
+    #[test]
+    fn invalid_frequencies_pass_through() {
+        for (frequency, q) in [
+            (0.0, 1.0),
+            (-10.0, 1.0),
+            (4000.0, 1.0),
+            (5000.0, 1.0),
+            (100.0, 0.0),
+        ] {
+            let mut filter = Biquad::low_pass(100.0, SAMPLE_HZ, Biquad::BUTTERWORTH_Q);
+            filter.set_low_pass(frequency, SAMPLE_HZ, q);
+            assert_eq!(filter.apply(0.7), 0.7);
+
+            filter.set_notch(frequency, SAMPLE_HZ, q);
+            assert_eq!(filter.apply(-0.3), -0.3);
+        }
+    }
+
+    #[test]
+    fn moving_the_notch_follows_the_new_center() {
+        let mut filter = Biquad::notch(150.0, SAMPLE_HZ, 3.0);
+        assert!(sine_gain(&mut filter, 150.0, SAMPLE_HZ) < 0.01);
+
+        filter.set_notch(250.0, SAMPLE_HZ, 3.0);
+        assert!(sine_gain(&mut filter, 250.0, SAMPLE_HZ) < 0.01);
+        assert!(sine_gain(&mut filter, 150.0, SAMPLE_HZ) > 0.8);
+    }
+
+    #[test]
+    fn reset_clears_the_history() {
+        let mut filter = Biquad::low_pass(50.0, SAMPLE_HZ, Biquad::BUTTERWORTH_Q);
+        for _ in 0..100 {
+            filter.apply(1.0);
+        }
+        filter.reset();
+        assert_eq!(filter.apply(0.0), 0.0);
+    }
+}
@@ -0,0 +1,347 @@
+//! Notch filters which follow the strongest noise peaks
+//!
+//! The spectrum of the most recent `FFT_SIZE` samples is analysed every
+//! `ANALYSIS_INTERVAL` samples. The largest peaks between `min_hz` and
+//! `max_hz` become the new centers of the notch filters, each moving the
+//! notch which is nearest to it. Notches stay where they are when their peak
+//! fades.
+//!
+//! The analysis takes far longer than filtering a sample, so filters on
+//! several axes are given different phases with `set_analysis_phase`, to
+//! spread their analyses over different samples.
+
+use core::f32::consts::PI;
+
+use crate::{Biquad, Fft, Filter};
+
+pub const FFT_SIZE: usize = 128;
+/// Samples between analyses, so that each sample is analysed twice.
+pub const ANALYSIS_INTERVAL: usize = FFT_SIZE / 2;
+pub const MAX_NOTCHES: usize = 3;
+
+/// How far a peak must stand above the average of the searched range to be
+/// followed.
+const PEAK_THRESHOLD: f32 = 3.0;
+/// Fraction of the distance to a new peak which a notch moves at each
+/// analysis, to smooth out the FFT's coarse frequency resolution.
+const CENTER_SMOOTHING: f32 = 0.5;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Range of frequencies searched for peaks.
+    pub min_hz: f32,
+    pub max_hz: f32,
+    pub q: f32,
+    /// Number of notches, up to `MAX_NOTCHES`.
+    pub notch_count: usize,
+}
+
+pub struct DynamicNotch {
+    config: Config,
+    sample_hz: f32,
+    fft: Fft<FFT_SIZE>,
+    window: [f32; FFT_SIZE],
+    /// The most recent samples, oldest first starting at `index`.
+    samples: [f32; FFT_SIZE],
+    index: usize,
+    new_samples: usize,
+    notches: [Biquad; MAX_NOTCHES],
+    centers: [Option<f32>; MAX_NOTCHES],
+}
+
+impl DynamicNotch {
+    pub fn new(config: Config, sample_hz: f32) -> Self {
+        let mut window = [0.0; FFT_SIZE];
+        for (i, w) in window.iter_mut().enumerate() {
+            // Hann window
+            *w = 0.5 - 0.5 * libm::cosf(2.0 * PI * i as f32 / FFT_SIZE as f32);
+        }
+
+        Self {
+            config: Config {
+                notch_count: config.notch_count.min(MAX_NOTCHES),
+                ..config
+            },
+            sample_hz,
+            fft: Fft::new(),
+            window,
+            samples: [0.0; FFT_SIZE],
+            index: 0,
+            new_samples: 0,
+            notches: [Biquad::passthrough(); MAX_NOTCHES],
+            centers: [None; MAX_NOTCHES],
+        }
+    }
+
+    /// Analyses the spectrum `phase` samples into each `ANALYSIS_INTERVAL`,
+    /// instead of at its start. Set before the first sample.
+    pub fn set_analysis_phase(&mut self, phase: usize) {
+        self.new_samples = (ANALYSIS_INTERVAL - phase % ANALYSIS_INTERVAL) % ANALYSIS_INTERVAL;
+    }
+
+    /// Center frequencies of the notches, which are `None` until a peak has
+    /// been found for them.
+    pub fn centers(&self) -> &[Option<f32>] {
+        &self.centers[..self.config.notch_count]
+    }
+
+    fn analyse(&mut self) {
+        let mean = self.samples.iter().sum::<f32>() / FFT_SIZE as f32;
+        let mut spectrum = [0.0; FFT_SIZE];
+        for (i, bin) in spectrum.iter_mut().enumerate() {
+            *bin = (self.samples[(self.index + i) % FFT_SIZE] - mean) * self.window[i];
+        }
+        self.fft.magnitudes(&mut spectrum);
+
+        let bin_hz = self.sample_hz / FFT_SIZE as f32;
+        let first = ((self.config.min_hz / bin_hz) as usize).max(1);
+        let last = ((self.config.max_hz / bin_hz) as usize).min(FFT_SIZE / 2 - 2);
+        if first > last {
+            return;
+        }
+        let range = &spectrum[first..=last];
+        let threshold = PEAK_THRESHOLD * range.iter().sum::<f32>() / range.len() as f32;
+
+        // Peaks as (frequency, magnitude), largest first.
+        let mut peaks = [(0.0, 0.0); MAX_NOTCHES];
+        for bin in first..=last {
+            let (before, magnitude, after) = (spectrum[bin - 1], spectrum[bin], spectrum[bin + 1]);
+            if magnitude <= threshold || magnitude <= before || magnitude < after {
+                continue;
+            }
+            let Some(slot) = peaks[..self.config.notch_count]
+                .iter()
+                .position(|(_, m)| magnitude > *m) else { continue };
+            peaks.copy_within(slot..MAX_NOTCHES - 1, slot + 1);
+
+            // Fit a parabola through the peak and its neighbours to find
+            // the peak's frequency between bins.
+            let curvature = before - 2.0 * magnitude + after;
+            let offset = if curvature < 0.0 {
+                0.5 * (before - after) / curvature
+            } else {
+                0.0
+            };
+            peaks[slot] = ((bin as f32 + offset) * bin_hz, magnitude);
+        }
+
+        let mut moved = [false; MAX_NOTCHES];
+        for (frequency, magnitude) in peaks {
+            if magnitude <= 0.0 {
+                break;
+            }
+
+            let distance =
+                |center: Option<f32>| center.map_or(f32::MAX, |c| libm::fabsf(c - frequency));
+            let Some(notch) = (0..self.config.notch_count)
+                .filter(|i| !moved[*i])
+                .min_by(|a, b| distance(self.centers[*a]).total_cmp(&distance(self.centers[*b])))
+            else {
+                break;
+            };
+            moved[notch] = true;
+
+            let center = match self.centers[notch] {
+                Some(center) => center + (frequency - center) * CENTER_SMOOTHING,
+                None => frequency,
+            };
+            self.centers[notch] = Some(center);
+            self.notches[notch].set_notch(center, self.sample_hz, self.config.q);
+        }
+    }
+}
+
+impl Filter for DynamicNotch {
+    fn apply(&mut self, input: f32) -> f32 {
+        self.samples[self.index] = input;
+        self.index = (self.index + 1) % FFT_SIZE;
+        self.new_samples += 1;
+        if self.new_samples >= ANALYSIS_INTERVAL {
+            self.new_samples = 0;
+            self.analyse();
+        }
+
+        self.notches[..self.config.notch_count]
+            .iter_mut()
+            .fold(input, |sample, notch| notch.apply(sample))
+    }
+
+    fn reset(&mut self) {
+        for notch in &mut self.notches {
+            notch.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sine_gain;
+
+    const SAMPLE_HZ: f32 = 1000.0;
+
+    const CONFIG: Config = Config {
+        min_hz: 80.0,
+        max_hz: 450.0,
+        q: 3.0,
+        notch_count: 2,
+    };
+
+    /// Runs `seconds` of the sum of sines at each `(frequency, amplitude)`.
+    fn run(filter: &mut DynamicNotch, tones: &[(f32, f32)], seconds: f32) {
+        for n in 0..(seconds * SAMPLE_HZ) as usize {
+            let t = n as f32 / SAMPLE_HZ;
+            let sample = tones
+                .iter()
+                .map(|(frequency, amplitude)| amplitude * libm::sinf(2.0 * PI * frequency * t))
+                .sum();
+            filter.apply(sample);
+        }
+    }
+
+    fn assert_center(center: Option<f32>, frequency: f32) {
+        let center = center.unwrap();
+        assert!(
+            libm::fabsf(center - frequency) < 4.0,
+            "{} != {}",
+            center,
+            frequency
+        );
+    }
+
+    /// Centers sorted, with `None` last.
+    fn sorted_centers(filter: &DynamicNotch) -> [Option<f32>; 2] {
+        let [a, b] = [filter.centers()[0], filter.centers()[1]];
+        match (a, b) {
+            (Some(x), Some(y)) if y < x => [b, a],
+            (None, _) => [b, a],
+            _ => [a, b],
+        }
+    }
+
+    #[test]
+    fn no_notches_until_a_peak_is_found() {
+        let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+        assert!(filter.centers() == [None, None]);
+
+        // Below the searched range, where the pilot's own inputs are.
+        run(&mut filter, &[(40.0, 1.0)], 1.0);
+        assert!(filter.centers() == [None, None]);
+        assert!(sine_gain(&mut filter, 40.0, SAMPLE_HZ) > 0.99);
+    }
+
+    #[test]
+    fn notches_a_single_tone() {
+        let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+        run(&mut filter, &[(10.0, 0.2), (180.0, 1.0)], 1.0);
+
+        let [center, other] = sorted_centers(&filter);
+        assert_center(center, 180.0);
+        assert!(other.is_none());
+        assert!(sine_gain(&mut filter, 180.0, SAMPLE_HZ) < 0.1);
+    }
+
+    #[test]
+    fn notches_two_tones() {
+        let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+        run(&mut filter, &[(150.0, 1.0), (320.0, 0.5)], 1.0);
+
+        let [low, high] = sorted_centers(&filter);
+        assert_center(low, 150.0);
+        assert_center(high, 320.0);
+
+        // The tones are still there while the gain is measured, so the
+        // notches stay on them.
+        let mut attenuated = 0.0f32;
+        for n in 0..1000 {
+            let t = n as f32 / SAMPLE_HZ;
+            let tones = libm::sinf(2.0 * PI * 150.0 * t) + 0.5 * libm::sinf(2.0 * PI * 320.0 * t);
+            let output = filter.apply(tones);
+            if n > 500 {
+                attenuated = attenuated.max(libm::fabsf(output));
+            }
+        }
+        assert!(attenuated < 0.15, "{}", attenuated);
+    }
+
+    #[test]
+    fn follows_a_moving_tone() {
+        let mut filter = DynamicNotch::new(
+            Config {
+                notch_count: 1,
+                ..CONFIG
+            },
+            SAMPLE_HZ,
+        );
+        run(&mut filter, &[(150.0, 1.0)], 1.0);
+        assert_center(filter.centers()[0], 150.0);
+
+        run(&mut filter, &[(250.0, 1.0)], 1.0);
+        assert_center(filter.centers()[0], 250.0);
+    }
+
+    #[test]
+    fn passes_low_frequencies() {
+        let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+        run(&mut filter, &[(200.0, 1.0)], 1.0);
+
+        let mut peak = 0.0f32;
+        for n in 0..1000 {
+            let t = n as f32 / SAMPLE_HZ;
+            let input = 0.2 * libm::sinf(2.0 * PI * 10.0 * t) + libm::sinf(2.0 * PI * 200.0 * t);
+            let output = filter.apply(input);
+            if n > 500 {
+                peak = peak.max(libm::fabsf(output));
+            }
+        }
+        assert!(peak > 0.19 && peak < 0.23, "{}", peak);
+    }
+
+    #[test]
+    fn notch_stays_when_its_peak_fades() {
+        let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+        run(&mut filter, &[(200.0, 1.0)], 1.0);
+        // Until the tone has left the analysed samples.
+        run(&mut filter, &[], 0.2);
+        let before = sorted_centers(&filter);
+        assert_center(before[0], 200.0);
+
+        run(&mut filter, &[], 1.0);
+        assert!(sorted_centers(&filter) == before);
+    }
+
+    #[test]
+    fn analyses_at_the_phase() {
+        for phase in [0, ANALYSIS_INTERVAL / 3, 2 * ANALYSIS_INTERVAL / 3] {
+            let mut filter = DynamicNotch::new(CONFIG, SAMPLE_HZ);
+            filter.set_analysis_phase(phase);
+
+            // The tone doesn't repeat every interval, so the notch moves a
+            // little at every analysis.
+            let mut analyses = 0;
+            let mut center = None;
+            for n in 0..1000 {
+                let t = n as f32 / SAMPLE_HZ;
+                filter.apply(libm::sinf(2.0 * PI * 153.0 * t));
+                if filter.centers()[0] != center {
+                    center = filter.centers()[0];
+                    analyses += 1;
+                    assert_eq!((n + 1) % ANALYSIS_INTERVAL, phase, "sample {}", n);
+                }
+            }
+            assert!(analyses > 10, "{}", analyses);
+        }
+    }
+
+    #[test]
+    fn notch_count_is_limited() {
+        let filter = DynamicNotch::new(
+            Config {
+                notch_count: 10,
+                ..CONFIG
+            },
+            SAMPLE_HZ,
+        );
+        assert_eq!(filter.centers().len(), MAX_NOTCHES);
+    }
+}
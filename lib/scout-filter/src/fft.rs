@@ -0,0 +1,149 @@
+//! Radix-2 fast Fourier transform
+
+use core::f32::consts::PI;
+
+/// A complex FFT of `N` points, where `N` is a power of two.
+pub struct Fft<const N: usize> {
+    /// `cos` and `sin` of `-2 pi k / N`, of which only the first half is
+    /// used.
+    cos: [f32; N],
+    sin: [f32; N],
+}
+
+impl<const N: usize> Fft<N> {
+    /// # Panics
+    ///
+    /// If `N` is not a power of two.
+    pub fn new() -> Self {
+        assert!(N.is_power_of_two());
+
+        let mut cos = [0.0; N];
+        let mut sin = [0.0; N];
+        for k in 0..N / 2 {
+            let (s, c) = libm::sincosf(-2.0 * PI * k as f32 / N as f32);
+            cos[k] = c;
+            sin[k] = s;
+        }
+
+        Self { cos, sin }
+    }
+
+    /// Transforms `re` and `im` in place.
+    pub fn transform(&self, re: &mut [f32; N], im: &mut [f32; N]) {
+        let bits = N.trailing_zeros();
+        if bits == 0 {
+            return;
+        }
+
+        for i in 0..N {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if j > i {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= N {
+            let step = N / len;
+            for start in (0..N).step_by(len) {
+                for k in 0..len / 2 {
+                    let (c, s) = (self.cos[k * step], self.sin[k * step]);
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let t_re = re[b] * c - im[b] * s;
+                    let t_im = re[b] * s + im[b] * c;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            len *= 2;
+        }
+    }
+
+    /// Writes the magnitude of each frequency bin of a real signal to the
+    /// first half of `signal`, where bin `k` is at `k * sample_hz / N`. The
+    /// second half is left as scratch.
+    pub fn magnitudes(&self, signal: &mut [f32; N]) {
+        let mut im = [0.0; N];
+        self.transform(signal, &mut im);
+
+        for k in 0..N / 2 {
+            signal[k] = libm::sqrtf(signal[k] * signal[k] + im[k] * im[k]);
+        }
+    }
+}
+
+impl<const N: usize> Default for Fft<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: usize = 64;
+
+    #[test]
+    fn sine_lands_in_its_bin() {
+        let fft = Fft::<N>::new();
+        for bin in [1, 5, 17, 31] {
+            let mut signal = [0.0; N];
+            for (i, sample) in signal.iter_mut().enumerate() {
+                *sample = libm::sinf(2.0 * PI * (bin * i) as f32 / N as f32);
+            }
+            fft.magnitudes(&mut signal);
+
+            for (k, magnitude) in signal[..N / 2].iter().enumerate() {
+                if k == bin {
+                    assert!(libm::fabsf(magnitude - N as f32 / 2.0) < 1e-3);
+                } else {
+                    assert!(*magnitude < 1e-3, "bin {}: {}", k, magnitude);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn impulse_is_flat() {
+        let fft = Fft::<N>::new();
+        let mut re = [0.0; N];
+        let mut im = [0.0; N];
+        re[0] = 1.0;
+        fft.transform(&mut re, &mut im);
+
+        for k in 0..N {
+            assert!(libm::fabsf(re[k] - 1.0) < 1e-6);
+            assert!(libm::fabsf(im[k]) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn constant_is_dc() {
+        let fft = Fft::<N>::new();
+        let mut signal = [0.5; N];
+        fft.magnitudes(&mut signal);
+
+        assert!(libm::fabsf(signal[0] - 0.5 * N as f32) < 1e-4);
+        assert!(signal[1..N / 2].iter().all(|magnitude| *magnitude < 1e-4));
+    }
+
+    #[test]
+    fn complex_exponential_has_one_sided_spectrum() {
+        let fft = Fft::<N>::new();
+        let mut re = [0.0; N];
+        let mut im = [0.0; N];
+        for i in 0..N {
+            let (s, c) = libm::sincosf(2.0 * PI * (3 * i) as f32 / N as f32);
+            re[i] = c;
+            im[i] = s;
+        }
+        fft.transform(&mut re, &mut im);
+
+        assert!(libm::fabsf(re[3] - N as f32) < 1e-3);
+        assert!(libm::fabsf(re[N - 3]) < 1e-3);
+    }
+}
@@ -0,0 +1,42 @@
+#![no_std]
+
+mod biquad;
+pub use biquad::Biquad;
+
+pub mod dynamic_notch;
+pub use dynamic_notch::DynamicNotch;
+
+mod fft;
+pub use fft::Fft;
+
+mod pt;
+pub use pt::{Pt1, Pt2};
+
+pub mod rpm_notch;
+pub use rpm_notch::RpmNotch;
+
+/// A filter which processes one sample at a time.
+pub trait Filter {
+    fn apply(&mut self, input: f32) -> f32;
+
+    /// Clears the filter's history, so that the next output follows the
+    /// next input.
+    fn reset(&mut self);
+}
+
+/// Peak output of `filter` for a unit sine at `frequency_hz`, over the
+/// second after it has had a second to settle.
+#[cfg(test)]
+fn sine_gain(filter: &mut impl Filter, frequency_hz: f32, sample_hz: f32) -> f32 {
+    let samples = sample_hz as usize;
+    let mut peak: f32 = 0.0;
+    for n in 0..2 * samples {
+        let phase = 2.0 * core::f32::consts::PI * frequency_hz * n as f32 / sample_hz;
+        let output = filter.apply(libm::sinf(phase));
+        if n >= samples {
+            peak = peak.max(libm::fabsf(output));
+        }
+    }
+
+    peak
+}
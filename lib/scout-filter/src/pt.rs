@@ -0,0 +1,161 @@
+//! Low pass filters made of cascaded RC stages
+
+use core::f32::consts::PI;
+
+use crate::Filter;
+
+/// Gain of an RC stage with the given cutoff at the given sample rate.
+fn gain(cutoff_hz: f32, sample_hz: f32) -> f32 {
+    if cutoff_hz <= 0.0 {
+        return 1.0;
+    }
+
+    let rc = 1.0 / (2.0 * PI * cutoff_hz);
+    let dt = 1.0 / sample_hz;
+
+    dt / (rc + dt)
+}
+
+/// A first order low pass filter. A cutoff of zero disables the filter.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Pt1 {
+    k: f32,
+    state: f32,
+}
+
+impl Pt1 {
+    pub fn new(cutoff_hz: f32, sample_hz: f32) -> Self {
+        Self {
+            k: gain(cutoff_hz, sample_hz),
+            state: 0.0,
+        }
+    }
+
+    pub fn set_cutoff(&mut self, cutoff_hz: f32, sample_hz: f32) {
+        self.k = gain(cutoff_hz, sample_hz);
+    }
+}
+
+impl Filter for Pt1 {
+    fn apply(&mut self, input: f32) -> f32 {
+        self.state += (input - self.state) * self.k;
+
+        self.state
+    }
+
+    fn reset(&mut self) {
+        self.state = 0.0;
+    }
+}
+
+/// A second order low pass filter of two identical RC stages, with no
+/// overshoot. The stages' cutoff is raised so that the whole filter is 3dB
+/// down at the given cutoff.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Pt2 {
+    k: f32,
+    state: [f32; 2],
+}
+
+/// 1 / sqrt(2^(1/2) - 1), the cutoff correction for two stages.
+const PT2_CUTOFF_CORRECTION: f32 = 1.553_774;
+
+impl Pt2 {
+    pub fn new(cutoff_hz: f32, sample_hz: f32) -> Self {
+        Self {
+            k: gain(cutoff_hz * PT2_CUTOFF_CORRECTION, sample_hz),
+            state: [0.0; 2],
+        }
+    }
+
+    pub fn set_cutoff(&mut self, cutoff_hz: f32, sample_hz: f32) {
+        self.k = gain(cutoff_hz * PT2_CUTOFF_CORRECTION, sample_hz);
+    }
+}
+
+impl Filter for Pt2 {
+    fn apply(&mut self, input: f32) -> f32 {
+        self.state[0] += (input - self.state[0]) * self.k;
+        self.state[1] += (self.state[0] - self.state[1]) * self.k;
+
+        self.state[1]
+    }
+
+    fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sine_gain;
+
+    /// High enough that the responses are close to those of the analog
+    /// filters they approximate.
+    const SAMPLE_HZ: f32 = 8000.0;
+
+    #[test]
+    fn pt1_response() {
+        let gain = |frequency| sine_gain(&mut Pt1::new(50.0, SAMPLE_HZ), frequency, SAMPLE_HZ);
+        assert!(gain(2.0) > 0.99);
+        let cutoff = gain(50.0);
+        assert!((0.68..0.74).contains(&cutoff), "{}", cutoff);
+        // First order, so 20dB per decade.
+        assert!(gain(400.0) < 0.15);
+    }
+
+    #[test]
+    fn pt1_step_reaches_63_percent_after_one_time_constant() {
+        let cutoff_hz = 10.0;
+        let mut filter = Pt1::new(cutoff_hz, SAMPLE_HZ);
+        let samples = (SAMPLE_HZ / (2.0 * PI * cutoff_hz)) as usize;
+        let mut output = 0.0;
+        for _ in 0..samples {
+            output = filter.apply(1.0);
+        }
+        assert!((0.6..0.66).contains(&output), "{}", output);
+    }
+
+    #[test]
+    fn pt2_response() {
+        let gain = |frequency| sine_gain(&mut Pt2::new(50.0, SAMPLE_HZ), frequency, SAMPLE_HZ);
+        assert!(gain(2.0) > 0.99);
+        let cutoff = gain(50.0);
+        assert!((0.68..0.74).contains(&cutoff), "{}", cutoff);
+        assert!(gain(400.0) < 0.05);
+    }
+
+    #[test]
+    fn pt2_step_has_no_overshoot() {
+        let mut filter = Pt2::new(50.0, SAMPLE_HZ);
+        let mut previous = 0.0;
+        for _ in 0..500 {
+            let output = filter.apply(1.0);
+            assert!(output >= previous && output <= 1.0);
+            previous = output;
+        }
+        assert!(previous > 0.999);
+    }
+
+    #[test]
+    fn zero_cutoff_disables_the_filter() {
+        let mut pt1 = Pt1::new(0.0, SAMPLE_HZ);
+        let mut pt2 = Pt2::new(0.0, SAMPLE_HZ);
+        for input in [1.0, -3.0, 0.25] {
+            assert_eq!(pt1.apply(input), input);
+            assert_eq!(pt2.apply(input), input);
+        }
+    }
+
+    #[test]
+    fn set_cutoff_keeps_the_state() {
+        let mut filter = Pt1::new(10.0, SAMPLE_HZ);
+        let before = filter.apply(1.0);
+        filter.set_cutoff(0.0, SAMPLE_HZ);
+        assert_eq!(filter.apply(before), before);
+
+        filter.reset();
+        assert_eq!(Pt2::new(10.0, SAMPLE_HZ).apply(0.0), 0.0);
+    }
+}
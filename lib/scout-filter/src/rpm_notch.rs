@@ -0,0 +1,393 @@
+//! Notch filters on the harmonics of each motor's rotation
+//!
+//! With motor speeds reported by the ESCs, notches can be placed exactly on
+//! the vibration the motors cause, rather than searched for in the gyro
+//! spectrum. Each motor has a notch on its rotation frequency and on each
+//! harmonic up to `Config::harmonics`.
+//!
+//! A notch fades out as its frequency falls towards `min_hz`, where it
+//! would otherwise cut into the frequencies the pilot controls, and as it
+//! approaches the Nyquist frequency. When a motor's telemetry stops, its
+//! notches stay where they were for `telemetry_timeout` and then fade out
+//! over `fade_time`, leaving the other filters to deal with its noise. When
+//! the telemetry returns, the notches move straight to the motor's new speed
+//! but fade back in over `fade_time`, rather than cutting in at full depth
+//! at a frequency they have not been filtering.
+
+use crate::{Biquad, Filter};
+
+pub const MAX_MOTORS: usize = 8;
+pub const MAX_HARMONICS: usize = 3;
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Config {
+    /// Number of harmonics notched, including the fundamental, up to
+    /// `MAX_HARMONICS`.
+    pub harmonics: usize,
+    pub q: f32,
+    /// Notches are disabled below `min_hz`, and fade in over the next
+    /// `fade_range_hz`.
+    pub min_hz: f32,
+    pub fade_range_hz: f32,
+    /// Seconds for which a motor's last frequency is kept after its
+    /// telemetry stops.
+    pub telemetry_timeout: f32,
+    /// Seconds over which a motor's notches fade out once its telemetry has
+    /// timed out, and back in once it returns.
+    pub fade_time: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Motor {
+    /// Rotation frequency from the latest telemetry, in Hz.
+    frequency: Option<f32>,
+    /// Seconds since the latest telemetry.
+    telemetry_age: f32,
+    /// Weight applied to all of the motor's notches as its telemetry is lost
+    /// and regained, from 0.0 to 1.0.
+    telemetry_weight: f32,
+    notches: [Biquad; MAX_HARMONICS],
+    weights: [f32; MAX_HARMONICS],
+}
+
+pub struct RpmNotch {
+    config: Config,
+    sample_hz: f32,
+    motor_count: usize,
+    motors: [Motor; MAX_MOTORS],
+}
+
+impl RpmNotch {
+    /// Notches are updated at `sample_hz`, which must be the rate at which
+    /// both `update` and `apply` are called.
+    pub fn new(config: Config, motor_count: usize, sample_hz: f32) -> Self {
+        Self {
+            config: Config {
+                harmonics: config.harmonics.min(MAX_HARMONICS),
+                ..config
+            },
+            sample_hz,
+            motor_count: motor_count.min(MAX_MOTORS),
+            motors: [Motor {
+                frequency: None,
+                telemetry_age: 0.0,
+                telemetry_weight: 0.0,
+                notches: [Biquad::passthrough(); MAX_HARMONICS],
+                weights: [0.0; MAX_HARMONICS],
+            }; MAX_MOTORS],
+        }
+    }
+
+    /// Moves the notches to the rotation frequency of each motor, in Hz, or
+    /// `None` for motors whose telemetry is missing.
+    pub fn update(&mut self, motor_hz: &[Option<f32>]) {
+        let dt = 1.0 / self.sample_hz;
+        let config = &self.config;
+
+        for (i, motor) in self.motors[..self.motor_count].iter_mut().enumerate() {
+            match motor_hz.get(i).copied().flatten() {
+                Some(frequency) => {
+                    motor.frequency = Some(frequency);
+                    motor.telemetry_age = 0.0;
+                    motor.telemetry_weight = if config.fade_time > 0.0 {
+                        (motor.telemetry_weight + dt / config.fade_time).min(1.0)
+                    } else {
+                        1.0
+                    };
+                }
+                None => {
+                    motor.telemetry_age += dt;
+                    if motor.telemetry_age > config.telemetry_timeout {
+                        motor.telemetry_weight = if config.fade_time > 0.0 {
+                            (motor.telemetry_weight - dt / config.fade_time).max(0.0)
+                        } else {
+                            0.0
+                        };
+                    }
+                }
+            }
+
+            let Some(fundamental) = motor.frequency else { continue };
+            for harmonic in 0..config.harmonics {
+                let frequency = fundamental * (harmonic + 1) as f32;
+                motor.weights[harmonic] =
+                    fade(frequency, config, self.sample_hz) * motor.telemetry_weight;
+                if motor.weights[harmonic] > 0.0 {
+                    motor.notches[harmonic].set_notch(frequency, self.sample_hz, config.q);
+                }
+            }
+        }
+    }
+
+    /// Weights of each motor's notches, from 0.0 when disabled to 1.0 when
+    /// fully applied.
+    pub fn weights(&self) -> impl Iterator<Item = &[f32]> {
+        let harmonics = self.config.harmonics;
+
+        self.motors[..self.motor_count]
+            .iter()
+            .map(move |motor| &motor.weights[..harmonics])
+    }
+}
+
+/// Weight of a notch at `frequency`, fading in above `min_hz` and out
+/// towards the Nyquist frequency.
+fn fade(frequency: f32, config: &Config, sample_hz: f32) -> f32 {
+    let nyquist = sample_hz / 2.0;
+    // Notches are never placed closer to the Nyquist frequency than this
+    // fraction of it, where they would be too wide to be useful.
+    const MAX_NYQUIST_FRACTION: f32 = 0.95;
+    let ramp = |distance: f32| {
+        if config.fade_range_hz > 0.0 {
+            (distance / config.fade_range_hz).clamp(0.0, 1.0)
+        } else if distance > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    };
+
+    ramp(frequency - config.min_hz).min(ramp(nyquist * MAX_NYQUIST_FRACTION - frequency))
+}
+
+impl Filter for RpmNotch {
+    fn apply(&mut self, input: f32) -> f32 {
+        let harmonics = self.config.harmonics;
+        let mut sample = input;
+        for motor in &mut self.motors[..self.motor_count] {
+            for (notch, weight) in motor.notches[..harmonics]
+                .iter_mut()
+                .zip(&motor.weights[..harmonics])
+            {
+                // The notch keeps running while disabled, so that it has no
+                // transient when it fades back in.
+                let filtered = notch.apply(sample);
+                sample += (filtered - sample) * weight;
+            }
+        }
+
+        sample
+    }
+
+    fn reset(&mut self) {
+        for motor in &mut self.motors {
+            for notch in &mut motor.notches {
+                notch.reset();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sine_gain;
+    use core::f32::consts::PI;
+
+    /// A power of two, so that the fades add up to whole samples.
+    const SAMPLE_HZ: f32 = 4096.0;
+
+    const CONFIG: Config = Config {
+        harmonics: 3,
+        q: 5.0,
+        min_hz: 80.0,
+        fade_range_hz: 40.0,
+        telemetry_timeout: 1.0 / 16.0,
+        fade_time: 0.25,
+    };
+
+    /// Rotation frequencies of four motors, in Hz.
+    const MOTOR_HZ: [f32; 4] = [150.0, 165.0, 180.0, 195.0];
+    /// Amplitude of the vibration at each harmonic.
+    const HARMONIC_AMPLITUDES: [f32; 3] = [1.0, 0.5, 0.3];
+
+    fn assert_close(a: f32, b: f32) {
+        assert!(libm::fabsf(a - b) < 1e-3, "{} != {}", a, b);
+    }
+
+    fn telemetry(motor_hz: [f32; 4]) -> [Option<f32>; 4] {
+        motor_hz.map(Some)
+    }
+
+    fn update_for(notch: &mut RpmNotch, motor_hz: &[Option<f32>], seconds: f32) {
+        for _ in 0..(seconds * SAMPLE_HZ) as usize {
+            notch.update(motor_hz);
+        }
+    }
+
+    /// Vibration of the motors at sample `n`, each motor with its own phase.
+    fn motor_noise(n: usize) -> f32 {
+        let t = n as f32 / SAMPLE_HZ;
+        let mut sample = 0.0;
+        for (motor, hz) in MOTOR_HZ.iter().enumerate() {
+            for (harmonic, amplitude) in HARMONIC_AMPLITUDES.iter().enumerate() {
+                let cycles = hz * (harmonic + 1) as f32 * t;
+                let phase = 2.0 * PI * (cycles - libm::floorf(cycles)) + motor as f32;
+                sample += amplitude * libm::sinf(phase);
+            }
+        }
+
+        sample
+    }
+
+    /// Peak output for the motor vibration over the second after it has had
+    /// a second to settle, with the notches following `motor_hz`.
+    fn noise_peak(notch: &mut RpmNotch, motor_hz: &[Option<f32>]) -> f32 {
+        let samples = SAMPLE_HZ as usize;
+        let mut peak: f32 = 0.0;
+        for n in 0..2 * samples {
+            notch.update(motor_hz);
+            let output = notch.apply(motor_noise(n));
+            if n >= samples {
+                peak = peak.max(libm::fabsf(output));
+            }
+        }
+
+        peak
+    }
+
+    #[test]
+    fn attenuates_motor_harmonics() {
+        let mut notch = RpmNotch::new(CONFIG, 4, SAMPLE_HZ);
+        let noise = noise_peak(&mut notch, &telemetry(MOTOR_HZ));
+        assert!(noise < 0.05, "{}", noise);
+    }
+
+    #[test]
+    fn passes_motor_noise_without_telemetry() {
+        let mut notch = RpmNotch::new(CONFIG, 4, SAMPLE_HZ);
+        let noise = noise_peak(&mut notch, &[None; 4]);
+        assert!(noise > 5.0, "{}", noise);
+        assert!(notch.weights().flatten().all(|weight| *weight == 0.0));
+    }
+
+    #[test]
+    fn passes_frequencies_between_the_harmonics() {
+        let mut notch = RpmNotch::new(CONFIG, 4, SAMPLE_HZ);
+        update_for(&mut notch, &telemetry(MOTOR_HZ), 1.0);
+
+        assert!(sine_gain(&mut notch, 10.0, SAMPLE_HZ) > 0.99);
+        assert!(sine_gain(&mut notch, 1000.0, SAMPLE_HZ) > 0.95);
+    }
+
+    #[test]
+    fn each_motor_notches_its_own_harmonics() {
+        let mut notch = RpmNotch::new(CONFIG, 4, SAMPLE_HZ);
+        // Only the first motor reports its speed.
+        let noise = noise_peak(&mut notch, &[Some(MOTOR_HZ[0]), None, None, None]);
+        assert!(noise > 3.0, "{}", noise);
+
+        let mut notch = RpmNotch::new(CONFIG, 4, SAMPLE_HZ);
+        update_for(&mut notch, &[Some(MOTOR_HZ[0]), None, None, None], 1.0);
+        assert!(sine_gain(&mut notch, MOTOR_HZ[0], SAMPLE_HZ) < 0.01);
+        assert!(sine_gain(&mut notch, MOTOR_HZ[0] * 2.0, SAMPLE_HZ) < 0.01);
+        assert!(sine_gain(&mut notch, MOTOR_HZ[0] * 3.0, SAMPLE_HZ) < 0.01);
+    }
+
+    #[test]
+    fn fades_out_when_telemetry_stops() {
+        let mut notch = RpmNotch::new(CONFIG, 1, SAMPLE_HZ);
+        update_for(&mut notch, &[Some(200.0)], 1.0);
+        assert!(notch.weights().flatten().all(|weight| *weight == 1.0));
+
+        // Held until the timeout.
+        update_for(&mut notch, &[None], CONFIG.telemetry_timeout);
+        assert!(notch.weights().flatten().all(|weight| *weight == 1.0));
+
+        update_for(&mut notch, &[None], CONFIG.fade_time / 2.0);
+        for weight in notch.weights().flatten() {
+            assert_close(*weight, 0.5);
+        }
+
+        update_for(&mut notch, &[None], CONFIG.fade_time / 2.0);
+        assert!(notch.weights().flatten().all(|weight| *weight == 0.0));
+        assert!(sine_gain(&mut notch, 200.0, SAMPLE_HZ) > 0.99);
+    }
+
+    #[test]
+    fn fades_in_when_telemetry_returns() {
+        let mut notch = RpmNotch::new(CONFIG, 1, SAMPLE_HZ);
+        update_for(&mut notch, &[Some(200.0)], 1.0);
+        update_for(&mut notch, &[None], 1.0);
+        assert!(notch.weights().flatten().all(|weight| *weight == 0.0));
+
+        // The motor has sped up while its telemetry was missing.
+        notch.update(&[Some(250.0)]);
+        for weight in notch.weights().flatten() {
+            assert!(*weight > 0.0 && *weight < 0.01, "{}", weight);
+        }
+
+        update_for(&mut notch, &[Some(250.0)], CONFIG.fade_time / 2.0);
+        for weight in notch.weights().flatten() {
+            assert_close(*weight, 0.5);
+        }
+
+        update_for(&mut notch, &[Some(250.0)], CONFIG.fade_time / 2.0);
+        assert!(notch.weights().flatten().all(|weight| *weight == 1.0));
+        assert!(sine_gain(&mut notch, 250.0, SAMPLE_HZ) < 0.01);
+    }
+
+    #[test]
+    fn fades_in_from_the_last_weight() {
+        let mut notch = RpmNotch::new(CONFIG, 1, SAMPLE_HZ);
+        update_for(&mut notch, &[Some(200.0)], 1.0);
+        update_for(
+            &mut notch,
+            &[None],
+            CONFIG.telemetry_timeout + CONFIG.fade_time / 4.0,
+        );
+
+        update_for(&mut notch, &[Some(200.0)], CONFIG.fade_time / 8.0);
+        for weight in notch.weights().flatten() {
+            assert_close(*weight, 0.875);
+        }
+    }
+
+    #[test]
+    fn no_fade_time_switches_immediately() {
+        let config = Config {
+            fade_time: 0.0,
+            ..CONFIG
+        };
+        let mut notch = RpmNotch::new(config, 1, SAMPLE_HZ);
+        notch.update(&[Some(200.0)]);
+        assert!(notch.weights().flatten().all(|weight| *weight == 1.0));
+
+        update_for(&mut notch, &[None], config.telemetry_timeout);
+        assert!(notch.weights().flatten().all(|weight| *weight == 1.0));
+        notch.update(&[None]);
+        assert!(notch.weights().flatten().all(|weight| *weight == 0.0));
+    }
+
+    #[test]
+    fn fades_near_min_hz_and_nyquist() {
+        let mut notch = RpmNotch::new(CONFIG, 3, SAMPLE_HZ);
+        // Notches are placed up to 95% of the Nyquist frequency, 1945.6 Hz.
+        update_for(&mut notch, &[Some(60.0), Some(100.0), Some(700.0)], 1.0);
+
+        let expected = [[0.0, 1.0, 1.0], [0.5, 1.0, 1.0], [1.0, 1.0, 0.0]];
+        for (weights, expected) in notch.weights().zip(expected) {
+            for (weight, expected) in weights.iter().zip(expected) {
+                assert_close(*weight, expected);
+            }
+        }
+
+        // Below `min_hz`, low frequencies are left alone.
+        let mut notch = RpmNotch::new(CONFIG, 1, SAMPLE_HZ);
+        update_for(&mut notch, &[Some(40.0)], 1.0);
+        assert!(sine_gain(&mut notch, 40.0, SAMPLE_HZ) > 0.99);
+    }
+
+    #[test]
+    fn limits_harmonics_and_motors() {
+        let config = Config {
+            harmonics: 10,
+            ..CONFIG
+        };
+        let notch = RpmNotch::new(config, 20, SAMPLE_HZ);
+        assert_eq!(notch.weights().count(), MAX_MOTORS);
+        assert!(notch
+            .weights()
+            .all(|weights| weights.len() == MAX_HARMONICS));
+    }
+}
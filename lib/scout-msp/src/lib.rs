@@ -0,0 +1,296 @@
+#![no_std]
+
+//! MultiWii Serial Protocol
+//!
+//! MSP v1 frames are laid out as `$M<`, the payload length, the command,
+//! the payload and an XOR checksum of everything after `<`. MSP v2 frames
+//! are laid out as `$X<`, a flag byte, a 16 bit command, a 16 bit payload
+//! length, the payload and a CRC-8/DVB-S2 of everything after `<`. Both are
+//! little endian. Requests from the configurator use `<`, responses `>`,
+//! and responses to requests which failed `!`.
+//!
+//! Responses are sent in the same version as the request they answer.
+
+mod request;
+mod response;
+
+pub use request::{Command, ModeRange, Pid, Request};
+pub use response::{ModeBox, MotorProtocol, Response, Sensors, Status};
+
+/// The largest payload accepted or sent. Requests with longer payloads are
+/// dropped.
+pub const MAX_PAYLOAD_LEN: usize = 64;
+
+/// `$`, the version, the direction and the v1 length and command bytes.
+const V1_HEADER_LEN: usize = 5;
+/// `$`, the version, the direction, the flag, and the v2 command and length.
+const V2_HEADER_LEN: usize = 8;
+
+/// The largest frame, including the header and checksum.
+pub const MAX_FRAME_LEN: usize = V2_HEADER_LEN + MAX_PAYLOAD_LEN + 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    const fn byte(&self) -> u8 {
+        match self {
+            Version::V1 => b'M',
+            Version::V2 => b'X',
+        }
+    }
+}
+
+pub struct Parser {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// True while part of a frame has been received, so that bytes shared
+    /// with other traffic on the port belong to MSP until it completes.
+    pub fn is_receiving(&self) -> bool {
+        self.len > 0
+    }
+
+    /// Returns a request when `byte` completes a valid frame.
+    pub fn push(&mut self, byte: u8) -> Option<Request> {
+        let expected = match self.len {
+            0 => byte == b'$',
+            1 => byte == Version::V1.byte() || byte == Version::V2.byte(),
+            2 => byte == b'<',
+            _ => true,
+        };
+        if !expected {
+            // The byte may start the next frame.
+            self.len = 0;
+            if byte != b'$' {
+                return None;
+            }
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        let frame_len = match self.buf[1] {
+            b'M' if self.len >= V1_HEADER_LEN => V1_HEADER_LEN + self.buf[3] as usize + 1,
+            b'X' if self.len >= V2_HEADER_LEN => {
+                V2_HEADER_LEN + u16::from_le_bytes([self.buf[6], self.buf[7]]) as usize + 1
+            }
+            _ => return None,
+        };
+        if frame_len > MAX_FRAME_LEN {
+            self.len = 0;
+            return None;
+        }
+        if self.len < frame_len {
+            return None;
+        }
+        self.len = 0;
+
+        // The checksum covers everything after the direction byte.
+        let (checksum, body) = self.buf[3..frame_len].split_last()?;
+        let (version, command, payload) = match self.buf[1] {
+            b'M' if xor(body) == *checksum => (Version::V1, body[1] as u16, &body[2..]),
+            b'X' if crc8_dvb_s2(body) == *checksum => (
+                Version::V2,
+                u16::from_le_bytes([body[1], body[2]]),
+                &body[5..],
+            ),
+            _ => return None,
+        };
+
+        Some(Request {
+            version,
+            command: Command::parse(command, payload),
+        })
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn xor(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+fn crc8_dvb_s2(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0xd5
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{MSP_SET_MODE_RANGE, MSP_SET_PID};
+
+    /// Pushes every byte, returning the number of requests and the last one.
+    fn push_all(parser: &mut Parser, bytes: &[u8]) -> (usize, Option<Request>) {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .fold((0, None), |(count, _), request| (count + 1, Some(request)))
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Request> {
+        let (count, request) = push_all(&mut Parser::new(), bytes);
+        assert!(count <= 1);
+
+        request
+    }
+
+    #[test]
+    fn checksums() {
+        // The standard check value of CRC-8/DVB-S2.
+        assert_eq!(crc8_dvb_s2(b"123456789"), 0xbc);
+        assert_eq!(xor(&[0x00, 0x64]), 0x64);
+        assert_eq!(xor(&[]), 0);
+    }
+
+    #[test]
+    fn parses_v1_request() {
+        let request = parse(b"$M<\x00\x64\x64").unwrap();
+        assert!(request.version == Version::V1);
+        assert!(request.command == Command::Ident);
+    }
+
+    #[test]
+    fn parses_v2_request() {
+        let request = parse(&[b'$', b'X', b'<', 0, 101, 0, 0, 0, 202]).unwrap();
+        assert!(request.version == Version::V2);
+        assert!(request.command == Command::Status);
+    }
+
+    #[test]
+    fn parses_payloads() {
+        let request = parse(&[
+            b'$', b'M', b'<', 9, 202, 40, 30, 25, 42, 32, 27, 60, 45, 0, 236,
+        ])
+        .unwrap();
+        let pid = |p, i, d| Pid { p, i, d };
+        assert!(
+            request.command == Command::SetPid([pid(40, 30, 25), pid(42, 32, 27), pid(60, 45, 0)])
+        );
+
+        let range = ModeRange {
+            box_id: 1,
+            aux_channel: 0,
+            start_step: 32,
+            end_step: 48,
+        };
+        let request = parse(&[b'$', b'M', b'<', 5, 35, 2, 1, 0, 32, 48, 53]).unwrap();
+        assert!(request.command == Command::SetModeRange { index: 2, range });
+        // As Betaflight configurators send it, with the mode logic and linked
+        // mode on the end.
+        let request = parse(&[b'$', b'M', b'<', 7, 35, 2, 1, 0, 32, 48, 0, 0, 55]).unwrap();
+        assert!(request.command == Command::SetModeRange { index: 2, range });
+    }
+
+    #[test]
+    fn short_payloads_are_unsupported() {
+        let request = parse(&[b'$', b'M', b'<', 4, 35, 2, 1, 0, 32, 4]).unwrap();
+        assert!(request.command == Command::Unsupported(MSP_SET_MODE_RANGE));
+
+        let request = parse(&[b'$', b'M', b'<', 0, 202, 202]).unwrap();
+        assert!(request.command == Command::Unsupported(MSP_SET_PID));
+    }
+
+    #[test]
+    fn unknown_commands_are_unsupported() {
+        let request = parse(&[b'$', b'M', b'<', 0, 0xfe, 0xfe]).unwrap();
+        assert!(request.command == Command::Unsupported(0xfe));
+
+        // V2 commands can be larger than a byte.
+        let body = [0, 0x01, 0x10, 0, 0];
+        let mut frame = [b'$', b'X', b'<', 0, 0, 0, 0, 0, crc8_dvb_s2(&body)];
+        frame[3..8].copy_from_slice(&body);
+        let request = parse(&frame).unwrap();
+        assert!(request.command == Command::Unsupported(0x1001));
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        assert!(parse(b"$M<\x00\x64\x65").is_none());
+        assert!(parse(&[b'$', b'X', b'<', 0, 101, 0, 0, 0, 203]).is_none());
+        // A v1 checksum on a v2 frame.
+        assert!(parse(&[b'$', b'X', b'<', 0, 101, 0, 0, 0, 101]).is_none());
+    }
+
+    #[test]
+    fn ignores_responses() {
+        assert!(parse(b"$M>\x00\x64\x64").is_none());
+        assert!(parse(b"$M!\x00\x64\x64").is_none());
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let mut parser = Parser::new();
+        assert_eq!(push_all(&mut parser, b"hello $$M$X").0, 0);
+        assert_eq!(push_all(&mut parser, b"$M<\x00\x64\x64").0, 1);
+
+        // A frame cut short by the next one.
+        assert_eq!(push_all(&mut parser, b"$M<\x05\x23").0, 0);
+        assert!(parser.is_receiving());
+        assert_eq!(push_all(&mut parser, b"$M<\x00\x64\x64").0, 0);
+        // The cut short frame's length swallowed the next frame, and the
+        // parser finds the one after.
+        assert!(!parser.is_receiving());
+        assert_eq!(push_all(&mut parser, b"$M<\x00\x64\x64").0, 1);
+    }
+
+    #[test]
+    fn drops_overlong_frames() {
+        let mut parser = Parser::new();
+        // A v2 frame with a 200 byte payload.
+        assert_eq!(
+            push_all(&mut parser, &[b'$', b'X', b'<', 0, 1, 0, 200, 0]).0,
+            0
+        );
+        assert!(!parser.is_receiving());
+        assert_eq!(push_all(&mut parser, b"$M<\x00\x64\x64").0, 1);
+
+        // The longest payload is accepted.
+        let mut frame = [0; V1_HEADER_LEN + MAX_PAYLOAD_LEN + 1];
+        frame[..V1_HEADER_LEN].copy_from_slice(&[b'$', b'M', b'<', MAX_PAYLOAD_LEN as u8, 0xfe]);
+        frame[V1_HEADER_LEN + MAX_PAYLOAD_LEN] = xor(&frame[3..V1_HEADER_LEN + MAX_PAYLOAD_LEN]);
+        assert_eq!(push_all(&mut parser, &frame).0, 1);
+    }
+
+    #[test]
+    fn is_receiving_during_frame() {
+        let mut parser = Parser::new();
+        assert!(!parser.is_receiving());
+        for byte in b"$M<\x00\x64" {
+            parser.push(*byte);
+            assert!(parser.is_receiving());
+        }
+        parser.push(0x64);
+        assert!(!parser.is_receiving());
+
+        // Other traffic doesn't start a frame.
+        parser.push(b'M');
+        assert!(!parser.is_receiving());
+    }
+}
@@ -0,0 +1,286 @@
+use crate::Version;
+
+pub(crate) const MSP_API_VERSION: u16 = 1;
+pub(crate) const MSP_FC_VARIANT: u16 = 2;
+pub(crate) const MSP_FC_VERSION: u16 = 3;
+pub(crate) const MSP_BOARD_INFO: u16 = 4;
+pub(crate) const MSP_MODE_RANGES: u16 = 34;
+pub(crate) const MSP_SET_MODE_RANGE: u16 = 35;
+pub(crate) const MSP_REBOOT: u16 = 68;
+pub(crate) const MSP_ADVANCED_CONFIG: u16 = 90;
+pub(crate) const MSP_SET_ADVANCED_CONFIG: u16 = 91;
+pub(crate) const MSP_IDENT: u16 = 100;
+pub(crate) const MSP_STATUS: u16 = 101;
+pub(crate) const MSP_RAW_IMU: u16 = 102;
+pub(crate) const MSP_MOTOR: u16 = 104;
+pub(crate) const MSP_RC: u16 = 105;
+pub(crate) const MSP_ATTITUDE: u16 = 108;
+pub(crate) const MSP_PID: u16 = 112;
+pub(crate) const MSP_BOXNAMES: u16 = 116;
+pub(crate) const MSP_BOXIDS: u16 = 119;
+pub(crate) const MSP_MOTOR_CONFIG: u16 = 131;
+pub(crate) const MSP_SET_PID: u16 = 202;
+pub(crate) const MSP_SET_MOTOR_CONFIG: u16 = 222;
+pub(crate) const MSP_EEPROM_WRITE: u16 = 250;
+
+/// Gains of one PID controller, in the units configurators show.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Pid {
+    pub p: u8,
+    pub i: u8,
+    pub d: u8,
+}
+
+/// A range of an aux channel which activates a mode, in steps of 25 us
+/// from 900 us, so that steps 0 to 48 cover 900 us to 2100 us. A range
+/// which doesn't end after it starts is unused.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct ModeRange {
+    /// The `ModeBox::id` of the mode.
+    pub box_id: u8,
+    /// The aux channel, counting from zero.
+    pub aux_channel: u8,
+    pub start_step: u8,
+    pub end_step: u8,
+}
+
+impl ModeRange {
+    pub fn is_used(&self) -> bool {
+        self.start_step < self.end_step
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    /// MSP protocol and API version.
+    ApiVersion,
+    /// Four letter identifier of the firmware.
+    FcVariant,
+    /// Firmware version.
+    FcVersion,
+    /// Four letter identifier of the board and its revision.
+    BoardInfo,
+    /// Every mode range slot.
+    ModeRanges,
+    /// Replaces the mode range in a slot.
+    SetModeRange {
+        index: u8,
+        range: ModeRange,
+    },
+    /// Legacy MultiWii identification, for older tools.
+    Ident,
+    Status,
+    RawImu,
+    Motor,
+    Rc,
+    Attitude,
+    /// Roll, pitch and yaw rate controller gains.
+    Pid,
+    /// Names of the modes reported by `Status`, separated by `;`.
+    BoxNames,
+    /// Permanent IDs of the modes reported by `Status`.
+    BoxIds,
+    /// Sets the roll, pitch and yaw rate controller gains. Any further
+    /// controllers in the request are ignored.
+    SetPid([Pid; 3]),
+    /// Motor protocol and rate, along with loop and gyro settings.
+    AdvancedConfig,
+    /// Sets the motor protocol, as a `MotorProtocol::id`, and the PWM rate.
+    /// The loop and gyro settings in the request are ignored.
+    SetAdvancedConfig {
+        motor_protocol: u8,
+        motor_rate_hz: u16,
+    },
+    /// Throttle range, motor count and poles, and whether bidirectional
+    /// DShot is used.
+    MotorConfig,
+    /// Sets whether bidirectional DShot is used, which is `None` from
+    /// configurators older than API 1.42. The throttle range in the request
+    /// is ignored.
+    SetMotorConfig {
+        dshot_telemetry: Option<bool>,
+    },
+    /// Saves the settings.
+    EepromWrite,
+    Reboot,
+    /// Unknown commands, and known commands with a payload too short for
+    /// them.
+    Unsupported(u16),
+}
+
+impl Command {
+    pub(crate) fn parse(code: u16, payload: &[u8]) -> Self {
+        match code {
+            MSP_API_VERSION => Command::ApiVersion,
+            MSP_FC_VARIANT => Command::FcVariant,
+            MSP_FC_VERSION => Command::FcVersion,
+            MSP_BOARD_INFO => Command::BoardInfo,
+            MSP_MODE_RANGES => Command::ModeRanges,
+            // Betaflight appends the mode logic and linked mode, which are
+            // ignored.
+            MSP_SET_MODE_RANGE if payload.len() >= 5 => Command::SetModeRange {
+                index: payload[0],
+                range: ModeRange {
+                    box_id: payload[1],
+                    aux_channel: payload[2],
+                    start_step: payload[3],
+                    end_step: payload[4],
+                },
+            },
+            MSP_REBOOT => Command::Reboot,
+            MSP_ADVANCED_CONFIG => Command::AdvancedConfig,
+            // After the gyro sync and PID loop dividers and the unsynced
+            // PWM flag.
+            MSP_SET_ADVANCED_CONFIG if payload.len() >= 6 => Command::SetAdvancedConfig {
+                motor_protocol: payload[3],
+                motor_rate_hz: u16::from_le_bytes([payload[4], payload[5]]),
+            },
+            MSP_MOTOR_CONFIG => Command::MotorConfig,
+            // After the throttle range and the motor poles.
+            MSP_SET_MOTOR_CONFIG if payload.len() >= 6 => Command::SetMotorConfig {
+                dshot_telemetry: payload.get(7).map(|value| *value != 0),
+            },
+            MSP_IDENT => Command::Ident,
+            MSP_STATUS => Command::Status,
+            MSP_RAW_IMU => Command::RawImu,
+            MSP_MOTOR => Command::Motor,
+            MSP_RC => Command::Rc,
+            MSP_ATTITUDE => Command::Attitude,
+            MSP_PID => Command::Pid,
+            MSP_BOXNAMES => Command::BoxNames,
+            MSP_BOXIDS => Command::BoxIds,
+            MSP_SET_PID if payload.len() >= 9 => {
+                Command::SetPid(core::array::from_fn(|axis| Pid {
+                    p: payload[axis * 3],
+                    i: payload[axis * 3 + 1],
+                    d: payload[axis * 3 + 2],
+                }))
+            }
+            MSP_EEPROM_WRITE => Command::EepromWrite,
+            _ => Command::Unsupported(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Command::ApiVersion => MSP_API_VERSION,
+            Command::FcVariant => MSP_FC_VARIANT,
+            Command::FcVersion => MSP_FC_VERSION,
+            Command::BoardInfo => MSP_BOARD_INFO,
+            Command::ModeRanges => MSP_MODE_RANGES,
+            Command::SetModeRange { .. } => MSP_SET_MODE_RANGE,
+            Command::Ident => MSP_IDENT,
+            Command::Status => MSP_STATUS,
+            Command::RawImu => MSP_RAW_IMU,
+            Command::Motor => MSP_MOTOR,
+            Command::Rc => MSP_RC,
+            Command::Attitude => MSP_ATTITUDE,
+            Command::Pid => MSP_PID,
+            Command::BoxNames => MSP_BOXNAMES,
+            Command::BoxIds => MSP_BOXIDS,
+            Command::SetPid(_) => MSP_SET_PID,
+            Command::AdvancedConfig => MSP_ADVANCED_CONFIG,
+            Command::SetAdvancedConfig { .. } => MSP_SET_ADVANCED_CONFIG,
+            Command::MotorConfig => MSP_MOTOR_CONFIG,
+            Command::SetMotorConfig { .. } => MSP_SET_MOTOR_CONFIG,
+            Command::EepromWrite => MSP_EEPROM_WRITE,
+            Command::Reboot => MSP_REBOOT,
+            Command::Unsupported(code) => *code,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Request {
+    /// The version to answer in.
+    pub version: Version,
+    pub command: Command,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for code in [
+            MSP_API_VERSION,
+            MSP_FC_VARIANT,
+            MSP_FC_VERSION,
+            MSP_BOARD_INFO,
+            MSP_MODE_RANGES,
+            MSP_SET_MODE_RANGE,
+            MSP_REBOOT,
+            MSP_ADVANCED_CONFIG,
+            MSP_SET_ADVANCED_CONFIG,
+            MSP_MOTOR_CONFIG,
+            MSP_SET_MOTOR_CONFIG,
+            MSP_IDENT,
+            MSP_STATUS,
+            MSP_RAW_IMU,
+            MSP_MOTOR,
+            MSP_RC,
+            MSP_ATTITUDE,
+            MSP_PID,
+            MSP_BOXNAMES,
+            MSP_BOXIDS,
+            MSP_SET_PID,
+            MSP_EEPROM_WRITE,
+        ] {
+            let command = Command::parse(code, &[0; 9]);
+            assert!(!matches!(command, Command::Unsupported(_)), "{}", code);
+            assert_eq!(command.code(), code);
+        }
+        assert_eq!(Command::parse(0x1234, &[]).code(), 0x1234);
+    }
+
+    #[test]
+    fn unused_mode_ranges() {
+        let range = |start_step, end_step| ModeRange {
+            box_id: 0,
+            aux_channel: 0,
+            start_step,
+            end_step,
+        };
+        assert!(range(0, 48).is_used());
+        assert!(range(47, 48).is_used());
+        assert!(!range(0, 0).is_used());
+        assert!(!range(32, 32).is_used());
+        assert!(!range(48, 0).is_used());
+        assert!(!ModeRange::default().is_used());
+    }
+
+    #[test]
+    fn parses_motor_settings() {
+        // Betaflight 4.2 sends the whole config, with DShot600 at 480Hz.
+        let advanced = [
+            1, 1, 0, 7, 0xe0, 0x01, 0, 0, 0, 0, 0, 0, 48, 125, 0, 0, 0, 0, 0, 0,
+        ];
+        assert!(
+            Command::parse(MSP_SET_ADVANCED_CONFIG, &advanced)
+                == Command::SetAdvancedConfig {
+                    motor_protocol: 7,
+                    motor_rate_hz: 480
+                }
+        );
+        assert!(matches!(
+            Command::parse(MSP_SET_ADVANCED_CONFIG, &advanced[..5]),
+            Command::Unsupported(MSP_SET_ADVANCED_CONFIG)
+        ));
+
+        let motor = [0x2e, 0x04, 0xd0, 0x07, 0xe8, 0x03, 14, 1];
+        assert!(
+            Command::parse(MSP_SET_MOTOR_CONFIG, &motor)
+                == Command::SetMotorConfig {
+                    dshot_telemetry: Some(true)
+                }
+        );
+        // Older configurators only send the throttle range.
+        assert!(
+            Command::parse(MSP_SET_MOTOR_CONFIG, &motor[..6])
+                == Command::SetMotorConfig {
+                    dshot_telemetry: None
+                }
+        );
+    }
+}
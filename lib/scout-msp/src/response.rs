@@ -0,0 +1,691 @@
+use crate::{
+    crc8_dvb_s2,
+    request::{
+        MSP_ADVANCED_CONFIG, MSP_API_VERSION, MSP_ATTITUDE, MSP_BOARD_INFO, MSP_BOXIDS,
+        MSP_BOXNAMES, MSP_FC_VARIANT, MSP_FC_VERSION, MSP_IDENT, MSP_MODE_RANGES, MSP_MOTOR,
+        MSP_MOTOR_CONFIG, MSP_PID, MSP_RAW_IMU, MSP_RC, MSP_STATUS,
+    },
+    xor, ModeRange, Pid, Version, MAX_FRAME_LEN, MAX_PAYLOAD_LEN, V1_HEADER_LEN, V2_HEADER_LEN,
+};
+
+/// Protocol version sent with the API version, which is zero for every
+/// version so far.
+const MSP_PROTOCOL_VERSION: u8 = 0;
+
+/// Motor outputs are always sent as this many values, with zero for motors
+/// which aren't present.
+const MOTOR_SLOTS: usize = 8;
+
+/// A mode which can be reported as active, identified by the permanent ID
+/// MultiWii gave it, which configurators still use.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ModeBox {
+    Arm,
+    Angle,
+    Horizon,
+    AltitudeHold,
+    ReturnToHome,
+    PositionHold,
+    Beeper,
+}
+
+impl ModeBox {
+    /// Every mode, in the order of `MSP_BOXNAMES` and `MSP_BOXIDS`.
+    pub const ALL: [ModeBox; 7] = [
+        ModeBox::Arm,
+        ModeBox::Angle,
+        ModeBox::Horizon,
+        ModeBox::AltitudeHold,
+        ModeBox::ReturnToHome,
+        ModeBox::PositionHold,
+        ModeBox::Beeper,
+    ];
+
+    pub const fn id(&self) -> u8 {
+        match self {
+            ModeBox::Arm => 0,
+            ModeBox::Angle => 1,
+            ModeBox::Horizon => 2,
+            ModeBox::AltitudeHold => 3,
+            ModeBox::ReturnToHome => 10,
+            ModeBox::PositionHold => 11,
+            ModeBox::Beeper => 13,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ModeBox::Arm => "ARM",
+            ModeBox::Angle => "ANGLE",
+            ModeBox::Horizon => "HORIZON",
+            ModeBox::AltitudeHold => "BARO",
+            ModeBox::ReturnToHome => "GPS HOME",
+            ModeBox::PositionHold => "GPS HOLD",
+            ModeBox::Beeper => "BEEPER",
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        ModeBox::ALL.into_iter().find(|mode| mode.id() == id)
+    }
+
+    /// The bit in `Status::modes` which is set while this mode is active,
+    /// which is its position in `ALL`.
+    pub fn flag(&self) -> u32 {
+        let index = ModeBox::ALL
+            .iter()
+            .position(|mode| mode == self)
+            .unwrap_or(0);
+        1 << index
+    }
+}
+
+/// A motor protocol, identified by Betaflight's number for it.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MotorProtocol {
+    Pwm,
+    Oneshot125,
+    Oneshot42,
+    Multishot,
+    Dshot150,
+    Dshot300,
+    Dshot600,
+}
+
+impl MotorProtocol {
+    pub const ALL: [MotorProtocol; 7] = [
+        MotorProtocol::Pwm,
+        MotorProtocol::Oneshot125,
+        MotorProtocol::Oneshot42,
+        MotorProtocol::Multishot,
+        MotorProtocol::Dshot150,
+        MotorProtocol::Dshot300,
+        MotorProtocol::Dshot600,
+    ];
+
+    pub const fn id(&self) -> u8 {
+        match self {
+            MotorProtocol::Pwm => 0,
+            MotorProtocol::Oneshot125 => 1,
+            MotorProtocol::Oneshot42 => 2,
+            MotorProtocol::Multishot => 3,
+            MotorProtocol::Dshot150 => 5,
+            MotorProtocol::Dshot300 => 6,
+            MotorProtocol::Dshot600 => 7,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        MotorProtocol::ALL
+            .into_iter()
+            .find(|protocol| protocol.id() == id)
+    }
+}
+
+/// Sensors which are present and working.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct Sensors {
+    pub accel: bool,
+    pub baro: bool,
+    pub mag: bool,
+    pub gps: bool,
+    pub gyro: bool,
+}
+
+impl Sensors {
+    fn bits(&self) -> u16 {
+        [self.accel, self.baro, self.mag, self.gps, false, self.gyro]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, present)| bits | (*present as u16) << i)
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Status {
+    /// Control loop period, in microseconds.
+    pub cycle_time_us: u16,
+    pub i2c_errors: u16,
+    pub sensors: Sensors,
+    /// The `ModeBox::flag` of each active mode.
+    pub modes: u32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Response<'a> {
+    ApiVersion {
+        major: u8,
+        minor: u8,
+    },
+    FcVariant([u8; 4]),
+    /// Major, minor and patch version.
+    FcVersion([u8; 3]),
+    BoardInfo {
+        identifier: [u8; 4],
+        hardware_revision: u16,
+    },
+    Ident {
+        version: u8,
+        /// The MultiWii airframe type, which is 3 for a quad X.
+        multitype: u8,
+    },
+    Status(Status),
+    RawImu {
+        /// In units of 1/512 g.
+        accel: [i16; 3],
+        /// In units of 4/16.4 degrees per second, the resolution of a
+        /// 2000 degrees per second gyro divided by four, which is what
+        /// configurators expect.
+        gyro: [i16; 3],
+        /// In units of 1/1090 gauss.
+        mag: [i16; 3],
+    },
+    /// Motor outputs from 1000 to 2000, at most eight of them.
+    Motor(&'a [u16]),
+    /// Roll, pitch, yaw and throttle, then the aux channels, from 1000 to
+    /// 2000.
+    Rc(&'a [u16]),
+    Attitude {
+        /// In units of 0.1 degrees, positive right side down.
+        roll: i16,
+        /// In units of 0.1 degrees, positive nose up.
+        pitch: i16,
+        /// Heading in degrees, from 0 to 359.
+        yaw: i16,
+    },
+    Pid(&'a [Pid]),
+    /// Every mode range slot, in order.
+    ModeRanges(&'a [ModeRange]),
+    AdvancedConfig {
+        /// The `MotorProtocol::id` of the protocol.
+        motor_protocol: u8,
+        motor_rate_hz: u16,
+    },
+    MotorConfig {
+        motor_count: u8,
+        motor_poles: u8,
+        dshot_telemetry: bool,
+    },
+    BoxNames,
+    BoxIds,
+    /// Acknowledges the command with this code, which has no reply data.
+    Ack(u16),
+    /// Reports that the command with this code failed or is unsupported.
+    Error(u16),
+}
+
+impl Response<'_> {
+    /// Encodes a complete frame into `buf`, returning its length. Payloads
+    /// longer than `MAX_PAYLOAD_LEN` are truncated.
+    pub fn encode(&self, version: Version, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut payload = Payload::new();
+        let command = match *self {
+            Response::ApiVersion { major, minor } => {
+                payload.push(&[MSP_PROTOCOL_VERSION, major, minor]);
+                MSP_API_VERSION
+            }
+            Response::FcVariant(variant) => {
+                payload.push(&variant);
+                MSP_FC_VARIANT
+            }
+            Response::FcVersion(version) => {
+                payload.push(&version);
+                MSP_FC_VERSION
+            }
+            Response::BoardInfo {
+                identifier,
+                hardware_revision,
+            } => {
+                payload.push(&identifier);
+                payload.push(&hardware_revision.to_le_bytes());
+                MSP_BOARD_INFO
+            }
+            Response::Ident { version, multitype } => {
+                // The MSP version and capabilities are unused.
+                payload.push(&[version, multitype, 0]);
+                payload.push(&0u32.to_le_bytes());
+                MSP_IDENT
+            }
+            Response::Status(status) => {
+                payload.push(&status.cycle_time_us.to_le_bytes());
+                payload.push(&status.i2c_errors.to_le_bytes());
+                payload.push(&status.sensors.bits().to_le_bytes());
+                payload.push(&status.modes.to_le_bytes());
+                // The PID profile, of which there is only one.
+                payload.push(&[0]);
+                MSP_STATUS
+            }
+            Response::RawImu { accel, gyro, mag } => {
+                for value in accel.iter().chain(&gyro).chain(&mag) {
+                    payload.push(&value.to_le_bytes());
+                }
+                MSP_RAW_IMU
+            }
+            Response::Motor(motors) => {
+                for i in 0..MOTOR_SLOTS {
+                    payload.push(&motors.get(i).copied().unwrap_or(0).to_le_bytes());
+                }
+                MSP_MOTOR
+            }
+            Response::Rc(channels) => {
+                for channel in channels {
+                    payload.push(&channel.to_le_bytes());
+                }
+                MSP_RC
+            }
+            Response::Attitude { roll, pitch, yaw } => {
+                payload.push(&roll.to_le_bytes());
+                payload.push(&pitch.to_le_bytes());
+                payload.push(&yaw.to_le_bytes());
+                MSP_ATTITUDE
+            }
+            Response::Pid(pids) => {
+                for pid in pids {
+                    payload.push(&[pid.p, pid.i, pid.d]);
+                }
+                MSP_PID
+            }
+            Response::ModeRanges(ranges) => {
+                for range in ranges {
+                    payload.push(&[
+                        range.box_id,
+                        range.aux_channel,
+                        range.start_step,
+                        range.end_step,
+                    ]);
+                }
+                MSP_MODE_RANGES
+            }
+            Response::AdvancedConfig {
+                motor_protocol,
+                motor_rate_hz,
+            } => {
+                // Gyro sync and PID loop dividers of one, and synced PWM.
+                payload.push(&[1, 1, 0, motor_protocol]);
+                payload.push(&motor_rate_hz.to_le_bytes());
+                // DShot idle offset, 32kHz gyro, PWM inversion, gyro
+                // selection, high gyro range, calibration threshold and
+                // duration, yaw offset, overflow check, and debug mode and
+                // count, none of which apply.
+                payload.push(&[0; 14]);
+                MSP_ADVANCED_CONFIG
+            }
+            Response::MotorConfig {
+                motor_count,
+                motor_poles,
+                dshot_telemetry,
+            } => {
+                // Minimum throttle, maximum throttle and minimum command,
+                // which are fixed.
+                for value in [1000u16, 2000, 1000] {
+                    payload.push(&value.to_le_bytes());
+                }
+                // No ESC sensor.
+                payload.push(&[motor_count, motor_poles, dshot_telemetry as u8, 0]);
+                MSP_MOTOR_CONFIG
+            }
+            Response::BoxNames => {
+                for mode in ModeBox::ALL {
+                    payload.push(mode.name().as_bytes());
+                    payload.push(b";");
+                }
+                MSP_BOXNAMES
+            }
+            Response::BoxIds => {
+                for mode in ModeBox::ALL {
+                    payload.push(&[mode.id()]);
+                }
+                MSP_BOXIDS
+            }
+            Response::Ack(command) | Response::Error(command) => command,
+        };
+        let direction = match self {
+            Response::Error(_) => b'!',
+            _ => b'>',
+        };
+
+        let payload = payload.as_bytes();
+        let header_len = match version {
+            Version::V1 => {
+                buf[3] = payload.len() as u8;
+                buf[4] = command as u8;
+                V1_HEADER_LEN
+            }
+            Version::V2 => {
+                buf[3] = 0;
+                buf[4..6].copy_from_slice(&command.to_le_bytes());
+                buf[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+                V2_HEADER_LEN
+            }
+        };
+        let frame_len = header_len + payload.len() + 1;
+        buf[0] = b'$';
+        buf[1] = version.byte();
+        buf[2] = direction;
+        buf[header_len..frame_len - 1].copy_from_slice(payload);
+        let body = &buf[3..frame_len - 1];
+        buf[frame_len - 1] = match version {
+            Version::V1 => xor(body),
+            Version::V2 => crc8_dvb_s2(body),
+        };
+
+        frame_len
+    }
+}
+
+struct Payload {
+    buf: [u8; MAX_PAYLOAD_LEN],
+    len: usize,
+}
+
+impl Payload {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_PAYLOAD_LEN],
+            len: 0,
+        }
+    }
+
+    /// Bytes which don't fit are dropped.
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_PAYLOAD_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::MSP_SET_PID, Command, Parser, Request};
+
+    fn encode(response: Response, version: Version) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = response.encode(version, &mut buf);
+
+        (buf, len)
+    }
+
+    /// The payload of an encoded frame, after checking its header and
+    /// checksum by parsing it as a request, since the checksum doesn't cover
+    /// the direction.
+    fn payload(frame: &[u8], command: u16) -> &[u8] {
+        let mut request = [0; MAX_FRAME_LEN];
+        request[..frame.len()].copy_from_slice(frame);
+        request[2] = b'<';
+        let mut parser = Parser::new();
+        let parsed = request[..frame.len()]
+            .iter()
+            .filter_map(|byte| parser.push(*byte))
+            .last()
+            .unwrap();
+        assert_eq!(parsed.command.code(), command);
+
+        match frame[1] {
+            b'M' => &frame[V1_HEADER_LEN..frame.len() - 1],
+            _ => &frame[V2_HEADER_LEN..frame.len() - 1],
+        }
+    }
+
+    #[test]
+    fn encodes_api_version() {
+        let response = Response::ApiVersion {
+            major: 1,
+            minor: 42,
+        };
+        let (buf, len) = encode(response, Version::V1);
+        assert_eq!(buf[..len], *b"$M>\x03\x01\x00\x01\x2a\x29");
+
+        let (buf, len) = encode(response, Version::V2);
+        assert_eq!(buf[..len], [b'$', b'X', b'>', 0, 1, 0, 3, 0, 0, 1, 42, 98]);
+    }
+
+    #[test]
+    fn encodes_status() {
+        let status = Status {
+            cycle_time_us: 1000,
+            i2c_errors: 0,
+            sensors: Sensors {
+                accel: true,
+                baro: false,
+                mag: true,
+                gps: false,
+                gyro: true,
+            },
+            modes: ModeBox::Arm.flag() | ModeBox::Angle.flag() | ModeBox::Beeper.flag(),
+        };
+        let (buf, len) = encode(Response::Status(status), Version::V1);
+        assert_eq!(
+            buf[..len],
+            [36, 77, 62, 11, 101, 232, 3, 0, 0, 37, 0, 67, 0, 0, 0, 0, 227]
+        );
+    }
+
+    #[test]
+    fn encodes_errors() {
+        let (buf, len) = encode(Response::Error(0xfe), Version::V1);
+        assert_eq!(buf[..len], *b"$M!\x00\xfe\xfe");
+
+        let (buf, len) = encode(Response::Ack(MSP_SET_PID), Version::V2);
+        assert_eq!(buf[2], b'>');
+        assert!(payload(&buf[..len], MSP_SET_PID).is_empty());
+    }
+
+    #[test]
+    fn encodes_live_data() {
+        for version in [Version::V1, Version::V2] {
+            let (buf, len) = encode(
+                Response::Attitude {
+                    roll: -123,
+                    pitch: 45,
+                    yaw: 359,
+                },
+                version,
+            );
+            assert_eq!(
+                payload(&buf[..len], MSP_ATTITUDE),
+                [0x85, 0xff, 45, 0, 0x67, 0x01]
+            );
+
+            let (buf, len) = encode(
+                Response::RawImu {
+                    accel: [0, 0, 512],
+                    gyro: [1, -1, 0],
+                    mag: [100, 0, -100],
+                },
+                version,
+            );
+            assert_eq!(
+                payload(&buf[..len], MSP_RAW_IMU),
+                [0, 0, 0, 0, 0, 2, 1, 0, 0xff, 0xff, 0, 0, 100, 0, 0, 0, 0x9c, 0xff]
+            );
+
+            let (buf, len) = encode(Response::Rc(&[1500, 1000, 2000, 1100]), version);
+            assert_eq!(
+                payload(&buf[..len], MSP_RC),
+                [0xdc, 0x05, 0xe8, 0x03, 0xd0, 0x07, 0x4c, 0x04]
+            );
+        }
+    }
+
+    #[test]
+    fn pads_motors_to_eight() {
+        let (buf, len) = encode(Response::Motor(&[1000, 1500, 2000, 1250]), Version::V1);
+        let payload = payload(&buf[..len], MSP_MOTOR);
+
+        assert_eq!(payload.len(), 16);
+        assert_eq!(
+            payload[..8],
+            [0xe8, 0x03, 0xdc, 0x05, 0xd0, 0x07, 0xe2, 0x04]
+        );
+        assert!(payload[8..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn truncates_long_payloads() {
+        let channels = [1500; 40];
+        let (buf, len) = encode(Response::Rc(&channels), Version::V1);
+        assert_eq!(buf[3] as usize, MAX_PAYLOAD_LEN);
+        assert_eq!(payload(&buf[..len], MSP_RC).len(), MAX_PAYLOAD_LEN);
+
+        let (buf, len) = encode(Response::Rc(&channels), Version::V2);
+        assert_eq!(len, MAX_FRAME_LEN);
+        assert_eq!(payload(&buf[..len], MSP_RC).len(), MAX_PAYLOAD_LEN);
+    }
+
+    #[test]
+    fn encodes_pids() {
+        let pids = [
+            Pid {
+                p: 40,
+                i: 30,
+                d: 25,
+            },
+            Pid { p: 60, i: 45, d: 0 },
+        ];
+        let (buf, len) = encode(Response::Pid(&pids), Version::V1);
+        assert_eq!(payload(&buf[..len], MSP_PID), [40, 30, 25, 60, 45, 0]);
+    }
+
+    #[test]
+    fn encodes_mode_ranges() {
+        let mut ranges = [ModeRange::default(); 16];
+        ranges[0] = ModeRange {
+            box_id: ModeBox::Arm.id(),
+            aux_channel: 0,
+            start_step: 32,
+            end_step: 48,
+        };
+        ranges[3] = ModeRange {
+            box_id: ModeBox::ReturnToHome.id(),
+            aux_channel: 2,
+            start_step: 40,
+            end_step: 48,
+        };
+
+        // Every slot fits a frame, as configurators expect them all.
+        let (buf, len) = encode(Response::ModeRanges(&ranges), Version::V1);
+        let payload = payload(&buf[..len], MSP_MODE_RANGES);
+        assert_eq!(payload.len(), 64);
+        assert_eq!(payload[..4], [0, 0, 32, 48]);
+        assert_eq!(payload[12..16], [10, 2, 40, 48]);
+        assert!(payload[4..12].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn encodes_motor_settings() {
+        let (buf, len) = encode(
+            Response::AdvancedConfig {
+                motor_protocol: MotorProtocol::Dshot600.id(),
+                motor_rate_hz: 480,
+            },
+            Version::V1,
+        );
+        // Configurators read every field up to API 1.42.
+        let advanced = payload(&buf[..len], MSP_ADVANCED_CONFIG);
+        assert_eq!(advanced.len(), 20);
+        assert_eq!(advanced[..6], [1, 1, 0, 7, 0xe0, 0x01]);
+
+        let (buf, len) = encode(
+            Response::MotorConfig {
+                motor_count: 4,
+                motor_poles: 14,
+                dshot_telemetry: true,
+            },
+            Version::V2,
+        );
+        assert_eq!(
+            payload(&buf[..len], MSP_MOTOR_CONFIG),
+            [0xe8, 0x03, 0xd0, 0x07, 0xe8, 0x03, 4, 14, 1, 0]
+        );
+    }
+
+    #[test]
+    fn motor_protocol_ids_round_trip() {
+        for protocol in MotorProtocol::ALL {
+            assert!(MotorProtocol::from_id(protocol.id()) == Some(protocol));
+        }
+        // Brushed motors aren't supported.
+        assert!(MotorProtocol::from_id(4).is_none());
+        assert!(MotorProtocol::from_id(8).is_none());
+    }
+
+    #[test]
+    fn mode_range_round_trip() {
+        // What a configurator reads back after setting a range.
+        let range = ModeRange {
+            box_id: ModeBox::Angle.id(),
+            aux_channel: 1,
+            start_step: 0,
+            end_step: 16,
+        };
+        let request = [b'$', b'M', b'<', 5, 35, 4, 1, 1, 0, 16];
+        let mut frame = [0; 11];
+        frame[..10].copy_from_slice(&request);
+        frame[10] = xor(&frame[3..10]);
+        let mut parser = Parser::new();
+        let parsed = frame.iter().filter_map(|byte| parser.push(*byte)).last();
+        assert!(matches!(
+            parsed,
+            Some(Request { command: Command::SetModeRange { index: 4, range: parsed }, .. })
+                if parsed == range
+        ));
+
+        let mut ranges = [ModeRange::default(); 16];
+        ranges[4] = range;
+        let (buf, len) = encode(Response::ModeRanges(&ranges), Version::V2);
+        assert_eq!(payload(&buf[..len], MSP_MODE_RANGES)[16..20], [1, 1, 0, 16]);
+    }
+
+    #[test]
+    fn encodes_boxes() {
+        let (buf, len) = encode(Response::BoxNames, Version::V1);
+        assert_eq!(
+            payload(&buf[..len], MSP_BOXNAMES),
+            *b"ARM;ANGLE;HORIZON;BARO;GPS HOME;GPS HOLD;BEEPER;"
+        );
+
+        let (buf, len) = encode(Response::BoxIds, Version::V1);
+        assert_eq!(payload(&buf[..len], MSP_BOXIDS), [0, 1, 2, 3, 10, 11, 13]);
+    }
+
+    #[test]
+    fn mode_boxes() {
+        for (i, mode) in ModeBox::ALL.iter().enumerate() {
+            assert!(ModeBox::from_id(mode.id()) == Some(*mode));
+            assert_eq!(mode.flag(), 1 << i);
+        }
+        assert!(ModeBox::from_id(4).is_none());
+    }
+
+    #[test]
+    fn encodes_identity() {
+        let (buf, len) = encode(Response::FcVariant(*b"SCOT"), Version::V1);
+        assert_eq!(payload(&buf[..len], MSP_FC_VARIANT), *b"SCOT");
+
+        let (buf, len) = encode(Response::FcVersion([0, 1, 0]), Version::V1);
+        assert_eq!(payload(&buf[..len], MSP_FC_VERSION), [0, 1, 0]);
+
+        let (buf, len) = encode(
+            Response::BoardInfo {
+                identifier: *b"N446",
+                hardware_revision: 2,
+            },
+            Version::V1,
+        );
+        assert_eq!(payload(&buf[..len], MSP_BOARD_INFO), *b"N446\x02\x00");
+
+        let (buf, len) = encode(
+            Response::Ident {
+                version: 231,
+                multitype: 3,
+            },
+            Version::V1,
+        );
+        assert_eq!(payload(&buf[..len], MSP_IDENT), [231, 3, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,60 @@
+//! Auxiliary channels carried alongside the sticks
+
+/// The most auxiliary channels any supported protocol can carry. This
+/// allows for 16 channel protocols, where four channels are sticks.
+pub const MAX_AUX_CHANNELS: usize = 12;
+
+/// Values of the auxiliary channels a radio provides, which is often fewer
+/// than `MAX_AUX_CHANNELS`.
+#[derive(Clone, Copy)]
+pub struct AuxChannels<T> {
+    values: [T; MAX_AUX_CHANNELS],
+    len: usize,
+}
+
+impl<T: Copy + Default> AuxChannels<T> {
+    /// Takes at most `MAX_AUX_CHANNELS` values, ignoring any beyond that.
+    pub fn new(values: &[T]) -> Self {
+        let len = values.len().min(MAX_AUX_CHANNELS);
+        let mut aux = Self {
+            values: [T::default(); MAX_AUX_CHANNELS],
+            len,
+        };
+        aux.values[..len].copy_from_slice(&values[..len]);
+
+        aux
+    }
+
+    /// Returns `None` for channels the radio does not provide.
+    pub fn get(&self, channel: usize) -> Option<T> {
+        self.as_slice().get(channel).copied()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.values[..self.len]
+    }
+
+    pub fn map<U: Copy + Default>(&self, mut f: impl FnMut(T) -> U) -> AuxChannels<U> {
+        let mut values = [U::default(); MAX_AUX_CHANNELS];
+        for (output, input) in values.iter_mut().zip(self.as_slice()) {
+            *output = f(*input);
+        }
+
+        AuxChannels {
+            values,
+            len: self.len,
+        }
+    }
+}
+
+impl<T: Copy + Default> Default for AuxChannels<T> {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+impl<T: Copy + Default + defmt::Format> defmt::Format for AuxChannels<T> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", self.as_slice())
+    }
+}
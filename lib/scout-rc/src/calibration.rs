@@ -0,0 +1,139 @@
+//! Mapping of raw protocol units onto normalised stick positions
+
+/// Calibration for a self-centering stick axis.
+///
+/// `min` and `max` are the raw values reported at full deflection in the
+/// negative and positive directions. Swapping them reverses the axis.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct AxisCalibration {
+    pub min: i16,
+    pub center: i16,
+    pub max: i16,
+}
+
+/// Calibration for the throttle axis, which does not self-center.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct ThrottleCalibration {
+    pub min: i16,
+    pub max: i16,
+}
+
+impl AxisCalibration {
+    pub const fn new(min: i16, center: i16, max: i16) -> Self {
+        Self { min, center, max }
+    }
+
+    /// Returns the stick position in the range -1.0..=1.0.
+    ///
+    /// Each side of center is scaled separately, so a transmitter whose
+    /// center is not halfway between its endpoints still reaches full
+    /// deflection in both directions.
+    pub fn normalise(&self, raw: i16) -> f32 {
+        let offset = raw as f32 - self.center as f32;
+        let towards_max = (offset >= 0.0) == (self.max >= self.center);
+        let span = if towards_max {
+            self.max as f32 - self.center as f32
+        } else {
+            self.center as f32 - self.min as f32
+        };
+
+        if span == 0.0 {
+            return 0.0;
+        }
+
+        (offset / span).clamp(-1.0, 1.0)
+    }
+}
+
+impl ThrottleCalibration {
+    pub const fn new(min: i16, max: i16) -> Self {
+        Self { min, max }
+    }
+
+    /// Returns the throttle position in the range 0.0..=1.0.
+    pub fn normalise(&self, raw: i16) -> f32 {
+        let span = self.max as f32 - self.min as f32;
+
+        if span == 0.0 {
+            return 0.0;
+        }
+
+        ((raw as f32 - self.min as f32) / span).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            libm::fabsf(actual - expected) < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn axis_endpoints_and_center() {
+        let axis = AxisCalibration::new(172, 992, 1811);
+        assert_close(axis.normalise(172), -1.0);
+        assert_close(axis.normalise(992), 0.0);
+        assert_close(axis.normalise(1811), 1.0);
+    }
+
+    #[test]
+    fn off_center_axis_reaches_both_ends() {
+        let axis = AxisCalibration::new(0, 100, 400);
+        assert_close(axis.normalise(50), -0.5);
+        assert_close(axis.normalise(250), 0.5);
+        assert_close(axis.normalise(0), -1.0);
+        assert_close(axis.normalise(400), 1.0);
+    }
+
+    #[test]
+    fn swapped_endpoints_reverse_axis() {
+        let axis = AxisCalibration::new(1811, 992, 172);
+        assert_close(axis.normalise(172), 1.0);
+        assert_close(axis.normalise(1811), -1.0);
+        assert_close(axis.normalise(992), 0.0);
+    }
+
+    #[test]
+    fn axis_clamps_beyond_endpoints() {
+        let axis = AxisCalibration::new(1000, 1500, 2000);
+        assert_close(axis.normalise(900), -1.0);
+        assert_close(axis.normalise(2100), 1.0);
+    }
+
+    #[test]
+    fn axis_without_span_is_centered() {
+        let axis = AxisCalibration::new(1500, 1500, 1500);
+        assert_close(axis.normalise(1000), 0.0);
+        assert_close(axis.normalise(2000), 0.0);
+    }
+
+    #[test]
+    fn throttle_endpoints_and_clamping() {
+        let throttle = ThrottleCalibration::new(1000, 2000);
+        assert_close(throttle.normalise(1000), 0.0);
+        assert_close(throttle.normalise(1500), 0.5);
+        assert_close(throttle.normalise(2000), 1.0);
+        assert_close(throttle.normalise(900), 0.0);
+        assert_close(throttle.normalise(2100), 1.0);
+    }
+
+    #[test]
+    fn reversed_throttle() {
+        let throttle = ThrottleCalibration::new(2000, 1000);
+        assert_close(throttle.normalise(2000), 0.0);
+        assert_close(throttle.normalise(1000), 1.0);
+    }
+
+    #[test]
+    fn throttle_without_span_is_zero() {
+        let throttle = ThrottleCalibration::new(1000, 1000);
+        assert_close(throttle.normalise(1500), 0.0);
+    }
+}
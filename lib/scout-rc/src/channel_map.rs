@@ -0,0 +1,55 @@
+//! Assignment of a receiver's numbered channels to sticks
+
+use crate::{AuxChannels, RcFrame, MAX_AUX_CHANNELS};
+
+/// Zero-based indexes of the channels carrying each stick.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct ChannelMap {
+    pub throttle: usize,
+    pub yaw: usize,
+    pub pitch: usize,
+    pub roll: usize,
+}
+
+impl ChannelMap {
+    /// Roll, pitch, throttle, yaw. This is the most common default.
+    pub const AETR: Self = Self {
+        roll: 0,
+        pitch: 1,
+        throttle: 2,
+        yaw: 3,
+    };
+
+    /// Throttle, roll, pitch, yaw.
+    pub const TAER: Self = Self {
+        throttle: 0,
+        roll: 1,
+        pitch: 2,
+        yaw: 3,
+    };
+
+    /// Builds a frame from channel values. Channels which are not assigned
+    /// to a stick become auxiliary channels, in order. Sticks assigned to
+    /// channels beyond the end of `channels` read as 0.
+    pub fn frame(&self, channels: &[u16]) -> RcFrame {
+        let stick = |index: usize| channels.get(index).map_or(0, |value| *value as i16);
+
+        let mut aux = [0; MAX_AUX_CHANNELS];
+        let mut aux_len = 0;
+        for (index, value) in channels.iter().enumerate() {
+            let is_stick = [self.throttle, self.yaw, self.pitch, self.roll].contains(&index);
+            if !is_stick && aux_len < MAX_AUX_CHANNELS {
+                aux[aux_len] = *value as i16;
+                aux_len += 1;
+            }
+        }
+
+        RcFrame {
+            throttle: stick(self.throttle),
+            yaw: stick(self.yaw),
+            pitch: stick(self.pitch),
+            roll: stick(self.roll),
+            aux: AuxChannels::new(&aux[..aux_len]),
+        }
+    }
+}
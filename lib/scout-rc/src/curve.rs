@@ -0,0 +1,128 @@
+//! Stick shaping curves
+//!
+//! These operate on normalised stick positions in the range -1.0..=1.0, and
+//! always map full deflection to full deflection.
+
+/// Zeroes stick positions within `width` of center, and rescales the
+/// remaining travel so there is no step at the edge of the deadband. A
+/// negative `width` is treated as zero, and a `width` of 1.0 or more zeroes
+/// the whole travel.
+pub fn deadband(stick: f32, width: f32) -> f32 {
+    let width = width.max(0.0);
+    let magnitude = libm::fabsf(stick);
+
+    if magnitude <= width || width >= 1.0 {
+        return 0.0;
+    }
+
+    libm::copysignf((magnitude - width) / (1.0 - width), stick)
+}
+
+/// Softens the stick response around center. An `amount` of 0.0 is linear
+/// and 1.0 is fully cubic.
+pub fn expo(stick: f32, amount: f32) -> f32 {
+    stick * (1.0 - amount) + stick * stick * stick * amount
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            libm::fabsf(actual - expected) < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn deadband_zeroes_center() {
+        for stick in [-0.1, -0.05, 0.0, 0.05, 0.1] {
+            assert_close(deadband(stick, 0.1), 0.0);
+        }
+    }
+
+    #[test]
+    fn deadband_keeps_full_deflection() {
+        assert_close(deadband(1.0, 0.1), 1.0);
+        assert_close(deadband(-1.0, 0.1), -1.0);
+    }
+
+    #[test]
+    fn deadband_is_continuous_at_its_edge() {
+        assert!(deadband(0.1001, 0.1) < 0.001);
+        assert!(deadband(-0.1001, 0.1) > -0.001);
+    }
+
+    #[test]
+    fn deadband_rescales_remaining_travel() {
+        assert_close(deadband(0.55, 0.1), 0.5);
+        assert_close(deadband(-0.55, 0.1), -0.5);
+    }
+
+    #[test]
+    fn zero_deadband_is_identity() {
+        for stick in [-1.0, -0.3, 0.0, 0.3, 1.0] {
+            assert_close(deadband(stick, 0.0), stick);
+        }
+    }
+
+    #[test]
+    fn negative_deadband_is_identity() {
+        for stick in [-1.0, -0.3, 0.0, 0.3, 1.0] {
+            assert_close(deadband(stick, -0.2), stick);
+        }
+    }
+
+    #[test]
+    fn full_width_deadband_zeroes_everything() {
+        for width in [1.0, 1.5] {
+            for stick in [-1.0, -0.3, 0.3, 1.0] {
+                let shaped = deadband(stick, width);
+                assert!(shaped.is_finite());
+                assert_close(shaped, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn expo_keeps_center_and_full_deflection() {
+        for amount in [0.0, 0.3, 0.7, 1.0] {
+            assert_close(expo(0.0, amount), 0.0);
+            assert_close(expo(1.0, amount), 1.0);
+            assert_close(expo(-1.0, amount), -1.0);
+        }
+    }
+
+    #[test]
+    fn zero_expo_is_linear() {
+        for stick in [-0.8, -0.2, 0.4, 0.9] {
+            assert_close(expo(stick, 0.0), stick);
+        }
+    }
+
+    #[test]
+    fn full_expo_is_cubic() {
+        for stick in [-0.8, -0.2, 0.4, 0.9] {
+            assert_close(expo(stick, 1.0), stick * stick * stick);
+        }
+    }
+
+    #[test]
+    fn expo_softens_center_and_is_symmetric() {
+        assert!(expo(0.5, 0.5) < 0.5);
+        assert_close(expo(-0.5, 0.5), -expo(0.5, 0.5));
+    }
+
+    #[test]
+    fn expo_is_monotonic() {
+        let mut previous = expo(-1.0, 1.0);
+        for i in -99..=100 {
+            let shaped = expo(i as f32 / 100.0, 1.0);
+            assert!(shaped >= previous);
+            previous = shaped;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+#![no_std]
+
+mod aux;
+pub use aux::{AuxChannels, MAX_AUX_CHANNELS};
+
+mod calibration;
+pub use calibration::{AxisCalibration, ThrottleCalibration};
+
+mod channel_map;
+pub use channel_map::ChannelMap;
+
+mod curve;
+pub use curve::{deadband, expo};
+
+pub mod modes;
+
+mod rates;
+pub use rates::RateProfile;
+
+/// Stick positions as received from the radio, in the units of whichever
+/// protocol delivered them.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct RcFrame {
+    pub throttle: i16,
+    pub yaw: i16,
+    pub pitch: i16,
+    pub roll: i16,
+    pub aux: AuxChannels<i16>,
+}
+
+/// Calibrated and shaped stick positions.
+///
+/// Yaw, pitch and roll are in the range -1.0..=1.0, with positive values
+/// meaning nose right, nose up and right wing down respectively. Throttle
+/// is in the range 0.0..=1.0. Auxiliary channels are in the range
+/// -1.0..=1.0, and are calibrated but not otherwise shaped.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct RcCommand {
+    pub throttle: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+    pub aux: AuxChannels<f32>,
+}
+
+/// Body rates requested by the pilot, in degrees per second.
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct RateSetpoint {
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+pub struct AxisConfig {
+    pub calibration: AxisCalibration,
+    /// Fraction of the stick travel around center which is treated as
+    /// center, in the range 0.0..1.0.
+    pub deadband: f32,
+    /// Blend between a linear (0.0) and cubic (1.0) stick response.
+    pub expo: f32,
+    pub rates: RateProfile,
+}
+
+pub struct RcConfig {
+    pub throttle: ThrottleCalibration,
+    pub yaw: AxisConfig,
+    pub pitch: AxisConfig,
+    pub roll: AxisConfig,
+    /// Shared by all auxiliary channels.
+    pub aux: AxisCalibration,
+}
+
+impl AxisConfig {
+    fn shape(&self, raw: i16) -> f32 {
+        let stick = self.calibration.normalise(raw);
+
+        expo(deadband(stick, self.deadband), self.expo)
+    }
+}
+
+impl RcConfig {
+    pub fn command(&self, frame: &RcFrame) -> RcCommand {
+        RcCommand {
+            throttle: self.throttle.normalise(frame.throttle),
+            yaw: self.yaw.shape(frame.yaw),
+            pitch: self.pitch.shape(frame.pitch),
+            roll: self.roll.shape(frame.roll),
+            aux: frame.aux.map(|raw| self.aux.normalise(raw)),
+        }
+    }
+
+    pub fn rate_setpoint(&self, command: &RcCommand) -> RateSetpoint {
+        RateSetpoint {
+            yaw: self.yaw.rates.rate(command.yaw),
+            pitch: self.pitch.rates.rate(command.pitch),
+            roll: self.roll.rates.rate(command.roll),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Selection of flight controller modes from auxiliary channel positions
+
+use crate::RcCommand;
+
+pub const MAX_MODE_RANGES: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    Arm,
+    /// Self-level. Acro is active when neither this nor `Horizon` is.
+    Angle,
+    /// Self-level near center stick, acro at full stick.
+    Horizon,
+    /// Beeps the motors while disarmed, to find the aircraft after a crash.
+    /// Requires DShot.
+    Beeper,
+    /// The throttle stick sets the climb rate, and altitude is held around
+    /// center stick.
+    AltitudeHold,
+    /// Holds the horizontal position while the roll and pitch sticks are
+    /// centered. Requires GPS.
+    PositionHold,
+    /// Flies back to where the aircraft was armed and lands. Requires GPS
+    /// and a barometer.
+    ReturnToHome,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 7] = [
+        Mode::Arm,
+        Mode::Angle,
+        Mode::Horizon,
+        Mode::Beeper,
+        Mode::AltitudeHold,
+        Mode::PositionHold,
+        Mode::ReturnToHome,
+    ];
+
+    /// The name the CLI uses for the mode.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Mode::Arm => "arm",
+            Mode::Angle => "angle",
+            Mode::Horizon => "horizon",
+            Mode::Beeper => "beeper",
+            Mode::AltitudeHold => "althold",
+            Mode::PositionHold => "poshold",
+            Mode::ReturnToHome => "rth",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Mode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    /// The position of the mode in `ALL`, which is how it is stored.
+    pub const fn index(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Mode::ALL.get(index as usize).copied()
+    }
+
+    fn bit(self) -> u8 {
+        1 << self.index()
+    }
+}
+
+/// A set of modes, stored as a bitfield, which has room for eight modes.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModeSet(u8);
+
+// `Mode::bit` would overflow with more modes than `ModeSet` has bits.
+const _: () = assert!(Mode::ALL.len() <= u8::BITS as usize);
+
+impl ModeSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, mode: Mode) -> bool {
+        self.0 & mode.bit() != 0
+    }
+
+    pub fn insert(&mut self, mode: Mode) {
+        self.0 |= mode.bit();
+    }
+
+    pub fn remove(&mut self, mode: Mode) {
+        self.0 &= !mode.bit();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Mode> + '_ {
+        Mode::ALL.into_iter().filter(|mode| self.contains(*mode))
+    }
+}
+
+impl defmt::Format for ModeSet {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "[");
+        for (i, mode) in self.iter().enumerate() {
+            if i > 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{}", mode);
+        }
+        defmt::write!(f, "]");
+    }
+}
+
+/// Activates `mode` while the normalised value of `aux_channel` is within
+/// `start..=end`.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct ModeRange {
+    pub mode: Mode,
+    pub aux_channel: usize,
+    pub start: f32,
+    pub end: f32,
+}
+
+#[derive(defmt::Format)]
+pub struct ModeMapFull;
+
+/// The set of mode ranges currently configured. A mode may have several
+/// ranges, and is active if any of them are.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct ModeMap {
+    ranges: [Option<ModeRange>; MAX_MODE_RANGES],
+}
+
+impl ModeRange {
+    fn is_active(&self, command: &RcCommand) -> bool {
+        match command.aux.get(self.aux_channel) {
+            Some(value) => self.start <= value && value <= self.end,
+            None => false,
+        }
+    }
+}
+
+impl ModeMap {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [None; MAX_MODE_RANGES],
+        }
+    }
+
+    /// Adds a range, returning the slot it was stored in.
+    pub fn add(&mut self, range: ModeRange) -> Result<usize, ModeMapFull> {
+        let (index, slot) = self
+            .ranges
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(ModeMapFull)?;
+        *slot = Some(range);
+
+        Ok(index)
+    }
+
+    /// Replaces the range in a slot. `None` clears the slot. Slots beyond
+    /// `MAX_MODE_RANGES` are ignored.
+    pub fn set(&mut self, index: usize, range: Option<ModeRange>) {
+        if let Some(slot) = self.ranges.get_mut(index) {
+            *slot = range;
+        }
+    }
+
+    /// The range in a slot, if there is one.
+    pub fn get(&self, index: usize) -> Option<ModeRange> {
+        self.ranges.get(index).copied().flatten()
+    }
+
+    /// Removes every range bound to `mode`.
+    pub fn clear_mode(&mut self, mode: Mode) {
+        for slot in self.ranges.iter_mut() {
+            if matches!(slot, Some(range) if range.mode == mode) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = &ModeRange> {
+        self.ranges.iter().flatten()
+    }
+
+    pub fn active_modes(&self, command: &RcCommand) -> ModeSet {
+        let mut modes = ModeSet::empty();
+        for range in self.ranges().filter(|range| range.is_active(command)) {
+            modes.insert(range.mode);
+        }
+
+        modes
+    }
+}
+
+impl Default for ModeMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AuxChannels;
+
+    fn command(aux: &[f32]) -> RcCommand {
+        RcCommand {
+            aux: AuxChannels::new(aux),
+            ..RcCommand::default()
+        }
+    }
+
+    fn range(mode: Mode, aux_channel: usize, start: f32, end: f32) -> ModeRange {
+        ModeRange {
+            mode,
+            aux_channel,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn mode_set_insert_and_remove() {
+        let mut modes = ModeSet::empty();
+        assert!(modes.is_empty());
+
+        modes.insert(Mode::Arm);
+        modes.insert(Mode::ReturnToHome);
+        assert!(modes.contains(Mode::Arm));
+        assert!(modes.contains(Mode::ReturnToHome));
+        assert!(!modes.contains(Mode::Angle));
+
+        modes.remove(Mode::Arm);
+        assert!(!modes.contains(Mode::Arm));
+        assert!(modes.iter().eq([Mode::ReturnToHome]));
+    }
+
+    #[test]
+    fn names_and_indices_round_trip() {
+        for mode in Mode::ALL {
+            assert!(Mode::from_name(mode.name()) == Some(mode));
+            assert!(Mode::from_index(mode.index()) == Some(mode));
+        }
+        assert!(Mode::from_name("acro").is_none());
+        assert!(Mode::from_index(Mode::ALL.len() as u8).is_none());
+    }
+
+    #[test]
+    fn range_is_inclusive() {
+        let mut map = ModeMap::new();
+        assert!(map.add(range(Mode::Arm, 0, 0.5, 1.0)).is_ok());
+
+        assert!(map.active_modes(&command(&[0.5])).contains(Mode::Arm));
+        assert!(map.active_modes(&command(&[1.0])).contains(Mode::Arm));
+        assert!(map.active_modes(&command(&[0.49])).is_empty());
+        assert!(map.active_modes(&command(&[-1.0])).is_empty());
+    }
+
+    #[test]
+    fn missing_channel_is_inactive() {
+        let mut map = ModeMap::new();
+        assert!(map.add(range(Mode::Beeper, 4, -1.0, 1.0)).is_ok());
+
+        assert!(map.active_modes(&command(&[0.0, 0.0])).is_empty());
+    }
+
+    #[test]
+    fn mode_with_several_ranges_is_active_in_any() {
+        let mut map = ModeMap::new();
+        assert!(map.add(range(Mode::Angle, 0, -1.0, -0.5)).is_ok());
+        assert!(map.add(range(Mode::Angle, 1, 0.5, 1.0)).is_ok());
+        assert!(map.add(range(Mode::Horizon, 0, -0.5, 0.5)).is_ok());
+
+        let modes = map.active_modes(&command(&[-1.0, -1.0]));
+        assert!(modes.iter().eq([Mode::Angle]));
+        let modes = map.active_modes(&command(&[0.0, 1.0]));
+        assert!(modes.iter().eq([Mode::Angle, Mode::Horizon]));
+        let modes = map.active_modes(&command(&[0.0, 0.0]));
+        assert!(modes.iter().eq([Mode::Horizon]));
+    }
+
+    #[test]
+    fn add_fills_free_slots_until_full() {
+        let mut map = ModeMap::new();
+        for i in 0..MAX_MODE_RANGES {
+            assert!(map.add(range(Mode::Beeper, 0, 0.0, 1.0)).ok() == Some(i));
+        }
+        assert!(map.add(range(Mode::Beeper, 0, 0.0, 1.0)).is_err());
+
+        map.set(3, None);
+        assert!(map.add(range(Mode::Arm, 1, 0.0, 1.0)).ok() == Some(3));
+        assert!(map.get(3).map(|range| range.mode) == Some(Mode::Arm));
+    }
+
+    #[test]
+    fn set_replaces_and_clears_slots() {
+        let mut map = ModeMap::new();
+        map.set(5, Some(range(Mode::Horizon, 2, 0.5, 1.0)));
+        assert!(map.get(5) == Some(range(Mode::Horizon, 2, 0.5, 1.0)));
+        assert!(map
+            .active_modes(&command(&[0.0, 0.0, 1.0]))
+            .contains(Mode::Horizon));
+
+        map.set(5, None);
+        assert!(map.get(5).is_none());
+        assert!(map.ranges().next().is_none());
+
+        // Out of range slots are ignored rather than panicking.
+        map.set(MAX_MODE_RANGES, Some(range(Mode::Arm, 0, 0.0, 1.0)));
+        assert!(map.get(MAX_MODE_RANGES).is_none());
+    }
+
+    #[test]
+    fn clear_mode_removes_only_that_mode() {
+        let mut map = ModeMap::new();
+        assert!(map.add(range(Mode::Arm, 0, 0.5, 1.0)).is_ok());
+        assert!(map.add(range(Mode::Angle, 1, 0.5, 1.0)).is_ok());
+        assert!(map.add(range(Mode::Arm, 2, 0.5, 1.0)).is_ok());
+
+        map.clear_mode(Mode::Arm);
+        assert!(map.ranges().all(|range| range.mode == Mode::Angle));
+        assert!(map.ranges().count() == 1);
+    }
+}
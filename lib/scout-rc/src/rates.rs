@@ -0,0 +1,87 @@
+//! Conversion of stick positions into requested body rates
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct RateProfile {
+    /// Rate per unit of stick deflection around center, in degrees per second.
+    pub center_rate: f32,
+    /// Rate at full stick deflection, in degrees per second.
+    pub max_rate: f32,
+}
+
+impl RateProfile {
+    pub const fn new(center_rate: f32, max_rate: f32) -> Self {
+        Self {
+            center_rate,
+            max_rate,
+        }
+    }
+
+    /// Returns the requested rate for a stick position in the range
+    /// -1.0..=1.0.
+    ///
+    /// The response is linear with slope `center_rate` around center, and
+    /// a cubic term adds whatever is left to reach `max_rate` at full
+    /// deflection. A `max_rate` below `center_rate` is treated as equal to it.
+    pub fn rate(&self, stick: f32) -> f32 {
+        let stick_movement = (self.max_rate - self.center_rate).max(0.0);
+
+        stick * self.center_rate + stick_movement * stick * stick * stick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            libm::fabsf(actual - expected) < 1e-3,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn full_deflection_reaches_max_rate() {
+        let rates = RateProfile::new(200.0, 670.0);
+        assert_close(rates.rate(1.0), 670.0);
+        assert_close(rates.rate(-1.0), -670.0);
+        assert_close(rates.rate(0.0), 0.0);
+    }
+
+    #[test]
+    fn slope_around_center_is_center_rate() {
+        let rates = RateProfile::new(200.0, 670.0);
+        assert_close(rates.rate(0.001) / 0.001, 200.0);
+    }
+
+    #[test]
+    fn equal_rates_are_linear() {
+        let rates = RateProfile::new(300.0, 300.0);
+        for stick in [-1.0, -0.5, 0.25, 0.75] {
+            assert_close(rates.rate(stick), stick * 300.0);
+        }
+    }
+
+    #[test]
+    fn max_rate_below_center_rate_is_linear() {
+        let rates = RateProfile::new(300.0, 100.0);
+        for stick in [-1.0, -0.5, 0.25, 1.0] {
+            assert_close(rates.rate(stick), stick * 300.0);
+        }
+    }
+
+    #[test]
+    fn rate_is_odd_and_monotonic() {
+        let rates = RateProfile::new(200.0, 670.0);
+        let mut previous = rates.rate(-1.0);
+        for i in -99..=100 {
+            let stick = i as f32 / 100.0;
+            let rate = rates.rate(stick);
+            assert!(rate > previous);
+            assert_close(rates.rate(-stick), -rate);
+            previous = rate;
+        }
+    }
+}
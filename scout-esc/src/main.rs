@@ -5,6 +5,8 @@
 use defmt_rtt as _;
 use panic_probe as _;
 
+use core::sync::atomic::Ordering;
+
 use cortex_m::peripheral::NVIC;
 use embassy_executor::Spawner;
 use embassy_stm32::{
@@ -16,18 +18,24 @@ use embassy_stm32::{
     timer::low_level::AdvancedControlInstance,
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
+use scout_dshot::Speed;
 use scout_st_spin_32::{
     bsp::steval_esc002v1::{OverCurrentThreshold, Peripherals},
     sip::OverCurrentVisibility,
 };
 
+mod telemetry;
+
 /// Channel used on TIM1 to trigger a read on the BEMF comparator.
 const BEMF_READ_WAKEUP_TIMER_CHANNEL: usize = 3;
 
 const STARTUP_PER_PHASE_DURATION: Duration = Duration::from_micros(2000);
 
+/// Speed of the DShot signal from the flight controller.
+const DSHOT_SPEED: Speed = Speed::Dshot600;
+
 static BEMF_SIGNAL: Signal<CriticalSectionRawMutex, Level> = Signal::new();
 static mut ACTIVE_BEMF_INPUT: Option<&'static Input<AnyPin>> = None;
 static mut BEMF_COMPARATOR_1: Option<Input<AnyPin>> = None;
@@ -66,9 +74,13 @@ async fn main(_spawner: Spawner) {
         BEMF_COMPARATOR_1: bemf_comparator_1,
         BEMF_COMPARATOR_2: bemf_comparator_2,
         BEMF_COMPARATOR_3: bemf_comparator_3,
+        PA15: pa15,
+        TIM2: tim2,
         ..
     } = scout_st_spin_32::bsp::steval_esc002v1::init();
 
+    telemetry::init(tim2, pa15, DSHOT_SPEED);
+
     let mut ls1 = ls1.degrade();
     let mut ls2 = ls2.degrade();
     let mut ls3 = ls3.degrade();
@@ -107,6 +119,7 @@ async fn main(_spawner: Spawner) {
     };
 
     let mut state = MotorState::Starting;
+    let mut revolution_start = Instant::now();
 
     loop {
         // phase 1
@@ -157,6 +170,16 @@ async fn main(_spawner: Spawner) {
             MotorState::Running => wait_for_bemf(Level::High).await,
         }
 
+        // The six phases make up one electrical revolution.
+        let now = Instant::now();
+        if let MotorState::Running = state {
+            telemetry::ELECTRICAL_PERIOD_US.store(
+                (now - revolution_start).as_micros() as u32,
+                Ordering::Relaxed,
+            );
+        }
+        revolution_start = now;
+
         state = MotorState::Running;
     }
 }
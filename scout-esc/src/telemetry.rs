@@ -0,0 +1,184 @@
+//! Bidirectional DShot eRPM replies
+//!
+//! The DShot signal from the flight controller is on PA15, which is TIM2
+//! channel 1. TIM2 counts freely at the timer clock. Channel 1 captures the
+//! falling edge which starts each frame (the signal is inverted, so it idles
+//! high), and the frame is then read by sampling the pin partway through
+//! each bit, timed from the capture. An edge per interrupt would be too
+//! much for the Cortex-M0 at DShot600.
+//!
+//! Only frames whose CRC is inverted are answered, as that is how the flight
+//! controller asks for a reply. Normal DShot idles low, so its frames are
+//! sampled out of step, fail the check, and the line is never driven. The
+//! telemetry bit asks for serial telemetry on a separate wire, which this ESC
+//! doesn't have, so it plays no part.
+//!
+//! Channel 2 fires once the frame and the turnaround time have passed. The
+//! reply is then bit-banged on PA15, timed from the counter, before the pin
+//! is handed back to the timer.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use embassy_stm32::{
+    interrupt, pac,
+    pac::Interrupt,
+    peripherals::{PA15, TIM2},
+    rcc,
+    timer::low_level::GeneralPurpose32bitInstance,
+};
+
+use scout_dshot::{telemetry::TELEMETRY_BITS, BitTiming, ErpmTelemetry, Frame, Speed};
+
+/// Time from the end of a frame to the start of the reply.
+const TURNAROUND_US: u32 = 30;
+
+const BITS_PER_FRAME: u32 = 16;
+
+/// Alternate function number of TIM2 channel 1 on PA15.
+const TIM2_AF: u8 = 2;
+const PIN: usize = 15;
+
+/// Time for one electrical revolution of the motor, in microseconds, or zero
+/// if the motor is stopped.
+pub static ELECTRICAL_PERIOD_US: AtomicU32 = AtomicU32::new(0);
+
+// Only written before the TIM2 interrupt is enabled.
+static mut REPLY_DELAY_TICKS: u32 = 0;
+static mut BIT_TICKS: u32 = 0;
+static mut FRAME_BIT_TICKS: u32 = 0;
+static mut SAMPLE_TICKS: u32 = 0;
+
+pub fn init(_tim2: TIM2, _pa15: PA15, speed: Speed) {
+    let timer_hz = rcc::get_freqs().apb1_tim.0;
+
+    // Safety: The TIM2 interrupt is not enabled until the statics are
+    // written, and taking ownership of the peripherals ensures nothing else
+    // uses them.
+    unsafe {
+        let timing = BitTiming::new(timer_hz, speed);
+        REPLY_DELAY_TICKS = timing.period * BITS_PER_FRAME + timer_hz / 1_000_000 * TURNAROUND_US;
+        BIT_TICKS = timer_hz / speed.telemetry_bit_rate();
+        FRAME_BIT_TICKS = timing.period;
+        SAMPLE_TICKS = timing.sample_time();
+
+        let gpio = pac::GPIOA;
+        gpio.pupdr()
+            .modify(|w| w.set_pupdr(PIN, pac::gpio::vals::Pupdr::PULLUP));
+        gpio.afr(PIN / 8).modify(|w| w.set_afr(PIN % 8, TIM2_AF));
+        gpio.moder()
+            .modify(|w| w.set_moder(PIN, pac::gpio::vals::Moder::ALTERNATE));
+
+        pac::RCC.apb1enr().modify(|w| w.set_tim2en(true));
+
+        let regs = TIM2::regs_gp32();
+        regs.arr().write(|w| w.set_arr(u32::MAX));
+        regs.ccmr_input(0)
+            .modify(|w| w.set_ccs(0, pac::timer::vals::CcmrInputCcs(0b01)));
+        regs.ccer().modify(|w| {
+            // Capture the falling edge.
+            w.set_ccp(0, true);
+            w.set_cce(0, true);
+        });
+        regs.dier().modify(|w| w.set_ccie(0, true));
+        regs.cr1().modify(|w| w.set_cen(true));
+
+        NVIC::unmask(Interrupt::TIM2);
+    }
+}
+
+#[interrupt]
+fn TIM2() {
+    let regs = TIM2::regs_gp32();
+    // Safety: The statics are not written once this interrupt is enabled.
+    let (reply_delay, bit_ticks) = unsafe { (REPLY_DELAY_TICKS, BIT_TICKS) };
+    let status = unsafe { regs.sr().read() };
+
+    unsafe {
+        if status.ccif(0) {
+            // Reading the capture clears its interrupt flag.
+            let frame_start = regs.ccr(0).read().ccr();
+            let word = read_frame(frame_start);
+
+            // The frame's own edges were captured too.
+            let _ = regs.ccr(0).read();
+            if Frame::decode_bidirectional(word).is_ok() {
+                regs.ccr(1)
+                    .write(|w| w.set_ccr(frame_start.wrapping_add(reply_delay)));
+                clear_interrupt(1);
+                regs.dier().modify(|w| {
+                    w.set_ccie(0, false);
+                    w.set_ccie(1, true);
+                });
+            }
+        } else if status.ccif(1) {
+            clear_interrupt(1);
+            send_reply(bit_ticks);
+
+            // Ignore the edges of our own reply.
+            let _ = regs.ccr(0).read();
+            regs.dier().modify(|w| {
+                w.set_ccie(0, true);
+                w.set_ccie(1, false);
+            });
+        }
+    }
+}
+
+/// Samples each bit of the frame which started at `frame_start`. The signal
+/// is inverted, so a bit which is still low at the sample time is a one.
+unsafe fn read_frame(frame_start: u32) -> u16 {
+    let (bit_ticks, sample_ticks) = (FRAME_BIT_TICKS, SAMPLE_TICKS);
+    let regs = TIM2::regs_gp32();
+    let gpio = pac::GPIOA;
+
+    let mut word = 0;
+    for bit in 0..BITS_PER_FRAME {
+        let sample_time = bit * bit_ticks + sample_ticks;
+        while regs.cnt().read().cnt().wrapping_sub(frame_start) < sample_time {}
+        let low = gpio.idr().read().idr(PIN) == pac::gpio::vals::Idr::LOW;
+        word = (word << 1) | low as u16;
+    }
+
+    word
+}
+
+unsafe fn clear_interrupt(channel: usize) {
+    TIM2::regs_gp32().sr().write(|w| {
+        w.0 = u32::MAX;
+        w.set_ccif(channel, false);
+    });
+}
+
+unsafe fn send_reply(bit_ticks: u32) {
+    let telemetry = match ELECTRICAL_PERIOD_US.load(Ordering::Relaxed) {
+        0 => ErpmTelemetry::STOPPED,
+        period => ErpmTelemetry::from_period_us(period),
+    };
+    let line = telemetry.encode();
+
+    let regs = TIM2::regs_gp32();
+    let gpio = pac::GPIOA;
+    gpio.bsrr().write(|w| w.set_bs(PIN, true));
+    gpio.moder()
+        .modify(|w| w.set_moder(PIN, pac::gpio::vals::Moder::OUTPUT));
+
+    let start = regs.cnt().read().cnt();
+    for bit in 0..TELEMETRY_BITS {
+        let high = (line >> (TELEMETRY_BITS - 1 - bit)) & 1 != 0;
+        while regs.cnt().read().cnt().wrapping_sub(start) < bit * bit_ticks {}
+        gpio.bsrr().write(|w| {
+            if high {
+                w.set_bs(PIN, true)
+            } else {
+                w.set_br(PIN, true)
+            }
+        });
+    }
+
+    // Return to idle once the last bit is over.
+    while regs.cnt().read().cnt().wrapping_sub(start) < TELEMETRY_BITS * bit_ticks {}
+    gpio.bsrr().write(|w| w.set_bs(PIN, true));
+    gpio.moder()
+        .modify(|w| w.set_moder(PIN, pac::gpio::vals::Moder::ALTERNATE));
+}
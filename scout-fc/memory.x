@@ -1,6 +1,7 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* The last 128K sector is reserved for settings, see src/settings.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 384K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
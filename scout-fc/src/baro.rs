@@ -0,0 +1,72 @@
+//! Barometer task
+//!
+//! The barometer shares SPI1 with the IMU, with its chip select on PA8
+//! (labeled D7 on the NUCLEO-F446RE). It is read at its sample rate, and the
+//! altitude above the pressure measured at boot is passed to the control
+//! loop.
+
+use defmt::{error, println};
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_stm32::{gpio::Output, peripherals::PA8};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+
+use scout_ahrs::altitude;
+use scout_baro::Baro;
+
+use crate::{
+    scheduler::{Run, Task},
+    SpiBus1,
+};
+
+pub type BaroSpi = SpiDevice<'static, CriticalSectionRawMutex, SpiBus1, Output<'static, PA8>>;
+
+/// The latest barometric altitude in meters, for the control loop.
+pub static ALTITUDE: Channel<CriticalSectionRawMutex, f32, 1> = Channel::new();
+
+/// The BMP280 produces samples at 40 Hz. The DPS310 is slightly slower, so
+/// some of its samples are read twice.
+pub const PERIOD: Duration = Duration::from_millis(25);
+
+/// Samples averaged for the reference pressure at boot.
+const REFERENCE_SAMPLES: u32 = 40;
+
+#[embassy_executor::task]
+pub async fn baro(mut baro: Baro<BaroSpi>) {
+    let mut reference_pressure = None;
+    let mut pressure_sum = 0.0;
+    let mut samples = 0;
+
+    let mut next = Instant::now();
+    loop {
+        next += PERIOD;
+        Timer::at(next).await;
+        let run = Run::start(Task::Baro);
+
+        match baro.read().await {
+            Ok(sample) => match reference_pressure {
+                Some(reference_pressure) => {
+                    // The control loop takes the latest altitude when it's
+                    // ready, so a full channel holds one it hasn't seen.
+                    let _ = ALTITUDE.try_recv();
+                    let _ = ALTITUDE.try_send(altitude::pressure_altitude(
+                        sample.pressure,
+                        reference_pressure,
+                    ));
+                }
+                None => {
+                    pressure_sum += sample.pressure;
+                    samples += 1;
+                    if samples == REFERENCE_SAMPLES {
+                        let pressure = pressure_sum / samples as f32;
+                        println!("Reference pressure: {} Pa", pressure);
+                        reference_pressure = Some(pressure);
+                    }
+                }
+            },
+            Err(e) => error!("{:?}", e),
+        }
+
+        run.finish();
+    }
+}
@@ -0,0 +1,69 @@
+//! Battery monitor task
+//!
+//! The battery voltage divider is on PA0 and the current sensor on PA1
+//! (labeled A0 and A1 on the NUCLEO-F446RE), both read by ADC1. The state of
+//! the battery is published for the control loop and telemetry with
+//! `latest`.
+
+use core::cell::Cell;
+
+use defmt::{println, warn};
+use embassy_stm32::{
+    adc::Adc,
+    peripherals::{ADC1, PA0, PA1},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+use scout_battery::{Battery, BatteryMonitor, Level};
+
+use crate::{
+    scheduler::{Run, Task},
+    BATTERY,
+};
+
+pub const PERIOD: Duration = Duration::from_millis(20);
+
+static BATTERY_STATE: Mutex<CriticalSectionRawMutex, Cell<Option<Battery>>> =
+    Mutex::new(Cell::new(None));
+
+/// Returns `None` until the battery has first been measured.
+pub fn latest() -> Option<Battery> {
+    BATTERY_STATE.lock(|battery| battery.get())
+}
+
+#[embassy_executor::task]
+pub async fn battery(mut adc: Adc<'static, ADC1>, mut voltage_pin: PA0, mut current_pin: PA1) {
+    let mut monitor = BatteryMonitor::new(BATTERY);
+    let dt = PERIOD.as_micros() as f32 / 1_000_000.0;
+    let mut level = Level::NotConnected;
+
+    let mut next = Instant::now();
+    loop {
+        next += PERIOD;
+        Timer::at(next).await;
+        let run = Run::start(Task::Battery);
+
+        let voltage = adc.read(&mut voltage_pin);
+        let current = adc.read(&mut current_pin);
+        monitor.update(
+            adc.to_millivolts(voltage) as f32 / 1000.0,
+            adc.to_millivolts(current) as f32 / 1000.0,
+            dt,
+        );
+        let battery = monitor.battery();
+        BATTERY_STATE.lock(|latest| latest.set(Some(battery)));
+
+        if battery.level != level {
+            match battery.level {
+                Level::Warning | Level::Critical => {
+                    warn!("Battery {:?}: {} V", battery.level, battery.voltage)
+                }
+                _ => println!("Battery {:?}: {:?} cells", battery.level, battery.cells),
+            }
+            level = battery.level;
+        }
+
+        run.finish();
+    }
+}
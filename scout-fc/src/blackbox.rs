@@ -0,0 +1,302 @@
+//! Blackbox flight recorder
+//!
+//! Logs are written to an SPI NOR flash chip, which shares SPI1 with the IMU
+//! with its chip select on PB12 (on the CN10 header). While armed, the
+//! control loop passes every `BLACKBOX_RATE_DIVIDER`th iteration to this
+//! task, which encodes it and programs the flash a page at a time. Each arm
+//! starts a new log on the page after the previous one, and recording stops
+//! when the flash is full. Logs are read out and erased with the CLI.
+//!
+//! The flash is only ever written in order after being erased, so at boot
+//! the end of the recorded logs is found by searching for the first erased
+//! page.
+
+use defmt::{error, println, warn};
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_stm32::{gpio::Output, peripherals::PB12};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_time::{Delay, Instant};
+
+use scout_blackbox::{Encoder, Header, Record, MAX_FRAME_LEN};
+use scout_flash::{SpiFlash, PAGE_SIZE};
+
+use crate::{
+    imu,
+    scheduler::{Run, Task},
+    SpiBus1, BLACKBOX_RATE_DIVIDER,
+};
+
+pub type BlackboxSpi = SpiDevice<'static, CriticalSectionRawMutex, SpiBus1, Output<'static, PB12>>;
+pub type BlackboxFlash = SpiFlash<BlackboxSpi, Delay>;
+
+enum Event {
+    Start,
+    Record(Record),
+    Stop {
+        /// Records which didn't fit in the channel.
+        dropped: u32,
+    },
+}
+
+/// Events from the control loop. Records are buffered while a page is
+/// programmed.
+static EVENTS: Channel<CriticalSectionRawMutex, Event, 32> = Channel::new();
+
+struct Storage {
+    flash: BlackboxFlash,
+    /// Address of the first erased page.
+    end: u32,
+}
+
+/// Shared between the blackbox task and the CLI, and `None` without a flash
+/// chip.
+static STORAGE: Mutex<CriticalSectionRawMutex, Option<Storage>> = Mutex::new(None);
+
+/// The control loop's side of the blackbox, which passes it arming,
+/// disarming and records without waiting.
+pub struct Recorder {
+    rate_divider: u32,
+    countdown: u32,
+    armed: bool,
+    start_time: Instant,
+    /// Events which didn't fit in the channel, and are sent before any more
+    /// records. A start is always sent after a stop.
+    pending_stop: Option<u32>,
+    pending_start: bool,
+    dropped: u32,
+}
+
+impl Recorder {
+    pub fn new(rate_divider: u32) -> Self {
+        Self {
+            rate_divider,
+            countdown: 0,
+            armed: false,
+            start_time: Instant::now(),
+            pending_stop: None,
+            pending_start: false,
+            dropped: 0,
+        }
+    }
+
+    /// Called every control loop iteration. `record` builds the record from
+    /// the time since arming in microseconds, and is only called for the
+    /// iterations which are recorded.
+    pub fn update(&mut self, armed: bool, time: Instant, record: impl FnOnce(u32) -> Record) {
+        if armed && !self.armed {
+            self.pending_start = true;
+            self.start_time = time;
+            self.countdown = 0;
+            self.dropped = 0;
+        } else if !armed && self.armed {
+            if self.pending_start {
+                // The log never started, so there's nothing to stop.
+                self.pending_start = false;
+            } else {
+                self.pending_stop = Some(self.dropped);
+            }
+        }
+        self.armed = armed;
+
+        if let Some(dropped) = self.pending_stop {
+            if EVENTS.try_send(Event::Stop { dropped }).is_err() {
+                return;
+            }
+            self.pending_stop = None;
+        }
+        if self.pending_start {
+            if EVENTS.try_send(Event::Start).is_err() {
+                return;
+            }
+            self.pending_start = false;
+        }
+
+        if !armed {
+            return;
+        }
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return;
+        }
+        self.countdown = self.rate_divider - 1;
+
+        let time_us = (time - self.start_time).as_micros() as u32;
+        if EVENTS.try_send(Event::Record(record(time_us))).is_err() {
+            self.dropped += 1;
+        }
+    }
+}
+
+/// A log being written, a page at a time.
+struct Log {
+    encoder: Encoder,
+    page: [u8; PAGE_SIZE as usize],
+    len: usize,
+    /// Address of the page being filled.
+    address: u32,
+}
+
+impl Log {
+    /// Returns `false` if the flash is full or couldn't be written, which
+    /// ends the log.
+    async fn write(&mut self, mut bytes: &[u8]) -> bool {
+        while !bytes.is_empty() {
+            let len = bytes.len().min(self.page.len() - self.len);
+            self.page[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+            self.len += len;
+            bytes = &bytes[len..];
+
+            if self.len == self.page.len() && !self.flush().await {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Programs the filled part of the page, and moves on to the next page.
+    async fn flush(&mut self) -> bool {
+        if self.len == 0 {
+            return true;
+        }
+
+        let mut storage = STORAGE.lock().await;
+        let Some(storage) = storage.as_mut() else { return false };
+        if self.address + PAGE_SIZE > storage.flash.capacity() {
+            warn!("Blackbox full");
+            return false;
+        }
+        if let Err(e) = storage
+            .flash
+            .program(self.address, &self.page[..self.len])
+            .await
+        {
+            error!("{:?}", e);
+            return false;
+        }
+
+        self.address += PAGE_SIZE;
+        self.len = 0;
+        storage.end = self.address;
+
+        true
+    }
+}
+
+#[embassy_executor::task]
+pub async fn blackbox(mut flash: BlackboxFlash) {
+    let end = match find_end(&mut flash).await {
+        Some(end) => end,
+        None => return,
+    };
+    println!("Blackbox: {} of {} bytes used", end, flash.capacity());
+    *STORAGE.lock().await = Some(Storage { flash, end });
+
+    let interval_us = 1_000_000 / imu::CONFIG.output_data_rate.hz() * BLACKBOX_RATE_DIVIDER;
+    let mut buf = [0; MAX_FRAME_LEN];
+    let mut log: Option<Log> = None;
+    loop {
+        let event = EVENTS.recv().await;
+        let run = Run::start(Task::Blackbox);
+
+        match event {
+            Event::Start => {
+                // Erasing from the CLI moves the end back to the start.
+                let address = STORAGE
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map_or(0, |storage| storage.end);
+                let mut new_log = Log {
+                    encoder: Encoder::new(),
+                    page: [0; PAGE_SIZE as usize],
+                    len: 0,
+                    address,
+                };
+                let len = new_log.encoder.header(&Header { interval_us }, &mut buf);
+                if new_log.write(&buf[..len]).await {
+                    println!("Blackbox log started at {}", address);
+                    log = Some(new_log);
+                }
+            }
+            Event::Record(record) => {
+                if let Some(current) = log.as_mut() {
+                    let len = current.encoder.record(&record, &mut buf);
+                    if !current.write(&buf[..len]).await {
+                        log = None;
+                    }
+                }
+            }
+            Event::Stop { dropped } => {
+                if let Some(mut current) = log.take() {
+                    let len = current.encoder.end(dropped, &mut buf);
+                    if current.write(&buf[..len]).await && current.flush().await {
+                        println!("Blackbox log ended, {} records dropped", dropped);
+                    }
+                }
+            }
+        }
+
+        run.finish();
+    }
+}
+
+/// Returns the address of the first erased page, by binary search.
+async fn find_end(flash: &mut BlackboxFlash) -> Option<u32> {
+    let mut page = [0; PAGE_SIZE as usize];
+    let (mut low, mut high) = (0, flash.capacity() / PAGE_SIZE);
+    while low < high {
+        let middle = (low + high) / 2;
+        if let Err(e) = flash.read(middle * PAGE_SIZE, &mut page).await {
+            error!("{:?}", e);
+            return None;
+        }
+        if page.iter().all(|byte| *byte == 0xFF) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    Some(low * PAGE_SIZE)
+}
+
+/// Returns the bytes used and the capacity, or `None` without a flash chip.
+pub async fn usage() -> Option<(u32, u32)> {
+    STORAGE
+        .lock()
+        .await
+        .as_ref()
+        .map(|storage| (storage.end, storage.flash.capacity()))
+}
+
+/// Reads recorded logs. Returns `false` on failure.
+pub async fn read(address: u32, buf: &mut [u8]) -> bool {
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage.as_mut() else { return false };
+
+    match storage.flash.read(address, buf).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{:?}", e);
+            false
+        }
+    }
+}
+
+/// Erases every log. Returns `false` on failure.
+pub async fn erase() -> bool {
+    let mut storage = STORAGE.lock().await;
+    let Some(storage) = storage.as_mut() else { return false };
+
+    match storage.flash.erase_chip().await {
+        Ok(()) => {
+            storage.end = 0;
+            true
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Sensor calibration
+//!
+//! The gyro is calibrated at every boot, and can be calibrated again, along
+//! with the accelerometer and magnetometer, from the CLI or with stick
+//! commands while disarmed. Calibrations started by a command are saved to
+//! flash. The gyro bias measured at boot is not, since it changes with
+//! temperature, and saving it would wear out the flash.
+//!
+//! The settings also hold the rate controller gains, which are set over MSP,
+//! and the mode map, which is set from the CLI or over MSP. Both are used
+//! straight away and saved with `save`. So is the motor protocol, which is
+//! only used from the next boot.
+
+use defmt::println;
+
+use scout_ahrs::{
+    calibration::{
+        AccelCalibrator, BoardAlignment, GyroCalibrator, MagCalibrator, Position, MAG_SAMPLES,
+    },
+    Vector3,
+};
+use scout_rc::RcCommand;
+
+use crate::{cli::Command, imu, motors, pilot, settings::Settings, MAG_ALIGNMENT};
+
+/// Samples needed for gyro calibration, one second at the IMU's output data
+/// rate, and the largest spread allowed between them in radians per second.
+const GYRO_SAMPLES: u32 = imu::CONFIG.output_data_rate.hz();
+const GYRO_THRESHOLD: f32 = 0.035;
+
+/// Samples needed to record each accelerometer position, half a second at
+/// the IMU's output data rate, and the largest spread allowed between them
+/// in g.
+const ACCEL_SAMPLES: u32 = imu::CONFIG.output_data_rate.hz() / 2;
+const ACCEL_THRESHOLD: f32 = 0.05;
+
+/// Smallest distance between the magnetometer readings recorded, in gauss,
+/// around a tenth of the earth's field, and the largest RMS error of the
+/// fit relative to the field strength.
+const MAG_SPACING: f32 = 0.05;
+const MAG_MAX_RESIDUAL: f32 = 0.05;
+/// Progress is printed each time this many readings have been recorded.
+const MAG_PROGRESS_STEP: usize = MAG_SAMPLES / 10;
+
+/// How far the sticks must be held for a stick command.
+const STICK_COMMAND_THRESHOLD: f32 = 0.9;
+
+enum State {
+    Idle,
+    Gyro {
+        calibrator: GyroCalibrator,
+        save: bool,
+    },
+    Accel(AccelCalibrator),
+    Mag(MagCalibrator),
+}
+
+pub struct Calibration {
+    settings: Settings,
+    alignment: BoardAlignment,
+    mag_alignment: BoardAlignment,
+    state: State,
+    gyro_calibrated: bool,
+}
+
+impl Calibration {
+    /// Starts gyro calibration, using the stored settings until it is done.
+    pub fn new(settings: Settings) -> Self {
+        let [roll, pitch, yaw] = settings.board_alignment;
+
+        Self {
+            settings,
+            alignment: BoardAlignment::new(roll, pitch, yaw),
+            mag_alignment: BoardAlignment::new(
+                MAG_ALIGNMENT[0],
+                MAG_ALIGNMENT[1],
+                MAG_ALIGNMENT[2],
+            ),
+            state: State::Gyro {
+                calibrator: GyroCalibrator::new(GYRO_SAMPLES, GYRO_THRESHOLD),
+                save: false,
+            },
+            gyro_calibrated: false,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// True once the gyro has been calibrated since boot, and no calibration
+    /// is running.
+    pub fn is_calibrated(&self) -> bool {
+        self.gyro_calibrated && matches!(self.state, State::Idle)
+    }
+
+    /// Returns true if the settings should be saved.
+    pub fn command(&mut self, command: Command) -> bool {
+        match command {
+            Command::CalibrateGyro => {
+                println!("Calibrating gyro, keep still");
+                self.state = State::Gyro {
+                    calibrator: GyroCalibrator::new(GYRO_SAMPLES, GYRO_THRESHOLD),
+                    save: true,
+                };
+                false
+            }
+            Command::CalibrateAccel => {
+                println!("Calibrating accelerometer, hold still with each side facing up");
+                self.state = State::Accel(AccelCalibrator::new(ACCEL_SAMPLES, ACCEL_THRESHOLD));
+                false
+            }
+            Command::CalibrateMag => {
+                println!("Calibrating magnetometer, turn slowly through every orientation");
+                self.state = State::Mag(MagCalibrator::new(MAG_SPACING, MAG_MAX_RESIDUAL));
+                false
+            }
+            Command::Align(angles) => {
+                let [roll, pitch, yaw] = angles;
+                self.settings.board_alignment = angles;
+                self.alignment = BoardAlignment::new(roll, pitch, yaw);
+                true
+            }
+            Command::SetRateGains(gains) => {
+                self.settings.rate_gains = gains;
+                false
+            }
+            Command::SetModeRange { index, range } => {
+                self.settings.mode_map.set(index, range);
+                pilot::set_mode_map(self.settings.mode_map);
+                false
+            }
+            Command::SetMotorProtocol(protocol) => {
+                println!(
+                    "Motor protocol set to {}, save and reboot to use it",
+                    protocol.name()
+                );
+                self.settings.motor_protocol = protocol;
+                motors::set_protocol(protocol);
+                false
+            }
+            Command::Save => true,
+            // Sent to the ESCs by the control loop.
+            Command::Esc(_) => false,
+        }
+    }
+
+    /// Feeds a sample in the board's axes to the calibration in progress.
+    /// Returns true when a calibration which should be saved is done.
+    pub fn update(&mut self, gyro: Vector3, accel: Vector3) -> bool {
+        match &mut self.state {
+            State::Idle | State::Mag(_) => false,
+            State::Gyro { calibrator, save } => {
+                let retries = calibrator.retries();
+                let Some(bias) = calibrator.push(gyro) else {
+                    if calibrator.retries() != retries {
+                        println!("Gyro moved during calibration, retrying");
+                    }
+                    return false;
+                };
+
+                println!("Gyro calibrated, bias: {:?}", bias);
+                let save = *save;
+                self.settings.gyro_bias = bias;
+                self.gyro_calibrated = true;
+                self.state = State::Idle;
+                save
+            }
+            State::Accel(calibrator) => {
+                let Some(position) = calibrator.push(accel) else { return false };
+                let remaining = Position::ALL
+                    .iter()
+                    .filter(|position| !calibrator.recorded(**position))
+                    .count();
+                println!("Recorded {:?}, {} positions remaining", position, remaining);
+
+                let Some(accel) = calibrator.result() else { return false };
+                println!("Accelerometer calibrated: {:?}", accel);
+                self.settings.accel = accel;
+                self.state = State::Idle;
+                true
+            }
+        }
+    }
+
+    /// Feeds a magnetometer sample in its own axes to the calibration in
+    /// progress. Returns true when the calibration is done and should be
+    /// saved.
+    pub fn update_mag(&mut self, mag: Vector3) -> bool {
+        let State::Mag(calibrator) = &mut self.state else { return false };
+        if !calibrator.push(mag) {
+            return false;
+        }
+        if calibrator.count() % MAG_PROGRESS_STEP == 0 {
+            println!(
+                "Recorded {} of {} magnetometer readings",
+                calibrator.count(),
+                MAG_SAMPLES
+            );
+        }
+        if !calibrator.is_full() {
+            return false;
+        }
+
+        let result = calibrator.fit();
+        self.state = State::Idle;
+        match result {
+            Ok(fit) => {
+                println!("Magnetometer calibrated: {:?}", fit);
+                self.settings.mag = fit.calibration;
+                true
+            }
+            Err(e) => {
+                println!("Magnetometer calibration failed: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Corrects a magnetometer sample in its own axes, and rotates it into
+    /// the body frame. Returns `None` while the magnetometer is being
+    /// calibrated.
+    pub fn apply_mag(&self, mag: Vector3) -> Option<Vector3> {
+        if let State::Mag(_) = self.state {
+            return None;
+        }
+
+        Some(self.mag_alignment.apply(self.settings.mag.apply(mag)))
+    }
+
+    /// Corrects a sample in the board's axes, and rotates it into the body
+    /// frame.
+    pub fn apply(&self, gyro: Vector3, accel: Vector3) -> (Vector3, Vector3) {
+        (
+            self.alignment.apply(gyro - self.settings.gyro_bias),
+            self.alignment.apply(self.settings.accel.apply(accel)),
+        )
+    }
+}
+
+/// Recognizes stick commands, which must only be acted on while disarmed:
+/// yaw left and pitch down with the throttle low to calibrate the gyro, or
+/// with the throttle high to calibrate the accelerometer, and yaw right and
+/// pitch down with the throttle high to calibrate the magnetometer.
+pub fn stick_command(command: &RcCommand) -> Option<Command> {
+    if command.pitch > -STICK_COMMAND_THRESHOLD {
+        return None;
+    }
+    let yaw_left = command.yaw <= -STICK_COMMAND_THRESHOLD;
+    let yaw_right = command.yaw >= STICK_COMMAND_THRESHOLD;
+    let throttle_low = command.throttle <= 1.0 - STICK_COMMAND_THRESHOLD;
+    let throttle_high = command.throttle >= STICK_COMMAND_THRESHOLD;
+
+    match (yaw_left, yaw_right, throttle_low, throttle_high) {
+        (true, _, true, _) => Some(Command::CalibrateGyro),
+        (true, _, _, true) => Some(Command::CalibrateAccel),
+        (_, true, _, true) => Some(Command::CalibrateMag),
+        _ => None,
+    }
+}
@@ -0,0 +1,380 @@
+//! Serial command line
+//!
+//! Commands are read a line at a time from USART2, which the NUCLEO-F446RE
+//! connects to the ST-LINK virtual COM port, at 115200 baud. Each command is
+//! answered with `ok`, or with an error message.
+//!
+//! Commands which change the flight controller's settings are passed to the
+//! control loop. Queries, and reading and erasing the blackbox, are handled
+//! by the CLI task itself.
+//!
+//! MSP frames start with `$`, which no command does, so configurators can
+//! share the port. They are handed to `msp` a frame at a time.
+
+use core::fmt::Write;
+
+use defmt::{error, println};
+use embassy_stm32::{
+    peripherals::{DMA1_CH5, DMA1_CH6, USART2},
+    usart::{UartRx, UartTx},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+
+use scout_analog_esc::Protocol as AnalogProtocol;
+use scout_control::pid::Gains;
+use scout_dshot::{Command as EscCommand, Speed};
+use scout_flash::PAGE_SIZE;
+use scout_msp::Parser;
+use scout_rc::modes::{Mode, ModeRange, MAX_MODE_RANGES};
+
+use crate::{
+    blackbox, control,
+    motors::{self, Protocol},
+    msp, pilot,
+    scheduler::{self, Run, Task},
+};
+
+/// Commands waiting to be handled by the control loop, from the CLI or from
+/// stick commands.
+pub static COMMAND: Channel<CriticalSectionRawMutex, Command, 1> = Channel::new();
+
+/// USART2 RX is on PA3 and TX is on PA2.
+pub type CliRx = UartRx<'static, USART2, DMA1_CH5>;
+pub type CliTx = UartTx<'static, USART2, DMA1_CH6>;
+
+const MAX_LINE_LEN: usize = 64;
+const MAX_REPLY_LEN: usize = 96;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Command {
+    /// `calibrate gyro`
+    CalibrateGyro,
+    /// `calibrate accel`
+    CalibrateAccel,
+    /// `calibrate mag`
+    CalibrateMag,
+    /// `align <roll> <pitch> <yaw>`, with the board alignment in degrees.
+    Align([f32; 3]),
+    /// Roll, pitch and yaw rate controller gains, set over MSP. They are
+    /// used straight away, but only kept after `Save`.
+    SetRateGains([Gains; 3]),
+    /// `mode <slot> <mode> <aux channel> <start> <end>`, or
+    /// `mode <slot> clear`, with the aux channel counted from zero and the
+    /// range from -1.0 to 1.0. Like the gains, the mode map is used
+    /// straight away, but only kept after `Save`.
+    SetModeRange {
+        index: usize,
+        range: Option<ModeRange>,
+    },
+    /// `esc beep`, `esc direction <1|2>`, `esc 3d <on|off>` or `esc save`,
+    /// which send a DShot special command to every ESC. The ESCs only keep
+    /// a new direction or 3D mode after `esc save`.
+    Esc(EscCommand),
+    /// `motors pwm [<rate>]`, `motors oneshot125`, `motors oneshot42`,
+    /// `motors multishot` or `motors dshot150|dshot300|dshot600 [bidir]`.
+    /// The new protocol is only used after `Save` and a reboot.
+    SetMotorProtocol(Protocol),
+    /// `save`
+    Save,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_ascii_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("calibrate", Some("gyro")) => Command::CalibrateGyro,
+            ("calibrate", Some("accel")) => Command::CalibrateAccel,
+            ("calibrate", Some("mag")) => Command::CalibrateMag,
+            ("align", Some(roll)) => Command::Align([
+                roll.parse().ok()?,
+                words.next()?.parse().ok()?,
+                words.next()?.parse().ok()?,
+            ]),
+            ("mode", Some(index)) => {
+                let index = index
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index < MAX_MODE_RANGES)?;
+                let range = match words.next()? {
+                    "clear" => None,
+                    mode => Some(ModeRange {
+                        mode: Mode::from_name(mode)?,
+                        aux_channel: words.next()?.parse().ok()?,
+                        start: words.next()?.parse().ok()?,
+                        end: words.next()?.parse().ok()?,
+                    }),
+                };
+                Command::SetModeRange { index, range }
+            }
+            ("esc", Some(command)) => Command::Esc(match (command, words.next()) {
+                ("beep", None) => EscCommand::Beep1,
+                ("direction", Some("1")) => EscCommand::SpinDirection1,
+                ("direction", Some("2")) => EscCommand::SpinDirection2,
+                ("3d", Some("on")) => EscCommand::ThreeDModeOn,
+                ("3d", Some("off")) => EscCommand::ThreeDModeOff,
+                ("save", None) => EscCommand::SaveSettings,
+                _ => return None,
+            }),
+            ("motors", Some(name)) => {
+                let dshot = |speed, option: Option<&str>| {
+                    let bidirectional = match option {
+                        None => false,
+                        Some("bidir") => true,
+                        Some(_) => return None,
+                    };
+                    Some(Protocol::Dshot {
+                        speed,
+                        bidirectional,
+                    })
+                };
+                Command::SetMotorProtocol(match (name, words.next()) {
+                    ("pwm", rate) => Protocol::Analog(AnalogProtocol::Pwm {
+                        rate_hz: match rate {
+                            Some(rate) => rate.parse().ok()?,
+                            None => motors::DEFAULT_PWM_RATE_HZ,
+                        },
+                    }),
+                    ("oneshot125", None) => Protocol::Analog(AnalogProtocol::Oneshot125),
+                    ("oneshot42", None) => Protocol::Analog(AnalogProtocol::Oneshot42),
+                    ("multishot", None) => Protocol::Analog(AnalogProtocol::Multishot),
+                    ("dshot150", option) => dshot(Speed::Dshot150, option)?,
+                    ("dshot300", option) => dshot(Speed::Dshot300, option)?,
+                    ("dshot600", option) => dshot(Speed::Dshot600, option)?,
+                    _ => return None,
+                })
+            }
+            ("save", None) => Command::Save,
+            _ => return None,
+        };
+
+        words.next().is_none().then_some(command)
+    }
+}
+
+enum Line {
+    Command(Command),
+    /// `tasks`, which prints each task's timing statistics.
+    Tasks,
+    /// `tasks reset`
+    ResetTasks,
+    /// `modes`, which prints the mode map.
+    Modes,
+    /// `motors`, which prints the motor protocol in the settings.
+    Motors,
+    /// `blackbox`, which prints how much of the flash is used.
+    Blackbox,
+    /// `blackbox read`, which sends the recorded logs as raw bytes, after a
+    /// line giving their length.
+    BlackboxRead,
+    /// `blackbox erase`
+    BlackboxErase,
+}
+
+impl Line {
+    fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_ascii_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("tasks"), None, _) => Some(Line::Tasks),
+            (Some("tasks"), Some("reset"), None) => Some(Line::ResetTasks),
+            (Some("modes"), None, _) => Some(Line::Modes),
+            (Some("motors"), None, _) => Some(Line::Motors),
+            (Some("blackbox"), None, _) => Some(Line::Blackbox),
+            (Some("blackbox"), Some("read"), None) => Some(Line::BlackboxRead),
+            (Some("blackbox"), Some("erase"), None) => Some(Line::BlackboxErase),
+            _ => Command::parse(line).map(Line::Command),
+        }
+    }
+}
+
+/// A reply which is built up with `write!`, and truncated if it doesn't fit.
+struct Reply {
+    buf: [u8; MAX_REPLY_LEN],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_REPLY_LEN],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(MAX_REPLY_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        Ok(())
+    }
+}
+
+#[embassy_executor::task]
+pub async fn cli(mut rx: CliRx, mut tx: CliTx) {
+    let mut line = [0; MAX_LINE_LEN];
+    let mut len = 0;
+    let mut msp = Parser::new();
+    loop {
+        let mut buf = [0];
+        if let Err(e) = rx.read(&mut buf).await {
+            error!("{:?}", e);
+            continue;
+        }
+
+        if msp.is_receiving() || (len == 0 && buf[0] == b'$') {
+            if let Some(request) = msp.push(buf[0]) {
+                let run = Run::start(Task::Cli);
+                msp::handle(request, &mut tx).await;
+                run.finish();
+            }
+            continue;
+        }
+
+        match buf[0] {
+            // Terminals may end lines with both characters.
+            b'\r' | b'\n' if len == 0 => {}
+            b'\r' | b'\n' => {
+                let run = Run::start(Task::Cli);
+                handle_line(&line[..len], &mut tx).await;
+                len = 0;
+                run.finish();
+            }
+            byte if len < MAX_LINE_LEN => {
+                line[len] = byte;
+                len += 1;
+            }
+            // Overlong lines are truncated, and then won't parse.
+            _ => {}
+        }
+    }
+}
+
+async fn handle_line(line: &[u8], tx: &mut CliTx) {
+    let line = core::str::from_utf8(line).ok().and_then(Line::parse);
+    let reply: &[u8] = match line {
+        Some(Line::Command(command)) => {
+            COMMAND.send(command).await;
+            b"ok\r\n"
+        }
+        Some(Line::Tasks) => {
+            for task in Task::ALL {
+                let stats = scheduler::stats(task);
+                println!("{}: {:?}", task.name(), stats);
+
+                let mut reply = Reply::new();
+                let _ = if stats.runs == 0 {
+                    write!(reply, "{}: no runs\r\n", task.name())
+                } else {
+                    write!(
+                        reply,
+                        "{}: runs {} min {} avg {} max {} us, jitter {} us, overruns {}\r\n",
+                        task.name(),
+                        stats.runs,
+                        stats.min_us,
+                        stats.average_us(),
+                        stats.max_us,
+                        stats.max_jitter_us,
+                        stats.overruns,
+                    )
+                };
+                write(tx, reply.as_bytes()).await;
+            }
+            b"ok\r\n"
+        }
+        Some(Line::ResetTasks) => {
+            scheduler::reset_stats();
+            b"ok\r\n"
+        }
+        Some(Line::Modes) => {
+            let mode_map = pilot::mode_map();
+            for index in 0..MAX_MODE_RANGES {
+                let Some(range) = mode_map.get(index) else { continue };
+                let mut reply = Reply::new();
+                let _ = write!(
+                    reply,
+                    "mode {} {} {} {} {}\r\n",
+                    index,
+                    range.mode.name(),
+                    range.aux_channel,
+                    range.start,
+                    range.end
+                );
+                write(tx, reply.as_bytes()).await;
+            }
+            b"ok\r\n"
+        }
+        Some(Line::Motors) => {
+            let protocol = motors::protocol();
+            let mut reply = Reply::new();
+            let _ = match protocol {
+                Protocol::Analog(AnalogProtocol::Pwm { rate_hz }) => {
+                    write!(reply, "motors {} {}\r\n", protocol.name(), rate_hz)
+                }
+                _ if protocol.bidirectional() => {
+                    write!(reply, "motors {} bidir\r\n", protocol.name())
+                }
+                _ => write!(reply, "motors {}\r\n", protocol.name()),
+            };
+            write(tx, reply.as_bytes()).await;
+            b"ok\r\n"
+        }
+        Some(Line::Blackbox) => match blackbox::usage().await {
+            Some((used, capacity)) => {
+                let mut reply = Reply::new();
+                let _ = write!(reply, "blackbox: {} of {} bytes used\r\n", used, capacity);
+                write(tx, reply.as_bytes()).await;
+                b"ok\r\n"
+            }
+            None => b"no blackbox flash\r\n",
+        },
+        // The blackbox task is writing the flash while armed.
+        Some(Line::BlackboxRead | Line::BlackboxErase)
+            if control::latest().map_or(false, |state| state.armed) =>
+        {
+            b"not while armed\r\n"
+        }
+        Some(Line::BlackboxRead) => read_blackbox(tx).await,
+        Some(Line::BlackboxErase) => {
+            // Erasing takes from seconds to minutes, depending on the chip.
+            if blackbox::erase().await {
+                b"ok\r\n"
+            } else {
+                b"erase failed\r\n"
+            }
+        }
+        None => b"unknown command\r\n",
+    };
+
+    write(tx, reply).await;
+}
+
+async fn read_blackbox(tx: &mut CliTx) -> &'static [u8] {
+    let Some((used, _)) = blackbox::usage().await else { return b"no blackbox flash\r\n" };
+
+    let mut reply = Reply::new();
+    let _ = write!(reply, "blackbox: {} bytes\r\n", used);
+    write(tx, reply.as_bytes()).await;
+
+    // Logs end on a page boundary.
+    let mut buf = [0; PAGE_SIZE as usize];
+    for address in (0..used).step_by(buf.len()) {
+        if !blackbox::read(address, &mut buf).await {
+            return b"\r\nread failed\r\n";
+        }
+        write(tx, &buf).await;
+    }
+
+    b"\r\nok\r\n"
+}
+
+pub async fn write(tx: &mut CliTx, bytes: &[u8]) {
+    if let Err(e) = tx.write(bytes).await {
+        error!("{:?}", e);
+    }
+}
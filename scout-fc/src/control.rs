@@ -0,0 +1,487 @@
+//! Control loop task
+//!
+//! Runs on the high priority executor once for every IMU sample: sensor
+//! calibration and filtering, attitude, altitude and position estimation,
+//! arming, navigation, the attitude, altitude and rate controllers, the
+//! mixer, the motor outputs and the blackbox records.
+//! The latest state is published for the lower priority tasks with `latest`.
+
+use core::cell::Cell;
+
+use defmt::{error, println};
+use embassy_stm32::{exti::ExtiInput, peripherals::PB0};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use scout_ahrs::{
+    altitude, position, AltitudeEstimator, EulerAngles, Location, Mahony, PositionEstimator,
+    Vector3,
+};
+use scout_battery::Level;
+use scout_blackbox::Record;
+use scout_control::{
+    altitude::AltitudeHold,
+    angle::{AttitudeController, FlightMode},
+    arming::{self, Arming},
+    mixer::Mixer,
+    navigation::{self, Estimate, Navigator, Request},
+    pid::{Gains, RateController},
+    Torque,
+};
+use scout_filter::dynamic_notch::MAX_NOTCHES;
+use scout_gps::FixType;
+use scout_imu::Imu;
+use scout_rc::{
+    modes::{Mode, ModeSet},
+    RateSetpoint, RcCommand,
+};
+
+use crate::{
+    baro, battery,
+    blackbox::Recorder,
+    calibration::Calibration,
+    cli, gps,
+    gyro_filter::GyroFilter,
+    imu::{self, ImuSpi},
+    mag,
+    motors::{Motors, MOTOR_COUNT},
+    pilot, receiver,
+    scheduler::{Run, Task},
+    settings, AHRS_KI, AHRS_KP, ALTITUDE_HOLD, ALTITUDE_TIME_CONSTANT, ANGLE_CONFIG, ARMING_CONFIG,
+    BEACON_INTERVAL, BLACKBOX_RATE_DIVIDER, FAILSAFE_ACTION, FAILSAFE_DELAY, GEOMETRY,
+    GPS_MIN_SATELLITES, GYRO_DYNAMIC_NOTCH, GYRO_LOWPASS_HZ, GYRO_RPM_NOTCH, MAG_DECLINATION,
+    MAG_TIMEOUT, MAX_LOOP_TIME, MIXER_CONFIG, MOTOR_POLES, NAVIGATION, POSITION_TIME_CONSTANT,
+    RX_TIMEOUT,
+};
+
+/// What to do while armed when the radio link has been lost for
+/// `FAILSAFE_DELAY`. A critical battery also returns to home when this is
+/// `ReturnToHome`, but never disarms.
+#[allow(dead_code)]
+pub enum FailsafeAction {
+    /// Stop the motors.
+    Disarm,
+    /// Return to home and land. Without a position, altitude or heading
+    /// estimate, the motors are stopped instead.
+    ReturnToHome,
+}
+
+#[derive(Clone, Copy)]
+pub struct State {
+    pub armed: bool,
+    pub flight_mode: FlightMode,
+    pub attitude: EulerAngles,
+    /// Filtered body rates, in radians per second.
+    pub gyro: Vector3,
+    /// Calibrated acceleration in the body frame, in g.
+    pub accel: Vector3,
+    /// Corrected magnetic field in the body frame, in gauss, or `None` while
+    /// the heading isn't corrected by the magnetometer.
+    pub mag: Option<Vector3>,
+    /// Meters above the starting point, or `None` without a barometer.
+    pub altitude: Option<f32>,
+    /// Meters per second, positive upwards.
+    pub climb_rate: f32,
+    /// Meters north and east of the first GPS fix, or `None` without one.
+    pub position: Option<[f32; 2]>,
+    /// North and east velocity, in meters per second.
+    pub ground_velocity: [f32; 2],
+    /// Satellites used in the latest GPS fix.
+    pub satellites: u8,
+    /// The active navigation mode.
+    pub navigation: Option<Request>,
+    pub return_phase: Option<navigation::Phase>,
+    /// Throttle given to the mixer, which in altitude hold comes from the
+    /// altitude controller rather than the stick.
+    pub throttle: f32,
+    pub rate_setpoint: RateSetpoint,
+    /// Roll, pitch and yaw rate controller gains in use.
+    pub rate_gains: [Gains; 3],
+    pub torque: Torque,
+    pub motors: [f32; MOTOR_COUNT],
+    /// Motor speeds reported by bidirectional DShot.
+    pub motor_rpm: [Option<u32>; MOTOR_COUNT],
+    /// Centers of the roll, pitch and yaw dynamic notches, in Hz.
+    pub notch_centers: [[Option<f32>; MAX_NOTCHES]; 3],
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<Option<State>>> = Mutex::new(Cell::new(None));
+
+/// Returns `None` until the control loop has run.
+pub fn latest() -> Option<State> {
+    STATE.lock(|state| state.get())
+}
+
+#[embassy_executor::task]
+pub async fn control_loop(
+    mut imu: Imu<ImuSpi>,
+    mut data_ready: ExtiInput<'static, PB0>,
+    mut motors: Motors,
+    mut calibration: Calibration,
+) {
+    let mut gyro_filter = GyroFilter::new(
+        GYRO_RPM_NOTCH,
+        MOTOR_COUNT,
+        GYRO_DYNAMIC_NOTCH,
+        GYRO_LOWPASS_HZ,
+        imu::CONFIG.output_data_rate.hz() as f32,
+    );
+    let mut estimator = Mahony::new(AHRS_KP, AHRS_KI);
+    estimator.declination = MAG_DECLINATION.to_radians();
+    // The latest corrected magnetometer sample, and when it arrived.
+    let mut latest_mag: Option<(Vector3, Instant)> = None;
+    let mut altitude_estimator = AltitudeEstimator::new(ALTITUDE_TIME_CONSTANT);
+    let mut position_estimator = PositionEstimator::new(POSITION_TIME_CONSTANT);
+    let mut origin: Option<Location> = None;
+    let mut satellites = 0;
+    let mut last_sample_time: Option<Instant> = None;
+    let mut attitude_controller = AttitudeController::new(ANGLE_CONFIG);
+    let mut altitude_hold = AltitudeHold::new(ALTITUDE_HOLD);
+    let mut navigator = Navigator::new(NAVIGATION);
+    // The throttle from the last iteration, and whether it came from the
+    // altitude controller.
+    let mut last_throttle = 0.0;
+    let mut automatic_throttle = false;
+    let mut rate_controller = RateController::new(calibration.settings().rate_pid());
+    let mut arming = Arming::new(ARMING_CONFIG);
+    let mixer = Mixer::new(GEOMETRY, MIXER_CONFIG);
+    let mut recorder = Recorder::new(BLACKBOX_RATE_DIVIDER);
+    // Moved forward while armed, so the beacon waits for the motors to stop
+    // after disarming.
+    let mut last_beacon = Instant::now();
+
+    loop {
+        // The interrupt output is held high until the sample is read, so
+        // waiting on the level rather than an edge can't miss a sample.
+        data_ready.wait_for_high().await;
+        let sample_time = Instant::now();
+        let run = Run::start(Task::Control);
+
+        let sample = match imu.read().await {
+            Ok(sample) => sample,
+            Err(e) => {
+                error!("{:?}", e);
+                run.finish();
+                continue;
+            }
+        };
+
+        if let Ok(command) = cli::COMMAND.try_recv() {
+            if arming.is_armed() {
+                println!("Ignoring {:?} while armed", command);
+            } else if let cli::Command::Esc(command) = command {
+                // Takes a few milliseconds for commands which are repeated,
+                // but the aircraft is disarmed.
+                if !motors.send_command(command).await {
+                    println!("ESC commands need DShot");
+                }
+            } else {
+                if calibration.command(command) {
+                    settings::request_save(*calibration.settings());
+                }
+                rate_controller.config = calibration.settings().rate_pid();
+            }
+        }
+
+        let (gyro, accel) = imu::body_frame(&sample);
+        if calibration.update(gyro, accel) && !arming.is_armed() {
+            settings::request_save(*calibration.settings());
+        }
+        let (gyro, accel) = calibration.apply(gyro, accel);
+        if let Ok(sample) = mag::SAMPLE.try_recv() {
+            let field = mag::body_frame(&sample);
+            if calibration.update_mag(field) && !arming.is_armed() {
+                settings::request_save(*calibration.settings());
+            }
+            latest_mag = calibration
+                .apply_mag(field)
+                .map(|field| (field, Instant::now()));
+        }
+        // Samples stop arriving if the magnetometer fails, or it may never
+        // have been found.
+        let mag_field = latest_mag
+            .filter(|(_, time)| time.elapsed() < MAG_TIMEOUT)
+            .map(|(field, _)| field);
+        gyro_filter.update_motors(
+            &motors.erpm_telemetry().map(|telemetry| {
+                telemetry.map(|telemetry| telemetry.rpm(MOTOR_POLES) as f32 / 60.0)
+            }),
+        );
+        let gyro = gyro_filter.apply(gyro);
+
+        let Some(dt) = last_sample_time
+            .replace(sample_time)
+            .map(|last_sample_time| (sample_time - last_sample_time).as_micros() as f32 * 1e-6)
+        else {
+            run.finish();
+            continue;
+        };
+
+        let pilot = pilot::latest();
+        let rc_command = pilot.map_or(RcCommand::default(), |pilot| pilot.command);
+        let flight_mode = pilot.map_or(FlightMode::Acro, |pilot| pilot.flight_mode);
+
+        let gyro = match mag_field {
+            Some(field) => estimator.update_with_mag(gyro, accel, field, dt),
+            None => estimator.update(gyro, accel, dt),
+        };
+        let attitude = estimator.euler();
+        if let Ok(baro_altitude) = baro::ALTITUDE.try_recv() {
+            altitude_estimator.update_baro(baro_altitude);
+        }
+        altitude_estimator.update(
+            altitude::vertical_acceleration(estimator.attitude(), accel),
+            dt,
+        );
+        if let Ok(fix) = gps::FIX.try_recv() {
+            satellites = fix.satellites;
+            if fix.fix_type == FixType::Fix3d && fix.satellites >= GPS_MIN_SATELLITES {
+                let location = Location {
+                    latitude: fix.latitude,
+                    longitude: fix.longitude,
+                };
+                let origin = *origin.get_or_insert(location);
+                position_estimator.update_gps(location.offset_from(origin), fix.velocity);
+            } else {
+                position_estimator.reset();
+            }
+        }
+        position_estimator.update(
+            position::horizontal_acceleration(estimator.attitude(), accel),
+            dt,
+        );
+        let battery = battery::latest();
+        let rc_frame_age = pilot.map(|pilot| pilot.time.elapsed());
+        let status = arming::Status {
+            rx_link_ok: !receiver::link_lost()
+                && rc_frame_age.map_or(false, |age| age < RX_TIMEOUT),
+            failsafe: rc_frame_age.map_or(true, |age| age >= FAILSAFE_DELAY),
+            imu_calibrated: calibration.is_calibrated(),
+            // Without a battery, for example while powered over USB, the
+            // motors can still be armed for testing.
+            battery_ok: battery.map_or(true, |battery| battery.level < Level::Warning),
+            tilt: libm::fabsf(attitude.roll)
+                .max(libm::fabsf(attitude.pitch))
+                .to_degrees(),
+            loop_timing_ok: dt <= MAX_LOOP_TIME,
+        };
+        let was_armed = arming.is_armed();
+        let blocked = arming.blocked_reasons();
+        let arm_switch = pilot.map_or(false, |pilot| pilot.arm_switch);
+        // While the altitude controller sets the throttle, a low throttle
+        // stick asks for a descent rather than an idle aircraft, so mustn't
+        // count towards auto-disarm.
+        let arming_command = RcCommand {
+            throttle: if automatic_throttle {
+                last_throttle
+            } else {
+                rc_command.throttle
+            },
+            ..rc_command
+        };
+        let mut armed = arming.update(&arming_command, arm_switch, &status, dt);
+        if armed && !was_armed {
+            if let (Some(position), Some(altitude)) =
+                (position_estimator.position(), altitude_estimator.altitude())
+            {
+                navigator.set_home(position, altitude);
+            }
+        }
+
+        let modes = pilot.map_or(ModeSet::empty(), |pilot| pilot.modes);
+        let request = if status.failsafe {
+            match FAILSAFE_ACTION {
+                FailsafeAction::Disarm => None,
+                FailsafeAction::ReturnToHome => Some(Request::ReturnToHome),
+            }
+        } else if modes.contains(Mode::ReturnToHome)
+            || (battery.map_or(false, |battery| battery.level == Level::Critical)
+                && matches!(FAILSAFE_ACTION, FailsafeAction::ReturnToHome))
+        {
+            Some(Request::ReturnToHome)
+        } else if modes.contains(Mode::PositionHold) {
+            Some(Request::PositionHold)
+        } else {
+            None
+        };
+        let altitude = altitude_estimator.altitude();
+        let navigation = match (
+            armed.then_some(request).flatten(),
+            position_estimator.position(),
+        ) {
+            // Steering needs the heading, and return to home also needs
+            // the altitude.
+            (Some(request), Some(position))
+                if mag_field.is_some()
+                    && (request == Request::PositionHold || altitude.is_some()) =>
+            {
+                let estimate = Estimate {
+                    position,
+                    velocity: position_estimator.velocity(),
+                    altitude: altitude.unwrap_or(0.0),
+                    climb_rate: altitude_estimator.climb_rate(),
+                    yaw: attitude.yaw,
+                };
+                let sticks = [rc_command.roll, rc_command.pitch];
+                Some((request, navigator.update(request, &estimate, sticks, dt)))
+            }
+            _ => {
+                navigator.reset();
+                None
+            }
+        };
+        if armed && status.failsafe && navigation.is_none() {
+            arming.disarm();
+            armed = false;
+        }
+        if navigation.map_or(false, |(_, output)| output.landed) {
+            println!("Landed");
+            arming.disarm();
+            armed = false;
+        }
+        if armed != was_armed {
+            println!("{}", if armed { "Armed" } else { "Disarmed" });
+        }
+        if arming.blocked_reasons() != blocked && !armed {
+            println!("Arming blocked: {:?}", arming.blocked_reasons());
+        }
+
+        // Navigation steers by moving the sticks in angle mode.
+        let (flight_mode, rc_command) = match navigation.and_then(|(_, output)| output.angles) {
+            Some([roll, pitch]) => (
+                FlightMode::Angle,
+                RcCommand {
+                    roll: roll / ANGLE_CONFIG.max_angle,
+                    pitch: pitch / ANGLE_CONFIG.max_angle,
+                    ..rc_command
+                },
+            ),
+            None => (flight_mode, rc_command),
+        };
+        let mut acro_setpoint = pilot.map_or(RateSetpoint::default(), |pilot| pilot.acro_setpoint);
+        // The yaw stick's last position can't be trusted without the link.
+        if status.failsafe {
+            acro_setpoint.yaw = 0.0;
+        }
+        let rate_setpoint = attitude_controller.update(
+            flight_mode,
+            &rc_command,
+            &acro_setpoint,
+            attitude.roll.to_degrees(),
+            attitude.pitch.to_degrees(),
+            dt,
+        );
+
+        let climb_rate_setpoint = navigation.and_then(|(_, output)| output.climb_rate);
+        let altitude_hold_active = armed && modes.contains(Mode::AltitudeHold);
+        let tilt_cos = libm::cosf(attitude.roll) * libm::cosf(attitude.pitch);
+        let (throttle, automatic) = match (altitude, climb_rate_setpoint) {
+            (Some(altitude), Some(climb_rate_setpoint)) => (
+                altitude_hold.update_climb_rate(
+                    climb_rate_setpoint,
+                    last_throttle,
+                    altitude,
+                    altitude_estimator.climb_rate(),
+                    tilt_cos,
+                    dt,
+                ),
+                true,
+            ),
+            (Some(altitude), None) if altitude_hold_active => (
+                altitude_hold.update(
+                    rc_command.throttle,
+                    altitude,
+                    altitude_estimator.climb_rate(),
+                    tilt_cos,
+                    dt,
+                ),
+                true,
+            ),
+            _ => {
+                altitude_hold.reset();
+                (rc_command.throttle, false)
+            }
+        };
+        last_throttle = throttle;
+        automatic_throttle = automatic;
+
+        if !armed {
+            rate_controller.reset();
+        }
+        let torque = rate_controller.update(
+            &rate_setpoint,
+            [
+                gyro.x.to_degrees(),
+                gyro.y.to_degrees(),
+                gyro.z.to_degrees(),
+            ],
+            throttle,
+            dt,
+        );
+        let mixer_output = mixer.mix(throttle, &torque);
+        rate_controller.set_mixer_saturated(mixer_output.saturated);
+
+        let motor_outputs = core::array::from_fn(|i| mixer_output.motors[i]);
+        if armed {
+            motors.set(&motor_outputs);
+            last_beacon = sample_time;
+        } else if modes.contains(Mode::Beeper) && sample_time - last_beacon >= BEACON_INTERVAL {
+            motors.beacon();
+            last_beacon = sample_time;
+        } else {
+            motors.stop();
+        }
+        recorder.update(armed, sample_time, |time_us| {
+            let command = pilot.map_or(RcCommand::default(), |pilot| pilot.command);
+            Record {
+                time_us,
+                gyro: [
+                    gyro.x.to_degrees(),
+                    gyro.y.to_degrees(),
+                    gyro.z.to_degrees(),
+                ],
+                setpoint: [rate_setpoint.roll, rate_setpoint.pitch, rate_setpoint.yaw],
+                pid: rate_controller
+                    .terms()
+                    .map(|terms| [terms.p, terms.i, terms.d, terms.f]),
+                motors: motor_outputs,
+                rc: [command.roll, command.pitch, command.yaw, command.throttle],
+            }
+        });
+
+        let notch_centers = gyro_filter.notch_centers();
+        let state = State {
+            armed,
+            flight_mode,
+            attitude,
+            gyro,
+            accel,
+            mag: mag_field,
+            altitude: altitude_estimator.altitude(),
+            climb_rate: altitude_estimator.climb_rate(),
+            position: position_estimator.position(),
+            ground_velocity: position_estimator.velocity(),
+            satellites,
+            navigation: navigation.map(|(request, _)| request),
+            return_phase: navigator.phase(),
+            throttle,
+            rate_setpoint,
+            rate_gains: [
+                rate_controller.config.roll,
+                rate_controller.config.pitch,
+                rate_controller.config.yaw,
+            ],
+            torque,
+            motors: motor_outputs,
+            motor_rpm: motors
+                .erpm_telemetry()
+                .map(|telemetry| telemetry.map(|telemetry| telemetry.rpm(MOTOR_POLES))),
+            notch_centers: core::array::from_fn(|axis| {
+                core::array::from_fn(|i| notch_centers[axis].get(i).copied().flatten())
+            }),
+        };
+        STATE.lock(|latest| latest.set(Some(state)));
+
+        run.finish();
+    }
+}
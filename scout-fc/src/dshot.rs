@@ -0,0 +1,327 @@
+//! DShot motor outputs
+//!
+//! TIM2 generates the DShot signal for four motors on channels 1 to 4: PA15,
+//! PB3, PB10 (labeled D6 on the NUCLEO-F446RE) and PB2. Each bit is one timer
+//! period, and its high time is set by the channel's compare register. On
+//! every update event the timer requests a DMA burst which loads the next
+//! bit's compare value into all four channels, so a whole frame is sent
+//! without involving the CPU.
+//!
+//! With bidirectional DShot the outputs are inverted, and once a frame has
+//! been sent the channels switch to capturing both edges of the ESCs'
+//! replies. TIM7 is started along with each frame, and its interrupt makes
+//! the switch once the frame is done, so the CPU needn't wait for it. The
+//! replies are decoded when the next frame is sent.
+
+use cortex_m::peripheral::NVIC;
+use embassy_stm32::{
+    interrupt, pac,
+    pac::Interrupt,
+    peripherals::{DMA1_CH1, PA15, PB10, PB2, PB3, TIM2, TIM7},
+    rcc,
+    timer::low_level::{Basic16bitInstance, GeneralPurpose32bitInstance},
+};
+use embassy_time::{Duration, Timer};
+
+use scout_dshot::{
+    telemetry::{self, TELEMETRY_BITS},
+    BitTiming, Command, ErpmTelemetry, Frame, Speed,
+};
+
+use crate::{motors::MOTOR_COUNT, pulse_input::configure_pin};
+
+/// Alternate function number of the TIM2 channels on the pins used here.
+const TIM2_AF: u8 = 1;
+
+/// DMA1 stream 1 is connected to TIM2_UP on channel 3. See RM0390 Table 28.
+const DMA_STREAM: usize = 1;
+const DMA_CHANNEL: u8 = 3;
+
+/// Word offset of CCR1 from the start of the timer registers, where each
+/// burst starts.
+const CCR1_OFFSET: u8 = 13;
+
+const BITS_PER_FRAME: usize = 16;
+/// Bits of zero high time sent after each frame, so the outputs are left
+/// idle.
+const TRAILING_BITS: usize = 2;
+const BUFFER_LEN: usize = (BITS_PER_FRAME + TRAILING_BITS) * MOTOR_COUNT;
+/// Bit periods from starting a frame until the capture starts. The first
+/// bit starts on the timer's next update, up to a period later, so this
+/// allows for one more than the frame and its trailing bits.
+const CAPTURE_DELAY_BITS: u32 = (BITS_PER_FRAME + TRAILING_BITS) as u32 + 1;
+
+/// A reply has at most one edge per bit, plus the return to idle.
+const MAX_EDGES: usize = TELEMETRY_BITS as usize + 1;
+
+// Only accessed while the DMA stream is disabled.
+static mut BUFFER: [u32; BUFFER_LEN] = [0; BUFFER_LEN];
+
+// Written by the TIM2 interrupt while capturing, and otherwise only accessed
+// with TIM7 stopped and the capture interrupts disabled.
+static mut EDGES: [[u32; MAX_EDGES]; MOTOR_COUNT] = [[0; MAX_EDGES]; MOTOR_COUNT];
+static mut EDGE_COUNTS: [usize; MOTOR_COUNT] = [0; MOTOR_COUNT];
+
+pub struct Dshot {
+    timing: BitTiming,
+    bidirectional: bool,
+    /// Length of a reply bit in timer ticks.
+    telemetry_bit_ticks: u32,
+    capturing: bool,
+    telemetry: [Option<ErpmTelemetry>; MOTOR_COUNT],
+}
+
+impl Dshot {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        _tim2: TIM2,
+        _tim7: TIM7,
+        _dma: DMA1_CH1,
+        _pa15: PA15,
+        _pb3: PB3,
+        _pb10: PB10,
+        _pb2: PB2,
+        speed: Speed,
+        bidirectional: bool,
+    ) -> Self {
+        let timer_hz = rcc::get_freqs().apb1_tim.0;
+        let dshot = Self {
+            timing: BitTiming::new(timer_hz, speed),
+            bidirectional,
+            telemetry_bit_ticks: timer_hz / speed.telemetry_bit_rate(),
+            capturing: false,
+            telemetry: [None; MOTOR_COUNT],
+        };
+
+        // Safety: Taking ownership of the peripherals ensures nothing else
+        // uses them, and the DMA stream isn't started until a frame is sent.
+        unsafe {
+            for (port, pin) in [
+                (pac::GPIOA, 15),
+                (pac::GPIOB, 3),
+                (pac::GPIOB, 10),
+                (pac::GPIOB, 2),
+            ] {
+                configure_pin(port, pin, TIM2_AF);
+                if bidirectional {
+                    // The ESCs release the line between frame and reply.
+                    port.pupdr()
+                        .modify(|w| w.set_pupdr(pin, pac::gpio::vals::Pupdr::PULLUP));
+                }
+            }
+
+            pac::RCC.apb1enr().modify(|w| w.set_tim2en(true));
+            pac::RCC.ahb1enr().modify(|w| w.set_dma1en(true));
+
+            let regs = TIM2::regs_gp32();
+            regs.dcr().write(|w| {
+                w.set_dba(CCR1_OFFSET);
+                w.set_dbl(MOTOR_COUNT as u8 - 1);
+            });
+            dshot.configure_output();
+            regs.cr1().modify(|w| w.set_cen(true));
+
+            if bidirectional {
+                // TIM7 counts at the same rate as TIM2, and is started for
+                // each frame.
+                pac::RCC.apb1enr().modify(|w| w.set_tim7en(true));
+                let capture_timer = TIM7::regs();
+                capture_timer
+                    .arr()
+                    .write(|w| w.set_arr((dshot.timing.period * CAPTURE_DELAY_BITS - 1) as u16));
+                capture_timer.dier().write(|w| w.set_uie(true));
+
+                NVIC::unmask(Interrupt::TIM2);
+                NVIC::unmask(Interrupt::TIM7);
+            }
+        }
+
+        dshot
+    }
+
+    /// Starts sending one frame to each motor. Returns `false`, and sends
+    /// nothing, if the previous frames are still being sent.
+    pub fn send(&mut self, frames: &[Frame; MOTOR_COUNT]) -> bool {
+        let stream = pac::DMA1.st(DMA_STREAM);
+
+        // Safety: The buffer is only written while the stream is disabled,
+        // and the stream only reads it. The edges are only accessed once TIM7
+        // and the capture interrupts are stopped.
+        unsafe {
+            if stream.cr().read().en() {
+                return false;
+            }
+
+            if self.capturing {
+                self.finish_capture();
+                self.configure_output();
+            }
+
+            let mut high_times = [0; BITS_PER_FRAME];
+            for (motor, frame) in frames.iter().enumerate() {
+                let word = if self.bidirectional {
+                    frame.encode_bidirectional()
+                } else {
+                    frame.encode()
+                };
+                self.timing.high_times(word, &mut high_times);
+                for (bit, high_time) in high_times.iter().enumerate() {
+                    BUFFER[bit * MOTOR_COUNT + motor] = *high_time;
+                }
+            }
+
+            // Stream 1's flags are in the low interrupt registers, at index 1.
+            pac::DMA1.ifcr(0).write(|w| {
+                w.set_tcif(DMA_STREAM, true);
+                w.set_htif(DMA_STREAM, true);
+                w.set_teif(DMA_STREAM, true);
+                w.set_dmeif(DMA_STREAM, true);
+                w.set_feif(DMA_STREAM, true);
+            });
+            stream
+                .par()
+                .write_value(TIM2::regs_gp32().dmar().as_ptr() as u32);
+            stream.m0ar().write_value(BUFFER.as_ptr() as u32);
+            stream.ndtr().write(|w| w.set_ndt(BUFFER_LEN as u16));
+            stream.cr().write(|w| {
+                w.set_chsel(DMA_CHANNEL);
+                w.set_dir(pac::dma::vals::Dir::MEMORYTOPERIPHERAL);
+                w.set_minc(pac::dma::vals::Inc::INCREMENTED);
+                w.set_msize(pac::dma::vals::Size::BITS32);
+                w.set_psize(pac::dma::vals::Size::BITS32);
+                w.set_pl(pac::dma::vals::Pl::VERYHIGH);
+                w.set_en(true);
+            });
+
+            if self.bidirectional {
+                // The ESCs reply 30us after the frame, which is too soon to
+                // rely on being woken by the executor, so the TIM7 interrupt
+                // starts the capture.
+                EDGE_COUNTS = [0; MOTOR_COUNT];
+                let capture_timer = TIM7::regs();
+                capture_timer.cnt().write(|w| w.set_cnt(0));
+                capture_timer.cr1().modify(|w| w.set_cen(true));
+                self.capturing = true;
+            }
+        }
+
+        true
+    }
+
+    /// Sends a special command to every motor, repeating it as many times as
+    /// the command requires. The motors must be stopped.
+    pub async fn send_command(&mut self, command: Command) {
+        let frames = [Frame::command(command); MOTOR_COUNT];
+        for _ in 0..command.repeat_count() {
+            while !self.send(&frames) {
+                Timer::after(Duration::from_micros(100)).await;
+            }
+            Timer::after(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// The latest reply from each ESC, as of the last call to `send`. `None`
+    /// if bidirectional DShot is disabled or the reply was missing or
+    /// corrupt.
+    pub fn erpm_telemetry(&self) -> &[Option<ErpmTelemetry>; MOTOR_COUNT] {
+        &self.telemetry
+    }
+
+    unsafe fn configure_output(&self) {
+        let regs = TIM2::regs_gp32();
+        regs.dier().write(|w| w.set_ude(true));
+        for channel in 0..MOTOR_COUNT {
+            regs.ccer().modify(|w| w.set_cce(channel, false));
+            regs.ccmr_input(channel / 2)
+                .modify(|w| w.set_ccs(channel % 2, pac::timer::vals::CcmrInputCcs(0b00)));
+            regs.ccmr_output(channel / 2).modify(|w| {
+                w.set_ocm(channel % 2, pac::timer::vals::Ocm::PWMMODE1);
+                // Only take the new compare value at the start of a bit.
+                w.set_ocpe(channel % 2, true);
+            });
+            regs.ccr(channel).write(|w| w.set_ccr(0));
+            regs.ccer().modify(|w| {
+                w.set_ccp(channel, self.bidirectional);
+                w.set_ccnp(channel, false);
+                w.set_cce(channel, true);
+            });
+        }
+        regs.arr().write(|w| w.set_arr(self.timing.period - 1));
+        regs.cnt().write(|w| w.set_cnt(0));
+    }
+
+    unsafe fn finish_capture(&mut self) {
+        // The capture may not have started yet, if the next frame follows
+        // closely.
+        cortex_m::interrupt::free(|_| {
+            TIM7::regs().cr1().modify(|w| w.set_cen(false));
+            TIM7::regs().sr().write(|w| w.0 = 0);
+            NVIC::unpend(Interrupt::TIM7);
+        });
+        TIM2::regs_gp32().dier().write(|w| w.0 = 0);
+        self.capturing = false;
+
+        for (motor, telemetry) in self.telemetry.iter_mut().enumerate() {
+            let edges = &EDGES[motor][..EDGE_COUNTS[motor]];
+            *telemetry = telemetry::line_from_edges(edges, self.telemetry_bit_ticks)
+                .and_then(|line| ErpmTelemetry::decode(line).ok());
+        }
+    }
+}
+
+/// Switches the TIM2 channels from sending the frame to capturing the
+/// replies.
+unsafe fn start_capture() {
+    let regs = TIM2::regs_gp32();
+    regs.dier().write(|w| w.set_ude(false));
+    regs.arr().write(|w| w.set_arr(u32::MAX));
+
+    for channel in 0..MOTOR_COUNT {
+        regs.ccer().modify(|w| w.set_cce(channel, false));
+        regs.ccmr_input(channel / 2)
+            .modify(|w| w.set_ccs(channel % 2, pac::timer::vals::CcmrInputCcs(0b01)));
+        regs.ccer().modify(|w| {
+            // Capture both edges.
+            w.set_ccp(channel, true);
+            w.set_ccnp(channel, true);
+            w.set_cce(channel, true);
+        });
+    }
+    regs.sr().write(|w| w.0 = 0);
+    regs.dier().modify(|w| {
+        for channel in 0..MOTOR_COUNT {
+            w.set_ccie(channel, true);
+        }
+    });
+}
+
+#[interrupt]
+fn TIM7() {
+    // Safety: TIM7 only runs after a frame has been started, and is stopped
+    // before the channels are reconfigured for the next one.
+    unsafe {
+        let regs = TIM7::regs();
+        regs.cr1().modify(|w| w.set_cen(false));
+        regs.sr().write(|w| w.0 = 0);
+        start_capture();
+    }
+}
+
+#[interrupt]
+fn TIM2() {
+    let regs = TIM2::regs_gp32();
+    // Safety: The statics are only accessed from this interrupt while it is
+    // enabled, and it can't preempt itself.
+    let (edges, edge_counts) = unsafe { (&mut EDGES, &mut EDGE_COUNTS) };
+    let status = unsafe { regs.sr().read() };
+
+    for motor in (0..MOTOR_COUNT).filter(|channel| status.ccif(*channel)) {
+        // Reading the capture clears its interrupt flag.
+        let capture = unsafe { regs.ccr(motor).read().ccr() };
+
+        if edge_counts[motor] < MAX_EDGES {
+            edges[motor][edge_counts[motor]] = capture;
+            edge_counts[motor] += 1;
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! GPS receiver task
+//!
+//! The receiver is connected to UART5, with the receiver's TX on PD2 and its
+//! RX on PC12. u-blox receivers are found by trying each common baud rate,
+//! and are switched to `BAUD_RATE`, UBX output at `UPDATE_PERIOD_MS` and the
+//! airborne dynamic model. Other receivers are expected to send NMEA at one
+//! of the common baud rates, and are used as they are.
+//!
+//! Fixes are passed to the control loop. If they stop arriving, the receiver
+//! may have restarted with its default settings, so it is configured again.
+//!
+//! Bytes are read by DMA until the line goes idle, which is after each burst
+//! of messages.
+
+use defmt::{error, println, warn};
+use embassy_stm32::{
+    peripherals::{DMA1_CH0, DMA1_CH7, PC12, PD2, UART5},
+    usart::{self, Uart},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use scout_gps::{
+    ubx::{self, Command, DynamicModel},
+    Fix, Packet, Parser,
+};
+
+/// The latest fix, for the control loop.
+pub static FIX: Channel<CriticalSectionRawMutex, Fix, 1> = Channel::new();
+
+/// Baud rates tried when looking for the receiver. u-blox receivers start at
+/// 9600 or 38400 baud.
+const BAUD_RATES: [u32; 5] = [9600, 38400, 57600, 115200, 230400];
+/// Baud rate u-blox receivers are switched to.
+const BAUD_RATE: u32 = 115200;
+/// Time between navigation solutions from u-blox receivers.
+const UPDATE_PERIOD_MS: u16 = 100;
+
+/// How long to wait for a reply to a command.
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to listen at each baud rate for NMEA sentences. Receivers send
+/// at least one a second.
+const NMEA_TIMEOUT: Duration = Duration::from_millis(1500);
+/// How long without a fix before the receiver is configured again.
+const FIX_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Bytes read at once. Reads end early when the line goes idle, so this
+/// only needs to hold the largest packet, a UBX NAV-PVT message.
+const READ_BUF_LEN: usize = 128;
+
+pub struct GpsUart {
+    pub uart: UART5,
+    pub rx: PD2,
+    pub tx: PC12,
+    pub tx_dma: DMA1_CH7,
+    pub rx_dma: DMA1_CH0,
+}
+
+type Connection<'d> = Uart<'d, UART5, DMA1_CH7, DMA1_CH0>;
+
+impl GpsUart {
+    /// The UART is created again for each baud rate.
+    fn connect(&mut self, baud_rate: u32) -> Connection<'_> {
+        let mut config = usart::Config::default();
+        config.baudrate = baud_rate;
+
+        Uart::new(
+            &mut self.uart,
+            &mut self.rx,
+            &mut self.tx,
+            &mut self.tx_dma,
+            &mut self.rx_dma,
+            config,
+        )
+    }
+}
+
+#[embassy_executor::task]
+pub async fn gps(mut uart: GpsUart) {
+    loop {
+        let baud_rate = match configure_ublox(&mut uart).await {
+            Some(baud_rate) => baud_rate,
+            None => match find_nmea(&mut uart).await {
+                Some(baud_rate) => baud_rate,
+                None => {
+                    warn!("No GPS receiver found");
+                    continue;
+                }
+            },
+        };
+
+        let mut connection = uart.connect(baud_rate);
+        let mut input = Input::new();
+        let mut last_fix = Instant::now();
+        while last_fix.elapsed() < FIX_TIMEOUT {
+            let Some(packet) = read_packet(&mut connection, &mut input, FIX_TIMEOUT).await else {
+                continue;
+            };
+
+            if let Packet::Fix(fix) = packet {
+                last_fix = Instant::now();
+                // The control loop takes the latest fix when it's ready, so
+                // a full channel holds one it hasn't seen.
+                let _ = FIX.try_recv();
+                let _ = FIX.try_send(fix);
+            }
+        }
+        warn!("GPS fixes stopped, {} overruns", input.overruns);
+    }
+}
+
+/// Returns the baud rate once a u-blox receiver has been configured.
+async fn configure_ublox(uart: &mut GpsUart) -> Option<u32> {
+    for baud_rate in BAUD_RATES {
+        send(
+            &mut uart.connect(baud_rate),
+            Command::ConfigureUart {
+                baud_rate: BAUD_RATE,
+            },
+        )
+        .await;
+        // Give the receiver time to switch baud rate.
+        Timer::after(Duration::from_millis(100)).await;
+
+        // Only a receiver which understood the port configuration will
+        // answer at the new baud rate.
+        let mut connection = uart.connect(BAUD_RATE);
+        let mut input = Input::new();
+        if request(
+            &mut connection,
+            &mut input,
+            Command::SetRate {
+                period_ms: UPDATE_PERIOD_MS,
+            },
+        )
+        .await
+        {
+            for configuration in [
+                Command::SetMessageRate {
+                    message: ubx::NAV_PVT,
+                    rate: 1,
+                },
+                Command::SetDynamicModel(DynamicModel::Airborne4g),
+            ] {
+                if !request(&mut connection, &mut input, configuration).await {
+                    warn!("GPS rejected {:?}", configuration);
+                }
+            }
+
+            println!("u-blox GPS found at {} baud", baud_rate);
+            return Some(BAUD_RATE);
+        }
+    }
+
+    None
+}
+
+/// Returns the baud rate at which NMEA fixes are being sent.
+async fn find_nmea(uart: &mut GpsUart) -> Option<u32> {
+    for baud_rate in BAUD_RATES {
+        let mut connection = uart.connect(baud_rate);
+        let mut input = Input::new();
+        let start = Instant::now();
+        while let Some(remaining) = NMEA_TIMEOUT.checked_sub(start.elapsed()) {
+            if let Some(Packet::Fix(_)) = read_packet(&mut connection, &mut input, remaining).await
+            {
+                println!("NMEA GPS found at {} baud", baud_rate);
+                return Some(baud_rate);
+            }
+        }
+    }
+
+    None
+}
+
+/// Sends a command, returning whether the receiver accepted it.
+async fn request(connection: &mut Connection<'_>, input: &mut Input, command: Command) -> bool {
+    send(connection, command).await;
+
+    let start = Instant::now();
+    while let Some(remaining) = ACK_TIMEOUT.checked_sub(start.elapsed()) {
+        match read_packet(connection, input, remaining).await {
+            Some(Packet::Ack(id)) if id == command.id() => return true,
+            Some(Packet::Nak(id)) if id == command.id() => return false,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+async fn send(connection: &mut Connection<'_>, command: Command) {
+    let mut buf = [0; ubx::MAX_FRAME_LEN];
+    let len = command.encode(&mut buf);
+    if let Err(e) = connection.write(&buf[..len]).await {
+        error!("{:?}", e);
+    }
+}
+
+/// Bytes read from the receiver, and the parser they are fed to. A read
+/// may end partway through a packet, or hold several.
+struct Input {
+    parser: Parser,
+    buf: [u8; READ_BUF_LEN],
+    /// The bytes of `buf` not yet parsed.
+    start: usize,
+    end: usize,
+    /// Reads which lost bytes because they arrived between reads. The
+    /// packet being received is lost, and the parser drops it on its
+    /// checksum.
+    overruns: u32,
+}
+
+impl Input {
+    fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            buf: [0; READ_BUF_LEN],
+            start: 0,
+            end: 0,
+            overruns: 0,
+        }
+    }
+
+    /// Parses the bytes read until a packet is complete.
+    fn next_packet(&mut self) -> Option<Packet> {
+        while self.start < self.end {
+            let byte = self.buf[self.start];
+            self.start += 1;
+            if let Some(packet) = self.parser.push(byte) {
+                return Some(packet);
+            }
+        }
+
+        None
+    }
+}
+
+/// Reads until a packet is complete, or returns `None` after `timeout`.
+async fn read_packet(
+    connection: &mut Connection<'_>,
+    input: &mut Input,
+    timeout: Duration,
+) -> Option<Packet> {
+    let start = Instant::now();
+    loop {
+        if let Some(packet) = input.next_packet() {
+            return Some(packet);
+        }
+
+        let remaining = timeout.checked_sub(start.elapsed())?;
+        match with_timeout(remaining, connection.read_until_idle(&mut input.buf)).await {
+            Ok(Ok(len)) => {
+                input.start = 0;
+                input.end = len;
+            }
+            Ok(Err(usart::Error::Overrun)) => input.overruns += 1,
+            Ok(Err(e)) => error!("{:?}", e),
+            Err(_) => return None,
+        }
+    }
+}
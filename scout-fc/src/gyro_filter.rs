@@ -0,0 +1,64 @@
+//! Gyro noise filtering
+//!
+//! Each axis passes through notches on the motors' harmonics, which follow
+//! the motor speeds reported by bidirectional DShot, then a dynamic notch,
+//! which removes the strongest remaining vibration, and then a low pass
+//! filter. The axes' dynamic notches analyse their spectra a third of an
+//! interval apart, so that no sample pays for more than one analysis.
+
+use scout_ahrs::Vector3;
+use scout_filter::{dynamic_notch, rpm_notch, Biquad, DynamicNotch, Filter, RpmNotch};
+
+pub struct GyroFilter {
+    rpm_notches: [RpmNotch; 3],
+    notches: [DynamicNotch; 3],
+    low_pass: [Biquad; 3],
+}
+
+impl GyroFilter {
+    /// A `low_pass_hz` of zero disables the low pass filter.
+    pub fn new(
+        rpm_notch: rpm_notch::Config,
+        motor_count: usize,
+        notch: dynamic_notch::Config,
+        low_pass_hz: f32,
+        sample_hz: f32,
+    ) -> Self {
+        Self {
+            rpm_notches: core::array::from_fn(|_| RpmNotch::new(rpm_notch, motor_count, sample_hz)),
+            notches: core::array::from_fn(|axis| {
+                let mut filter = DynamicNotch::new(notch, sample_hz);
+                filter.set_analysis_phase(axis * dynamic_notch::ANALYSIS_INTERVAL / 3);
+                filter
+            }),
+            low_pass: [Biquad::low_pass(low_pass_hz, sample_hz, Biquad::BUTTERWORTH_Q); 3],
+        }
+    }
+
+    /// Moves the motor notches to each motor's rotation frequency in Hz, or
+    /// `None` where there is no telemetry. Must be called for every sample.
+    pub fn update_motors(&mut self, motor_hz: &[Option<f32>]) {
+        for rpm_notch in &mut self.rpm_notches {
+            rpm_notch.update(motor_hz);
+        }
+    }
+
+    pub fn apply(&mut self, gyro: Vector3) -> Vector3 {
+        let [x, y, z] = [gyro.x, gyro.y, gyro.z];
+        let mut axis = |i: usize, value: f32| {
+            let value = self.rpm_notches[i].apply(value);
+            self.low_pass[i].apply(self.notches[i].apply(value))
+        };
+
+        Vector3::new(axis(0, x), axis(1, y), axis(2, z))
+    }
+
+    /// Center frequencies of the roll, pitch and yaw notches, in Hz.
+    pub fn notch_centers(&self) -> [&[Option<f32>]; 3] {
+        [
+            self.notches[0].centers(),
+            self.notches[1].centers(),
+            self.notches[2].centers(),
+        ]
+    }
+}
@@ -0,0 +1,37 @@
+//! Inertial measurement unit
+//!
+//! The IMU shares SPI1 with the radio. Its chip select is PA4 (labeled A2 on
+//! the NUCLEO-F446RE) and its interrupt output is on PB0 (labeled A3). It is
+//! read by the control loop, which runs once for every sample.
+
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_stm32::{gpio::Output, peripherals::PA4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use scout_ahrs::Vector3;
+use scout_imu::{AccelRange, Config, GyroRange, OutputDataRate, Sample};
+
+use crate::SpiBus1;
+
+pub const CONFIG: Config = Config {
+    gyro_range: GyroRange::Dps2000,
+    accel_range: AccelRange::G16,
+    output_data_rate: OutputDataRate::Hz1000,
+};
+
+pub type ImuSpi = SpiDevice<'static, CriticalSectionRawMutex, SpiBus1, Output<'static, PA4>>;
+
+/// Returns the gyro rate in radians per second and the acceleration in
+/// units of standard gravity, in the body frame used by `scout_ahrs`.
+///
+/// The IMU is expected to be mounted flat with its x axis forward, in which
+/// case its y and z axes point left and up.
+pub fn body_frame(sample: &Sample) -> (Vector3, Vector3) {
+    let [gx, gy, gz] = sample.gyro;
+    let [ax, ay, az] = sample.accel;
+
+    (
+        Vector3::new(gx, -gy, -gz) * (core::f32::consts::PI / 180.0),
+        Vector3::new(ax, -ay, -az),
+    )
+}
@@ -0,0 +1,58 @@
+//! Magnetometer task
+//!
+//! The magnetometer is on I2C1, with SCL on PB8 and SDA on PB9 (labeled D15
+//! and D14 on the NUCLEO-F446RE), which is where the compass on most GPS
+//! modules connects. It is read periodically, and each sample is passed to
+//! the control loop.
+
+use defmt::error;
+use embassy_stm32::{i2c::I2c, peripherals::I2C1};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+
+use scout_ahrs::Vector3;
+use scout_mag::{Mag, Sample};
+
+use crate::scheduler::{Run, Task};
+
+pub type MagI2c = I2c<'static, I2C1>;
+
+/// The latest sample, for the control loop.
+pub static SAMPLE: Channel<CriticalSectionRawMutex, Sample, 1> = Channel::new();
+
+/// Slower than every supported chip's sample rate, and plenty for heading.
+pub const PERIOD: Duration = Duration::from_millis(20);
+
+/// Returns the field in the body frame used by `scout_ahrs`, before
+/// calibration.
+///
+/// The magnetometer is expected to be mounted flat with its x axis forward,
+/// in which case its y and z axes point left and up. Other orientations are
+/// set with `MAG_ALIGNMENT`.
+pub fn body_frame(sample: &Sample) -> Vector3 {
+    let [x, y, z] = sample.field;
+
+    Vector3::new(x, -y, -z)
+}
+
+#[embassy_executor::task]
+pub async fn mag(mut mag: Mag<MagI2c>) {
+    let mut next = Instant::now();
+    loop {
+        next += PERIOD;
+        Timer::at(next).await;
+        let run = Run::start(Task::Mag);
+
+        match mag.read() {
+            Ok(sample) => {
+                // The control loop takes the latest sample when it's ready,
+                // so a full channel holds one it hasn't seen.
+                let _ = SAMPLE.try_recv();
+                let _ = SAMPLE.try_send(sample);
+            }
+            Err(e) => error!("{:?}", e),
+        }
+
+        run.finish();
+    }
+}
@@ -7,27 +7,380 @@ use defmt_rtt as _;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
 use embassy_stm32::{
-    gpio::{Level, Output, Speed},
+    adc::Adc,
+    dma::NoDma,
+    exti::ExtiInput,
+    flash::Flash,
+    gpio::{Input, Level, Output, Pull, Speed},
+    i2c::{self, I2c},
+    interrupt, pac,
     peripherals::{DMA2_CH0, DMA2_CH3, SPI1},
+    rcc,
     spi::{self, Spi},
-    time::mhz,
+    time::{khz, Hertz},
+    usart::{self, Uart},
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
-use embassy_time::{Delay, Duration, Timer};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Delay, Duration};
 use static_cell::StaticCell;
 
 use panic_probe as _;
 
+use scout_baro::Baro;
+use scout_control::{
+    altitude, angle,
+    arming::{self, ArmMethod},
+    mixer::{self, Geometry},
+    navigation,
+    pid::{self, Gains},
+};
+use scout_dshot::Speed;
+use scout_filter::{dynamic_notch, rpm_notch};
+use scout_flash::SpiFlash;
+use scout_imu::Imu;
+use scout_mag::Mag;
 use scout_nrf24l01::SymaX5C;
+use scout_rc::{
+    modes::{Mode, ModeRange},
+    AxisConfig, RateProfile, RcConfig,
+};
+
+mod baro;
+mod battery;
+mod blackbox;
+mod calibration;
+mod cli;
+mod control;
+mod dshot;
+mod gps;
+mod gyro_filter;
+mod imu;
+mod mag;
+mod motors;
+mod msp;
+mod pilot;
+mod pulse_input;
+mod receiver;
+mod scheduler;
+mod settings;
+mod telemetry;
+use calibration::Calibration;
+use control::FailsafeAction;
+use motors::{Motors, Protocol};
+use receiver::Receiver;
+use settings::Settings;
 
 type SpiBus1 = embassy_stm32::spi::Spi<'static, SPI1, DMA2_CH3, DMA2_CH0>;
-static SPI_BUS: StaticCell<Mutex<ThreadModeRawMutex, SpiBus1>> = StaticCell::new();
+/// The bus is shared between executors of different priorities, so its
+/// mutex must be safe to lock from interrupts.
+static SPI_BUS: StaticCell<Mutex<CriticalSectionRawMutex, SpiBus1>> = StaticCell::new();
+/// The MPU-6000 only accepts register writes at up to 1MHz, so the IMU is
+/// configured with SPI1 at that rate. Afterwards only its samples are read,
+/// which it allows at up to 20MHz, and the bus is raised to the fastest rate
+/// within that of the barometer and radio.
+const SPI1_CONFIG_CLOCK: Hertz = Hertz(1_000_000);
+const SPI1_MAX_CLOCK: Hertz = Hertz(10_000_000);
+
+/// The NUCLEO-F446RE's HSE is the ST-LINK's 8MHz clock output. The PLL runs
+/// the core at its maximum of 180MHz, with APB1 and APB2 at their maximums.
+const HSE: Hertz = Hertz(8_000_000);
+const SYSCLK: Hertz = Hertz(180_000_000);
+const PCLK1: Hertz = Hertz(45_000_000);
+const PCLK2: Hertz = Hertz(90_000_000);
+
+const RECEIVER: Receiver = Receiver::SymaX5C;
+
+/// Attitude estimator gains. See `scout_ahrs::Mahony` for their effect.
+const AHRS_KP: f32 = 0.5;
+const AHRS_KI: f32 = 0.05;
+
+/// Angle from true north to magnetic north where the aircraft flies, in
+/// degrees, positive when magnetic north is to the east. Published for any
+/// location by NOAA.
+const MAG_DECLINATION: f32 = 0.0;
+/// Roll, pitch and yaw of the magnetometer relative to the aircraft, in
+/// degrees, for when it is on a GPS module mounted in another orientation.
+const MAG_ALIGNMENT: [f32; 3] = [0.0; 3];
+/// The heading is no longer corrected when no magnetometer sample has
+/// arrived for this long.
+const MAG_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Notches on the motor harmonics, which require bidirectional DShot.
+const GYRO_RPM_NOTCH: rpm_notch::Config = rpm_notch::Config {
+    harmonics: 3,
+    q: 5.0,
+    min_hz: 80.0,
+    fade_range_hz: 40.0,
+    telemetry_timeout: 0.05,
+    fade_time: 0.2,
+};
+/// Vibration is followed between these frequencies, which must be below half
+/// the IMU's output data rate.
+const GYRO_DYNAMIC_NOTCH: dynamic_notch::Config = dynamic_notch::Config {
+    min_hz: 80.0,
+    max_hz: 450.0,
+    q: 3.0,
+    notch_count: 2,
+};
+/// Cutoff of the low pass filter after the dynamic notch, or zero to disable
+/// it.
+const GYRO_LOWPASS_HZ: f32 = 150.0;
+
+/// Rate controller gains, producing torque demands where 1.0 is full motor
+/// authority from rates in degrees per second. The roll, pitch and yaw gains
+/// are defaults, which can be changed over MSP and saved.
+const RATE_PID: pid::Config = pid::Config {
+    roll: Gains {
+        p: 0.004,
+        i: 0.03,
+        d: 0.00004,
+        f: 0.00002,
+    },
+    pitch: Gains {
+        p: 0.004,
+        i: 0.03,
+        d: 0.00004,
+        f: 0.00002,
+    },
+    yaw: Gains {
+        p: 0.006,
+        i: 0.04,
+        d: 0.0,
+        f: 0.00002,
+    },
+    i_limit: 0.3,
+    output_limit: 1.0,
+    d_lowpass_hz: 100.0,
+    feedforward_lowpass_hz: 30.0,
+    iterm_relax_hz: 15.0,
+    iterm_relax_threshold: 40.0,
+    tpa_breakpoint: 0.5,
+    tpa_rate: 0.3,
+};
+
+/// Roughly how long the altitude estimate takes to correct towards the
+/// barometer, in seconds. See `scout_ahrs::AltitudeEstimator`.
+const ALTITUDE_TIME_CONSTANT: f32 = 2.0;
+
+/// Roughly how long the position estimate takes to correct towards GPS, in
+/// seconds. See `scout_ahrs::PositionEstimator`.
+const POSITION_TIME_CONSTANT: f32 = 2.0;
+/// GPS fixes from fewer satellites are not used.
+const GPS_MIN_SATELLITES: u8 = 6;
+
+const ALTITUDE_HOLD: altitude::Config = altitude::Config {
+    max_climb_rate: 2.0,
+    deadband: 0.1,
+    altitude_gain: 1.0,
+    climb_rate_p: 0.1,
+    climb_rate_i: 0.05,
+    i_limit: 0.2,
+    hover_throttle: 0.4,
+    min_throttle: 0.1,
+    max_throttle: 0.9,
+};
+
+/// Position hold and return to home. `max_angle` should be within
+/// `ANGLE_CONFIG.max_angle`, and the climb and descent rates within
+/// `ALTITUDE_HOLD.max_climb_rate`.
+const NAVIGATION: navigation::Config = navigation::Config {
+    position_gain: 1.0,
+    velocity_p: 1.5,
+    velocity_i: 0.5,
+    i_limit: 4.0,
+    max_angle: 30.0,
+    max_speed: 8.0,
+    return_altitude: 30.0,
+    arrival_radius: 3.0,
+    climb_rate: 2.0,
+    descent_rate: 1.5,
+    landing_altitude: 5.0,
+    landing_rate: 0.5,
+    landed_time: 1.0,
+};
+
+const ANGLE_CONFIG: angle::Config = angle::Config {
+    max_angle: 45.0,
+    level_gain: 5.0,
+    transition_time: 0.2,
+};
+
+/// The motor protocol used until another is set from the CLI or over MSP and
+/// saved. Bidirectional DShot requires ESCs which support it.
+const MOTOR_PROTOCOL: Protocol = Protocol::Dshot {
+    speed: Speed::Dshot600,
+    bidirectional: true,
+};
+/// Number of magnet poles in the motors, for converting eRPM to RPM.
+const MOTOR_POLES: u8 = 14;
+/// While disarmed with the beeper mode on, DShot ESCs are told to beep this
+/// often. Each beep lasts around a quarter of a second.
+const BEACON_INTERVAL: Duration = Duration::from_millis(500);
+
+const GEOMETRY: Geometry = Geometry::QUAD_X;
+
+const MIXER_CONFIG: mixer::Config = mixer::Config {
+    idle_throttle: 0.05,
+    airmode: true,
+    motor_order: mixer::Config::STANDARD_MOTOR_ORDER,
+    yaw_reversed: false,
+};
+
+const ARMING_CONFIG: arming::Config = arming::Config {
+    method: ArmMethod::Switch,
+    low_throttle: 0.05,
+    max_tilt: 25.0,
+    gesture_time: 1.0,
+    auto_disarm_time: 5.0,
+};
+
+/// For a 10k/1k voltage divider and no current sensor. Warnings block
+/// arming, and a critical battery in flight triggers `FAILSAFE_ACTION` if it
+/// is return to home.
+const BATTERY: scout_battery::Config = scout_battery::Config {
+    voltage_scale: 11.0,
+    current_scale: 0.0,
+    current_offset: 0.0,
+    internal_resistance: 0.0,
+    cells: 0,
+    max_cell_voltage: 4.3,
+    warning_cell_voltage: 3.5,
+    critical_cell_voltage: 3.3,
+    alert_delay: 2.0,
+    capacity: 0.0,
+    time_constant: 0.2,
+};
+
+/// The radio link is considered lost when no frame has arrived for this
+/// long, and failsafe is entered after `FAILSAFE_DELAY`.
+const RX_TIMEOUT: Duration = Duration::from_millis(100);
+const FAILSAFE_DELAY: Duration = Duration::from_secs(1);
+const FAILSAFE_ACTION: FailsafeAction = FailsafeAction::ReturnToHome;
+/// A CRSF receiver's link is also considered lost when the percentage of
+/// packets arriving from the transmitter drops below `CRSF_MIN_LINK_QUALITY`,
+/// or the signal at the receiver's active antenna is weaker than
+/// `CRSF_MIN_RSSI`, in dBm. The RSSI limit should be near the receiver's
+/// sensitivity in the RF mode used.
+const CRSF_MIN_LINK_QUALITY: u8 = 10;
+const CRSF_MIN_RSSI: i16 = -120;
+
+/// While armed, the blackbox records every this many control loop
+/// iterations.
+const BLACKBOX_RATE_DIVIDER: u32 = 2;
+
+/// Longest time between IMU samples at which the control loop is considered
+/// to be keeping up, in seconds.
+const MAX_LOOP_TIME: f32 = 0.002;
+
+/// Mode ranges used until others are saved. The Syma transmitter flags
+/// arrive on aux channels 0 (flip, which is unused), 1 (picture), 2 (video)
+/// and 3 (high rates). Altitude hold, position hold and return to home are
+/// on channels only other receivers have.
+const MODE_RANGES: [ModeRange; 6] = [
+    ModeRange {
+        mode: Mode::Arm,
+        aux_channel: 2,
+        start: 0.5,
+        end: 1.0,
+    },
+    ModeRange {
+        mode: Mode::Angle,
+        aux_channel: 3,
+        start: -1.0,
+        end: -0.5,
+    },
+    ModeRange {
+        mode: Mode::Beeper,
+        aux_channel: 1,
+        start: 0.5,
+        end: 1.0,
+    },
+    ModeRange {
+        mode: Mode::AltitudeHold,
+        aux_channel: 4,
+        start: 0.5,
+        end: 1.0,
+    },
+    ModeRange {
+        mode: Mode::PositionHold,
+        aux_channel: 5,
+        start: 0.5,
+        end: 1.0,
+    },
+    ModeRange {
+        mode: Mode::ReturnToHome,
+        aux_channel: 6,
+        start: 0.5,
+        end: 1.0,
+    },
+];
+
+const RC_CONFIG: RcConfig = RcConfig {
+    throttle: RECEIVER.throttle_calibration(),
+    yaw: AxisConfig {
+        calibration: RECEIVER.stick_calibration(),
+        deadband: 0.02,
+        expo: 0.0,
+        rates: RateProfile::new(200.0, 400.0),
+    },
+    pitch: AxisConfig {
+        calibration: RECEIVER.stick_calibration(),
+        deadband: 0.02,
+        expo: 0.0,
+        rates: RateProfile::new(200.0, 670.0),
+    },
+    roll: AxisConfig {
+        calibration: RECEIVER.stick_calibration(),
+        deadband: 0.02,
+        expo: 0.0,
+        rates: RateProfile::new(200.0, 670.0),
+    },
+    aux: RECEIVER.stick_calibration(),
+};
+
+/// Sets the SPI1 clock to the fastest rate within `max`, which embassy's
+/// `Spi` only does when it is created.
+async fn set_spi1_clock(bus: &Mutex<CriticalSectionRawMutex, SpiBus1>, max: Hertz) {
+    // Holding the bus keeps the other devices from starting a transfer.
+    let _bus = bus.lock().await;
+    // The SPI clock is PCLK2 divided by 2 to the power of BR + 1.
+    let pclk2_hz = rcc::get_freqs().apb2.0;
+    let br = (0..8u8).find(|br| pclk2_hz >> (br + 1) <= max.0).unwrap_or(7);
+    let cr1 = pac::SPI1.cr1();
+    unsafe {
+        cr1.modify(|w| w.set_spe(false));
+        cr1.modify(|w| w.set_br(pac::spi::vals::Br(br)));
+        cr1.modify(|w| w.set_spe(true));
+    }
+}
 
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
-    let p = embassy_stm32::init(Default::default());
+async fn main(spawner: Spawner) {
+    let mut config = embassy_stm32::Config::default();
+    config.rcc.hse = Some(HSE);
+    config.rcc.bypass_hse = true;
+    config.rcc.sys_ck = Some(SYSCLK);
+    config.rcc.hclk = Some(SYSCLK);
+    config.rcc.pclk1 = Some(PCLK1);
+    config.rcc.pclk2 = Some(PCLK2);
+    let p = embassy_stm32::init(config);
 
-    let spi_bus_1 = {
+    let mut flash = Flash::new(p.FLASH);
+    let settings = Settings::load(&mut flash);
+    println!("{:?}", settings);
+    pilot::set_mode_map(settings.mode_map);
+    motors::set_protocol(settings.motor_protocol);
+    let calibration = Calibration::new(settings);
+
+    {
+        let mut config = usart::Config::default();
+        config.baudrate = 115200;
+        let uart = Uart::new(p.USART2, p.PA3, p.PA2, p.DMA1_CH6, p.DMA1_CH5, config);
+        let (tx, rx) = uart.split();
+        unwrap!(spawner.spawn(cli::cli(rx, tx)));
+    }
+
+    let spi_bus_1: &'static Mutex<CriticalSectionRawMutex, SpiBus1> = {
         let sck = p.PA5;
         let miso = p.PA6;
         let mosi = p.PA7;
@@ -38,31 +391,128 @@ async fn main(_spawner: Spawner) {
             miso,
             p.DMA2_CH3,
             p.DMA2_CH0,
-            mhz(1),
+            SPI1_CONFIG_CLOCK,
             spi::Config::default(),
         );
-        SPI_BUS.init(Mutex::<ThreadModeRawMutex, _>::new(spi))
+        SPI_BUS.init(Mutex::<CriticalSectionRawMutex, _>::new(spi))
     };
-    let csn = Output::new(p.PB6, Level::High, Speed::High);
-    let spi_dev_1 = SpiDevice::new(spi_bus_1, csn);
 
-    // PC7 is labeled D9 on the NUCLEO-F446RE
-    // See UM1724 Table 19
-    let ce = Output::new(p.PC7, Level::Low, Speed::High);
+    let cs = Output::new(p.PA4, Level::High, Speed::High);
+    let mut imu = unwrap!(Imu::new(SpiDevice::new(spi_bus_1, cs), Delay, imu::CONFIG).await);
+    println!("IMU: {:?}", imu.chip());
+    unwrap!(imu.enable_data_ready_interrupt().await);
+    set_spi1_clock(spi_bus_1, SPI1_MAX_CLOCK).await;
+    let data_ready = ExtiInput::new(Input::new(p.PB0, Pull::Down), p.EXTI0);
 
-    let mut radio = unwrap!(SymaX5C::new(spi_dev_1, ce, Delay).await);
+    // PA8 is labeled D7 on the NUCLEO-F446RE. Altitude hold isn't available
+    // without a barometer, but the aircraft can fly.
+    let cs = Output::new(p.PA8, Level::High, Speed::High);
+    match Baro::new(SpiDevice::new(spi_bus_1, cs), Delay).await {
+        Ok(baro) => {
+            println!("Baro: {:?}", baro.chip());
+            unwrap!(spawner.spawn(baro::baro(baro)));
+        }
+        Err(e) => error!("Baro: {:?}", e),
+    }
 
-    loop {
-        match radio.read().await {
-            Ok(Some(radio_data)) => {
-                println!("{:?}", radio_data);
-            }
-            Ok(None) => {
-                // No new radio packet available
-            }
-            Err(e) => error!("{:?}", e),
+    // PB12 is on the CN10 header. Without the flash chip nothing is
+    // recorded.
+    let cs = Output::new(p.PB12, Level::High, Speed::High);
+    match SpiFlash::new(SpiDevice::new(spi_bus_1, cs), Delay).await {
+        Ok(flash) => {
+            println!("Blackbox flash: {:?}", flash.jedec_id());
+            unwrap!(spawner.spawn(blackbox::blackbox(flash)));
         }
+        Err(e) => error!("Blackbox flash: {:?}", e),
+    }
+
+    // The heading isn't corrected without a magnetometer, so navigation
+    // modes aren't available, but the aircraft can fly.
+    let i2c = I2c::new(
+        p.I2C1,
+        p.PB8,
+        p.PB9,
+        interrupt::take!(I2C1_EV),
+        NoDma,
+        NoDma,
+        khz(400),
+        i2c::Config::default(),
+    );
+    match Mag::new(i2c, Delay).await {
+        Ok(mag) => {
+            println!("Mag: {:?}", mag.chip());
+            unwrap!(spawner.spawn(mag::mag(mag)));
+        }
+        Err(e) => error!("Mag: {:?}", e),
+    }
+
+    let adc = Adc::new(p.ADC1, &mut Delay);
+    unwrap!(spawner.spawn(battery::battery(adc, p.PA0, p.PA1)));
+
+    unwrap!(spawner.spawn(gps::gps(gps::GpsUart {
+        uart: p.UART5,
+        rx: p.PD2,
+        tx: p.PC12,
+        tx_dma: p.DMA1_CH7,
+        rx_dma: p.DMA1_CH0,
+    })));
+
+    match RECEIVER {
+        Receiver::SymaX5C => {
+            let csn = Output::new(p.PB6, Level::High, Speed::High);
+            let spi_dev_1 = SpiDevice::new(spi_bus_1, csn);
 
-        Timer::after(Duration::from_millis(1)).await;
+            // PC7 is labeled D9 on the NUCLEO-F446RE
+            // See UM1724 Table 19
+            let ce = Output::new(p.PC7, Level::Low, Speed::High);
+
+            let radio = unwrap!(SymaX5C::new(spi_dev_1, ce, Delay).await);
+            unwrap!(spawner.spawn(receiver::syma_x5c(radio)));
+        }
+        Receiver::Sbus | Receiver::Crsf | Receiver::Ibus => {
+            let uart = Uart::new(
+                p.USART1,
+                p.PA10,
+                p.PA9,
+                p.DMA2_CH7,
+                p.DMA2_CH2,
+                unwrap!(RECEIVER.uart_config()),
+            );
+            let (tx, rx) = uart.split();
+
+            match RECEIVER {
+                Receiver::Sbus => unwrap!(spawner.spawn(receiver::sbus(rx))),
+                Receiver::Crsf => {
+                    unwrap!(spawner.spawn(receiver::crsf(rx)));
+                    unwrap!(spawner.spawn(receiver::crsf_telemetry(tx)));
+                }
+                _ => unwrap!(spawner.spawn(receiver::ibus(rx))),
+            }
+        }
+        Receiver::Ppm => pulse_input::init_ppm(p.TIM3, p.PB4),
+        Receiver::Pwm => pulse_input::init_pwm(p.TIM3, p.PB4, p.PB5, p.PC8, p.PC9),
     }
+
+    let motors = Motors::new(
+        settings.motor_protocol,
+        p.TIM2,
+        p.TIM7,
+        p.DMA1_CH1,
+        p.PA15,
+        p.PB3,
+        p.PB10,
+        p.PB2,
+    );
+
+    unwrap!(spawner.spawn(pilot::pilot_input()));
+    unwrap!(spawner.spawn(telemetry::telemetry()));
+    unwrap!(spawner.spawn(settings::save_settings(flash)));
+    unwrap!(
+        scheduler::start_high_priority().spawn(control::control_loop(
+            imu,
+            data_ready,
+            motors,
+            calibration
+        ))
+    );
 }
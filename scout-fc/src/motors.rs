@@ -0,0 +1,217 @@
+//! Motor outputs
+//!
+//! Motors 1 to 4 are on TIM2 channels 1 to 4 (PA15, PB3, PB10 and PB2),
+//! whichever protocol drives them. DShot is handled by `dshot`, which also
+//! uses TIM7 for bidirectional DShot, and the analog protocols use
+//! `SimplePwm`, with the pulse width setting the throttle. The pulse timing
+//! of the analog protocols comes from `scout_analog_esc`.
+//!
+//! The protocol is kept in the settings, and the driver is chosen from it at
+//! boot. A new protocol set from the CLI or over MSP is used from the next
+//! boot after it is saved.
+
+use core::cell::Cell;
+
+use embassy_stm32::{
+    peripherals::{DMA1_CH1, PA15, PB10, PB2, PB3, TIM2, TIM7},
+    pwm::{
+        simple_pwm::{PwmPin, SimplePwm},
+        Channel,
+    },
+    time::Hertz,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use scout_analog_esc::{DutyRange, Protocol as AnalogProtocol};
+use scout_dshot::{Command, ErpmTelemetry, Frame, Speed};
+
+use crate::{dshot::Dshot, MOTOR_PROTOCOL};
+
+pub const MOTOR_COUNT: usize = 4;
+/// PWM rate used when a rate isn't given with the protocol.
+pub const DEFAULT_PWM_RATE_HZ: u32 = 490;
+
+const CHANNELS: [Channel; MOTOR_COUNT] = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+
+static PROTOCOL: Mutex<CriticalSectionRawMutex, Cell<Protocol>> =
+    Mutex::new(Cell::new(MOTOR_PROTOCOL));
+
+/// The protocol in the settings, which is used from the next boot.
+pub fn protocol() -> Protocol {
+    PROTOCOL.lock(|protocol| protocol.get())
+}
+
+pub fn set_protocol(protocol: Protocol) {
+    PROTOCOL.lock(|cell| cell.set(protocol));
+}
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub enum Protocol {
+    /// Standard PWM, Oneshot or Multishot.
+    Analog(AnalogProtocol),
+    Dshot {
+        speed: Speed,
+        bidirectional: bool,
+    },
+}
+
+impl Protocol {
+    /// The name used by the CLI.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Protocol::Analog(AnalogProtocol::Pwm { .. }) => "pwm",
+            Protocol::Analog(AnalogProtocol::Oneshot125) => "oneshot125",
+            Protocol::Analog(AnalogProtocol::Oneshot42) => "oneshot42",
+            Protocol::Analog(AnalogProtocol::Multishot) => "multishot",
+            Protocol::Dshot {
+                speed: Speed::Dshot150,
+                ..
+            } => "dshot150",
+            Protocol::Dshot {
+                speed: Speed::Dshot300,
+                ..
+            } => "dshot300",
+            Protocol::Dshot {
+                speed: Speed::Dshot600,
+                ..
+            } => "dshot600",
+        }
+    }
+
+    pub const fn bidirectional(&self) -> bool {
+        matches!(
+            self,
+            Protocol::Dshot {
+                bidirectional: true,
+                ..
+            }
+        )
+    }
+}
+
+pub struct AnalogMotors {
+    pwm: SimplePwm<'static, TIM2>,
+    duty_range: DutyRange,
+}
+
+impl AnalogMotors {
+    fn new(pwm: SimplePwm<'static, TIM2>, duty_range: DutyRange) -> Self {
+        let mut motors = Self { pwm, duty_range };
+
+        for channel in CHANNELS {
+            motors.pwm.set_duty(channel, duty_range.min);
+            motors.pwm.enable(channel);
+        }
+
+        motors
+    }
+
+    fn set(&mut self, throttle: &[f32; MOTOR_COUNT]) {
+        for (channel, throttle) in CHANNELS.into_iter().zip(throttle) {
+            self.pwm.set_duty(channel, self.duty_range.duty(*throttle));
+        }
+    }
+}
+
+pub enum Motors {
+    Dshot(Dshot),
+    Analog(AnalogMotors),
+}
+
+impl Motors {
+    pub fn new(
+        protocol: Protocol,
+        tim2: TIM2,
+        tim7: TIM7,
+        dma: DMA1_CH1,
+        pa15: PA15,
+        pb3: PB3,
+        pb10: PB10,
+        pb2: PB2,
+    ) -> Self {
+        let timing = match protocol {
+            Protocol::Dshot {
+                speed,
+                bidirectional,
+            } => {
+                return Motors::Dshot(Dshot::new(
+                    tim2,
+                    tim7,
+                    dma,
+                    pa15,
+                    pb3,
+                    pb10,
+                    pb2,
+                    speed,
+                    bidirectional,
+                ))
+            }
+            Protocol::Analog(protocol) => protocol.timing(),
+        };
+
+        let pwm = SimplePwm::new(
+            tim2,
+            Some(PwmPin::new_ch1(pa15)),
+            Some(PwmPin::new_ch2(pb3)),
+            Some(PwmPin::new_ch3(pb10)),
+            Some(PwmPin::new_ch4(pb2)),
+            Hertz(timing.rate_hz),
+        );
+        let duty_range = timing.duty_range(pwm.get_max_duty());
+
+        Motors::Analog(AnalogMotors::new(pwm, duty_range))
+    }
+
+    /// Sets each motor's throttle, in the range 0.0..=1.0. Zero is the lowest
+    /// throttle, which may still spin the motor.
+    pub fn set(&mut self, throttle: &[f32; MOTOR_COUNT]) {
+        match self {
+            Motors::Dshot(dshot) => {
+                dshot.send(&throttle.map(|throttle| Frame::throttle(throttle, false)));
+            }
+            Motors::Analog(analog) => analog.set(throttle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        match self {
+            Motors::Dshot(dshot) => {
+                dshot.send(&[Frame::MOTOR_STOP; MOTOR_COUNT]);
+            }
+            // Analog ESCs treat the lowest throttle as stopped.
+            Motors::Analog(analog) => analog.set(&[0.0; MOTOR_COUNT]),
+        }
+    }
+
+    /// Makes DShot ESCs beep, so a lost aircraft can be found. The ESCs
+    /// ignore the following frames until the beep is done. Analog ESCs can't
+    /// beep, and are stopped instead. The motors must already be stopped.
+    pub fn beacon(&mut self) {
+        match self {
+            Motors::Dshot(dshot) => {
+                dshot.send(&[Frame::command(Command::Beep1); MOTOR_COUNT]);
+            }
+            Motors::Analog(analog) => analog.set(&[0.0; MOTOR_COUNT]),
+        }
+    }
+
+    /// Sends a DShot special command to every ESC. Returns `false` for
+    /// analog ESCs, which have no commands. The motors must be stopped.
+    pub async fn send_command(&mut self, command: Command) -> bool {
+        match self {
+            Motors::Dshot(dshot) => {
+                dshot.send_command(command).await;
+                true
+            }
+            Motors::Analog(_) => false,
+        }
+    }
+
+    /// Latest reply from each ESC, when using bidirectional DShot.
+    pub fn erpm_telemetry(&self) -> [Option<ErpmTelemetry>; MOTOR_COUNT] {
+        match self {
+            Motors::Dshot(dshot) => *dshot.erpm_telemetry(),
+            Motors::Analog(_) => [None; MOTOR_COUNT],
+        }
+    }
+}
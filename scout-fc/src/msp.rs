@@ -0,0 +1,374 @@
+//! MSP server
+//!
+//! Ground station configurators talk MSP over the CLI's port. Requests are
+//! answered from the latest state of the control loop and pilot input, and
+//! changes to the gains, mode ranges and motor protocol, saving and
+//! rebooting are refused while armed.
+
+use embassy_time::{Duration, Timer};
+
+use scout_analog_esc::Protocol as AnalogProtocol;
+use scout_control::{angle::FlightMode, navigation::Request as Navigation, pid::Gains};
+use scout_dshot::Speed;
+use scout_msp::{
+    Command, ModeBox, ModeRange as MspModeRange, MotorProtocol, Pid, Request, Response, Sensors,
+    Status, MAX_FRAME_LEN,
+};
+use scout_rc::{
+    modes::{Mode, ModeRange, MAX_MODE_RANGES},
+    MAX_AUX_CHANNELS,
+};
+
+use crate::{
+    cli::{self, CliTx, COMMAND},
+    control, imu,
+    motors::{self, Protocol, DEFAULT_PWM_RATE_HZ, MOTOR_COUNT},
+    pilot, MOTOR_POLES,
+};
+
+/// The MSP API version configurators see, which decides the messages they
+/// use.
+const API_VERSION: [u8; 2] = [1, 42];
+const FC_VARIANT: [u8; 4] = *b"SCOT";
+/// The NUCLEO-F446RE.
+const BOARD_IDENTIFIER: [u8; 4] = *b"N446";
+/// `GEOMETRY` isn't reported, so legacy tools are told the aircraft is a
+/// quad X, which they only use to draw it.
+const MULTITYPE_QUAD_X: u8 = 3;
+
+/// Betaflight's scaling of its P, I and D gains, in the units of
+/// `scout_control::pid`, so that configurators show familiar numbers.
+const P_SCALE: f32 = 0.032029 / 1000.0;
+const I_SCALE: f32 = 0.244381 / 1000.0;
+const D_SCALE: f32 = 0.000529 / 1000.0;
+
+/// Mode range steps are 25 us, and step 24 is center stick.
+const STEPS_PER_UNIT: f32 = 20.0;
+const CENTER_STEP: f32 = 24.0;
+const MAX_STEP: f32 = 48.0;
+
+/// Time for the acknowledgement to leave the UART before rebooting.
+const REBOOT_DELAY: Duration = Duration::from_millis(10);
+
+pub async fn handle(request: Request, tx: &mut CliTx) {
+    let state = control::latest();
+    let armed = state.map_or(false, |state| state.armed);
+    let command = request.command;
+
+    let mut channels = [0; 4 + MAX_AUX_CHANNELS];
+    let mut motors = [0; MOTOR_COUNT];
+    let mut pids = [Pid::default(); 3];
+    let mut mode_ranges = [MspModeRange::default(); MAX_MODE_RANGES];
+    let response = match (command, state) {
+        (Command::ApiVersion, _) => Response::ApiVersion {
+            major: API_VERSION[0],
+            minor: API_VERSION[1],
+        },
+        (Command::FcVariant, _) => Response::FcVariant(FC_VARIANT),
+        (Command::FcVersion, _) => Response::FcVersion(fc_version()),
+        (Command::BoardInfo, _) => Response::BoardInfo {
+            identifier: BOARD_IDENTIFIER,
+            hardware_revision: 0,
+        },
+        (Command::Ident, _) => {
+            let [major, minor, _] = fc_version();
+            Response::Ident {
+                version: major.saturating_mul(100).saturating_add(minor),
+                multitype: MULTITYPE_QUAD_X,
+            }
+        }
+        (Command::Status, Some(state)) => {
+            let modes = pilot::latest().map(|pilot| pilot.modes);
+            let active = [
+                (ModeBox::Arm, state.armed),
+                (ModeBox::Angle, state.flight_mode == FlightMode::Angle),
+                (ModeBox::Horizon, state.flight_mode == FlightMode::Horizon),
+                (
+                    ModeBox::AltitudeHold,
+                    modes.map_or(false, |modes| modes.contains(Mode::AltitudeHold)),
+                ),
+                (
+                    ModeBox::ReturnToHome,
+                    state.navigation == Some(Navigation::ReturnToHome),
+                ),
+                (
+                    ModeBox::PositionHold,
+                    state.navigation == Some(Navigation::PositionHold),
+                ),
+                (
+                    ModeBox::Beeper,
+                    modes.map_or(false, |modes| modes.contains(Mode::Beeper)),
+                ),
+            ];
+
+            Response::Status(Status {
+                cycle_time_us: (1_000_000 / imu::CONFIG.output_data_rate.hz()) as u16,
+                i2c_errors: 0,
+                sensors: Sensors {
+                    accel: true,
+                    baro: state.altitude.is_some(),
+                    mag: state.mag.is_some(),
+                    gps: state.satellites > 0,
+                    gyro: true,
+                },
+                modes: active
+                    .iter()
+                    .filter(|(_, active)| *active)
+                    .fold(0, |modes, (mode, _)| modes | mode.flag()),
+            })
+        }
+        (Command::RawImu, Some(state)) => {
+            let (gyro, accel) = (state.gyro, state.accel);
+            let mag = state.mag.unwrap_or_default();
+            let gyro = [gyro.x, gyro.y, gyro.z].map(|rate| rate.to_degrees() * 16.4 / 4.0);
+            Response::RawImu {
+                accel: [accel.x, accel.y, accel.z].map(|g| (g * 512.0) as i16),
+                gyro: gyro.map(|rate| rate as i16),
+                mag: [mag.x, mag.y, mag.z].map(|gauss| (gauss * 1090.0) as i16),
+            }
+        }
+        (Command::Motor, Some(state)) => {
+            for (output, motor) in motors.iter_mut().zip(state.motors) {
+                *output = if state.armed {
+                    1000 + (motor * 1000.0) as u16
+                } else {
+                    1000
+                };
+            }
+            Response::Motor(&motors)
+        }
+        (Command::Rc, _) => match pilot::latest() {
+            Some(pilot) => {
+                let rc = pilot.command;
+                let aux = rc.aux.as_slice();
+                let sticks = [rc.roll, rc.pitch, rc.yaw];
+                for (channel, value) in channels.iter_mut().zip(sticks) {
+                    *channel = stick_us(value);
+                }
+                channels[3] = 1000 + (rc.throttle * 1000.0) as u16;
+                for (channel, value) in channels[4..].iter_mut().zip(aux) {
+                    *channel = stick_us(*value);
+                }
+                Response::Rc(&channels[..4 + aux.len()])
+            }
+            None => Response::Rc(&[]),
+        },
+        (Command::Attitude, Some(state)) => {
+            let attitude = state.attitude;
+            let heading = libm::roundf(attitude.yaw.to_degrees()) as i16;
+            Response::Attitude {
+                roll: (attitude.roll.to_degrees() * 10.0) as i16,
+                pitch: (attitude.pitch.to_degrees() * 10.0) as i16,
+                yaw: (heading + 360) % 360,
+            }
+        }
+        (Command::Pid, Some(state)) => {
+            for (pid, gains) in pids.iter_mut().zip(state.rate_gains) {
+                *pid = Pid {
+                    p: libm::roundf(gains.p / P_SCALE) as u8,
+                    i: libm::roundf(gains.i / I_SCALE) as u8,
+                    d: libm::roundf(gains.d / D_SCALE) as u8,
+                };
+            }
+            Response::Pid(&pids)
+        }
+        (Command::ModeRanges, _) => {
+            let mode_map = pilot::mode_map();
+            for (index, slot) in mode_ranges.iter_mut().enumerate() {
+                if let Some(range) = mode_map.get(index) {
+                    *slot = msp_mode_range(&range);
+                }
+            }
+            Response::ModeRanges(&mode_ranges)
+        }
+        (Command::AdvancedConfig, _) => {
+            let protocol = motors::protocol();
+            Response::AdvancedConfig {
+                motor_protocol: msp_motor_protocol(&protocol).id(),
+                motor_rate_hz: pwm_rate_hz(&protocol) as u16,
+            }
+        }
+        (Command::MotorConfig, _) => Response::MotorConfig {
+            motor_count: MOTOR_COUNT as u8,
+            motor_poles: MOTOR_POLES,
+            dshot_telemetry: motors::protocol().bidirectional(),
+        },
+        (Command::BoxNames, _) => Response::BoxNames,
+        (Command::BoxIds, _) => Response::BoxIds,
+        (Command::SetPid(new_pids), Some(state)) if !armed => {
+            let gains = core::array::from_fn(|axis| Gains {
+                p: new_pids[axis].p as f32 * P_SCALE,
+                i: new_pids[axis].i as f32 * I_SCALE,
+                d: new_pids[axis].d as f32 * D_SCALE,
+                // Feedforward isn't part of the message.
+                f: state.rate_gains[axis].f,
+            });
+            COMMAND.send(cli::Command::SetRateGains(gains)).await;
+            Response::Ack(command.code())
+        }
+        // Unused ranges clear the slot, and unsupported modes are refused.
+        (Command::SetModeRange { index, range }, _)
+            if !armed
+                && (index as usize) < MAX_MODE_RANGES
+                && (!range.is_used() || mode_range(&range).is_some()) =>
+        {
+            COMMAND
+                .send(cli::Command::SetModeRange {
+                    index: index as usize,
+                    range: range.is_used().then(|| mode_range(&range)).flatten(),
+                })
+                .await;
+            Response::Ack(command.code())
+        }
+        // Unknown protocols, such as brushed, are refused.
+        (
+            Command::SetAdvancedConfig {
+                motor_protocol,
+                motor_rate_hz,
+            },
+            _,
+        ) if !armed => match MotorProtocol::from_id(motor_protocol) {
+            Some(protocol) => {
+                let current = motors::protocol();
+                let protocol =
+                    motor_protocol_from(protocol, motor_rate_hz as u32, current.bidirectional());
+                if protocol != current {
+                    COMMAND.send(cli::Command::SetMotorProtocol(protocol)).await;
+                }
+                Response::Ack(command.code())
+            }
+            None => Response::Error(command.code()),
+        },
+        (Command::SetMotorConfig { dshot_telemetry }, _) if !armed => {
+            // Bidirectional DShot is the only setting kept, and only applies
+            // to DShot.
+            if let (Some(bidirectional), Protocol::Dshot { speed, .. }) =
+                (dshot_telemetry, motors::protocol())
+            {
+                let protocol = Protocol::Dshot {
+                    speed,
+                    bidirectional,
+                };
+                if protocol != motors::protocol() {
+                    COMMAND.send(cli::Command::SetMotorProtocol(protocol)).await;
+                }
+            }
+            Response::Ack(command.code())
+        }
+        (Command::EepromWrite, _) if !armed => {
+            COMMAND.send(cli::Command::Save).await;
+            Response::Ack(command.code())
+        }
+        (Command::Reboot, _) if !armed => {
+            send(request, &Response::Ack(command.code()), tx).await;
+            Timer::after(REBOOT_DELAY).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        // Live data is unavailable until the control loop has run.
+        _ => Response::Error(command.code()),
+    };
+
+    send(request, &response, tx).await;
+}
+
+async fn send(request: Request, response: &Response<'_>, tx: &mut CliTx) {
+    let mut frame = [0; MAX_FRAME_LEN];
+    let len = response.encode(request.version, &mut frame);
+    cli::write(tx, &frame[..len]).await;
+}
+
+/// Converts a stick or aux channel from -1.0..=1.0 to a pulse width in
+/// microseconds.
+fn stick_us(value: f32) -> u16 {
+    (1500.0 + value * 500.0) as u16
+}
+
+/// Converts a mode range to MSP's steps.
+fn msp_mode_range(range: &ModeRange) -> MspModeRange {
+    let mode = mode_box(range.mode);
+    let step = |value: f32| libm::roundf(value * STEPS_PER_UNIT + CENTER_STEP).clamp(0.0, MAX_STEP);
+
+    MspModeRange {
+        box_id: mode.id(),
+        aux_channel: range.aux_channel as u8,
+        start_step: step(range.start) as u8,
+        end_step: step(range.end) as u8,
+    }
+}
+
+/// Returns `None` for modes which aren't supported.
+fn mode_range(range: &MspModeRange) -> Option<ModeRange> {
+    let requested = ModeBox::from_id(range.box_id)?;
+    let mode = Mode::ALL
+        .into_iter()
+        .find(|mode| mode_box(*mode) == requested)?;
+    let value = |step: u8| (step as f32 - CENTER_STEP) / STEPS_PER_UNIT;
+
+    Some(ModeRange {
+        mode,
+        aux_channel: range.aux_channel as usize,
+        start: value(range.start_step),
+        end: value(range.end_step),
+    })
+}
+
+fn mode_box(mode: Mode) -> ModeBox {
+    match mode {
+        Mode::Arm => ModeBox::Arm,
+        Mode::Angle => ModeBox::Angle,
+        Mode::Horizon => ModeBox::Horizon,
+        Mode::Beeper => ModeBox::Beeper,
+        Mode::AltitudeHold => ModeBox::AltitudeHold,
+        Mode::PositionHold => ModeBox::PositionHold,
+        Mode::ReturnToHome => ModeBox::ReturnToHome,
+    }
+}
+
+fn msp_motor_protocol(protocol: &Protocol) -> MotorProtocol {
+    match protocol {
+        Protocol::Analog(AnalogProtocol::Pwm { .. }) => MotorProtocol::Pwm,
+        Protocol::Analog(AnalogProtocol::Oneshot125) => MotorProtocol::Oneshot125,
+        Protocol::Analog(AnalogProtocol::Oneshot42) => MotorProtocol::Oneshot42,
+        Protocol::Analog(AnalogProtocol::Multishot) => MotorProtocol::Multishot,
+        Protocol::Dshot { speed, .. } => match speed {
+            Speed::Dshot150 => MotorProtocol::Dshot150,
+            Speed::Dshot300 => MotorProtocol::Dshot300,
+            Speed::Dshot600 => MotorProtocol::Dshot600,
+        },
+    }
+}
+
+/// The PWM rate is only used by PWM, and bidirectional only by DShot.
+fn motor_protocol_from(protocol: MotorProtocol, rate_hz: u32, bidirectional: bool) -> Protocol {
+    let dshot = |speed| Protocol::Dshot {
+        speed,
+        bidirectional,
+    };
+    match protocol {
+        MotorProtocol::Pwm => Protocol::Analog(AnalogProtocol::Pwm { rate_hz }),
+        MotorProtocol::Oneshot125 => Protocol::Analog(AnalogProtocol::Oneshot125),
+        MotorProtocol::Oneshot42 => Protocol::Analog(AnalogProtocol::Oneshot42),
+        MotorProtocol::Multishot => Protocol::Analog(AnalogProtocol::Multishot),
+        MotorProtocol::Dshot150 => dshot(Speed::Dshot150),
+        MotorProtocol::Dshot300 => dshot(Speed::Dshot300),
+        MotorProtocol::Dshot600 => dshot(Speed::Dshot600),
+    }
+}
+
+/// Configurators show a rate for every protocol, so the others are given
+/// the default PWM rate.
+fn pwm_rate_hz(protocol: &Protocol) -> u32 {
+    match protocol {
+        Protocol::Analog(AnalogProtocol::Pwm { rate_hz }) => *rate_hz,
+        _ => DEFAULT_PWM_RATE_HZ,
+    }
+}
+
+fn fc_version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR"),
+        env!("CARGO_PKG_VERSION_PATCH"),
+    ]
+    .map(|part| part.parse().unwrap_or(0))
+}
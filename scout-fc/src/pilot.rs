@@ -0,0 +1,88 @@
+//! Pilot input task
+//!
+//! Turns each frame from the receiver into calibrated stick commands and
+//! active modes, for the control loop to pick up with `latest`.
+//!
+//! The mode map starts as the one in the settings, and is replaced with
+//! `set_mode_map` when the CLI or MSP changes it.
+
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use scout_control::angle::FlightMode;
+use scout_rc::{
+    modes::{Mode, ModeMap, ModeSet},
+    RateSetpoint, RcCommand,
+};
+
+use crate::{
+    calibration, cli, receiver,
+    scheduler::{Run, Task},
+    RC_CONFIG,
+};
+
+#[derive(Clone, Copy)]
+pub struct Pilot {
+    pub command: RcCommand,
+    /// Rates requested by the sticks in acro mode.
+    pub acro_setpoint: RateSetpoint,
+    pub modes: ModeSet,
+    pub flight_mode: FlightMode,
+    pub arm_switch: bool,
+    /// When the frame these were derived from arrived.
+    pub time: Instant,
+}
+
+static PILOT: Mutex<CriticalSectionRawMutex, Cell<Option<Pilot>>> = Mutex::new(Cell::new(None));
+
+static MODE_MAP: Mutex<CriticalSectionRawMutex, RefCell<ModeMap>> =
+    Mutex::new(RefCell::new(ModeMap::new()));
+
+/// Returns `None` until the first frame has arrived.
+pub fn latest() -> Option<Pilot> {
+    PILOT.lock(|pilot| pilot.get())
+}
+
+/// Takes effect from the next frame.
+pub fn set_mode_map(mode_map: ModeMap) {
+    MODE_MAP.lock(|map| *map.borrow_mut() = mode_map);
+}
+
+pub fn mode_map() -> ModeMap {
+    MODE_MAP.lock(|map| *map.borrow())
+}
+
+#[embassy_executor::task]
+pub async fn pilot_input() {
+    let mut last_stick_command = None;
+    loop {
+        let rc_frame = receiver::RC_FRAME.wait().await;
+        let run = Run::start(Task::Pilot);
+
+        let command = RC_CONFIG.command(&rc_frame);
+        let modes = MODE_MAP.lock(|map| map.borrow().active_modes(&command));
+        let pilot = Pilot {
+            command,
+            acro_setpoint: RC_CONFIG.rate_setpoint(&command),
+            modes,
+            flight_mode: FlightMode::from_modes(&modes),
+            arm_switch: modes.contains(Mode::Arm),
+            time: Instant::now(),
+        };
+        PILOT.lock(|latest| latest.set(Some(pilot)));
+
+        // Stick commands are acted on once when the sticks reach their
+        // position. The control loop ignores them while armed.
+        let stick_command = calibration::stick_command(&command);
+        if stick_command != last_stick_command {
+            if let Some(command) = stick_command {
+                let _ = cli::COMMAND.try_send(command);
+            }
+        }
+        last_stick_command = stick_command;
+
+        run.finish();
+    }
+}
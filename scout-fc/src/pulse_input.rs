@@ -0,0 +1,144 @@
+//! Timer input capture for PPM and PWM receivers
+//!
+//! TIM3 captures the receiver signals, counting at 1MHz so that captures
+//! are in microseconds. A PPM receiver connects to channel 1 on PB4 (labeled
+//! D5 on the NUCLEO-F446RE). A PWM receiver connects channels 1 to 4 to
+//! PB4 (D5), PB5 (D4), PC8 and PC9.
+
+use cortex_m::peripheral::NVIC;
+use embassy_stm32::{
+    interrupt, pac,
+    pac::Interrupt,
+    peripherals::{PB4, PB5, PC8, PC9, TIM3},
+    rcc,
+    timer::low_level::GeneralPurpose16bitInstance,
+};
+
+use scout_pulse_rx::{ppm, pwm, EdgeTimer};
+use scout_rc::ChannelMap;
+
+use crate::receiver::RC_FRAME;
+
+const PWM_CHANNELS: usize = 4;
+
+/// Alternate function number of the TIM3 channels on the pins used here.
+const TIM3_AF: u8 = 2;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Ppm,
+    Pwm,
+}
+
+// These are only accessed from the TIM3 interrupt, after being initialized.
+static mut MODE: Mode = Mode::Ppm;
+static mut PPM_DECODER: ppm::Decoder = ppm::Decoder::new();
+static mut PWM_DECODER: pwm::Decoder<PWM_CHANNELS> = pwm::Decoder::new();
+static mut EDGE_TIMERS: [EdgeTimer; PWM_CHANNELS] = [
+    EdgeTimer::new(),
+    EdgeTimer::new(),
+    EdgeTimer::new(),
+    EdgeTimer::new(),
+];
+
+pub fn init_ppm(_tim3: TIM3, _pb4: PB4) {
+    // Safety: The TIM3 interrupt is not enabled until the timer is configured,
+    // and taking ownership of the peripherals ensures nothing else uses them.
+    unsafe {
+        MODE = Mode::Ppm;
+        configure_pin(pac::GPIOB, 4, TIM3_AF);
+        configure_timer(1);
+    }
+}
+
+pub fn init_pwm(_tim3: TIM3, _pb4: PB4, _pb5: PB5, _pc8: PC8, _pc9: PC9) {
+    // Safety: The TIM3 interrupt is not enabled until the timer is configured,
+    // and taking ownership of the peripherals ensures nothing else uses them.
+    unsafe {
+        MODE = Mode::Pwm;
+        configure_pin(pac::GPIOB, 4, TIM3_AF);
+        configure_pin(pac::GPIOB, 5, TIM3_AF);
+        configure_pin(pac::GPIOC, 8, TIM3_AF);
+        configure_pin(pac::GPIOC, 9, TIM3_AF);
+        configure_timer(PWM_CHANNELS);
+    }
+}
+
+/// Connects `pin` to the peripheral using alternate function `af`.
+pub unsafe fn configure_pin(port: pac::gpio::Gpio, pin: usize, af: u8) {
+    port.afr(pin / 8).modify(|w| w.set_afr(pin % 8, af));
+    port.moder()
+        .modify(|w| w.set_moder(pin, pac::gpio::vals::Moder::ALTERNATE));
+}
+
+/// Captures rising edges on the first `channels` channels.
+unsafe fn configure_timer(channels: usize) {
+    pac::RCC.apb1enr().modify(|w| w.set_tim3en(true));
+
+    let regs = TIM3::regs_gp16();
+    let timer_hz = rcc::get_freqs().apb1_tim.0;
+    regs.psc()
+        .write(|w| w.set_psc((timer_hz / 1_000_000 - 1) as u16));
+    regs.arr().write(|w| w.set_arr(u16::MAX));
+    // Load the prescaler, which otherwise only takes effect on overflow.
+    regs.egr().write(|w| w.set_ug(true));
+
+    for channel in 0..channels {
+        // Map each capture channel onto its own input.
+        regs.ccmr_input(channel / 2)
+            .modify(|w| w.set_ccs(channel % 2, pac::timer::vals::CcmrInputCcs(0b01)));
+        regs.ccer().modify(|w| {
+            w.set_ccp(channel, false);
+            w.set_cce(channel, true);
+        });
+        regs.dier().modify(|w| w.set_ccie(channel, true));
+    }
+
+    regs.cr1().modify(|w| w.set_cen(true));
+    NVIC::unmask(Interrupt::TIM3);
+}
+
+#[interrupt]
+fn TIM3() {
+    let regs = TIM3::regs_gp16();
+    // Safety: The statics are only accessed from this interrupt, which can't
+    // preempt itself.
+    let (mode, edge_timers) = unsafe { (MODE, &mut EDGE_TIMERS) };
+    let channels = match mode {
+        Mode::Ppm => 1,
+        Mode::Pwm => PWM_CHANNELS,
+    };
+    let status = unsafe { regs.sr().read() };
+
+    for channel in (0..channels).filter(|channel| status.ccif(*channel)) {
+        // Reading the capture clears its interrupt flag.
+        let capture = unsafe { regs.ccr(channel).read().ccr() };
+
+        match mode {
+            Mode::Ppm => {
+                let Some(period) = edge_timers[0].capture(capture) else { continue };
+                if let Some(frame) = unsafe { PPM_DECODER.push(period) } {
+                    RC_FRAME.signal(ChannelMap::AETR.frame(frame.channels()));
+                }
+            }
+            Mode::Pwm => {
+                // Alternate between capturing the rising and falling edges, so
+                // the time between them is the pulse width.
+                let rising = unsafe { !regs.ccer().read().ccp(channel) };
+                unsafe { regs.ccer().modify(|w| w.set_ccp(channel, rising)) };
+
+                let edge_timer = &mut edge_timers[channel];
+                if rising {
+                    edge_timer.reset();
+                    edge_timer.capture(capture);
+                    continue;
+                }
+
+                let Some(width) = edge_timer.capture(capture) else { continue };
+                if let Some(channels) = unsafe { PWM_DECODER.push(channel, width) } {
+                    RC_FRAME.signal(ChannelMap::AETR.frame(&channels));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Radio receiver tasks
+//!
+//! Each supported receiver has a task (or for PPM and PWM receivers, an
+//! interrupt handler in `pulse_input`) which publishes the latest `RcFrame`
+//! to `RC_FRAME`. Frames are not published while a receiver reports that it
+//! has lost the transmitter, so a stale frame means the link is down.
+//! Receivers which report this also set `link_lost`, so that the link is
+//! known to be down without waiting for the frame to go stale.
+//!
+//! Serial receivers are read with DMA until the line goes idle, which
+//! happens between frames, rather than a byte at a time.
+
+use core::cell::Cell;
+
+use defmt::{error, println};
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_stm32::{
+    gpio::Output,
+    peripherals::{DMA2_CH2, DMA2_CH7, PB6, PC7, USART1},
+    usart::{self, UartRx, UartTx},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+
+use scout_nrf24l01::{FourChannelRadioData, SymaX5C};
+use scout_rc::{AxisCalibration, ChannelMap, RcFrame, ThrottleCalibration};
+use scout_serial_rx::{crsf, ibus, sbus};
+
+use crate::{SpiBus1, CRSF_MIN_LINK_QUALITY, CRSF_MIN_RSSI};
+
+pub static RC_FRAME: Signal<CriticalSectionRawMutex, RcFrame> = Signal::new();
+
+static LINK_LOST: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Telemetry waiting to be sent to a CRSF receiver. Senders should drop
+/// telemetry rather than wait when this is full.
+pub static CRSF_TELEMETRY: Channel<CriticalSectionRawMutex, crsf::Telemetry<'static>, 4> =
+    Channel::new();
+
+pub type SymaRadio = SymaX5C<
+    SpiDevice<'static, CriticalSectionRawMutex, SpiBus1, Output<'static, PB6>>,
+    Output<'static, PC7>,
+>;
+
+/// Serial receivers are connected to USART1, with RX on PA10 (labeled D2 on
+/// the NUCLEO-F446RE) and TX on PA9 (labeled D8).
+pub type SerialRx = UartRx<'static, USART1, DMA2_CH2>;
+pub type SerialTx = UartTx<'static, USART1, DMA2_CH7>;
+
+/// Bytes read at once. Reads end early when the line goes idle, so this
+/// only needs to hold a couple of frames.
+const READ_BUF_LEN: usize = 64;
+
+/// The time to send an SBUS byte, which is a start bit, 8 data bits, a
+/// parity bit and 2 stop bits.
+const SBUS_BYTE_TIME: Duration = Duration::from_micros(12 * 1_000_000 / sbus::BAUD_RATE as u64);
+/// SBUS frames are at least this far apart, so a longer silence means the
+/// next byte starts a frame.
+const SBUS_FRAME_GAP: Duration = Duration::from_micros(3000);
+
+#[allow(dead_code)]
+pub enum Receiver {
+    SymaX5C,
+    Sbus,
+    Crsf,
+    Ibus,
+    Ppm,
+    Pwm,
+}
+
+impl Receiver {
+    pub const fn stick_calibration(&self) -> AxisCalibration {
+        match self {
+            Receiver::SymaX5C => FourChannelRadioData::STICK_CALIBRATION,
+            Receiver::Sbus => sbus::STICK_CALIBRATION,
+            Receiver::Crsf => crsf::STICK_CALIBRATION,
+            Receiver::Ibus => ibus::STICK_CALIBRATION,
+            Receiver::Ppm | Receiver::Pwm => scout_pulse_rx::STICK_CALIBRATION,
+        }
+    }
+
+    pub const fn throttle_calibration(&self) -> ThrottleCalibration {
+        match self {
+            Receiver::SymaX5C => FourChannelRadioData::THROTTLE_CALIBRATION,
+            Receiver::Sbus => sbus::THROTTLE_CALIBRATION,
+            Receiver::Crsf => crsf::THROTTLE_CALIBRATION,
+            Receiver::Ibus => ibus::THROTTLE_CALIBRATION,
+            Receiver::Ppm | Receiver::Pwm => scout_pulse_rx::THROTTLE_CALIBRATION,
+        }
+    }
+
+    /// Returns `None` for receivers which are not connected to a UART.
+    pub fn uart_config(&self) -> Option<usart::Config> {
+        let mut config = usart::Config::default();
+        match self {
+            Receiver::SymaX5C | Receiver::Ppm | Receiver::Pwm => return None,
+            Receiver::Sbus => {
+                config.baudrate = sbus::BAUD_RATE;
+                config.parity = usart::Parity::ParityEven;
+                config.stop_bits = usart::StopBits::STOP2;
+            }
+            Receiver::Crsf => config.baudrate = crsf::BAUD_RATE,
+            Receiver::Ibus => config.baudrate = ibus::BAUD_RATE,
+        }
+
+        Some(config)
+    }
+}
+
+/// True while the receiver reports that it has lost the transmitter.
+pub fn link_lost() -> bool {
+    LINK_LOST.lock(|lost| lost.get())
+}
+
+#[embassy_executor::task]
+pub async fn syma_x5c(mut radio: SymaRadio) {
+    loop {
+        match radio.read().await {
+            Ok(Some(radio_data)) => RC_FRAME.signal(RcFrame::from(&radio_data)),
+            Ok(None) => {
+                // No new radio packet available
+            }
+            Err(e) => error!("{:?}", e),
+        }
+
+        Timer::after(Duration::from_millis(1)).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn sbus(mut rx: SerialRx) {
+    let mut parser = sbus::Parser::new();
+    let mut buf = [0; READ_BUF_LEN];
+    let mut last_read = Instant::now();
+    loop {
+        let Some(bytes) = read(&mut rx, &mut buf).await else { continue };
+
+        // The bytes arrived just before the read ended, so the line was
+        // silent for the time since the last read, less the time they took.
+        let now = Instant::now();
+        let chunk_time = SBUS_BYTE_TIME * bytes.len() as u32;
+        if now.duration_since(last_read) >= SBUS_FRAME_GAP + chunk_time {
+            parser.reset();
+        }
+        last_read = now;
+
+        for frame in bytes.iter().filter_map(|byte| parser.push(*byte)) {
+            LINK_LOST.lock(|lost| lost.set(frame.failsafe));
+            // A lost frame repeats the last positions the receiver had, so
+            // doesn't count as a new frame, and failsafe frames carry the
+            // receiver's failsafe positions rather than the sticks.
+            if !frame.failsafe && !frame.frame_lost {
+                RC_FRAME.signal(ChannelMap::AETR.frame(&frame.channels));
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn crsf(mut rx: SerialRx) {
+    let mut parser = crsf::Parser::new();
+    let mut buf = [0; READ_BUF_LEN];
+    loop {
+        let Some(bytes) = read(&mut rx, &mut buf).await else { continue };
+
+        for packet in bytes.iter().filter_map(|byte| parser.push(*byte)) {
+            match packet {
+                // Some receivers keep sending their failsafe positions once
+                // the link is down.
+                crsf::Packet::RcChannels(channels) if !link_lost() => {
+                    RC_FRAME.signal(ChannelMap::AETR.frame(&channels))
+                }
+                crsf::Packet::RcChannels(_) => {}
+                crsf::Packet::LinkStatistics(link_statistics) => {
+                    let lost = crsf_link_lost(&link_statistics);
+                    if lost != link_lost() {
+                        let change = if lost { "lost" } else { "regained" };
+                        println!("Link {}: {:?}", change, link_statistics);
+                    }
+                    LINK_LOST.lock(|link_lost| link_lost.set(lost));
+                }
+            }
+        }
+    }
+}
+
+/// Link statistics arrive several times a second, even after the
+/// transmitter is lost, when the link quality drops to zero.
+fn crsf_link_lost(link_statistics: &crsf::LinkStatistics) -> bool {
+    let antenna = link_statistics.active_antenna as usize % 2;
+    let rssi = -(link_statistics.uplink_rssi[antenna] as i16);
+
+    link_statistics.uplink_link_quality < CRSF_MIN_LINK_QUALITY || rssi < CRSF_MIN_RSSI
+}
+
+#[embassy_executor::task]
+pub async fn crsf_telemetry(mut tx: SerialTx) {
+    let mut buf = [0; crsf::MAX_FRAME_LEN];
+    loop {
+        let len = CRSF_TELEMETRY.recv().await.encode(&mut buf);
+
+        if let Err(e) = tx.write(&buf[..len]).await {
+            error!("{:?}", e);
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn ibus(mut rx: SerialRx) {
+    let mut parser = ibus::Parser::new();
+    let mut buf = [0; READ_BUF_LEN];
+    loop {
+        let Some(bytes) = read(&mut rx, &mut buf).await else { continue };
+
+        for frame in bytes.iter().filter_map(|byte| parser.push(*byte)) {
+            RC_FRAME.signal(ChannelMap::AETR.frame(&frame.channels));
+        }
+    }
+}
+
+/// Reads until the buffer is full or the line goes idle.
+async fn read<'a>(rx: &mut SerialRx, buf: &'a mut [u8; READ_BUF_LEN]) -> Option<&'a [u8]> {
+    match rx.read_until_idle(buf).await {
+        Ok(len) => Some(&buf[..len]),
+        Err(e) => {
+            error!("{:?}", e);
+            None
+        }
+    }
+}
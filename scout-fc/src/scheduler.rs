@@ -0,0 +1,183 @@
+//! Task priorities and timing statistics
+//!
+//! The control loop runs on a high priority executor, woken by the IMU's
+//! data ready interrupt, so it preempts everything else. Pilot input, the
+//! barometer, the magnetometer, the battery monitor, the blackbox, telemetry
+//! and the CLI run in thread mode on the main executor, in the time the
+//! control loop leaves. Interrupts which must not be delayed, such as DShot
+//! telemetry capture and DMA, stay above both.
+//!
+//! Each task records the start and end of every run. From these, the
+//! execution time, the jitter of periodic tasks and overruns, where a run
+//! takes longer than the task's period, are tracked.
+
+use core::cell::RefCell;
+
+use embassy_executor::SendSpawner;
+use embassy_stm32::{
+    executor::InterruptExecutor,
+    interrupt::{self, InterruptExt},
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use static_cell::StaticCell;
+
+use crate::{baro, battery, imu, mag, telemetry};
+
+/// UART4 is unused, so its interrupt drives the high priority executor.
+static HIGH_PRIORITY_EXECUTOR: StaticCell<InterruptExecutor<interrupt::UART4>> = StaticCell::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Task {
+    Control,
+    Pilot,
+    Baro,
+    Mag,
+    Battery,
+    Blackbox,
+    Telemetry,
+    Cli,
+}
+
+impl Task {
+    pub const ALL: [Task; 8] = [
+        Task::Control,
+        Task::Pilot,
+        Task::Baro,
+        Task::Mag,
+        Task::Battery,
+        Task::Blackbox,
+        Task::Telemetry,
+        Task::Cli,
+    ];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Task::Control => "control",
+            Task::Pilot => "pilot",
+            Task::Baro => "baro",
+            Task::Mag => "mag",
+            Task::Battery => "battery",
+            Task::Blackbox => "blackbox",
+            Task::Telemetry => "telemetry",
+            Task::Cli => "cli",
+        }
+    }
+
+    /// Returns `None` for tasks which run when an event arrives rather than
+    /// periodically.
+    pub fn period(&self) -> Option<Duration> {
+        match self {
+            Task::Control => Some(Duration::from_hz(imu::CONFIG.output_data_rate.hz() as u64)),
+            Task::Baro => Some(baro::PERIOD),
+            Task::Mag => Some(mag::PERIOD),
+            Task::Battery => Some(battery::PERIOD),
+            Task::Telemetry => Some(telemetry::PERIOD),
+            Task::Pilot | Task::Blackbox | Task::Cli => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Stats {
+    pub runs: u32,
+    /// Execution time of a run, in microseconds.
+    pub min_us: u32,
+    pub max_us: u32,
+    total_us: u64,
+    /// Largest difference between the period and the time from one run's
+    /// start to the next, in microseconds.
+    pub max_jitter_us: u32,
+    /// Runs which took longer than the period.
+    pub overruns: u32,
+    last_start: Option<Instant>,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            runs: 0,
+            min_us: u32::MAX,
+            max_us: 0,
+            total_us: 0,
+            max_jitter_us: 0,
+            overruns: 0,
+            last_start: None,
+        }
+    }
+
+    pub fn average_us(&self) -> u32 {
+        if self.runs == 0 {
+            return 0;
+        }
+
+        (self.total_us / self.runs as u64) as u32
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<[Stats; Task::ALL.len()]>> =
+    Mutex::new(RefCell::new([Stats::new(); Task::ALL.len()]));
+
+/// A run of a task, which is recorded when it is finished.
+pub struct Run {
+    task: Task,
+    start: Instant,
+}
+
+impl Run {
+    pub fn start(task: Task) -> Self {
+        let start = Instant::now();
+
+        STATS.lock(|stats| {
+            let stats = &mut stats.borrow_mut()[task as usize];
+            if let (Some(period), Some(last_start)) = (task.period(), stats.last_start) {
+                let interval = (start - last_start).as_micros() as i64;
+                let jitter = (interval - period.as_micros() as i64).unsigned_abs() as u32;
+                stats.max_jitter_us = stats.max_jitter_us.max(jitter);
+            }
+            stats.last_start = Some(start);
+        });
+
+        Self { task, start }
+    }
+
+    pub fn finish(self) {
+        let duration = self.start.elapsed();
+        let us = duration.as_micros() as u32;
+
+        STATS.lock(|stats| {
+            let stats = &mut stats.borrow_mut()[self.task as usize];
+            stats.runs += 1;
+            stats.min_us = stats.min_us.min(us);
+            stats.max_us = stats.max_us.max(us);
+            stats.total_us += us as u64;
+            if self.task.period().map_or(false, |period| duration > period) {
+                stats.overruns += 1;
+            }
+        });
+    }
+}
+
+pub fn stats(task: Task) -> Stats {
+    STATS.lock(|stats| stats.borrow()[task as usize])
+}
+
+/// Clears the statistics of every task.
+pub fn reset_stats() {
+    STATS.lock(|stats| *stats.borrow_mut() = [Stats::new(); Task::ALL.len()]);
+}
+
+/// Total overruns of all tasks.
+pub fn overruns() -> u32 {
+    STATS.lock(|stats| stats.borrow().iter().map(|stats| stats.overruns).sum())
+}
+
+/// Starts the high priority executor, for the control loop.
+pub fn start_high_priority() -> SendSpawner {
+    let irq = interrupt::take!(UART4);
+    irq.set_priority(interrupt::Priority::P6);
+
+    HIGH_PRIORITY_EXECUTOR
+        .init(InterruptExecutor::new(irq))
+        .start()
+}
@@ -0,0 +1,329 @@
+//! Settings kept in flash
+//!
+//! Settings are stored in the last flash sector, which is left out of the
+//! program area in `memory.x`. The record starts with a magic number and
+//! version, and ends with a CRC, so that an erased or outdated sector loads
+//! the defaults instead.
+//!
+//! Erasing the sector takes a second or two, so the control loop hands
+//! settings to `save_settings` with `request_save`, and the flash is written
+//! from the main executor. The F446 has a single flash bank, and reading it
+//! stalls while the sector is erased, so the whole flight controller still
+//! pauses for the erase. Saves are only requested while disarmed, and are
+//! held back if the aircraft has been armed since.
+
+use defmt::{error, println};
+use embassy_stm32::flash::Flash;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use scout_ahrs::{
+    calibration::{AccelCalibration, MagCalibration},
+    Vector3,
+};
+use scout_analog_esc::Protocol as AnalogProtocol;
+use scout_control::pid::{self, Gains};
+use scout_dshot::Speed;
+use scout_rc::modes::{Mode, ModeMap, ModeRange, MAX_MODE_RANGES};
+
+use crate::{control, motors::Protocol, MODE_RANGES, MOTOR_PROTOCOL, RATE_PID};
+
+/// Sector 7, the last 128K of flash.
+const SECTOR_OFFSET: u32 = 0x6_0000;
+const SECTOR_SIZE: u32 = 0x2_0000;
+
+const MAGIC: u32 = 0x5343_4647;
+/// Increment when the layout of the record changes.
+const VERSION: u16 = 6;
+
+const FLOATS: usize = 36;
+/// Each mode range slot is stored as the mode's index, or `EMPTY_SLOT`,
+/// the aux channel, and the start and end.
+const MODE_RANGE_LEN: usize = 10;
+const EMPTY_SLOT: u8 = 0xff;
+const MODE_RANGES_OFFSET: usize = 8 + FLOATS * 4;
+/// The motor protocol is stored as its kind, whether DShot is
+/// bidirectional, and the PWM rate.
+const MOTOR_PROTOCOL_OFFSET: usize = MODE_RANGES_OFFSET + MAX_MODE_RANGES * MODE_RANGE_LEN;
+const MOTOR_PROTOCOL_LEN: usize = 4;
+const RECORD_LEN: usize = MOTOR_PROTOCOL_OFFSET + MOTOR_PROTOCOL_LEN + 4;
+
+/// How often a held back save checks whether the aircraft has been disarmed.
+const ARMED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The latest settings waiting to be saved. Only the newest are kept.
+static SAVE: Signal<CriticalSectionRawMutex, Settings> = Signal::new();
+
+/// Saves the settings from `save_settings`, without waiting for the flash.
+pub fn request_save(settings: Settings) {
+    SAVE.signal(settings);
+}
+
+#[embassy_executor::task]
+pub async fn save_settings(mut flash: Flash<'static>) {
+    loop {
+        let settings = SAVE.wait().await;
+
+        while control::latest().map_or(false, |state| state.armed) {
+            Timer::after(ARMED_POLL_INTERVAL).await;
+        }
+        // Newer settings may have arrived while waiting.
+        if SAVE.signaled() {
+            continue;
+        }
+
+        settings.save(&mut flash);
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Settings {
+    /// Gyro bias from the last calibration, in radians per second.
+    pub gyro_bias: Vector3,
+    pub accel: AccelCalibration,
+    /// Roll, pitch and yaw of the board relative to the aircraft, in
+    /// degrees.
+    pub board_alignment: [f32; 3],
+    pub mag: MagCalibration,
+    /// Roll, pitch and yaw rate controller gains, which start as those in
+    /// `RATE_PID`.
+    pub rate_gains: [Gains; 3],
+    /// Which aux channel positions select each mode, which starts as
+    /// `MODE_RANGES`.
+    pub mode_map: ModeMap,
+    /// Which protocol drives the motors from the next boot, which starts as
+    /// `MOTOR_PROTOCOL`.
+    pub motor_protocol: Protocol,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            gyro_bias: Vector3::zero(),
+            accel: AccelCalibration::identity(),
+            board_alignment: [0.0; 3],
+            mag: MagCalibration::identity(),
+            rate_gains: [RATE_PID.roll, RATE_PID.pitch, RATE_PID.yaw],
+            mode_map: default_mode_map(),
+            motor_protocol: MOTOR_PROTOCOL,
+        }
+    }
+}
+
+impl Settings {
+    /// Returns the defaults if no valid settings are stored.
+    pub fn load(flash: &mut Flash<'_>) -> Self {
+        let mut record = [0; RECORD_LEN];
+        if let Err(e) = flash.blocking_read(SECTOR_OFFSET, &mut record) {
+            error!("Failed to read settings: {:?}", e);
+            return Self::default();
+        }
+
+        Self::from_bytes(&record).unwrap_or_else(|| {
+            println!("No stored settings, using defaults");
+            Self::default()
+        })
+    }
+
+    /// `RATE_PID` with the stored gains.
+    pub fn rate_pid(&self) -> pid::Config {
+        let [roll, pitch, yaw] = self.rate_gains;
+
+        pid::Config {
+            roll,
+            pitch,
+            yaw,
+            ..RATE_PID
+        }
+    }
+
+    /// Erasing the sector takes around a second, during which the flight
+    /// controller does nothing else, so this must only be used while
+    /// disarmed. Use `request_save` from the control loop.
+    fn save(&self, flash: &mut Flash<'_>) {
+        let result = flash
+            .blocking_erase(SECTOR_OFFSET, SECTOR_OFFSET + SECTOR_SIZE)
+            .and_then(|()| flash.blocking_write(SECTOR_OFFSET, &self.to_bytes()));
+
+        match result {
+            Ok(()) => println!("Settings saved"),
+            Err(e) => error!("Failed to save settings: {:?}", e),
+        }
+    }
+
+    fn floats(&self) -> [f32; FLOATS] {
+        let Self {
+            gyro_bias: g,
+            accel,
+            board_alignment: a,
+            mag,
+            rate_gains: [r, p, y],
+            mode_map: _,
+            motor_protocol: _,
+        } = *self;
+        let (o, s) = (accel.offset, accel.scale);
+        let (m, [i, j, k]) = (mag.offset, mag.soft_iron);
+
+        [
+            g.x, g.y, g.z, o.x, o.y, o.z, s.x, s.y, s.z, a[0], a[1], a[2], m.x, m.y, m.z, i.x, i.y,
+            i.z, j.x, j.y, j.z, k.x, k.y, k.z, r.p, r.i, r.d, r.f, p.p, p.i, p.d, p.f, y.p, y.i,
+            y.d, y.f,
+        ]
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        for (i, value) in self.floats().iter().enumerate() {
+            record[8 + i * 4..12 + i * 4].copy_from_slice(&value.to_le_bytes());
+        }
+        for i in 0..MAX_MODE_RANGES {
+            let slot = MODE_RANGES_OFFSET + i * MODE_RANGE_LEN;
+            let bytes = &mut record[slot..slot + MODE_RANGE_LEN];
+            match self.mode_map.get(i) {
+                Some(range) => {
+                    bytes[0] = range.mode.index();
+                    bytes[1] = range.aux_channel as u8;
+                    bytes[2..6].copy_from_slice(&range.start.to_le_bytes());
+                    bytes[6..10].copy_from_slice(&range.end.to_le_bytes());
+                }
+                None => bytes[0] = EMPTY_SLOT,
+            }
+        }
+        let bytes = &mut record[MOTOR_PROTOCOL_OFFSET..MOTOR_PROTOCOL_OFFSET + MOTOR_PROTOCOL_LEN];
+        let (kind, bidirectional, rate_hz) = match self.motor_protocol {
+            Protocol::Analog(AnalogProtocol::Pwm { rate_hz }) => {
+                (0, false, rate_hz.min(u16::MAX as u32) as u16)
+            }
+            Protocol::Analog(AnalogProtocol::Oneshot125) => (1, false, 0),
+            Protocol::Analog(AnalogProtocol::Oneshot42) => (2, false, 0),
+            Protocol::Analog(AnalogProtocol::Multishot) => (3, false, 0),
+            Protocol::Dshot {
+                speed,
+                bidirectional,
+            } => {
+                let kind = match speed {
+                    Speed::Dshot150 => 4,
+                    Speed::Dshot300 => 5,
+                    Speed::Dshot600 => 6,
+                };
+                (kind, bidirectional, 0)
+            }
+        };
+        bytes[0] = kind;
+        bytes[1] = bidirectional as u8;
+        bytes[2..4].copy_from_slice(&rate_hz.to_le_bytes());
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        record
+    }
+
+    fn from_bytes(record: &[u8; RECORD_LEN]) -> Option<Self> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                record[offset],
+                record[offset + 1],
+                record[offset + 2],
+                record[offset + 3],
+            ])
+        };
+        if word(0) != MAGIC
+            || u16::from_le_bytes([record[4], record[5]]) != VERSION
+            || word(RECORD_LEN - 4) != crc32(&record[..RECORD_LEN - 4])
+        {
+            return None;
+        }
+
+        let f = |i: usize| f32::from_bits(word(8 + i * 4));
+        let mut mode_map = ModeMap::new();
+        for i in 0..MAX_MODE_RANGES {
+            let slot = MODE_RANGES_OFFSET + i * MODE_RANGE_LEN;
+            // Empty slots, and modes from newer firmware, are left clear.
+            let Some(mode) = Mode::from_index(record[slot]) else { continue };
+            mode_map.set(
+                i,
+                Some(ModeRange {
+                    mode,
+                    aux_channel: record[slot + 1] as usize,
+                    start: f32::from_bits(word(slot + 2)),
+                    end: f32::from_bits(word(slot + 6)),
+                }),
+            );
+        }
+
+        let bytes = &record[MOTOR_PROTOCOL_OFFSET..MOTOR_PROTOCOL_OFFSET + MOTOR_PROTOCOL_LEN];
+        let dshot = |speed| Protocol::Dshot {
+            speed,
+            bidirectional: bytes[1] != 0,
+        };
+        let motor_protocol = match bytes[0] {
+            0 => Protocol::Analog(AnalogProtocol::Pwm {
+                rate_hz: u16::from_le_bytes([bytes[2], bytes[3]]) as u32,
+            }),
+            1 => Protocol::Analog(AnalogProtocol::Oneshot125),
+            2 => Protocol::Analog(AnalogProtocol::Oneshot42),
+            3 => Protocol::Analog(AnalogProtocol::Multishot),
+            4 => dshot(Speed::Dshot150),
+            5 => dshot(Speed::Dshot300),
+            6 => dshot(Speed::Dshot600),
+            // A protocol from newer firmware.
+            _ => MOTOR_PROTOCOL,
+        };
+
+        Some(Self {
+            gyro_bias: Vector3::new(f(0), f(1), f(2)),
+            accel: AccelCalibration {
+                offset: Vector3::new(f(3), f(4), f(5)),
+                scale: Vector3::new(f(6), f(7), f(8)),
+            },
+            board_alignment: [f(9), f(10), f(11)],
+            mag: MagCalibration {
+                offset: Vector3::new(f(12), f(13), f(14)),
+                soft_iron: [
+                    Vector3::new(f(15), f(16), f(17)),
+                    Vector3::new(f(18), f(19), f(20)),
+                    Vector3::new(f(21), f(22), f(23)),
+                ],
+            },
+            rate_gains: core::array::from_fn(|axis| {
+                let term = |i: usize| f(24 + axis * 4 + i);
+                Gains {
+                    p: term(0),
+                    i: term(1),
+                    d: term(2),
+                    f: term(3),
+                }
+            }),
+            mode_map,
+            motor_protocol,
+        })
+    }
+}
+
+fn default_mode_map() -> ModeMap {
+    let mut mode_map = ModeMap::new();
+    for (i, range) in MODE_RANGES.into_iter().enumerate() {
+        mode_map.set(i, Some(range));
+    }
+
+    mode_map
+}
+
+/// The CRC-32 used by zlib and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
@@ -0,0 +1,97 @@
+//! Telemetry task
+//!
+//! Periodically prints the flight controller's state over defmt, and sends
+//! it to the transmitter through a CRSF receiver. Overruns are reported as
+//! they are detected.
+
+use defmt::{println, warn};
+use embassy_time::{Duration, Instant, Timer};
+
+use scout_control::{angle::FlightMode, navigation::Request};
+use scout_serial_rx::crsf;
+
+use crate::{
+    battery, control, pilot,
+    receiver::{self, Receiver},
+    scheduler::{self, Run, Task},
+    RECEIVER,
+};
+
+pub const PERIOD: Duration = Duration::from_millis(100);
+
+#[embassy_executor::task]
+pub async fn telemetry() {
+    let mut next = Instant::now();
+    let mut overruns = 0;
+    loop {
+        next += PERIOD;
+        Timer::at(next).await;
+        let run = Run::start(Task::Telemetry);
+
+        let total_overruns = scheduler::overruns();
+        if total_overruns != overruns {
+            warn!("{} task overruns", total_overruns - overruns);
+            overruns = total_overruns;
+        }
+
+        if let Some(state) = control::latest() {
+            let modes = pilot::latest().map(|pilot| pilot.modes);
+            let battery = battery::latest();
+            let attitude = state.attitude;
+            println!(
+                "{:?} armed: {} {:?} {:?} motors: {:?} rpm: {:?} notches: {:?} {:?} roll: {} pitch: {} yaw: {} mag: {:?} throttle: {} altitude: {:?} climb: {} position: {:?} velocity: {:?} satellites: {} navigation: {:?} {:?} battery: {:?}",
+                state.flight_mode,
+                state.armed,
+                state.rate_setpoint,
+                state.torque,
+                state.motors,
+                state.motor_rpm,
+                state.notch_centers,
+                modes,
+                attitude.roll.to_degrees(),
+                attitude.pitch.to_degrees(),
+                attitude.yaw.to_degrees(),
+                state.mag,
+                state.throttle,
+                state.altitude,
+                state.climb_rate,
+                state.position,
+                state.ground_velocity,
+                state.satellites,
+                state.navigation,
+                state.return_phase,
+                battery,
+            );
+
+            if let Receiver::Crsf = RECEIVER {
+                let flight_mode = match (state.navigation, state.flight_mode) {
+                    (Some(Request::ReturnToHome), _) => "RTH",
+                    (Some(Request::PositionHold), _) => "POSH",
+                    (None, FlightMode::Acro) => "ACRO",
+                    (None, FlightMode::Angle) => "ANGL",
+                    (None, FlightMode::Horizon) => "HOR",
+                };
+                for telemetry in [
+                    crsf::Telemetry::FlightMode(flight_mode),
+                    crsf::Telemetry::Attitude {
+                        pitch: (attitude.pitch * 10000.0) as i16,
+                        roll: (attitude.roll * 10000.0) as i16,
+                        yaw: (attitude.yaw * 10000.0) as i16,
+                    },
+                ] {
+                    let _ = receiver::CRSF_TELEMETRY.try_send(telemetry);
+                }
+                if let Some(battery) = battery {
+                    let _ = receiver::CRSF_TELEMETRY.try_send(crsf::Telemetry::Battery {
+                        voltage: (battery.voltage * 10.0) as u16,
+                        current: (battery.current.unwrap_or(0.0).max(0.0) * 10.0) as u16,
+                        capacity_used: battery.consumed.unwrap_or(0.0) as u32,
+                        remaining: battery.remaining.unwrap_or(0),
+                    });
+                }
+            }
+        }
+
+        run.finish();
+    }
+}
@@ -0,0 +1,301 @@
+//! Step response and noise spectrum
+
+use std::f32::consts::PI;
+
+use scout_filter::Fft;
+
+/// Samples in each segment for the step response, about 4 s at the rate
+/// the flight controller logs at.
+const STEP_SEGMENT: usize = 2048;
+/// Length of the step response, in seconds.
+const STEP_LENGTH: f64 = 0.5;
+/// The response is taken as settled from this time, in seconds.
+const SETTLED_FROM: f64 = 0.2;
+/// Segments where the setpoint never reaches this, in degrees per second,
+/// are left out of the step response, since their response is mostly noise.
+const MIN_SETPOINT: f64 = 20.0;
+/// Regularization of the transfer function, relative to the mean power of
+/// the setpoint, which stops frequencies the setpoint barely contains from
+/// dominating the response.
+const REGULARIZATION: f32 = 0.01;
+
+/// Samples in each segment for the spectrum, which sets its resolution.
+const SPECTRUM_SEGMENT: usize = 1024;
+
+pub struct StepResponse {
+    /// Response to a unit step in the setpoint, one value per sample.
+    pub response: Vec<f64>,
+    /// Number of segments averaged.
+    pub segments: usize,
+    /// Mean of the response once settled, which is 1.0 when the measurement
+    /// follows the setpoint exactly.
+    pub final_value: f64,
+    /// Time for the response to reach half the final value, in seconds.
+    pub delay: Option<f64>,
+    /// Time for the response to go from 10% to 90% of the final value, in
+    /// seconds.
+    pub rise_time: Option<f64>,
+    /// Peak of the response above the final value, as a fraction of it.
+    pub overshoot: f64,
+}
+
+/// Estimates how `measured` responds to a step in `setpoint`, from their
+/// transfer function averaged over half overlapping segments. Returns
+/// `None` if the signals are too short, or the setpoint never moved enough.
+pub fn step_response(setpoint: &[f64], measured: &[f64], sample_hz: f64) -> Option<StepResponse> {
+    const N: usize = STEP_SEGMENT;
+    let length = ((STEP_LENGTH * sample_hz) as usize).min(N);
+    if setpoint.len() < N || length == 0 {
+        return None;
+    }
+
+    let fft = Fft::<N>::new();
+    let window = hann();
+    let mut cross_re = [0.0; N];
+    let mut cross_im = [0.0; N];
+    let mut power = [0.0; N];
+    let mut segments = 0;
+    for start in (0..=setpoint.len() - N).step_by(N / 2) {
+        let setpoint = &setpoint[start..start + N];
+        if setpoint.iter().all(|value| value.abs() < MIN_SETPOINT) {
+            continue;
+        }
+
+        let (in_re, in_im) = transform(&fft, setpoint, &window, 0.0);
+        let (out_re, out_im) = transform(&fft, &measured[start..start + N], &window, 0.0);
+        for k in 0..N {
+            // The output times the conjugate of the input.
+            cross_re[k] += out_re[k] * in_re[k] + out_im[k] * in_im[k];
+            cross_im[k] += out_im[k] * in_re[k] - out_re[k] * in_im[k];
+            power[k] += in_re[k] * in_re[k] + in_im[k] * in_im[k];
+        }
+        segments += 1;
+    }
+    if segments == 0 {
+        return None;
+    }
+
+    let regularization = REGULARIZATION * power.iter().sum::<f32>() / N as f32;
+    let mut impulse_re = [0.0; N];
+    let mut impulse_im = [0.0; N];
+    for k in 0..N {
+        impulse_re[k] = cross_re[k] / (power[k] + regularization);
+        impulse_im[k] = cross_im[k] / (power[k] + regularization);
+    }
+    // Swapping the real and imaginary parts either side of the transform
+    // makes it the inverse, unscaled by the length.
+    fft.transform(&mut impulse_im, &mut impulse_re);
+
+    let mut sum = 0.0;
+    let response: Vec<f64> = impulse_re[..length]
+        .iter()
+        .map(|value| {
+            sum += *value as f64 / N as f64;
+            sum
+        })
+        .collect();
+
+    let settled = &response[((SETTLED_FROM * sample_hz) as usize).min(length - 1)..];
+    let final_value = settled.iter().sum::<f64>() / settled.len() as f64;
+    let crossing = |fraction: f64| {
+        (final_value > 0.0)
+            .then(|| {
+                response
+                    .iter()
+                    .position(|value| *value >= fraction * final_value)
+            })
+            .flatten()
+            .map(|i| i as f64 / sample_hz)
+    };
+    let peak = response
+        .iter()
+        .fold(f64::MIN, |peak, value| peak.max(*value));
+
+    Some(StepResponse {
+        segments,
+        final_value,
+        delay: crossing(0.5),
+        rise_time: crossing(0.9)
+            .zip(crossing(0.1))
+            .map(|(end, start)| end - start),
+        overshoot: (peak - final_value) / final_value.abs(),
+        response,
+    })
+}
+
+pub struct Spectrum {
+    /// Width of each frequency bin, in hertz.
+    pub bin_hz: f64,
+    /// Power spectral density in units squared per hertz, from zero to half
+    /// the sample rate.
+    pub power: Vec<f64>,
+}
+
+impl Spectrum {
+    /// Amplitude spectral density in units per root hertz.
+    pub fn density(&self) -> impl Iterator<Item = f64> + '_ {
+        self.power.iter().map(|power| power.sqrt())
+    }
+
+    /// RMS of the signal above `min_hz`.
+    pub fn rms_above(&self, min_hz: f64) -> f64 {
+        self.bins_above(min_hz)
+            .map(|(_, power)| power * self.bin_hz)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Frequency of the largest peak above `min_hz`, if there is any power
+    /// there.
+    pub fn peak_above(&self, min_hz: f64) -> Option<f64> {
+        self.bins_above(min_hz)
+            .filter(|(_, power)| *power > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(hz, _)| hz)
+    }
+
+    fn bins_above(&self, min_hz: f64) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.power
+            .iter()
+            .enumerate()
+            .map(|(i, power)| (i as f64 * self.bin_hz, *power))
+            .filter(move |(hz, _)| *hz >= min_hz)
+    }
+}
+
+/// Estimates the spectrum of `signal` by Welch's method, averaging half
+/// overlapping segments. Returns `None` if the signal is too short.
+pub fn spectrum(signal: &[f64], sample_hz: f64) -> Option<Spectrum> {
+    const N: usize = SPECTRUM_SEGMENT;
+    if signal.len() < N {
+        return None;
+    }
+
+    let fft = Fft::<N>::new();
+    let window = hann();
+    let window_power: f64 = window.iter().map(|w| (w * w) as f64).sum();
+    let mut power = vec![0.0; N / 2 + 1];
+    let mut segments = 0;
+    for start in (0..=signal.len() - N).step_by(N / 2) {
+        let segment = &signal[start..start + N];
+        let mean = segment.iter().sum::<f64>() / N as f64;
+        let (re, im) = transform(&fft, segment, &window, mean);
+        for ((power, re), im) in power.iter_mut().zip(re).zip(im) {
+            *power += (re * re + im * im) as f64;
+        }
+        segments += 1;
+    }
+
+    // One sided, so every bin but zero and half the sample rate also holds
+    // the power of its negative frequency.
+    let last = power.len() - 1;
+    for (i, power) in power.iter_mut().enumerate() {
+        let sides = if i == 0 || i == last { 1.0 } else { 2.0 };
+        *power *= sides / (segments as f64 * sample_hz * window_power);
+    }
+
+    Some(Spectrum {
+        bin_hz: sample_hz / N as f64,
+        power,
+    })
+}
+
+/// The Hann window, which tapers each segment to zero at its ends to reduce
+/// leakage between frequencies.
+fn hann<const N: usize>() -> [f32; N] {
+    std::array::from_fn(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / N as f32).cos())
+}
+
+/// Transforms `signal` less `mean`, tapered by `window`.
+fn transform<const N: usize>(
+    fft: &Fft<N>,
+    signal: &[f64],
+    window: &[f32; N],
+    mean: f64,
+) -> ([f32; N], [f32; N]) {
+    let mut re = [0.0; N];
+    for ((re, value), w) in re.iter_mut().zip(signal).zip(window) {
+        *re = (value - mean) as f32 * w;
+    }
+    let mut im = [0.0; N];
+    fft.transform(&mut re, &mut im);
+
+    (re, im)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_HZ: f64 = 1024.0;
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() < tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn sine_lands_in_its_bin() {
+        let signal: Vec<f64> = (0..4 * SPECTRUM_SEGMENT)
+            .map(|i| 10.0 + 3.0 * (2.0 * std::f64::consts::PI * 150.0 * i as f64 / SAMPLE_HZ).sin())
+            .collect();
+        let spectrum = spectrum(&signal, SAMPLE_HZ).unwrap();
+
+        assert_eq!(spectrum.bin_hz, 1.0);
+        assert_eq!(spectrum.power.len(), SPECTRUM_SEGMENT / 2 + 1);
+        assert_eq!(spectrum.peak_above(20.0), Some(150.0));
+        // All of its power is there, and the offset is removed.
+        assert_close(spectrum.rms_above(20.0), 3.0 / 2f64.sqrt(), 0.01);
+        assert_close(spectrum.rms_above(0.0), 3.0 / 2f64.sqrt(), 0.01);
+        assert_close(spectrum.rms_above(160.0), 0.0, 1e-3);
+    }
+
+    #[test]
+    fn first_order_step_rises_in_its_time_constant() {
+        // A setpoint stepping between rates at uneven intervals, which the
+        // gyro follows with a time constant of 20 ms.
+        let time_constant = 0.02;
+        let alpha = 1.0 - (-1.0 / (SAMPLE_HZ * time_constant)).exp();
+        let mut seed = 7u32;
+        let mut setpoint = Vec::new();
+        while setpoint.len() < 10 * STEP_SEGMENT {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let rate = (seed >> 16) as f64 / 65536.0 * 800.0 - 400.0;
+            let samples = 100 + (seed & 0xff) as usize;
+            setpoint.resize(setpoint.len() + samples, rate);
+        }
+        let mut gyro = 0.0;
+        let measured: Vec<f64> = setpoint
+            .iter()
+            .map(|setpoint| {
+                gyro += (setpoint - gyro) * alpha;
+                gyro
+            })
+            .collect();
+
+        let step = step_response(&setpoint, &measured, SAMPLE_HZ).unwrap();
+        assert_eq!(step.response.len(), (STEP_LENGTH * SAMPLE_HZ) as usize);
+        // The regularization takes a little off the frequencies the setpoint
+        // holds the least of.
+        assert_close(step.final_value, 1.0, 0.05);
+        assert!(step.overshoot < 0.02, "{}", step.overshoot);
+        // From 10% to 90% takes ln 9 time constants, and half takes ln 2.
+        assert_close(
+            step.rise_time.unwrap(),
+            9f64.ln() * time_constant,
+            2.0 / SAMPLE_HZ,
+        );
+        assert_close(
+            step.delay.unwrap(),
+            2f64.ln() * time_constant,
+            2.0 / SAMPLE_HZ,
+        );
+    }
+
+    #[test]
+    fn needs_stick_input_for_a_step_response() {
+        let hover = vec![5.0; 2 * STEP_SEGMENT];
+        assert!(step_response(&hover, &hover, SAMPLE_HZ).is_none());
+        let short = vec![100.0; STEP_SEGMENT - 1];
+        assert!(step_response(&short, &short, SAMPLE_HZ).is_none());
+    }
+}
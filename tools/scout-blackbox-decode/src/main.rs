@@ -0,0 +1,444 @@
+//! Decodes blackbox logs read from the flight controller with
+//! `blackbox read`.
+//!
+//! Each log is exported as `<prefix>-<n>.csv`, with the step response of
+//! each axis in `<prefix>-<n>-step.csv` and the gyro noise spectrum in
+//! `<prefix>-<n>-spectrum.csv`, and a summary of each is printed. The
+//! prefix defaults to the input file name without its extension.
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use scout_blackbox::{End, Error, Frame, Header, Reader, Record, FIELDS};
+
+mod analysis;
+
+const AXES: [&str; 3] = ["roll", "pitch", "yaw"];
+/// Gyro content below this is mostly the aircraft's intended motion rather
+/// than noise.
+const NOISE_MIN_HZ: f64 = 20.0;
+
+struct Log {
+    /// Byte offset of the log in the input.
+    position: usize,
+    /// `None` if the header was lost.
+    header: Option<Header>,
+    records: Vec<Record>,
+    /// `None` if the log was cut off, for example by a power loss.
+    end: Option<End>,
+    corrupt_bytes: usize,
+    /// Records which were lost after damaged data.
+    lost_records: usize,
+}
+
+impl Log {
+    fn new(position: usize, header: Option<Header>) -> Self {
+        Self {
+            position,
+            header,
+            records: Vec::new(),
+            end: None,
+            corrupt_bytes: 0,
+            lost_records: 0,
+        }
+    }
+
+    /// Sample rate from the header, or from the timestamps without one.
+    fn sample_hz(&self) -> Option<f64> {
+        let interval_us = match self.header {
+            Some(header) => header.interval_us as f64,
+            None => {
+                let [first, .., last] = self.records.as_slice() else { return None };
+                last.time_us.wrapping_sub(first.time_us) as f64 / (self.records.len() - 1) as f64
+            }
+        };
+
+        (interval_us > 0.0).then_some(1e6 / interval_us)
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    let (input, prefix) = match args.as_slice() {
+        [_, input] => (input, Path::new(input).with_extension("")),
+        [_, input, prefix] => (input, PathBuf::from(prefix)),
+        _ => {
+            eprintln!("usage: scout-blackbox-decode <log file> [output prefix]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let bytes = match fs::read(input) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", input, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (logs, skipped) = read_logs(&bytes);
+    if skipped > 0 {
+        println!("skipped {} bytes outside of logs", skipped);
+    }
+    if logs.is_empty() {
+        eprintln!("no logs found");
+        return ExitCode::FAILURE;
+    }
+
+    for (i, log) in logs.iter().enumerate() {
+        let prefix = format!("{}-{}", prefix.display(), i + 1);
+        if let Err(e) = export(log, i + 1, &prefix) {
+            eprintln!("{}: {}", prefix, e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Splits the input into logs. Also returns the number of bytes which
+/// weren't part of any log, such as the line `blackbox read` sends first.
+fn read_logs(bytes: &[u8]) -> (Vec<Log>, usize) {
+    let mut logs: Vec<Log> = Vec::new();
+    // Whether the last log hasn't ended.
+    let mut open = false;
+    let mut skipped = 0;
+
+    let mut reader = Reader::new(bytes);
+    loop {
+        let position = reader.position();
+        let Some(item) = reader.next() else { break };
+
+        match item {
+            Ok(Frame::Header(header)) => {
+                logs.push(Log::new(position, Some(header)));
+                open = true;
+            }
+            Ok(Frame::Record(record)) => {
+                if !open {
+                    logs.push(Log::new(position, None));
+                    open = true;
+                }
+                if let Some(log) = logs.last_mut() {
+                    log.records.push(record);
+                }
+            }
+            Ok(Frame::End(end)) => {
+                if let (true, Some(log)) = (open, logs.last_mut()) {
+                    log.end = Some(end);
+                }
+                open = false;
+            }
+            Err(Error::Corrupt { len, .. }) => match (open, logs.last_mut()) {
+                (true, Some(log)) => log.corrupt_bytes += len,
+                _ => skipped += len,
+            },
+            Err(Error::MissingKeyframe { .. }) => {
+                if let (true, Some(log)) = (open, logs.last_mut()) {
+                    log.lost_records += 1;
+                }
+            }
+            Err(Error::UnsupportedVersion { position, version }) => {
+                println!(
+                    "skipped the log at byte {}, which is version {} of the format",
+                    position, version
+                );
+                open = false;
+            }
+        }
+    }
+
+    (logs, skipped)
+}
+
+fn export(log: &Log, number: usize, prefix: &str) -> Result<(), std::io::Error> {
+    let sample_hz = log.sample_hz();
+    let duration = match log.records.as_slice() {
+        [first, .., last] => last.time_us.wrapping_sub(first.time_us) as f64 / 1e6,
+        _ => 0.0,
+    };
+    println!(
+        "log {} at byte {}: {:.1} s, {} records at {}",
+        number,
+        log.position,
+        duration,
+        log.records.len(),
+        sample_hz.map_or("an unknown rate".to_string(), |hz| format!("{:.0} Hz", hz)),
+    );
+    match log.end {
+        Some(end) => {
+            println!("  {} dropped while recording", end.dropped);
+            if end.records as usize != log.records.len() + log.lost_records {
+                println!(
+                    "  {} records were written, but {} were found",
+                    end.records,
+                    log.records.len() + log.lost_records
+                );
+            }
+        }
+        None => println!("  no end frame, so the log was cut off"),
+    }
+    if log.header.is_none() {
+        println!("  the header was lost");
+    }
+    if log.corrupt_bytes > 0 || log.lost_records > 0 {
+        println!(
+            "  {} bytes damaged, {} records lost",
+            log.corrupt_bytes, log.lost_records
+        );
+    }
+    let backwards = log
+        .records
+        .windows(2)
+        .filter(|pair| pair[1].time_us < pair[0].time_us)
+        .count();
+    if backwards > 0 {
+        println!("  time goes backwards {} times", backwards);
+    }
+
+    fs::write(format!("{}.csv", prefix), records_csv(&log.records))?;
+
+    let Some(sample_hz) = sample_hz else { return Ok(()) };
+    let axis = |values: fn(&Record) -> [f32; 3], axis: usize| -> Vec<f64> {
+        log.records
+            .iter()
+            .map(|record| values(record)[axis] as f64)
+            .collect()
+    };
+    let mut steps = Vec::new();
+    let mut spectra = Vec::new();
+    for (i, name) in AXES.iter().enumerate() {
+        let gyro = axis(|record| record.gyro, i);
+        let step = analysis::step_response(&axis(|record| record.setpoint, i), &gyro, sample_hz);
+        let spectrum = analysis::spectrum(&gyro, sample_hz);
+
+        let mut summary = format!("  {}: ", name);
+        let _ = match &step {
+            Some(step) => write!(
+                summary,
+                "delay {} ms, rise {} ms, overshoot {:.0}%, settles at {:.2} ({} segments)",
+                milliseconds(step.delay),
+                milliseconds(step.rise_time),
+                step.overshoot * 100.0,
+                step.final_value,
+                step.segments,
+            ),
+            None => write!(summary, "not enough stick input for a step response"),
+        };
+        if let Some(spectrum) = &spectrum {
+            let _ = write!(
+                summary,
+                "; noise {:.2} deg/s RMS above {} Hz",
+                spectrum.rms_above(NOISE_MIN_HZ),
+                NOISE_MIN_HZ
+            );
+            if let Some(peak) = spectrum.peak_above(NOISE_MIN_HZ) {
+                let _ = write!(summary, ", peak at {:.0} Hz", peak);
+            }
+        }
+        println!("{}", summary);
+
+        steps.push(step.map(|step| step.response));
+        spectra.push(spectrum);
+    }
+
+    fs::write(
+        format!("{}-step.csv", prefix),
+        axes_csv("time (s)", 1.0 / sample_hz, &steps),
+    )?;
+    let bin_hz = spectra
+        .iter()
+        .flatten()
+        .map(|spectrum| spectrum.bin_hz)
+        .next();
+    let density: Vec<Option<Vec<f64>>> = spectra
+        .iter()
+        .map(|spectrum| {
+            spectrum
+                .as_ref()
+                .map(|spectrum| spectrum.density().collect())
+        })
+        .collect();
+    if let Some(bin_hz) = bin_hz {
+        fs::write(
+            format!("{}-spectrum.csv", prefix),
+            axes_csv("frequency (Hz)", bin_hz, &density),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Every field, with the time in seconds, and values printed at their
+/// resolution.
+fn records_csv(records: &[Record]) -> String {
+    let decimals = FIELDS.map(|field| (-field.resolution.log10()).round().max(0.0) as usize);
+
+    let mut csv = String::from("time (s)");
+    for field in &FIELDS[1..] {
+        let _ = match field.unit {
+            "" => write!(csv, ",{}", field.name),
+            unit => write!(csv, ",{} ({})", field.name, unit),
+        };
+    }
+    csv.push('\n');
+
+    for record in records {
+        let fields = record.to_fields();
+        let _ = write!(csv, "{:.6}", record.time_us as f64 / 1e6);
+        for ((value, field), decimals) in fields.iter().zip(&FIELDS).zip(decimals).skip(1) {
+            let _ = write!(
+                csv,
+                ",{:.*}",
+                decimals,
+                *value as f64 * field.resolution as f64
+            );
+        }
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// A column for each axis against `x`, which advances by `step` each row.
+/// Axes without values are left empty.
+fn axes_csv(x: &str, step: f64, axes: &[Option<Vec<f64>>]) -> String {
+    let mut csv = format!("{},{}\n", x, AXES.join(","));
+    let rows = axes.iter().flatten().map(Vec::len).max().unwrap_or(0);
+    for row in 0..rows {
+        let _ = write!(csv, "{:.6}", row as f64 * step);
+        for axis in axes {
+            match axis.as_ref().and_then(|values| values.get(row)) {
+                Some(value) => {
+                    let _ = write!(csv, ",{:.6}", value);
+                }
+                None => csv.push(','),
+            }
+        }
+        csv.push('\n');
+    }
+
+    csv
+}
+
+fn milliseconds(seconds: Option<f64>) -> String {
+    seconds.map_or("-".to_string(), |seconds| {
+        format!("{:.0}", seconds * 1000.0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use scout_blackbox::{Encoder, KEYFRAME_INTERVAL, MAX_FRAME_LEN};
+
+    use super::*;
+
+    /// Encodes a log of `records` records, returning the start of each
+    /// record's frame.
+    fn encode(bytes: &mut Vec<u8>, records: u32, end: bool) -> Vec<usize> {
+        let mut encoder = Encoder::new();
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = encoder.header(&Header { interval_us: 2000 }, &mut buf);
+        bytes.extend(&buf[..len]);
+
+        let mut frames = Vec::new();
+        for i in 0..records {
+            let record = Record {
+                time_us: i * 2000,
+                gyro: [i as f32, 0.0, 0.0],
+                ..Record::default()
+            };
+            frames.push(bytes.len());
+            let len = encoder.record(&record, &mut buf);
+            bytes.extend(&buf[..len]);
+        }
+
+        if end {
+            let len = encoder.end(0, &mut buf);
+            bytes.extend(&buf[..len]);
+        }
+
+        frames
+    }
+
+    #[test]
+    fn splits_logs_and_counts_damage() {
+        let mut bytes = b"blackbox read\r\n".to_vec();
+        encode(&mut bytes, 40, true);
+        let second = bytes.len();
+        let frames = encode(&mut bytes, 40, false);
+        // Damage a delta frame, losing the records up to the next keyframe,
+        // then cut the log off partway through a frame.
+        bytes[frames[5] + 4] ^= 0x01;
+        bytes.truncate(frames[39] + 3);
+
+        let (logs, skipped) = read_logs(&bytes);
+        assert_eq!(skipped, b"blackbox read\r\n".len());
+        assert_eq!(logs.len(), 2);
+
+        let log = &logs[0];
+        assert_eq!(log.position, skipped);
+        assert_eq!(log.records.len(), 40);
+        assert_eq!(log.end.map(|end| end.records), Some(40));
+        assert_eq!((log.corrupt_bytes, log.lost_records), (0, 0));
+        assert_eq!(log.sample_hz(), Some(500.0));
+
+        let log = &logs[1];
+        assert_eq!(log.position, second);
+        assert!(log.end.is_none());
+        let keyframe = KEYFRAME_INTERVAL as usize;
+        assert_eq!(log.lost_records, keyframe - 6);
+        assert_eq!(log.records.len(), 39 - 1 - (keyframe - 6));
+        assert_eq!(
+            log.corrupt_bytes,
+            frames[6] - frames[5] + bytes.len() - frames[39]
+        );
+        assert_eq!(log.records[4].time_us, 4 * 2000);
+        assert_eq!(log.records[5].time_us, KEYFRAME_INTERVAL * 2000);
+    }
+
+    #[test]
+    fn log_without_a_header_takes_its_rate_from_the_timestamps() {
+        let mut bytes = Vec::new();
+        let frames = encode(&mut bytes, 40, true);
+        let (logs, skipped) = read_logs(&bytes[frames[0]..]);
+
+        assert_eq!(skipped, 0);
+        assert!(logs[0].header.is_none());
+        assert_eq!(logs[0].sample_hz(), Some(500.0));
+    }
+
+    #[test]
+    fn csv_is_in_units() {
+        let record = Record {
+            time_us: 1_234_567,
+            gyro: [123.44, -0.06, 0.0],
+            setpoint: [-400.0, 0.0, 12.5],
+            pid: [[0.12345, -0.5, 0.0, 0.0], [0.0; 4], [0.0; 4]],
+            motors: [0.4567, 1.0, 0.0, 0.0],
+            rc: [-1.0, 0.0, 0.0, 0.25],
+        };
+        let csv = records_csv(&[record]);
+        let mut lines = csv.lines();
+
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("time (s),gyro_roll (deg/s),gyro_pitch (deg/s),"));
+        assert!(header.ends_with(",rc_yaw,rc_throttle"));
+
+        let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+        assert_eq!(row.len(), FIELDS.len());
+        assert_eq!(
+            &row[..7],
+            ["1.234567", "123.4", "-0.1", "0.0", "-400.0", "0.0", "12.5"]
+        );
+        assert_eq!(&row[7..9], ["0.1235", "-0.5000"]);
+        assert_eq!(&row[19..21], ["0.457", "1.000"]);
+        assert_eq!(&row[23..], ["-1.000", "0.000", "0.000", "0.250"]);
+        assert!(lines.next().is_none());
+    }
+}